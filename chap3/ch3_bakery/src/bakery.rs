@@ -0,0 +1,220 @@
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+
+// N 個のスレッドで共有するベーカリーロック
+// 保護対象データを保持し、ロック獲得中のみガード経由でアクセスできる
+pub struct BakeryLock<T, const N: usize> {
+    entering: [AtomicBool; N],
+    tickets: [AtomicU64; N], // 0 はチケットを持っていないことを表す
+    // lock_current で各スレッドに割り当てたスロット。0 は未割り当て
+    owners: [AtomicU64; N],
+    // lock_current で各スロットのロックを獲得中か
+    // 同じスレッドから再度呼び出した場合に、同じスレッド番号で lock しないようにする
+    in_use: [AtomicBool; N],
+    // スレッド番号ごとの、ロック獲得時に待機した相手のチケットとの差の最大値
+    // 待機時間の目安として、デバッグビルドでのみ記録する
+    #[cfg(debug_assertions)]
+    max_waits: [AtomicU64; N],
+    data: UnsafeCell<T>,
+}
+
+// ロック管理用の型
+pub struct BakeryLockGuard<'a, T, const N: usize> {
+    lock: &'a BakeryLock<T, N>,
+    idx: usize,
+}
+
+// BakeryLock型はスレッド間で共有可能と設定
+unsafe impl<T, const N: usize> Sync for BakeryLock<T, N> {}
+unsafe impl<T, const N: usize> Send for BakeryLock<T, N> {}
+
+// スレッドを識別するための番号。0 は使用しない
+static NEXT_THREAD_KEY: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static THREAD_KEY: u64 = NEXT_THREAD_KEY.fetch_add(1, Ordering::Relaxed);
+}
+
+impl<T, const N: usize> BakeryLock<T, N> {
+    pub fn new(v: T) -> Self {
+        BakeryLock {
+            entering: std::array::from_fn(|_| AtomicBool::new(false)),
+            tickets: std::array::from_fn(|_| AtomicU64::new(0)),
+            owners: std::array::from_fn(|_| AtomicU64::new(0)),
+            in_use: std::array::from_fn(|_| AtomicBool::new(false)),
+            #[cfg(debug_assertions)]
+            max_waits: std::array::from_fn(|_| AtomicU64::new(0)),
+            data: UnsafeCell::new(v),
+        }
+    }
+
+    // ロック関数。idx はスレッド番号
+    // 安全性: 同じ idx で獲得したガードが存在する間に、他のスレッドや同じスレッドから
+    // 同じ idx で lock を呼び出してはならない。チケットが上書きされ、相互排除されなくなる
+    pub unsafe fn lock(&self, idx: usize) -> BakeryLockGuard<'_, T, N> {
+        assert!(idx < N);
+
+        // entering[idx] は、ticket を取得中であることを示すために true にする
+        fence(Ordering::SeqCst);
+        self.entering[idx].store(true, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+
+        let max = self
+            .tickets
+            .iter()
+            .map(|t| t.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or(0);
+
+        let ticket = max + 1;
+        self.tickets[idx].store(ticket, Ordering::Relaxed);
+
+        fence(Ordering::SeqCst);
+        self.entering[idx].store(false, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+
+        // ここから待機処理
+        #[cfg(debug_assertions)]
+        let mut max_wait = 0;
+        for i in 0..N {
+            if i == idx {
+                continue;
+            }
+
+            // スレッドiがチケット取得中なら待機
+            while self.entering[i].load(Ordering::Relaxed) {
+                std::hint::spin_loop();
+            }
+
+            // チケットが同じ場合はスレッド番号の小さい方を優先する
+            loop {
+                let t = self.tickets[i].load(Ordering::Relaxed);
+                if t == 0 || ticket < t || (ticket == t && idx < i) {
+                    break;
+                }
+                #[cfg(debug_assertions)]
+                {
+                    max_wait = max_wait.max(ticket - t);
+                }
+                std::hint::spin_loop();
+            }
+        }
+        #[cfg(debug_assertions)]
+        self.max_waits[idx].fetch_max(max_wait, Ordering::Relaxed);
+
+        fence(Ordering::SeqCst);
+        BakeryLockGuard { lock: self, idx }
+    }
+
+    // スレッド番号ごとの、ロック獲得時に待機した相手のチケットとの差の最大値
+    // 先に来たスレッドから順に獲得するため、差はスレッド数程度に収まる
+    // デバッグビルドでのみ使用可能
+    #[cfg(debug_assertions)]
+    #[allow(dead_code)] // main では使用しない
+    pub fn stats(&self) -> [u64; N] {
+        std::array::from_fn(|i| self.max_waits[i].load(Ordering::Relaxed))
+    }
+
+    // 呼び出したスレッドに割り当てたスロットをスレッド番号としてロック
+    // 初回の呼び出し時に空いているスロットを割り当て、以降は同じスロットを使う
+    // 割り当てたスロットは解放しないため、N 個を超えるスレッドから呼び出すと panic
+    // ガードを保持したまま同じスレッドから再度呼び出した場合も panic
+    #[allow(dead_code)] // main では使用しない
+    pub fn lock_current(&self) -> BakeryLockGuard<'_, T, N> {
+        let key = THREAD_KEY.with(|k| *k);
+        let idx = match self
+            .owners
+            .iter()
+            .position(|o| o.load(Ordering::Relaxed) == key)
+        {
+            Some(idx) => idx,
+            None => self
+                .owners
+                .iter()
+                .position(|o| {
+                    o.compare_exchange(0, key, Ordering::Relaxed, Ordering::Relaxed)
+                        .is_ok()
+                })
+                .expect("too many threads for the bakery lock"),
+        };
+        assert!(
+            !self.in_use[idx].swap(true, Ordering::Relaxed),
+            "lock_current: the slot is already in use"
+        );
+        // スロットは呼び出したスレッドのみが使用し、獲得中でないことを確認済み
+        unsafe { self.lock(idx) }
+    }
+}
+
+impl<'a, T, const N: usize> Drop for BakeryLockGuard<'a, T, N> {
+    fn drop(&mut self) {
+        fence(Ordering::SeqCst);
+        self.lock.tickets[self.idx].store(0, Ordering::Relaxed);
+        self.lock.in_use[self.idx].store(false, Ordering::Relaxed);
+    }
+}
+
+impl<'a, T, const N: usize> Deref for BakeryLockGuard<'a, T, N> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T, const N: usize> DerefMut for BakeryLockGuard<'a, T, N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_stats() {
+        const NUM_THREADS: usize = 4;
+        const NUM_LOOP: usize = 200;
+        let lock = Arc::new(BakeryLock::<u64, NUM_THREADS>::new(0));
+        let v: Vec<_> = (0..NUM_THREADS)
+            .map(|i| {
+                let lock = lock.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..NUM_LOOP {
+                        let mut data = unsafe { lock.lock(i) };
+                        *data += 1;
+                    }
+                })
+            })
+            .collect();
+        for t in v {
+            t.join().unwrap();
+        }
+        assert_eq!(*unsafe { lock.lock(0) }, (NUM_THREADS * NUM_LOOP) as u64);
+
+        // 待機中に他のスレッドに追い越されないため、ループ回数によらず差はスレッド数以下
+        for wait in lock.stats() {
+            assert!(wait <= NUM_THREADS as u64, "stats = {:?}", lock.stats());
+        }
+    }
+
+    #[test]
+    fn test_lock_current() {
+        let lock = BakeryLock::<u64, 2>::new(0);
+        // ガードを破棄した後は、同じスレッドから再度獲得できる
+        *lock.lock_current() += 1;
+        *lock.lock_current() += 1;
+        assert_eq!(*lock.lock_current(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "lock_current: the slot is already in use")]
+    fn test_lock_current_reentrant() {
+        let lock = BakeryLock::<u64, 2>::new(0);
+        let _guard = lock.lock_current();
+        let _nested = lock.lock_current();
+    }
+}
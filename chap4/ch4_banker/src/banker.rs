@@ -1,4 +1,8 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 
 // 銀行家のアルゴリズム
 #[derive(Debug)]
@@ -25,8 +29,16 @@ impl<const NUM_RESOURCES: usize, const NUM_THREADS: usize> Resource<NUM_RESOURCE
 
     // 現在の状態がデッドロック or 飢餓状態に陥らないか
     fn is_safe(&self) -> bool {
+        self.safe_sequence().is_some()
+    }
+
+    // すべてのスレッドがリソースの取得と解放に成功する順序 (安全系列) をシミュレートする
+    // safe でない場合は None
+    fn safe_sequence(&self) -> Option<Vec<usize>> {
         // 各スレッドがリソース取得と解放に成功したか
         let mut finish = [false; NUM_THREADS];
+        // リソース取得と解放に成功した順のスレッド番号
+        let mut sequence = Vec::with_capacity(NUM_THREADS);
         // 利用可能なリソースのシミュレート値
         let mut available_resource = self.available_resource;
 
@@ -63,6 +75,7 @@ impl<const NUM_RESOURCES: usize, const NUM_THREADS: usize> Resource<NUM_RESOURCE
                 if is_available {
                     num_true += 1;
                     finish[i] = true;
+                    sequence.push(i);
                     // 必要なリソースをすべて借り切ったので、すべて返却する
                     for (available, alocation) in available_resource.iter_mut().zip(aloc) {
                         *available += *alocation;
@@ -73,8 +86,8 @@ impl<const NUM_RESOURCES: usize, const NUM_THREADS: usize> Resource<NUM_RESOURCE
             }
 
             match num_true {
-                0 => return false,
-                num if num == NUM_THREADS => return true,
+                0 => return None,
+                num if num == NUM_THREADS => return Some(sequence),
                 _ => continue,
             }
         }
@@ -128,6 +141,26 @@ impl<const NUM_RESOURCES: usize, const NUM_THREADS: usize> Resource<NUM_RESOURCE
         }
     }
 
+    // thread_id 番目のスレッドが必要とする resource_id 番目のリソースの最大値を変更
+    // 確保済みの量を下回る場合や、変更後の状態が safe でない場合は変更せずに false をリターン
+    fn set_max_need(&mut self, thread_id: usize, resource_id: usize, new_max: usize) -> bool {
+        assert!(thread_id < NUM_THREADS && resource_id < NUM_RESOURCES);
+
+        if new_max < self.allocation_for_threads[thread_id][resource_id] {
+            return false;
+        }
+
+        let old = self.needed_for_threads[thread_id][resource_id];
+        self.needed_for_threads[thread_id][resource_id] = new_max;
+        if self.is_safe() {
+            true
+        } else {
+            // 遷移先が safe 状態じゃなかったので、状態を戻す
+            self.needed_for_threads[thread_id][resource_id] = old;
+            false
+        }
+    }
+
     fn release(&mut self, t_id: usize, r_id: usize) {
         assert!(t_id < NUM_THREADS && r_id < NUM_RESOURCES);
 
@@ -140,9 +173,17 @@ impl<const NUM_RESOURCES: usize, const NUM_THREADS: usize> Resource<NUM_RESOURCE
     }
 }
 
+// Banker が Mutex で保護する状態
+struct State<const NUM_RESOURCES: usize, const NUM_THREADS: usize> {
+    resource: Resource<NUM_RESOURCES, NUM_THREADS>,
+    // take_async で待機中のタスクの Waker。(スレッド番号, リソース番号) をキーに保持
+    // リソースと同じロックで保護し、release との間で通知を取りこぼさないようにする
+    wakers: HashMap<(usize, usize), Waker>,
+}
+
 #[derive(Clone)]
 pub struct Banker<const NUM_RESOURCES: usize, const NUM_THREADS: usize> {
-    resource: Arc<Mutex<Resource<NUM_RESOURCES, NUM_THREADS>>>,
+    state: Arc<Mutex<State<NUM_RESOURCES, NUM_THREADS>>>,
 }
 
 impl<const NUM_RESOURCES: usize, const NUM_THREADS: usize> Banker<NUM_RESOURCES, NUM_THREADS> {
@@ -151,18 +192,102 @@ impl<const NUM_RESOURCES: usize, const NUM_THREADS: usize> Banker<NUM_RESOURCES,
         needed_for_threads: [[usize; NUM_RESOURCES]; NUM_THREADS],
     ) -> Self {
         Banker {
-            resource: Arc::new(Mutex::new(Resource::new(available, needed_for_threads))),
+            state: Arc::new(Mutex::new(State {
+                resource: Resource::new(available, needed_for_threads),
+                wakers: HashMap::new(),
+            })),
         }
     }
 
     pub fn take(&self, t_id: usize, r_id: usize) -> bool {
-        let mut r = self.resource.lock().unwrap();
-        r.take(t_id, r_id)
+        let mut state = self.state.lock().unwrap();
+        state.resource.take(t_id, r_id)
+    }
+
+    // take が成功するまで待機する Future をリターン
+    // 取得できない間はビジーループせずに Waker を登録し、release で起こされると再度 take を試みる
+    #[allow(dead_code)] // main では使用しない
+    pub fn take_async(
+        &self,
+        t_id: usize,
+        r_id: usize,
+    ) -> TakeAsync<'_, NUM_RESOURCES, NUM_THREADS> {
+        TakeAsync {
+            banker: self,
+            t_id,
+            r_id,
+        }
+    }
+
+    // 現在の状態の安全系列。各スレッドが残りのリソースを取得して終了できる順にスレッド番号を並べたもの
+    // safe でない場合は None
+    #[allow(dead_code)] // main では使用しない
+    pub fn safety_trace(&self) -> Option<Vec<usize>> {
+        self.state.lock().unwrap().resource.safe_sequence()
+    }
+
+    // t_id 番目のスレッドが必要とする r_id 番目のリソースの最大値を変更
+    // 確保済みの量を下回る場合や、変更すると safe でなくなる場合は変更せずに false をリターン
+    #[allow(dead_code)] // main では使用しない
+    pub fn set_max_need(&self, t_id: usize, r_id: usize, new_max: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.resource.set_max_need(t_id, r_id, new_max) {
+            return false;
+        }
+
+        // 最大値を下げると他のスレッドが取得可能になる場合があるため、待機中のタスクをすべて起こす
+        for (_, waker) in state.wakers.drain() {
+            waker.wake();
+        }
+        true
     }
 
     pub fn release(&self, t_id: usize, r_id: usize) {
-        let mut r = self.resource.lock().unwrap();
-        r.release(t_id, r_id);
+        let mut state = self.state.lock().unwrap();
+        state.resource.release(t_id, r_id);
+
+        // 割り当て可能かどうかはすべてのスレッドの状態に依存するため、待機中のタスクをすべて起こす
+        // 取得できなかったタスクは再度 Waker を登録する
+        for (_, waker) in state.wakers.drain() {
+            waker.wake();
+        }
+    }
+}
+
+// Banker::take_async の Future
+pub struct TakeAsync<'a, const NUM_RESOURCES: usize, const NUM_THREADS: usize> {
+    banker: &'a Banker<NUM_RESOURCES, NUM_THREADS>,
+    t_id: usize,
+    r_id: usize,
+}
+
+impl<const NUM_RESOURCES: usize, const NUM_THREADS: usize> Future
+    for TakeAsync<'_, NUM_RESOURCES, NUM_THREADS>
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.banker.state.lock().unwrap();
+        if state.resource.take(self.t_id, self.r_id) {
+            return Poll::Ready(());
+        }
+
+        // release されるまで待機
+        state
+            .wakers
+            .insert((self.t_id, self.r_id), cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<const NUM_RESOURCES: usize, const NUM_THREADS: usize> Drop
+    for TakeAsync<'_, NUM_RESOURCES, NUM_THREADS>
+{
+    fn drop(&mut self) {
+        // 完了前に破棄された場合に Waker が残らないように取り除く
+        if let Ok(mut state) = self.banker.state.lock() {
+            state.wakers.remove(&(self.t_id, self.r_id));
+        }
     }
 }
 
@@ -191,4 +316,121 @@ mod test {
 
         assert!(resource.is_safe())
     }
+
+    #[test]
+    fn test_safe_sequence() {
+        let resource = Resource {
+            available_resource: [3, 3, 2],
+            allocation_for_threads: [[0, 1, 0], [2, 0, 0], [3, 0, 2], [2, 1, 1], [0, 0, 2]],
+            needed_for_threads: [[7, 5, 3], [3, 2, 2], [9, 0, 2], [2, 2, 2], [4, 3, 3]],
+        };
+        assert_eq!(resource.safe_sequence(), Some(vec![1, 3, 0, 2, 4]));
+
+        // どのスレッドも残りのリソースを取得できない
+        let resource = Resource {
+            available_resource: [0, 0],
+            allocation_for_threads: [[1, 0], [0, 1]],
+            needed_for_threads: [[1, 1], [1, 1]],
+        };
+        assert_eq!(resource.safe_sequence(), None);
+        assert!(!resource.is_safe());
+    }
+
+    #[test]
+    fn test_safety_trace() {
+        let banker = Banker::<2, 2>::new([1, 1], [[1, 1], [1, 1]]);
+        assert_eq!(banker.safety_trace(), Some(vec![0, 1]));
+
+        // スレッド 1 がリソースを取得すると、スレッド 1 が先に終了する必要がある
+        assert!(banker.take(1, 0));
+        assert_eq!(banker.safety_trace(), Some(vec![1, 0]));
+        // unsafe になる取得は拒否される
+        assert!(!banker.take(0, 1));
+        assert_eq!(banker.safety_trace(), Some(vec![1, 0]));
+    }
+
+    #[test]
+    fn test_set_max_need() {
+        let banker = Banker::<1, 2>::new([2], [[1], [1]]);
+        assert!(banker.take(0, 0));
+
+        // スレッド 0 が終了すれば足りるため、safe のまま
+        assert!(banker.set_max_need(1, 0, 2));
+        assert_eq!(banker.safety_trace(), Some(vec![0, 1]));
+
+        // 両方のスレッドが残り 2 を必要とし、残り 1 ではどちらも終了できない
+        assert!(!banker.set_max_need(0, 0, 3));
+        // 確保済みの量を下回る
+        assert!(!banker.set_max_need(0, 0, 0));
+
+        // 拒否された変更は反映されていない
+        let state = banker.state.lock().unwrap();
+        assert_eq!(state.resource.needed_for_threads, [[1], [2]]);
+    }
+
+    // 一度だけ Pending を返して再スケジューリングされる Future
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn test_take_async() {
+        use futures::executor::LocalPool;
+        use futures::task::LocalSpawnExt;
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        const NUM_LOOP: usize = 100;
+        let banker = Banker::<2, 2>::new([1, 1], [[1, 1], [1, 1]]);
+        let eaten = Rc::new([Cell::new(0), Cell::new(0)]);
+        let eating = Rc::new(Cell::new(0));
+
+        // 1つの OS スレッド上で2つのタスクがリソースを取り合う
+        // take_async がスレッドをブロックすると、もう一方のタスクが release できずに停止する
+        let mut pool = LocalPool::new();
+        for t_id in 0..2 {
+            let banker = banker.clone();
+            let eaten = eaten.clone();
+            let eating = eating.clone();
+            // 2つのタスクは逆の順序でリソースを取得する
+            let (first, second) = if t_id == 0 { (0, 1) } else { (1, 0) };
+            pool.spawner()
+                .spawn_local(async move {
+                    for _ in 0..NUM_LOOP {
+                        banker.take_async(t_id, first).await;
+                        // 1つ目を取得した状態で他のタスクに実行を譲り、競合させる
+                        YieldOnce(false).await;
+                        banker.take_async(t_id, second).await;
+
+                        // 両方のリソースを持つのは同時に1つのタスクのみ
+                        eating.set(eating.get() + 1);
+                        assert_eq!(eating.get(), 1);
+                        eaten[t_id].set(eaten[t_id].get() + 1);
+                        YieldOnce(false).await;
+                        eating.set(eating.get() - 1);
+
+                        banker.release(t_id, first);
+                        banker.release(t_id, second);
+                    }
+                })
+                .unwrap();
+        }
+        pool.run();
+
+        assert_eq!(eaten[0].get(), NUM_LOOP);
+        assert_eq!(eaten[1].get(), NUM_LOOP);
+        assert!(banker.state.lock().unwrap().wakers.is_empty());
+    }
 }
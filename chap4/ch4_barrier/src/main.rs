@@ -1,75 +1,12 @@
-use std::{
-    cell::UnsafeCell,
-    ops::{Deref, DerefMut},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    thread,
-};
+use std::{sync::Arc, thread};
 
-const NUM_THREADS: usize = 4;
-const NUM_LOOP: usize = 100000;
-
-// スピンロック用の型
-struct SpinLock<T> {
-    lock: AtomicBool,    // ロック用共有変数
-    data: UnsafeCell<T>, // 保護対象データ
-}
+use spinlock::SpinLock;
 
-// ロックの解放および、ロック中に保護対象データを操作するための型
-struct SpinLockGuard<'a, T> {
-    spin_lock: &'a SpinLock<T>,
-}
-
-impl<T> SpinLock<T> {
-    fn new(v: T) -> Self {
-        SpinLock {
-            lock: AtomicBool::new(false),
-            data: UnsafeCell::new(v),
-        }
-    }
-
-    fn lock(&self) -> SpinLockGuard<T> {
-        loop {
-            while self.lock.load(Ordering::Relaxed) {}
-
-            if self
-                .lock
-                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
-                .is_ok()
-            {
-                break;
-            }
-        }
-
-        SpinLockGuard { spin_lock: self }
-    }
-}
+mod spinlock;
+mod ticketlock;
 
-// SpinLock型はスレッド間で共有可能と設定
-unsafe impl<T> Sync for SpinLock<T> {}
-unsafe impl<T> Send for SpinLock<T> {}
-
-impl<'a, T> Drop for SpinLockGuard<'a, T> {
-    fn drop(&mut self) {
-        self.spin_lock.lock.store(false, Ordering::Release);
-    }
-}
-
-impl<'a, T> Deref for SpinLockGuard<'a, T> {
-    type Target = T;
-
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.spin_lock.data.get() }
-    }
-}
-
-impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.spin_lock.data.get() }
-    }
-}
+const NUM_THREADS: usize = 4;
+const NUM_LOOP: usize = 100000;
 
 fn main() {
     let lock = Arc::new(SpinLock::new(0));
@@ -80,9 +17,8 @@ fn main() {
         let lock0 = lock.clone();
         let t = thread::spawn(move || {
             for _ in 0..NUM_LOOP {
-                // ロック
-                let mut data = lock0.lock();
-                *data += 1;
+                // ロックを獲得してインクリメントし、解放
+                lock0.with(|data| *data += 1);
             }
         });
         v.push(t);
@@ -94,7 +30,7 @@ fn main() {
 
     println!(
         "COUNT {} (expected = {})",
-        *lock.lock(),
+        lock.with(|data| *data),
         NUM_LOOP * NUM_THREADS
     );
 }
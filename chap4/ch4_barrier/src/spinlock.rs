@@ -0,0 +1,357 @@
+use std::{
+    cell::UnsafeCell,
+    collections::VecDeque,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+    thread::{self, Thread},
+    time::Duration,
+};
+
+// loom でモデル検査する場合は、アトミック変数とスピンのヒントを loom のものに差し替える
+// RUSTFLAGS="--cfg loom" cargo test --release loom で実行する
+#[cfg(loom)]
+use loom::{
+    hint,
+    sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+};
+#[cfg(not(loom))]
+use std::{
+    hint,
+    sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+};
+
+// lock_adaptive でスピンする最大回数。超えるとスレッドを park して待機する
+const SPIN_LIMIT: usize = 100;
+
+// lock_adaptive で park したスレッドが、起こされなくても獲得を再試行するまでの時間
+// lock や with で獲得したガードの解放では起こされないため、この間隔で解放に気付く
+const PARK_TIMEOUT: Duration = Duration::from_millis(1);
+
+// lock_adaptive で park しているスレッドと、待機している SpinLock のアドレス
+// SpinLock ごとに持つと lock_adaptive を使わない場合もサイズが増えるため、すべての SpinLock で共有する
+static WAITERS: Mutex<VecDeque<(usize, Thread)>> = Mutex::new(VecDeque::new());
+
+// スピンロック用の型
+pub struct SpinLock<T> {
+    lock: AtomicBool,    // ロック用共有変数
+    parked: AtomicUsize, // WAITERS 中のこの SpinLock を待つスレッドの数。解放時に Mutex を取らずに確認するため
+    data: UnsafeCell<T>, // 保護対象データ
+}
+
+// ロックの解放および、ロック中に保護対象データを操作するための型
+pub struct SpinLockGuard<'a, T> {
+    spin_lock: &'a SpinLock<T>,
+}
+
+// lock_adaptive で獲得したロックのガード
+// 解放時に park しているスレッドを起こす。lock のガードは起こさないため、解放が軽量なままとなる
+pub struct SpinLockAdaptiveGuard<'a, T> {
+    spin_lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub fn new(v: T) -> Self {
+        SpinLock {
+            lock: AtomicBool::new(false),
+            parked: AtomicUsize::new(0),
+            data: UnsafeCell::new(v),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        loop {
+            while self.lock.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+
+            if self
+                .lock
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+        }
+
+        SpinLockGuard { spin_lock: self }
+    }
+
+    // ロックを獲得して f を実行し、リターン時に解放する
+    // ガードを返さないため、ロックを保持したまま他の処理を行ってしまうことがない
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut guard = self.lock();
+        f(&mut guard)
+    }
+
+    // 一定回数スピンしても獲得できない場合は、スレッドを park して解放を待つ
+    // ロックが長時間保持される場合に、待機中のスレッドが CPU を消費し続けないようにする
+    // park したスレッドは lock_adaptive のガードの解放時に起こされる
+    // lock や with のガードの解放では起こされないが、PARK_TIMEOUT ごとに再試行するため獲得できる
+    #[allow(dead_code)] // main では使用しない
+    pub fn lock_adaptive(&self) -> SpinLockAdaptiveGuard<'_, T> {
+        for _ in 0..SPIN_LIMIT {
+            if self.try_acquire() {
+                return SpinLockAdaptiveGuard { spin_lock: self };
+            }
+            hint::spin_loop();
+        }
+
+        loop {
+            // 待機キューに入ってから再度獲得を試みる
+            // 先に獲得を試みると、その直後に解放された場合に起こされず park し続ける
+            // unpark_one で取り出された後に獲得できなかった場合も、ここで再度待機キューに入る
+            self.push_waiter();
+            fence(Ordering::SeqCst);
+            if self.try_acquire() {
+                self.remove_waiter();
+                return SpinLockAdaptiveGuard { spin_lock: self };
+            }
+            // 解放時に unpark される。既に unpark されていれば即座にリターンする
+            thread::park_timeout(PARK_TIMEOUT);
+        }
+    }
+
+    // lock_adaptive で park しているスレッドの数
+    #[allow(dead_code)] // main では使用しない
+    pub fn parked(&self) -> usize {
+        self.parked.load(Ordering::Relaxed)
+    }
+
+    fn try_acquire(&self) -> bool {
+        !self.lock.load(Ordering::Relaxed)
+            && self
+                .lock
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    // 呼び出したスレッドを待機キューに入れる。既に入っている場合は何もしない
+    fn push_waiter(&self) {
+        let me = thread::current();
+        let mut waiters = WAITERS.lock().unwrap();
+        if !waiters
+            .iter()
+            .any(|(addr, t)| *addr == self.addr() && t.id() == me.id())
+        {
+            waiters.push_back((self.addr(), me));
+            self.parked.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn remove_waiter(&self) {
+        let id = thread::current().id();
+        let mut waiters = WAITERS.lock().unwrap();
+        if let Some(i) = waiters
+            .iter()
+            .position(|(addr, t)| *addr == self.addr() && t.id() == id)
+        {
+            waiters.remove(i);
+            self.parked.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // park しているスレッドを1つ起こす
+    fn unpark_one(&self) {
+        let mut waiters = WAITERS.lock().unwrap();
+        if let Some(i) = waiters.iter().position(|(addr, _)| *addr == self.addr()) {
+            let (_, t) = waiters.remove(i).unwrap();
+            self.parked.fetch_sub(1, Ordering::Relaxed);
+            t.unpark();
+        }
+    }
+}
+
+// SpinLock型はスレッド間で共有可能と設定
+unsafe impl<T> Sync for SpinLock<T> {}
+unsafe impl<T> Send for SpinLock<T> {}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.spin_lock.lock.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.spin_lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.spin_lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockAdaptiveGuard<'a, T> {
+    fn drop(&mut self) {
+        self.spin_lock.lock.store(false, Ordering::Release);
+
+        // lock_adaptive で park しているスレッドがいれば起こす
+        // lock_adaptive 側の fence と対になり、待機キューへの追加と解放のどちらかが必ず相手に見える
+        fence(Ordering::SeqCst);
+        if self.spin_lock.parked.load(Ordering::Relaxed) > 0 {
+            self.spin_lock.unpark_one();
+        }
+    }
+}
+
+impl<'a, T> Deref for SpinLockAdaptiveGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.spin_lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockAdaptiveGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.spin_lock.data.get() }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_with() {
+        const NUM_THREADS: usize = 4;
+        const NUM_LOOP: usize = 1000;
+        let lock = Arc::new(SpinLock::new(0));
+        let v: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..NUM_LOOP {
+                        lock.with(|n| *n += 1);
+                    }
+                })
+            })
+            .collect();
+        for t in v {
+            t.join().unwrap();
+        }
+        assert_eq!(lock.with(|n| *n), NUM_THREADS * NUM_LOOP);
+        // f の返り値がリターンされ、その時点でロックは解放されている
+        assert_eq!(lock.with(|n| *n * 2), NUM_THREADS * NUM_LOOP * 2);
+        assert!(!lock.lock.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_lock_adaptive() {
+        let lock = Arc::new(SpinLock::new(0));
+        let guard = lock.lock_adaptive();
+
+        let lock0 = lock.clone();
+        let t = thread::spawn(move || {
+            let mut data = lock0.lock_adaptive();
+            *data += 1;
+        });
+
+        // ロックを保持し続けると、待機中のスレッドはスピンをやめて park する
+        let start = Instant::now();
+        while lock.parked() == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "waiter never parked"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(lock.parked(), 1);
+
+        // 解放すると park していたスレッドが起こされて獲得する
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(*lock.lock_adaptive(), 1);
+        assert_eq!(lock.parked(), 0);
+    }
+
+    #[test]
+    fn test_lock_adaptive_mixed() {
+        let lock = Arc::new(SpinLock::new(0));
+        let guard = lock.lock();
+
+        let lock0 = lock.clone();
+        let t = thread::spawn(move || {
+            let mut data = lock0.lock_adaptive();
+            *data += 1;
+        });
+        let start = Instant::now();
+        while lock.parked() == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "waiter never parked"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // lock のガードの解放では起こされないが、再試行して獲得する
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(*lock.lock(), 1);
+        assert_eq!(lock.parked(), 0);
+    }
+
+    #[test]
+    fn test_lock_adaptive_contended() {
+        const NUM_THREADS: usize = 4;
+        const NUM_LOOP: usize = 1000;
+        let lock = Arc::new(SpinLock::new(0));
+        let v: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..NUM_LOOP {
+                        let mut data = lock.lock_adaptive();
+                        *data += 1;
+                    }
+                })
+            })
+            .collect();
+        for t in v {
+            t.join().unwrap();
+        }
+        assert_eq!(*lock.lock_adaptive(), NUM_THREADS * NUM_LOOP);
+        assert_eq!(lock.parked(), 0);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_test {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    // 2つのスレッドから同時にインクリメントしても、更新が失われない
+    #[test]
+    fn loom_spinlock() {
+        loom::model(|| {
+            let lock = Arc::new(SpinLock::new(0));
+            let v: Vec<_> = (0..2)
+                .map(|_| {
+                    let lock = lock.clone();
+                    thread::spawn(move || {
+                        let mut data = lock.lock();
+                        let n = *data;
+                        *data = n + 1;
+                    })
+                })
+                .collect();
+            for t in v {
+                t.join().unwrap();
+            }
+
+            assert_eq!(*lock.lock(), 2);
+        });
+    }
+}
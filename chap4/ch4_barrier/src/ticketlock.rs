@@ -0,0 +1,114 @@
+use std::{
+    cell::UnsafeCell,
+    hint,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// チケットロック用の型
+// SpinLock と異なり、ロックを要求した順に獲得するため飢餓状態にならない
+pub struct TicketSpinLock<T> {
+    next_ticket: AtomicUsize, // 次に発行するチケット
+    now_serving: AtomicUsize, // ロックを獲得できるチケット
+    data: UnsafeCell<T>,      // 保護対象データ
+}
+
+// ロックの解放および、ロック中に保護対象データを操作するための型
+pub struct TicketSpinLockGuard<'a, T> {
+    ticket_lock: &'a TicketSpinLock<T>,
+    ticket: usize, // 獲得時のチケット
+}
+
+impl<T> TicketSpinLock<T> {
+    #[allow(dead_code)] // main では使用しない
+    pub fn new(v: T) -> Self {
+        TicketSpinLock {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            data: UnsafeCell::new(v),
+        }
+    }
+
+    #[allow(dead_code)] // main では使用しない
+    pub fn lock(&self) -> TicketSpinLockGuard<'_, T> {
+        // チケットを取得し、自身の番になるまでスピン
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            hint::spin_loop();
+        }
+        TicketSpinLockGuard {
+            ticket_lock: self,
+            ticket,
+        }
+    }
+}
+
+impl<T> TicketSpinLockGuard<'_, T> {
+    // ロック獲得時のチケット。ロックを要求した順に 0 から振られる
+    #[allow(dead_code)] // main では使用しない
+    pub fn ticket(&self) -> usize {
+        self.ticket
+    }
+}
+
+// TicketSpinLock型はスレッド間で共有可能と設定
+unsafe impl<T> Sync for TicketSpinLock<T> {}
+unsafe impl<T> Send for TicketSpinLock<T> {}
+
+impl<T> Drop for TicketSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // 次のチケットの持ち主にロックを渡す
+        // now_serving を更新するのはロックの保持者のみのため、fetch_add でなくてもよい
+        self.ticket_lock
+            .now_serving
+            .store(self.ticket + 1, Ordering::Release);
+    }
+}
+
+impl<T> Deref for TicketSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ticket_lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for TicketSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.ticket_lock.data.get() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn test_ticket_order() {
+        const NUM_THREADS: usize = 4;
+        const NUM_LOOP: usize = 50;
+        let lock = Arc::new(TicketSpinLock::new(Vec::new()));
+
+        let v: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..NUM_LOOP {
+                        // 獲得した順に自身のチケットを記録
+                        let mut data = lock.lock();
+                        let ticket = data.ticket();
+                        data.push(ticket);
+                    }
+                })
+            })
+            .collect();
+        for t in v {
+            t.join().unwrap();
+        }
+
+        // 獲得順はチケット順と一致する
+        let order = lock.lock();
+        assert_eq!(*order, (0..NUM_THREADS * NUM_LOOP).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+struct Inner {
+    cancelled: AtomicBool,
+    wakers: Mutex<Vec<Waker>>, // cancelled で待機中のタスク
+}
+
+// 複数のタスクへ停止を通知するためのトークン
+// 複製したトークンは同じ状態を共有し、いずれかで cancel するとすべての cancelled が完了する
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                wakers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    // 停止を通知し、待機中のタスクをすべて起床させる
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::Release);
+        let wakers = std::mem::take(&mut *self.inner.wakers.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    // cancel されると完了する Future をリターン
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled { token: self }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// CancellationToken::cancelled の Future
+pub struct Cancelled<'a> {
+    token: &'a CancellationToken,
+}
+
+impl Future for Cancelled<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+        let mut wakers = self.token.inner.wakers.lock().unwrap();
+        // ロック獲得前に cancel され、Waker を取りこぼしていないか再度確認
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+        wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor;
+    use std::time::Duration;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancellationToken::new();
+        assert!(!token.is_cancelled());
+
+        // 別スレッドで複製したトークンから cancel すると、待機中の cancelled が完了する
+        let token0 = token.clone();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            token0.cancel();
+        });
+        executor::block_on(token.cancelled());
+        t.join().unwrap();
+        assert!(token.is_cancelled());
+
+        // cancel 後の cancelled はすぐに完了する
+        executor::block_on(token.cancelled());
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+// send のエラー。送信できなかったデータを返す
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T); // Receiver がすべて破棄された
+
+impl<T> std::fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sending on a disconnected channel")
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for SendError<T> {}
+
+// recv のエラー。バッファが空で、Sender がすべて破棄された
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "receiving on a disconnected channel")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+struct State<T> {
+    buf: VecDeque<T>,
+    cap: usize,              // バッファの上限
+    recv_wakers: Vec<Waker>, // バッファが空で待機中の recv
+    send_wakers: Vec<Waker>, // バッファが一杯で待機中の send
+    senders: usize,          // 生存している Sender の数
+    receivers: usize,        // 生存している Receiver の数
+}
+
+// 待機中のタスクをすべて起床させる
+// 完了前に破棄された Future の Waker も残っている可能性があるため、1つだけ起床させると通知を取りこぼす
+fn wake_all(wakers: &mut Vec<Waker>) {
+    for waker in wakers.drain(..) {
+        waker.wake();
+    }
+}
+
+// Executor 上のタスク間で使用する有限チャネル
+// Condvar で待機する代わりに Waker を登録して Pending をリターンするため、ワーカースレッドをブロックしない
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    // バッファが一杯の場合は、空きができるまで待機する Future をリターン
+    pub fn send(&self, data: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            data: Some(data),
+        }
+    }
+
+    // send と同様に送信するが、送信側タスクの予算に応じて実行を他のタスクに譲る
+    // バッファの充填率が高い状態で送信を続けると budget を消費し、使い切るか、
+    // バッファが一杯で待機した後に送信すると、送信後に一度 Pending をリターンしてタスクを実行キューの末尾に回す
+    // 高速な送信側が Executor を占有し、受信側が実行されなくなるのを防ぐ
+    pub fn send_with_budget<'a>(
+        &'a self,
+        data: T,
+        budget: &'a mut SendBudget,
+    ) -> SendWithBudget<'a, T> {
+        SendWithBudget {
+            sender: self,
+            data: Some(data),
+            budget,
+            sent: false,
+        }
+    }
+}
+
+// 予算を消費する充填率の閾値
+const BUDGET_FILL_THRESHOLD: f64 = 0.5;
+
+// send_with_budget で用いる送信側タスクごとの予算
+// 送信したタスクが保持し、同じタスクからの送信で使い回す
+pub struct SendBudget {
+    limit: usize,     // 予算の上限
+    remaining: usize, // 残りの予算。0 の場合は次の送信後に実行を譲る
+    fill: f64,        // 最後に送信した時点のバッファの充填率
+    yields: usize,    // 実行を譲った回数
+}
+
+impl SendBudget {
+    // 充填率が閾値以上の状態で、limit 回送信するごとに実行を譲る
+    pub fn new(limit: usize) -> Self {
+        assert!(limit > 0);
+        SendBudget {
+            limit,
+            remaining: limit,
+            fill: 0.0,
+            yields: 0,
+        }
+    }
+
+    // 最後に送信した時点のバッファの充填率 (0.0 から 1.0)
+    pub fn fill_ratio(&self) -> f64 {
+        self.fill
+    }
+
+    // 実行を譲った回数
+    pub fn yields(&self) -> usize {
+        self.yields
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.state.lock().unwrap().senders += 1;
+        Sender {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        state.senders -= 1;
+        if state.senders == 0 {
+            // 待機中の recv を起床させ、RecvError をリターンさせる
+            wake_all(&mut state.recv_wakers);
+        }
+    }
+}
+
+// Sender::send の Future
+pub struct Send<'a, T> {
+    sender: &'a Sender<T>,
+    data: Option<T>, // 送信後は None
+}
+
+// data はピン留めする必要がないため Unpin とする
+impl<T> Unpin for Send<'_, T> {}
+
+impl<T> Future for Send<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.sender.state.lock().unwrap();
+        let data = self.data.take().expect("polled after completion");
+        if state.receivers == 0 {
+            return Poll::Ready(Err(SendError(data)));
+        }
+        if state.buf.len() < state.cap {
+            state.buf.push_back(data);
+            wake_all(&mut state.recv_wakers);
+            return Poll::Ready(Ok(()));
+        }
+
+        // バッファが一杯の場合は recv されるまで待機
+        state.send_wakers.push(cx.waker().clone());
+        drop(state);
+        self.data = Some(data);
+        Poll::Pending
+    }
+}
+
+// Sender::send_with_budget の Future
+pub struct SendWithBudget<'a, T> {
+    sender: &'a Sender<T>,
+    data: Option<T>, // 送信後は None
+    budget: &'a mut SendBudget,
+    sent: bool, // 送信済みで、実行を譲っている
+}
+
+impl<T> Unpin for SendWithBudget<'_, T> {}
+
+impl<T> Future for SendWithBudget<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // 実行を譲った後に再度 poll された
+        if self.sent {
+            return Poll::Ready(Ok(()));
+        }
+
+        let this = &mut *self;
+        let mut state = this.sender.state.lock().unwrap();
+        let data = this.data.take().expect("polled after completion");
+        if state.receivers == 0 {
+            return Poll::Ready(Err(SendError(data)));
+        }
+        if state.buf.len() >= state.cap {
+            // バッファが一杯の場合は recv されるまで待機し、送信後は優先度を下げるため予算を使い切る
+            state.send_wakers.push(cx.waker().clone());
+            drop(state);
+            this.data = Some(data);
+            this.budget.remaining = 0;
+            return Poll::Pending;
+        }
+
+        state.buf.push_back(data);
+        wake_all(&mut state.recv_wakers);
+        let budget = &mut *this.budget;
+        budget.fill = state.buf.len() as f64 / state.cap as f64;
+        drop(state);
+
+        if budget.fill >= BUDGET_FILL_THRESHOLD {
+            budget.remaining = budget.remaining.saturating_sub(1);
+        }
+        if budget.remaining > 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        // 予算を使い切った場合は、自身を wake して実行キューの末尾に回る
+        budget.remaining = budget.limit;
+        budget.yields += 1;
+        this.sent = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Receiver<T> {
+    // バッファが空の場合は、送信されるまで待機する Future をリターン
+    pub fn recv(&self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.state.lock().unwrap().receivers += 1;
+        Receiver {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            // 待機中の send を起床させ、SendError をリターンさせる
+            wake_all(&mut state.send_wakers);
+        }
+    }
+}
+
+// Receiver::recv の Future
+pub struct Recv<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.receiver.state.lock().unwrap();
+        if let Some(data) = state.buf.pop_front() {
+            wake_all(&mut state.send_wakers);
+            return Poll::Ready(Ok(data));
+        }
+        if state.senders == 0 {
+            return Poll::Ready(Err(RecvError));
+        }
+
+        // バッファが空の場合は send されるまで待機
+        state.recv_wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// 最大 cap 個のデータをバッファできる非同期チャネルを作成
+pub fn channel<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(cap > 0);
+    let state = Arc::new(Mutex::new(State {
+        buf: VecDeque::with_capacity(cap),
+        cap,
+        recv_wakers: Vec::new(),
+        send_wakers: Vec::new(),
+        senders: 1,
+        receivers: 1,
+    }));
+    let tx = Sender {
+        state: state.clone(),
+    };
+    let rx = Receiver { state };
+    (tx, rx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor::Executor;
+    use std::time::Duration;
+
+    #[test]
+    fn test_channel_on_executor() {
+        const NUM_ITEMS: usize = 100;
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let (tx, rx) = channel(4);
+
+        // ワーカーが1つのため、send や recv がスレッドをブロックするとデッドロックする
+        spawner.spawn(async move {
+            for i in 0..NUM_ITEMS {
+                tx.send(i).await.unwrap();
+            }
+        });
+        let consumer = spawner.spawn_with_output(async move {
+            let mut v = Vec::new();
+            while let Ok(n) = rx.recv().await {
+                v.push(n);
+            }
+            v
+        });
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| executor.run());
+            s.spawn(move || done_tx.send(consumer.join()).unwrap());
+
+            let v = done_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!(v, (0..NUM_ITEMS).collect::<Vec<_>>());
+            executor.shutdown();
+        });
+    }
+
+    #[test]
+    fn test_channel_disconnected() {
+        let (tx, rx) = channel(1);
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+
+        // バッファが一杯で待機中の send は、Receiver が破棄されるとエラーとなる
+        let sent = spawner.spawn_with_output(async move {
+            tx.send(1).await.unwrap();
+            tx.send(2).await
+        });
+        let received = spawner.spawn_with_output(async move {
+            let n = rx.recv().await;
+            drop(rx);
+            n
+        });
+
+        std::thread::scope(|s| {
+            s.spawn(|| executor.run());
+            assert_eq!(received.join(), Ok(1));
+            assert_eq!(sent.join(), Err(SendError(2)));
+            executor.shutdown();
+        });
+
+        let (tx, rx) = channel::<i32>(1);
+        drop(tx);
+        assert_eq!(crate::executor::block_on(rx.recv()), Err(RecvError));
+    }
+
+    // 1回目の poll では自身を wake して Pending をリターンし、実行を他のタスクに譲る
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    const CAP: usize = 64;
+    const LIMIT: usize = 4;
+
+    // 高速な送信側と、1つ受信するごとに実行を譲る低速な受信側を1つのワーカーで実行する
+    // 受信側が連続して受信するまでの間に、送信側が送信した数の最大値をリターン
+    fn max_burst(with_budget: bool) -> (usize, Option<SendBudget>) {
+        const NUM_ITEMS: usize = 500;
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let (tx, rx) = channel(CAP);
+        let sent = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let sent0 = sent.clone();
+        let producer = spawner.spawn_with_output(async move {
+            let mut budget = SendBudget::new(LIMIT);
+            for i in 0..NUM_ITEMS {
+                if with_budget {
+                    tx.send_with_budget(i, &mut budget).await.unwrap();
+                } else {
+                    tx.send(i).await.unwrap();
+                }
+                sent0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            with_budget.then_some(budget)
+        });
+        let consumer = spawner.spawn_with_output(async move {
+            let mut max = 0;
+            let mut prev = 0;
+            for i in 0..NUM_ITEMS {
+                assert_eq!(rx.recv().await, Ok(i));
+                let n = sent.load(std::sync::atomic::Ordering::Relaxed);
+                max = max.max(n - prev);
+                prev = n;
+                YieldNow(false).await;
+            }
+            max
+        });
+
+        std::thread::scope(|s| {
+            s.spawn(|| executor.run());
+            let result = (consumer.join(), producer.join());
+            executor.shutdown();
+            result
+        })
+    }
+
+    #[test]
+    fn test_send_with_budget() {
+        // 予算なしでは、受信側が実行されるまでにバッファが一杯になる
+        let (burst, _) = max_burst(false);
+        assert_eq!(burst, CAP);
+
+        // 予算ありでは、充填率が閾値を超えてから LIMIT 回送信するごとに受信側が実行される
+        let (burst, budget) = max_burst(true);
+        let budget = budget.unwrap();
+        assert!(burst <= CAP / 2 + LIMIT, "burst = {}", burst);
+        assert!(budget.yields() > 0);
+        assert!(budget.fill_ratio() > 0.0);
+    }
+}
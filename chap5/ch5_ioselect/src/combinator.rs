@@ -0,0 +1,255 @@
+use futures::future::Either;
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// join_all の子 Future の状態
+enum MaybeDone<F: Future> {
+    Pending(Pin<Box<F>>), // 未完了
+    Done(F::Output),      // 完了し、結果を保持
+    Taken,                // 結果を取り出し済み
+}
+
+// すべての子 Future が完了するまで待機する Future
+pub struct JoinAll<F: Future> {
+    children: Vec<MaybeDone<F>>,
+}
+
+// 子 Future は Box でピン留めしており、結果は移動しても問題ないため Unpin とする
+impl<F: Future> Unpin for JoinAll<F> {}
+
+// futures がすべて完了すると、それぞれの結果を futures と同じ順序でリターン
+// 子 Future には親の Waker をそのまま渡すため、いずれかの子が wake されると親の Task が再度 poll される
+pub fn join_all<F: Future>(futures: Vec<F>) -> JoinAll<F> {
+    JoinAll {
+        children: futures
+            .into_iter()
+            .map(|f| MaybeDone::Pending(Box::pin(f)))
+            .collect(),
+    }
+}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut done = true;
+        for child in self.children.iter_mut() {
+            // 完了済みの子は再度 poll しない
+            if let MaybeDone::Pending(future) = child {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(val) => *child = MaybeDone::Done(val),
+                    Poll::Pending => done = false,
+                }
+            }
+        }
+        if !done {
+            return Poll::Pending;
+        }
+
+        let outputs = self
+            .children
+            .iter_mut()
+            .map(|child| match std::mem::replace(child, MaybeDone::Taken) {
+                MaybeDone::Done(val) => val,
+                _ => panic!("polled after completion"),
+            })
+            .collect();
+        Poll::Ready(outputs)
+    }
+}
+
+// 2つの Future のうち、先に完了した方の結果をリターンする Future
+pub struct Select<A, B> {
+    // 完了後はどちらも None
+    a: Option<Pin<Box<A>>>,
+    b: Option<Pin<Box<B>>>,
+}
+
+// a と b を同時に poll し、先に完了した方の結果をリターン
+// 完了しなかった方はその時点で破棄するため、Drop により IOSelector への登録なども解除される
+// 同時に完了した場合は a を優先する
+pub fn select<A: Future, B: Future>(a: A, b: B) -> Select<A, B> {
+    Select {
+        a: Some(Box::pin(a)),
+        b: Some(Box::pin(b)),
+    }
+}
+
+impl<A: Future, B: Future> Future for Select<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let a = self.a.as_mut().expect("polled after completion");
+        if let Poll::Ready(val) = a.as_mut().poll(cx) {
+            self.a = None;
+            self.b = None;
+            return Poll::Ready(Either::Left(val));
+        }
+
+        let b = self.b.as_mut().expect("polled after completion");
+        if let Poll::Ready(val) = b.as_mut().poll(cx) {
+            self.a = None;
+            self.b = None;
+            return Poll::Ready(Either::Right(val));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::oneshot;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    // poll された回数を数える Future
+    struct CountPoll<F> {
+        future: F,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl<F: Future + Unpin> Future for CountPoll<F> {
+        type Output = F::Output;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Pin::new(&mut self.future).poll(cx)
+        }
+    }
+
+    #[test]
+    fn test_join_all_polls_pending_only() {
+        let (tx0, rx0) = oneshot::channel();
+        let (tx1, rx1) = oneshot::channel();
+        let counts = [Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))];
+        let mut join = join_all(vec![
+            CountPoll {
+                future: rx0,
+                count: counts[0].clone(),
+            },
+            CountPoll {
+                future: rx1,
+                count: counts[1].clone(),
+            },
+        ]);
+
+        let waker = futures::task::noop_waker();
+        let mut ctx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut join).poll(&mut ctx).is_pending());
+
+        // 完了した子は以降 poll されない
+        tx0.send(0).unwrap();
+        assert!(Pin::new(&mut join).poll(&mut ctx).is_pending());
+        tx1.send(1).unwrap();
+        let outputs = match Pin::new(&mut join).poll(&mut ctx) {
+            Poll::Ready(outputs) => outputs,
+            Poll::Pending => panic!("not ready"),
+        };
+        assert_eq!(outputs, vec![Ok(0), Ok(1)]);
+        assert_eq!(counts[0].load(Ordering::SeqCst), 2);
+        assert_eq!(counts[1].load(Ordering::SeqCst), 3);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_join_all_sleep() {
+        use crate::{executor, selector::IOSelector, timer::sleep};
+        use std::time::{Duration, Instant};
+
+        let selector = IOSelector::new().unwrap();
+        // 後に作成したタイマーほど先に満了する
+        let futures: Vec<_> = (0..5)
+            .map(|i| {
+                let selector = selector.clone();
+                async move {
+                    sleep(selector, Duration::from_millis(100 - i * 20))
+                        .await
+                        .unwrap();
+                    i
+                }
+            })
+            .collect();
+
+        let start = Instant::now();
+        let outputs = executor::block_on(join_all(futures));
+        let elapsed = start.elapsed();
+
+        // 満了した順ではなく、渡した順に結果が揃う
+        assert_eq!(outputs, vec![0, 1, 2, 3, 4]);
+        // タイマーは並行に待機するため、最も長いタイマーの時間で完了する
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_millis(300));
+    }
+
+    // 破棄されるとフラグを立てる
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_select_drops_loser() {
+        let (tx0, rx0) = oneshot::channel::<()>();
+        let (tx1, rx1) = oneshot::channel();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        let a = async move {
+            let _flag = flag;
+            rx0.await
+        };
+        let mut select = select(a, rx1);
+
+        let waker = futures::task::noop_waker();
+        let mut ctx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut select).poll(&mut ctx).is_pending());
+
+        // b が先に完了すると、a は完了を待たずに破棄される
+        tx1.send(1).unwrap();
+        match Pin::new(&mut select).poll(&mut ctx) {
+            Poll::Ready(Either::Right(val)) => assert_eq!(val, Ok(1)),
+            _ => panic!("b should win"),
+        }
+        assert!(dropped.load(Ordering::SeqCst));
+        drop(tx0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_select_read_line_and_timer() {
+        use crate::{executor, selector::IOSelector, timer::sleep, AsyncListener};
+        use std::{net::TcpStream, time::Duration};
+
+        let selector = IOSelector::new().unwrap();
+        let listener = AsyncListener::listen("127.0.0.1:0", selector.clone()).unwrap();
+        let addr = listener.listener.local_addr().unwrap();
+
+        // 接続するが何も送信しないクライアント
+        let _client = TcpStream::connect(addr).unwrap();
+        let (mut reader, _writer) = executor::block_on(listener.accept()).unwrap().split();
+        let fd = reader.fd;
+
+        // データが届かないため、タイマーが先に完了する
+        let timer = sleep(selector.clone(), Duration::from_millis(50));
+        let result = executor::block_on(select(reader.read_line(), timer));
+        assert!(matches!(result, Either::Right(Ok(()))));
+
+        // 破棄された ReadLine の登録は取り消される
+        for _ in 0..1000 {
+            if !selector.is_registered(fd) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("read_line is still registered");
+    }
+}
@@ -0,0 +1,881 @@
+use futures::{
+    channel::oneshot,
+    future::{BoxFuture, FutureExt, LocalBoxFuture},
+    task::{waker_ref, ArcWake},
+};
+
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+pub struct Task {
+    // 実行するコルーチン
+    // 完了または中断した場合は None
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    // AbortHandle::abort で中断されたか
+    aborted: AtomicBool,
+    // 実行キューに入っているか
+    // 既に入っている場合は wake されても再度キューに入れず、無駄な poll を防ぐ
+    scheduled: AtomicBool,
+    // Executor へスケジューリングするためのチャネル
+    sender: SyncSender<Option<Arc<Task>>>,
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // 実行キューに入っていない場合のみスケジューリング
+        if arc_self.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let self0 = arc_self.clone();
+        // Executor が既に破棄されている場合は実行できないため、Task を破棄する
+        let _ = arc_self.sender.send(Some(self0));
+    }
+}
+
+// spawn_local で生成した Task の一覧
+// Send でない Future を保持するため、スレッドごとに持ち、そのスレッドの run のみが実行する
+struct LocalTasks {
+    futures: RefCell<HashMap<usize, LocalBoxFuture<'static, ()>>>, // 未完了の Task
+    next_id: Cell<usize>,
+    // 実行可能な Task の ID
+    // Waker は他のスレッドから呼び出される可能性があるため、Arc と Mutex で保護する
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+thread_local! {
+    static LOCAL_TASKS: Rc<LocalTasks> = Rc::new(LocalTasks {
+        futures: RefCell::new(HashMap::new()),
+        next_id: Cell::new(0),
+        ready: Arc::new(Mutex::new(VecDeque::new())),
+    });
+}
+
+// ローカルな Task の Waker
+// Future 自体は持たず、ID を実行可能な一覧に入れて所有スレッドの run を起床させる
+struct LocalWaker {
+    id: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+    sender: SyncSender<Option<Arc<Task>>>,
+}
+
+impl ArcWake for LocalWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.ready.lock().unwrap().push_back(arc_self.id);
+        notify_local(&arc_self.sender);
+    }
+}
+
+// 受信待ちの run を起床させるため、何もしない Task を実行キューに入れる
+// キューが一杯の場合は、ワーカーが受信待ちではないため通知しなくてよい
+fn notify_local(sender: &SyncSender<Option<Arc<Task>>>) {
+    let task = Arc::new(Task {
+        future: Mutex::new(None),
+        aborted: AtomicBool::new(false),
+        scheduled: AtomicBool::new(true),
+        sender: sender.clone(),
+    });
+    let _ = sender.try_send(Some(task));
+}
+
+// 呼び出したスレッドのローカルな Task のうち、実行可能なものを1回ずつ poll する
+// 自身を wake し続ける Task が他の Task を飢餓状態にしないよう、呼び出し時点で実行可能なもののみ poll する
+fn run_local_tasks(sender: &SyncSender<Option<Arc<Task>>>) {
+    LOCAL_TASKS.with(|local| {
+        let n = local.ready.lock().unwrap().len();
+        for _ in 0..n {
+            let Some(id) = local.ready.lock().unwrap().pop_front() else {
+                break;
+            };
+            // poll 中に spawn_local される場合があるため、poll 中は RefCell を借用しない
+            // 完了済みの Task が再度 wake された場合は見つからない
+            let Some(mut fut) = local.futures.borrow_mut().remove(&id) else {
+                continue;
+            };
+            let waker = futures::task::waker(Arc::new(LocalWaker {
+                id,
+                ready: local.ready.clone(),
+                sender: sender.clone(),
+            }));
+            let mut ctx = Context::from_waker(&waker);
+            match panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(&mut ctx))) {
+                Ok(Poll::Ready(())) => (),
+                Ok(Poll::Pending) => {
+                    local.futures.borrow_mut().insert(id, fut);
+                }
+                Err(err) => eprintln!("task panicked: {}", panic_message(&*err)),
+            }
+        }
+    });
+}
+
+pub struct Executor {
+    // 実行キュー。None は shutdown の通知
+    // 複数のワーカースレッドから受信できるように Mutex で保護する
+    sender: SyncSender<Option<Arc<Task>>>,
+    receiver: Arc<Mutex<Receiver<Option<Arc<Task>>>>>,
+    closed: Arc<AtomicBool>,  // shutdown され、新たな Task を生成できないか
+    aborted: Arc<AtomicBool>, // shutdown_now され、実行キューの Task を実行せずに終了するべきか
+    workers: Arc<AtomicBool>, // run_workers で実行しているか
+    local: Arc<AtomicBool>,   // spawn_local で Task を生成したか
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self::with_capacity(1024)
+    }
+
+    // 実行キューに cap 個まで Task を格納できる Executor を作成
+    // キューが一杯の場合、spawn や Task の wake は空きができるまでブロックし、try_spawn は Full をリターンする
+    // ワーカースレッド上で wake した場合もブロックするため、ワーカーが1つで cap が小さいとデッドロックする可能性がある
+    pub fn with_capacity(cap: usize) -> Self {
+        assert!(cap > 0, "capacity must be non-zero");
+        // チャネルを生成
+        let (sender, receiver) = sync_channel(cap);
+        Executor {
+            sender: sender.clone(),
+            receiver: Arc::new(Mutex::new(receiver)),
+            closed: Arc::new(AtomicBool::new(false)),
+            aborted: Arc::new(AtomicBool::new(false)),
+            workers: Arc::new(AtomicBool::new(false)),
+            local: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // 新たに Task を生成するための Spawner を作成
+    pub fn get_spawner(&self) -> Spawner {
+        Spawner {
+            sender: self.sender.clone(),
+            closed: self.closed.clone(),
+            workers: self.workers.clone(),
+            local: self.local.clone(),
+        }
+    }
+
+    // 呼び出したスレッドで Task を実行
+    // このスレッドで spawn_local した Task も実行する
+    // shutdown されるまでリターンしない
+    pub fn run(&self) {
+        worker(&self.receiver, &self.sender, &self.aborted);
+    }
+
+    // n 個のワーカースレッドで Task を並行に実行
+    // すべてのワーカーが終了するまでリターンしない
+    // ローカルな Task の wake の通知を他のワーカーが受信すると実行されないため、spawn_local とは併用できない
+    // spawn_local で Task を生成している場合はパニック
+    pub fn run_workers(&self, n: usize) {
+        // spawn_local と同時に呼び出された場合も、少なくとも一方がパニックするように SeqCst とする
+        self.workers.store(true, Ordering::SeqCst);
+        assert!(
+            !self.local.load(Ordering::SeqCst),
+            "run_workers cannot be used with spawn_local"
+        );
+        let workers: Vec<_> = (0..n)
+            .map(|_| {
+                let receiver = self.receiver.clone();
+                let sender = self.sender.clone();
+                let aborted = self.aborted.clone();
+                std::thread::spawn(move || worker(&receiver, &sender, &aborted))
+            })
+            .collect();
+
+        for w in workers {
+            w.join().unwrap();
+        }
+    }
+
+    // 新たな Task の生成を停止し、その時点で実行キューに入っている Task を実行してから run を終了させる
+    // 以降にキューに入った Task は実行しない
+    // ただし run_workers では、終了の通知を受け取るまでに他のワーカーが実行する場合がある
+    pub fn shutdown(&self) {
+        self.closed.store(true, Ordering::Release);
+        // 実行キューの末尾に終了の通知を入れる
+        let _ = self.sender.send(None);
+    }
+
+    // 新たな Task の生成を停止し、実行キューに入っている Task も実行せずに run を終了させる
+    // poll 中の Task は poll が完了してから終了する
+    pub fn shutdown_now(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.aborted.store(true, Ordering::Release);
+        // 受信待ちのワーカーを起床させる
+        // キューが一杯の場合は受信待ちのワーカーはいないため、通知しなくてよい
+        let _ = self.sender.try_send(None);
+    }
+}
+
+// チャネルから Task を受信して順に実行
+// shutdown の通知を受け取ると終了する
+fn worker(
+    receiver: &Mutex<Receiver<Option<Arc<Task>>>>,
+    sender: &SyncSender<Option<Arc<Task>>>,
+    aborted: &AtomicBool,
+) {
+    loop {
+        // ローカルな Task は wake されると実行キューにも通知が入るため、受信の前に実行すれば取りこぼさない
+        run_local_tasks(sender);
+
+        // ロックは受信中のみ保持し、poll 中は他のワーカーが受信できるようにする
+        let task = receiver.lock().unwrap().recv();
+        let Ok(task) = task else {
+            break;
+        };
+
+        match task {
+            // shutdown の通知より前に入った Task は、他のワーカーが通知を受け取った後でも実行する
+            Some(task) if !aborted.load(Ordering::Acquire) => run_task(&task),
+            // shutdown の通知、または shutdown_now 後
+            // 他のワーカーや、次に run を呼び出した場合も終了するように通知を戻す
+            _ => {
+                let _ = sender.try_send(None);
+                break;
+            }
+        }
+    }
+}
+
+// Task を1回 poll する
+fn run_task(task: &Arc<Task>) {
+    // poll 中に wake された場合は再度キューに入れる必要があるため、poll の前にフラグを下ろす
+    task.scheduled.store(false, Ordering::Release);
+
+    // コンテキストを生成
+    let mut future = task.future.lock().unwrap();
+    // 中断された Task は poll せずに future を破棄する
+    // future が保持していた IOSelector への登録などは、各 Drop で解除される
+    if task.aborted.load(Ordering::Acquire) {
+        *future = None;
+        return;
+    }
+    let Some(fut) = future.as_mut() else {
+        // 完了済みの Task が再度 wake された
+        return;
+    };
+    let waker = waker_ref(task);
+    let mut ctx = Context::from_waker(&waker);
+    // poll 呼び出し実行
+    // Task 内でパニックが発生しても、その Task のみ破棄して他の Task の実行を続ける
+    match panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(&mut ctx))) {
+        Ok(Poll::Ready(())) => *future = None,
+        Ok(Poll::Pending) => (),
+        Err(err) => {
+            eprintln!("task panicked: {}", panic_message(&*err));
+            *future = None;
+        }
+    }
+}
+
+// パニック時に渡された値からメッセージを取り出す
+// panic! に文字列以外を渡した場合は取り出せない
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+// try_spawn のエラー
+#[derive(Debug, PartialEq, Eq)]
+pub enum SpawnError {
+    Full,     // 実行キューが一杯
+    Shutdown, // Executor が shutdown または破棄されている
+}
+
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpawnError::Full => write!(f, "run queue is full"),
+            SpawnError::Shutdown => write!(f, "executor has shut down"),
+        }
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+pub struct Spawner {
+    sender: SyncSender<Option<Arc<Task>>>,
+    closed: Arc<AtomicBool>,  // Executor が shutdown されたか
+    workers: Arc<AtomicBool>, // Executor が run_workers で実行しているか
+    local: Arc<AtomicBool>,   // spawn_local で Task を生成したか
+}
+
+impl Spawner {
+    // 今回のコードは Output = Option<String> のやつもあったけどそれはここには関係ないのかな
+    // Executor が shutdown されている場合はパニック
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) {
+        assert!(!self.is_closed(), "executor has shut down");
+        let future = future.boxed();
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future)),
+            aborted: AtomicBool::new(false),
+            scheduled: AtomicBool::new(true), // 生成時に実行キューに入れる
+            sender: self.sender.clone(),
+        });
+
+        // 実行キューにえんきゅー
+        self.sender.send(Some(task)).unwrap();
+    }
+
+    // Send でない Future から Task を生成
+    // Task は呼び出したスレッドのローカルな一覧に入り、このスレッドで run を呼び出した場合のみ実行される
+    // Executor が shutdown されている場合や、run_workers で実行している場合はパニック
+    pub fn spawn_local(&self, future: impl Future<Output = ()> + 'static) {
+        assert!(!self.is_closed(), "executor has shut down");
+        self.local.store(true, Ordering::SeqCst);
+        assert!(
+            !self.workers.load(Ordering::SeqCst),
+            "spawn_local cannot be used with run_workers"
+        );
+        LOCAL_TASKS.with(|local| {
+            let id = local.next_id.get();
+            local.next_id.set(id + 1);
+            local.futures.borrow_mut().insert(id, future.boxed_local());
+            local.ready.lock().unwrap().push_back(id);
+        });
+        notify_local(&self.sender);
+    }
+
+    // Executor が shutdown されたか
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    // 実行キューが一杯の場合や Executor が破棄されている場合に、ブロックやパニックせずにエラーをリターン
+    pub fn try_spawn(
+        &self,
+        future: impl Future<Output = ()> + 'static + Send,
+    ) -> Result<(), SpawnError> {
+        if self.is_closed() {
+            return Err(SpawnError::Shutdown);
+        }
+        let future = future.boxed();
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future)),
+            aborted: AtomicBool::new(false),
+            scheduled: AtomicBool::new(true), // 生成時に実行キューに入れる
+            sender: self.sender.clone(),
+        });
+
+        self.sender.try_send(Some(task)).map_err(|err| match err {
+            TrySendError::Full(_) => SpawnError::Full,
+            TrySendError::Disconnected(_) => SpawnError::Shutdown,
+        })
+    }
+
+    // 中断可能な Task を生成し、中断するための AbortHandle をリターン
+    pub fn spawn_cancelable(
+        &self,
+        future: impl Future<Output = ()> + 'static + Send,
+    ) -> AbortHandle {
+        assert!(!self.is_closed(), "executor has shut down");
+        let future = future.boxed();
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future)),
+            aborted: AtomicBool::new(false),
+            scheduled: AtomicBool::new(true), // 生成時に実行キューに入れる
+            sender: self.sender.clone(),
+        });
+
+        self.sender.send(Some(task.clone())).unwrap();
+        AbortHandle { task }
+    }
+
+    // future を実行する Task を生成し、その結果を受け取るための JoinHandle をリターン
+    pub fn spawn_with_output<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> {
+        // 結果は oneshot チャネル経由で JoinHandle に渡す
+        let (tx, rx) = oneshot::channel();
+        self.spawn(async move {
+            // JoinHandle が既に破棄されている場合は結果を捨てる
+            let _ = tx.send(future.await);
+        });
+        JoinHandle { receiver: rx }
+    }
+}
+
+// spawn_cancelable で生成した Task を中断するためのハンドル
+pub struct AbortHandle {
+    task: Arc<Task>,
+}
+
+impl AbortHandle {
+    // Task を中断する
+    // 次に Executor が Task を取り出した際に、poll せずに future を破棄する
+    pub fn abort(&self) {
+        self.task.aborted.store(true, Ordering::Release);
+        // 待機中の Task でも破棄されるように、実行キューに入れる
+        ArcWake::wake_by_ref(&self.task);
+    }
+
+    // 中断済みか
+    pub fn is_aborted(&self) -> bool {
+        self.task.aborted.load(Ordering::Acquire)
+    }
+}
+
+// spawn_with_output で生成した Task の結果を受け取るためのハンドル
+// await するか join でブロックして待機する
+pub struct JoinHandle<T> {
+    receiver: oneshot::Receiver<T>,
+}
+
+impl<T> JoinHandle<T> {
+    // Task が完了するまで呼び出したスレッドをブロックして結果をリターン
+    // Task を実行する Executor とは別のスレッドから呼び出す必要がある
+    pub fn join(self) -> T {
+        block_on(self)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        match self.receiver.poll_unpin(cx) {
+            Poll::Ready(Ok(val)) => Poll::Ready(val),
+            // 結果を送信せずに Task が破棄された
+            Poll::Ready(Err(oneshot::Canceled)) => panic!("task was dropped before completion"),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// block_on で使用する Waker
+// wake されるとフラグを立て、Condvar で待機中のスレッドを起床させる
+struct Parker {
+    woken: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl ArcWake for Parker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        *arc_self.woken.lock().unwrap() = true;
+        arc_self.cond.notify_one();
+    }
+}
+
+// future を呼び出したスレッドで完了するまで実行し、結果をリターン
+// Pending の間は wake されるまでスレッドを停止する
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let parker = Arc::new(Parker {
+        woken: Mutex::new(false),
+        cond: Condvar::new(),
+    });
+    let waker = waker_ref(&parker);
+    let mut ctx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(val) = future.as_mut().poll(&mut ctx) {
+            return val;
+        }
+
+        // wake されるまで待機
+        // poll 中に wake されていた場合はフラグが立っているので待機しない
+        let mut woken = parker.woken.lock().unwrap();
+        while !*woken {
+            woken = parker.cond.wait(woken).unwrap();
+        }
+        *woken = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    // 一度だけ Pending を返して再スケジューリングされる Future
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_workers() {
+        const NUM_TASKS: usize = 500;
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+
+        for _ in 0..NUM_TASKS {
+            let count = count.clone();
+            let threads = threads.clone();
+            spawner.spawn(async move {
+                YieldOnce(false).await;
+                // CPU を使う処理の代わり
+                std::thread::sleep(Duration::from_millis(1));
+                threads.lock().unwrap().insert(std::thread::current().id());
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        std::thread::spawn(move || executor.run_workers(4));
+
+        let start = Instant::now();
+        while count.load(Ordering::SeqCst) < NUM_TASKS {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), NUM_TASKS);
+        assert!(threads.lock().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn test_spawn_with_output() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+
+        let handle = spawner.spawn_with_output(async { 40 + 2 });
+        // 別の Task の中で await して結果を受け取る
+        let inner = spawner.spawn_with_output(async { String::from("hello") });
+        let outer = spawner.spawn_with_output(async move { inner.await.len() });
+
+        std::thread::spawn(move || executor.run());
+        assert_eq!(handle.join(), 42);
+        assert_eq!(outer.join(), 5);
+    }
+
+    #[test]
+    fn test_block_on() {
+        assert_eq!(block_on(async { 1 + 1 }), 2);
+        // 自身を wake して Pending を返す Future
+        block_on(YieldOnce(false));
+    }
+
+    #[test]
+    fn test_block_on_wake_from_other_thread() {
+        let (tx, rx) = oneshot::channel();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            tx.send(10).unwrap();
+        });
+
+        let start = Instant::now();
+        assert_eq!(block_on(async { rx.await.unwrap() * 2 }), 20);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_try_spawn() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+
+        // 実行キューを一杯にする
+        for _ in 0..1024 {
+            spawner.try_spawn(async {}).unwrap();
+        }
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Full));
+
+        // Executor 破棄後の spawn や wake はパニックしない
+        let task = executor.receiver.lock().unwrap().recv().unwrap().unwrap();
+        drop(executor);
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Shutdown));
+        futures::task::waker(task).wake();
+    }
+
+    // 破棄されるとフラグを立てる
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_spawn_cancelable() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let polled = Arc::new(AtomicBool::new(false));
+
+        // wake されないため永遠に完了しない Task
+        let flag = DropFlag(dropped.clone());
+        let polled0 = polled.clone();
+        let handle = spawner.spawn_cancelable(async move {
+            let _flag = flag;
+            polled0.store(true, Ordering::SeqCst);
+            futures::future::pending::<()>().await;
+        });
+        std::thread::spawn(move || executor.run());
+
+        let start = Instant::now();
+        while !polled.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        handle.abort();
+        assert!(handle.is_aborted());
+        while !dropped.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_panic_in_task() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        // パニックする Task の後に通常の Task を実行
+        spawner.spawn(async {
+            YieldOnce(false).await;
+            panic!("task panicked");
+        });
+        spawner.spawn(async move {
+            YieldOnce(false).await;
+            tx.send(()).unwrap();
+        });
+        std::thread::spawn(move || executor.run());
+
+        // パニックしても Executor は停止せず、他の Task は完了する
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    // 1回目の poll で自身を2回 wake して Pending を返し、2回目の poll で完了する Future
+    struct WakeTwice(bool);
+
+    impl Future for WakeTwice {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn test_wake_dedup() {
+        let executor = Executor::new();
+        executor.get_spawner().spawn(WakeTwice(false));
+        let receiver = executor.receiver.lock().unwrap();
+
+        // poll される前に wake されても、再度キューには入らない
+        let task = receiver.try_recv().unwrap().unwrap();
+        ArcWake::wake_by_ref(&task);
+        ArcWake::wake_by_ref(&task);
+        assert!(receiver.try_recv().is_err());
+
+        // poll 中に2回 wake されても、キューに入るのは1回のみ
+        run_task(&task);
+        let task = receiver.try_recv().unwrap().unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        run_task(&task);
+        assert!(task.future.lock().unwrap().is_none());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let count = count.clone();
+            spawner.spawn(async move {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // shutdown 前に実行キューに入っていた Task は実行してから run が終了する
+        executor.shutdown();
+        executor.run();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert!(spawner.is_closed());
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Shutdown));
+
+        // 再度 run を呼び出してもすぐに終了する
+        executor.run();
+    }
+
+    #[test]
+    fn test_shutdown_workers_drain() {
+        const NUM_TASKS: usize = 1000;
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..NUM_TASKS {
+            let count = count.clone();
+            spawner.spawn(async move {
+                std::thread::yield_now();
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // 他のワーカーが終了の通知を受け取っても、それより前に取り出した Task は実行する
+        executor.shutdown();
+        executor.run_workers(4);
+        assert_eq!(count.load(Ordering::SeqCst), NUM_TASKS);
+    }
+
+    #[test]
+    fn test_shutdown_now() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+        let count0 = count.clone();
+        spawner.spawn(async move {
+            count0.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // 実行キューに入っている Task も実行せずに終了する
+        executor.shutdown_now();
+        executor.run();
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Shutdown));
+    }
+
+    #[test]
+    fn test_shutdown_running_workers() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        std::thread::scope(|s| {
+            let workers = s.spawn(|| executor.run_workers(4));
+            spawner.spawn(async move {
+                tx.send(()).unwrap();
+            });
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+            // 受信待ちのワーカーもすべて終了する
+            executor.shutdown();
+            workers.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let executor = Executor::with_capacity(1);
+        let spawner = executor.get_spawner();
+        spawner.try_spawn(async {}).unwrap();
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Full));
+
+        // キューが一杯の場合、spawn は Task が取り出されるまでブロックする
+        let (tx, rx) = std::sync::mpsc::channel();
+        let t = std::thread::spawn(move || {
+            spawner.spawn(async {});
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        let receiver = executor.receiver.lock().unwrap();
+        receiver.recv().unwrap().unwrap();
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        t.join().unwrap();
+        receiver.recv().unwrap().unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_spawn_local() {
+        use std::rc::Rc;
+
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let (tx, rx) = oneshot::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+
+        // Rc を保持するため Send でない Future
+        let count = Rc::new(Cell::new(0));
+        let count0 = count.clone();
+        let spawner0 = executor.get_spawner();
+        spawner.spawn_local(async move {
+            // 他のスレッドからの wake で再度 poll される
+            let n = rx.await.unwrap();
+            count0.set(count0.get() + n);
+
+            // ローカルな Task 内からもローカルな Task を生成できる
+            let count1 = count0.clone();
+            spawner0.spawn_local(async move {
+                YieldOnce(false).await;
+                count1.set(count1.get() + 1);
+                done_tx.send(()).unwrap();
+            });
+        });
+
+        let executor = &executor;
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                tx.send(10).unwrap();
+                done_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+                executor.shutdown();
+            });
+            executor.run();
+        });
+        assert_eq!(count.get(), 11);
+        // 完了した Task は破棄され、Rc の参照も解放される
+        assert_eq!(Rc::strong_count(&count), 1);
+    }
+
+    #[test]
+    fn test_spawn_local_with_workers() {
+        // run_workers で実行中は spawn_local できない
+        let executor = Arc::new(Executor::new());
+        let executor0 = executor.clone();
+        let t = std::thread::spawn(move || executor0.run_workers(2));
+        let (tx, rx) = std::sync::mpsc::channel();
+        executor.get_spawner().spawn(async move {
+            tx.send(()).unwrap();
+        });
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        let spawner = executor.get_spawner();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| spawner.spawn_local(async {})));
+        assert!(result.is_err());
+        executor.shutdown();
+        t.join().unwrap();
+
+        // spawn_local した後は run_workers で実行できない
+        let executor = Executor::new();
+        executor.get_spawner().spawn_local(async {});
+        let result = panic::catch_unwind(AssertUnwindSafe(|| executor.run_workers(2)));
+        assert!(result.is_err());
+    }
+}
@@ -1,457 +1,2509 @@
-use futures::{
-    future::{BoxFuture, FutureExt},
-    task::{waker_ref, ArcWake},
-};
+// cancel、channel、combinator と executor はライブラリとして API を公開しており、main ではその一部のみを使用する
+#[allow(dead_code)]
+mod cancel;
+#[allow(dead_code)]
+mod channel;
+#[allow(dead_code)]
+mod combinator;
+#[allow(dead_code)]
+mod executor;
+mod selector;
+// signalfd と timerfd は Linux のみ使用できる
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+mod signal;
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+mod timer;
+
+use cancel::CancellationToken;
+use combinator::select;
+use executor::{Executor, Spawner};
+use selector::{IOSelector, IOSelectorPool, Interest};
+
+use futures::{future::Either, Stream};
 
 use nix::{
     errno::Errno,
-    sys::{
-        epoll::{
-            epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
-        },
-        eventfd::{eventfd, EfdFlags},
+    fcntl::{fcntl, FcntlArg, OFlag},
+    sys::socket::{
+        bind, connect, getsockopt, listen, setsockopt, socket, sockopt, AddressFamily, InetAddr,
+        SockAddr, SockFlag, SockType,
     },
-    unistd::{read, write},
 };
 
-use core::panic;
 use std::{
-    collections::{HashMap, VecDeque},
     future::Future,
-    io::{BufRead, BufReader, BufWriter, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
-    os::unix::io::{AsRawFd, RawFd},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::{SocketAddr as UnixSocketAddr, UnixListener, UnixStream},
+    },
+    path::Path,
     pin::Pin,
     sync::{
-        mpsc::{sync_channel, Receiver, SyncSender},
+        atomic::{AtomicUsize, Ordering},
         Arc, Mutex,
     },
     task::{Context, Poll, Waker},
 };
 
-fn write_eventfd(fd: RawFd, n: usize) {
-    let ptr = &n as *const usize as *const u8;
-    // n をメモリ上の生バイト列としてスライス形式で取得する
-    let val = unsafe { std::slice::from_raw_parts(ptr, std::mem::size_of_val(&n)) };
-    // fd の直観はチャネル。ここに val を流し込むイメージ
-    // 「流し込む」が意味するとこをは、fd が指す具体的なリソースに依存する
-    // たとえば file なら末尾に書き込みだったり、eventfd ならカウント値に追加されるとか？
-    write(fd, val).unwrap();
-}
-
-enum IOOps {
-    Add(EpollFlags, RawFd, Waker), // epoll へ追加
-    Remove(RawFd),                 // epoll から削除
-}
-
-// epfd と event はどちらも RawFd だけど全然違うものらしい
-
-// 1. epfd（epoll のファイルディスクリプタ）
-// 生成元: epoll_create1 システムコール。
-// 役割:
-//  epfd は epoll インスタンスを表すファイルディスクリプタ です。
-//  複数のファイルディスクリプタ（ソケット、パイプ、eventfd など）をまとめて監視するために使用されます。
-// 主な使い方:
-//  - イベントの登録: 監視対象のファイルディスクリプタを epoll_ctl を使って epfd に登録します。
-//  - イベントの待機: epoll_wait を使って、登録したファイルディスクリプタに発生したイベントを待機します。
-// 特徴:
-//  - 複数のファイルディスクリプタを効率的に監視:
-//      - ネットワーク接続やファイルI/Oなど、多数のイベントを扱うプログラムで重要。
-//  - 状態管理はカーネルが担当:
-//      - 登録された監視対象の状態を、ユーザー空間が個別に管理する必要がない。
-
-// 2. event（eventfd のファイルディスクリプタ）
-// 生成元: eventfd システムコール。
-// 役割:
-//  event は プロセス間通信（IPC）やスレッド間通信のためのファイルディスクリプタ です。
-//  特定のイベント（通知やシグナル）を発火する目的で使用されます。
-// 主な使い方:
-//  - 通知の送信:
-//      - eventfd_write を使って特定の値（通知）を送信します。
-//  - 通知の受信:
-//      - eventfd_read を使って通知を受信し、その後に必要な処理を行います。
-//  - epoll と組み合わせる:
-//      - eventfd を epoll に登録し、タスクやスレッド間の通知を効率よく処理します。
-// 特徴:
-//  - 通知専用:
-//      - eventfd は簡易的な通知の送信・受信専用。
-//  - スレッド間やプロセス間での利用:
-//      - スレッドセーフなイベント通知を実現。
-//  - 軽量でシンプル:
-//      - 通知専用の仕組みなので、特定の用途に対して非常に効率的。
-
-// | 特徴               | epfd（epoll fd）                     | event（eventfd）                 |
-// |--------------------|-------------------------------------|----------------------------------|
-// | **生成システムコール** | `epoll_create1`                   | `eventfd`                       |
-// | **目的**            | 複数のファイルディスクリプタを効率的に監視 | 通知（イベント発火）の送受信       |
-// | **監視対象**        | ソケット、ファイル、パイプ、`eventfd` など | なし（自身がイベントの発火元）     |
-// | **主な操作**        | `epoll_ctl` で対象を登録・管理       | `eventfd_write` / `eventfd_read` |
-// | **使い方の規模**    | 大規模な非同期I/Oや多重化             | 単純な通知やシグナル              |
-// | **`epoll` との組み合わせ** | `epfd` 自体が `epoll` のインスタンス  | `event` を `epoll` に登録可能     |
-
-// また、この eventfd はほかの全く関係ないプロセスの eventfd とバッティングすることはないらしい
-// なぜなら、Linux の eventfd はプロセスやスレッドごとに独立したカーネルリソースとして扱われるから
-// 別の観点だが、このプログラム自体は、1つの eventfd を使って処理を実現するように作っていそう
-
-struct IOSelector {
-    wakers: Mutex<HashMap<RawFd, Waker>>,
-    queue: Mutex<VecDeque<IOOps>>, // IO のキュー
-    epfd: RawFd,                   // epoll の fd
-    event: RawFd,                  // eventfd の fd
-}
-
-impl IOSelector {
-    fn new() -> Arc<Self> {
-        let s = IOSelector {
-            wakers: Mutex::new(HashMap::new()),
-            queue: Mutex::new(VecDeque::new()),
-            epfd: epoll_create1(EpollCreateFlags::empty()).unwrap(),
-            event: eventfd(0, EfdFlags::empty()).unwrap(),
-        };
-        let result = Arc::new(s);
-        let s = result.clone();
+// nix のエラーを io::Error に変換
+// ランタイム内のエラーはすべて io::Error に統一する
+fn nix_to_io(err: nix::Error) -> io::Error {
+    match err {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        err => io::Error::other(err),
+    }
+}
+
+// ノンブロッキングかつ close-on-exec のソケットを作成
+#[cfg(target_os = "linux")]
+fn nonblocking_socket(family: AddressFamily, ty: SockType) -> nix::Result<RawFd> {
+    socket(
+        family,
+        ty,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+}
 
-        // epoll 用スレッド作成
-        std::thread::spawn(move || s.select());
+// macOS や BSD では SOCK_NONBLOCK と SOCK_CLOEXEC を指定できない場合があるため、作成後に fcntl で設定する
+#[cfg(not(target_os = "linux"))]
+fn nonblocking_socket(family: AddressFamily, ty: SockType) -> nix::Result<RawFd> {
+    use nix::{
+        fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+        unistd::close,
+    };
 
-        result
+    let fd = socket(family, ty, SockFlag::empty(), None)?;
+    let result = fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+        .and_then(|_| fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)));
+    if let Err(err) = result {
+        close(fd).ok();
+        return Err(err);
     }
+    Ok(fd)
+}
 
-    // epoll で監視するための関数
-    fn add_event(
-        &self,
-        flag: EpollFlags, // epoll のフラグ
-        fd: RawFd,        // 監視対象のファイルディスクリプタ
-        waker: Waker,
-        wakers: &mut HashMap<RawFd, Waker>,
-    ) {
-        // 各定義のショートカット
-        let epoll_add = EpollOp::EpollCtlAdd;
-        let epoll_mod = EpollOp::EpollCtlMod;
-        let epoll_one = EpollFlags::EPOLLONESHOT;
-
-        // EPOLLONESHOT を指定して、一度イベントが発生すると
-        // その fd へのイベントは再設定するまで通知されないようにする
-        // ONSHOT にすることでマルチスレッド環境で同じ fd を複数回処理する問題を防げる
-        let mut ev = EpollEvent::new(flag | epoll_one, fd as u64);
-
-        // 監視対象に追加
-        if let Err(err) = epoll_ctl(self.epfd, epoll_add, fd, &mut ev) {
-            match err {
-                nix::Error::Sys(Errno::EEXIST) => {
-                    // 既に追加されていた場合は再設定
-                    // epoll_add じゃなくて epoll_mod にしてる
-                    epoll_ctl(self.epfd, epoll_mod, fd, &mut ev).unwrap();
-                }
-                _ => {
-                    panic!("epoll_ctl: {}", err)
-                }
-            }
+// fd をノンブロッキングに設定
+// TcpStream::set_nonblocking と異なり、パイプなど任意の fd に使用できる
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = fcntl(fd, FcntlArg::F_GETFL).map_err(nix_to_io)?;
+    let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+    fcntl(fd, FcntlArg::F_SETFL(flags)).map_err(nix_to_io)?;
+    Ok(())
+}
+
+// AsyncListener のソケットオプション
+// ListenOptions::new().reuse_port(true).listen(addr, selector) のように指定する
+struct ListenOptions {
+    reuse_addr: bool, // SO_REUSEADDR。TIME_WAIT のコネクションが残っていても bind できる
+    reuse_port: bool, // SO_REUSEPORT。複数のソケットで同じポートを待ち受けられる
+    nodelay: bool,    // アクセプトしたストリームに TCP_NODELAY を設定
+    backlog: usize,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)] // main では使用しない
+impl ListenOptions {
+    // TcpListener::bind と同じく SO_REUSEADDR のみ有効
+    fn new() -> Self {
+        ListenOptions {
+            reuse_addr: true,
+            reuse_port: false,
+            nodelay: false,
+            backlog: 128,
         }
+    }
 
-        assert!(!wakers.contains_key(&fd));
-        wakers.insert(fd, waker);
+    fn reuse_addr(mut self, reuse_addr: bool) -> Self {
+        self.reuse_addr = reuse_addr;
+        self
     }
 
-    // epoll の監視から削除するための関数
-    fn rm_event(&self, fd: RawFd, wakers: &mut HashMap<RawFd, Waker>) {
-        let epoll_del = EpollOp::EpollCtlDel;
-        let mut ev = EpollEvent::new(EpollFlags::empty(), fd as u64);
-        epoll_ctl(self.epfd, epoll_del, fd, &mut ev).ok();
-        wakers.remove(&fd);
+    fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
     }
 
-    // 専用のスレッドでファイルディスクリプタの監視を行うための関
-    fn select(&self) {
-        // 各定義のショートカット
-        let epoll_in = EpollFlags::EPOLLIN;
-        let epoll_add = EpollOp::EpollCtlAdd;
+    fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
 
-        // eventfd を epoll の監視対象に追加
-        let mut ev = EpollEvent::new(epoll_in, self.event as u64);
-        epoll_ctl(self.epfd, epoll_add, self.event, &mut ev).unwrap();
+    fn backlog(mut self, backlog: usize) -> Self {
+        self.backlog = backlog;
+        self
+    }
 
-        let mut events = vec![EpollEvent::empty(); 1024];
-        // event 発生を監視
-        while let Ok(nfds) = epoll_wait(self.epfd, &mut events, -1) {
-            let mut t = self.wakers.lock().unwrap();
-            for n in 0..nfds {
-                if events[n].data() == self.event as u64 {
-                    // eventfd の場合、追加、削除要求を処理
-                    let mut q = self.queue.lock().unwrap();
-                    while let Some(op) = q.pop_front() {
-                        match op {
-                            // 追加
-                            IOOps::Add(flag, fd, waker) => self.add_event(flag, fd, waker, &mut t),
-                            IOOps::Remove(fd) => self.rm_event(fd, &mut t),
-                        }
-                    }
-                    let mut buf: [u8; 8] = [0; 8];
-                    read(self.event, &mut buf).unwrap(); // eventfd の通知解除
-                } else {
-                    // 発生したイベントが eventfd じゃない、つまりファイルディスクリプタの場合の処理
-                    // 実行キューに追加
-                    let data = events[n].data() as i32;
-                    let waker = t.remove(&data).unwrap();
-                    waker.wake_by_ref();
+    fn listen(self, addr: &str, selector: Arc<IOSelector>) -> io::Result<AsyncListener> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on")
+        })?;
+        let family = match addr {
+            SocketAddr::V4(_) => AddressFamily::Inet,
+            SocketAddr::V6(_) => AddressFamily::Inet6,
+        };
+
+        // ノンブロッキングに指定
+        // ブロッキングだと、アクセプトすべきコネクションがくるまで停止する
+        // ノンブロッキングならアクセプトすべきコネクションがない場合は即座にエラーを投げて停止する
+        let fd = nonblocking_socket(family, SockType::Stream).map_err(nix_to_io)?;
+        // 以降はエラー時も含めて TcpListener がクローズする
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+        // ソケットオプションは bind の前に設定する必要がある
+        setsockopt(fd, sockopt::ReuseAddr, &self.reuse_addr).map_err(nix_to_io)?;
+        setsockopt(fd, sockopt::ReusePort, &self.reuse_port).map_err(nix_to_io)?;
+
+        // リッスンアドレスを指定
+        bind(fd, &SockAddr::new_inet(InetAddr::from_std(&addr))).map_err(nix_to_io)?;
+        listen(fd, self.backlog).map_err(nix_to_io)?;
+
+        Ok(AsyncListener {
+            listener,
+            nodelay: self.nodelay,
+            limit: None,
+            accepted: AtomicUsize::new(0),
+            selector,
+            pool: None,
+        })
+    }
+
+    // listen と同様に待ち受け、リッスンソケットとアクセプトしたストリームを pool の IOSelector に振り分ける
+    fn listen_on_pool(self, addr: &str, pool: Arc<IOSelectorPool>) -> io::Result<AsyncListener> {
+        // まだ登録していないため、リッスンソケットの fd に応じて IOSelector を選び直してよい
+        let mut listener = self.listen(addr, pool.selector(0).clone())?;
+        listener.selector = pool.selector(listener.listener.as_raw_fd()).clone();
+        listener.pool = Some(pool);
+        Ok(listener)
+    }
+}
+
+// 同時接続数の制限
+struct ConnectionLimit {
+    max: usize,
+    active: AtomicUsize,        // 接続中のコネクション数
+    waiters: Mutex<Vec<Waker>>, // 空きを待機中の Accept
+}
+
+impl ConnectionLimit {
+    fn try_acquire(self: &Arc<Self>) -> Option<Arc<ConnectionPermit>> {
+        let mut n = self.active.load(Ordering::Acquire);
+        loop {
+            if n >= self.max {
+                return None;
+            }
+            match self
+                .active
+                .compare_exchange_weak(n, n + 1, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    return Some(Arc::new(ConnectionPermit {
+                        limit: self.clone(),
+                    }))
                 }
+                Err(current) => n = current,
             }
         }
     }
 
-    // ファイルディスクリプタ登録用関数
-    fn register(&self, flags: EpollFlags, fd: RawFd, waker: Waker) {
-        let mut q = self.queue.lock().unwrap();
-        q.push_back(IOOps::Add(flags, fd, waker));
-        // eventfd は内部的に 64 ビットの整数カウンタを持っているので 1 を使うことが多い
-        // 多分決まりはない？
-        // write でここに指定した値が加算される
-        // read の時に 0 にリセットされ
-        // epoll と連携してるとき、eventfd のカウンタが 0 から
-        write_eventfd(self.event, 1);
+    // 空きがなければ Waker を登録して Pending をリターン
+    // 接続枠は確保しないため、Ready をリターンした後に他の Accept が先に確保する場合がある
+    fn poll_available(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.active.load(Ordering::Acquire) < self.max {
+            return Poll::Ready(());
+        }
+        self.register(cx);
+        // 登録する前に解放されていた場合に備えて再確認
+        if self.active.load(Ordering::Acquire) < self.max {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    // 空きがなければ Waker を登録して Pending をリターン
+    fn poll_acquire(self: &Arc<Self>, cx: &mut Context<'_>) -> Poll<Arc<ConnectionPermit>> {
+        if let Some(permit) = self.try_acquire() {
+            return Poll::Ready(permit);
+        }
+        self.register(cx);
+        // 登録する前に解放されていた場合に備えて再確認
+        match self.try_acquire() {
+            Some(permit) => Poll::Ready(permit),
+            None => Poll::Pending,
+        }
+    }
+
+    // 同じタスクの Waker が登録済みであれば追加しない
+    // Pending のたびに追加すると、解放されるまで waiters が増え続けるため
+    fn register(&self, cx: &mut Context<'_>) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            waiters.push(cx.waker().clone());
+        }
     }
+}
+
+// 1コネクション分の接続枠
+// AsyncReader と AsyncWriter で共有し、両方が破棄されると解放される
+struct ConnectionPermit {
+    limit: Arc<ConnectionLimit>,
+}
 
-    // ファイルディスクリプタ削除用関数
-    fn unregister(&self, fd: RawFd) {
-        let mut q = self.queue.lock().unwrap();
-        q.push_back(IOOps::Remove(fd));
-        write_eventfd(self.event, 1);
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limit.active.fetch_sub(1, Ordering::AcqRel);
+        for waker in self.limit.waiters.lock().unwrap().drain(..) {
+            waker.wake();
+        }
     }
 }
 
 struct AsyncListener {
     listener: TcpListener,
+    nodelay: bool, // アクセプトしたストリームに TCP_NODELAY を設定するか
+    limit: Option<Arc<ConnectionLimit>>, // 同時接続数の制限
+    accepted: AtomicUsize, // これまでにアクセプトしたコネクション数
     selector: Arc<IOSelector>,
+    pool: Option<Arc<IOSelectorPool>>, // 指定した場合、アクセプトしたストリームを fd ごとに振り分ける
 }
 
 impl AsyncListener {
-    fn listen(addr: &str, selector: Arc<IOSelector>) -> AsyncListener {
-        // リッスンアドレスを指定
-        let listener = TcpListener::bind(addr).unwrap();
+    fn listen(addr: &str, selector: Arc<IOSelector>) -> io::Result<AsyncListener> {
+        ListenOptions::new().listen(addr, selector)
+    }
 
-        // ノンブロッキングに指定
-        // ブロッキングだと、アクセプトすべきコネクションがくるまで停止する
-        // ノンブロッキングならアクセプトすべきコネクションがない場合は即座にエラーを投げて停止する
-        listener.set_nonblocking(true).unwrap();
+    // 複数の IOSelector に fd を振り分けて待ち受ける
+    #[allow(dead_code)] // main では使用しない
+    fn listen_on_pool(addr: &str, pool: Arc<IOSelectorPool>) -> io::Result<AsyncListener> {
+        ListenOptions::new().listen_on_pool(addr, pool)
+    }
+
+    // fd の監視に用いる IOSelector
+    fn selector_for(&self, fd: RawFd) -> Arc<IOSelector> {
+        match &self.pool {
+            Some(pool) => pool.selector(fd).clone(),
+            None => self.selector.clone(),
+        }
+    }
+
+    // 同時接続数を n に制限する
+    // n 個のコネクションが接続中の場合、accept はいずれかが切断されるまで待機する
+    fn with_max_connections(mut self, n: usize) -> AsyncListener {
+        self.limit = Some(Arc::new(ConnectionLimit {
+            max: n,
+            active: AtomicUsize::new(0),
+            waiters: Mutex::new(Vec::new()),
+        }));
+        self
+    }
+
+    // 接続中のコネクション数
+    #[allow(dead_code)] // main では使用しない
+    fn active_connections(&self) -> usize {
+        self.limit
+            .as_ref()
+            .map_or(0, |limit| limit.active.load(Ordering::Acquire))
+    }
+
+    // 待ち受けているアドレス
+    // ポート 0 で待ち受けた場合に、割り当てられたポートを得るために用いる
+    #[allow(dead_code)] // main では使用しない
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
 
-        AsyncListener { listener, selector }
+    // これまでにアクセプトしたコネクション数
+    // 切断されたコネクションも含む
+    #[allow(dead_code)] // main では使用しない
+    fn accepted_count(&self) -> usize {
+        self.accepted.load(Ordering::Relaxed)
     }
 
     // コネクションをアクセプトするための Future をリターン
-    fn accept(&self) -> Accept {
-        Accept { listener: self }
+    fn accept(&self) -> Accept<'_> {
+        Accept {
+            listener: self,
+            accepted: None,
+            registered: false,
+        }
     }
 }
 
 impl Drop for AsyncListener {
     fn drop(&mut self) {
-        self.selector.unregister(self.listener.as_raw_fd());
+        let _ = self.selector.unregister(self.listener.as_raw_fd());
+    }
+}
+
+// アクセプトしたコネクション
+// 読み込みと書き込みストリーム、接続相手のアドレスをまとめて保持する
+// fd の登録解除や接続枠の解放は、それぞれのストリームの破棄時に行われる
+struct Connection {
+    reader: AsyncReader,
+    writer: AsyncWriter,
+    peer_addr: SocketAddr,
+}
+
+impl Connection {
+    // 接続相手のアドレス
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    // 読み込みストリーム
+    #[allow(dead_code)] // main では使用しない
+    fn reader(&mut self) -> &mut AsyncReader {
+        &mut self.reader
+    }
+
+    // 書き込みストリーム
+    #[allow(dead_code)] // main では使用しない
+    fn writer(&mut self) -> &mut AsyncWriter {
+        &mut self.writer
+    }
+
+    // 読み込みと書き込みストリームに分割する
+    // 読み込みと書き込みを別々のタスクで行う場合などに用いる
+    fn split(self) -> (AsyncReader, AsyncWriter) {
+        (self.reader, self.writer)
     }
 }
 
 // 非同期アクセプト用 Future の実装
-// この Future ではノンブロッキングにアクセプトを実行し、アクセプトできた場合は読み込みと
-// 書き込みストリーム及びアドレスをリターンし終了する
-// アクセプトすべきコネクションがない場合はリッスンソケットを epoll に監視対象として追加して実行を中断する
+// この Future ではノンブロッキングにアクセプトを実行し、アクセプトできた場合は
+// Connection をリターンし終了する
+// アクセプトすべきコネクションがない場合はリッスンソケットを IOSelector に監視対象として追加して実行を中断する
 
 struct Accept<'a> {
     listener: &'a AsyncListener,
+    accepted: Option<(TcpStream, SocketAddr)>, // アクセプトしたが、接続枠を確保できていないコネクション
+    registered: bool,                          // IOSelector に登録して待機中か
 }
 
 impl<'a> Future for Accept<'a> {
     // 返り値の型
-    type Output = (
-        AsyncReader,          // 非同期読み込みストリーム
-        BufWriter<TcpStream>, // 書き込みストリーム
-        SocketAddr,           // アドレス
-    );
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        // アクセプトをノンブロッキングで実行
+    type Output = io::Result<Connection>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.accepted.is_none() {
+            // 同時接続数を制限している場合は、接続枠が空くまで待機
+            // 接続枠はアクセプトした後に確保し、待機中の Accept は接続中のコネクション数に含めない
+            if let Some(limit) = &self.listener.limit {
+                if limit.poll_available(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+
+            // アクセプトをノンブロッキングで実行
+            self.registered = false;
+            match self.listener.listener.accept() {
+                Ok(accepted) => {
+                    self.listener.accepted.fetch_add(1, Ordering::Relaxed);
+                    self.accepted = Some(accepted);
+                }
+                Err(err) => {
+                    // アクセプトすべきコネクションがない場合は IOSelector に登録
+                    if err.kind() == std::io::ErrorKind::WouldBlock {
+                        return match self.listener.selector.register(
+                            Interest::READ,
+                            self.listener.listener.as_raw_fd(),
+                            cx.waker().clone(),
+                        ) {
+                            Ok(()) => {
+                                self.registered = true;
+                                Poll::Pending
+                            }
+                            Err(err) => Poll::Ready(Err(err)),
+                        };
+                    } else {
+                        return Poll::Ready(Err(err));
+                    }
+                }
+            }
+        }
+
+        // 空きを確認してからアクセプトするまでに、他の Accept が接続枠を確保した場合は
+        // アクセプトしたコネクションを保持したまま、接続枠が空くまで待機
+        let permit = match &self.listener.limit {
+            Some(limit) => match limit.poll_acquire(cx) {
+                Poll::Ready(permit) => Some(permit),
+                Poll::Pending => return Poll::Pending,
+            },
+            None => None,
+        };
+
+        // アクセプトした倍は
+        // 読み込みと書き込み用オブジェクト及びアドレスを Connection にまとめてリターン
+        let (stream, addr) = self.accepted.take().unwrap();
+        let listener = self.listener;
+        let nodelay = listener.nodelay;
+        let accepted = stream.set_nodelay(nodelay).and_then(|_| {
+            let stream0 = stream.try_clone()?;
+            let selector = listener.selector_for(stream0.as_raw_fd());
+            let mut reader = AsyncReader::new(stream0, selector)?;
+            let selector = listener.selector_for(stream.as_raw_fd());
+            let mut writer = AsyncWriter::new(stream, selector)?;
+            reader.permit = permit.clone();
+            writer.permit = permit;
+            Ok(Connection {
+                reader,
+                writer,
+                peer_addr: addr,
+            })
+        });
+        Poll::Ready(accepted)
+    }
+}
+
+impl<'a> Drop for Accept<'a> {
+    fn drop(&mut self) {
+        // 完了前に破棄された場合は、登録した Waker が残らないように取り消す
+        if self.registered {
+            let fd = self.listener.listener.as_raw_fd();
+            let _ = self.listener.selector.cancel(Interest::READ, fd);
+        }
+    }
+}
+
+// Unix ドメインソケットの非同期リスナ
+// TCP のポートを使わずに、同じホスト上のプロセス間で通信する場合に用いる
+#[allow(dead_code)] // main では使用しない
+struct AsyncUnixListener {
+    listener: UnixListener,
+    selector: Arc<IOSelector>,
+}
+
+#[allow(dead_code)] // main では使用しない
+impl AsyncUnixListener {
+    // path にソケットファイルを作成して待ち受ける
+    // ソケットファイルは破棄時に削除されないため、再度 bind する場合は呼び出し元で削除する
+    fn bind<P: AsRef<Path>>(path: P, selector: Arc<IOSelector>) -> io::Result<AsyncUnixListener> {
+        let listener = UnixListener::bind(path)?;
+        // ノンブロッキングに設定
+        listener.set_nonblocking(true)?;
+        Ok(AsyncUnixListener { listener, selector })
+    }
+
+    // コネクションをアクセプトするための Future をリターン
+    fn accept(&self) -> UnixAccept<'_> {
+        UnixAccept {
+            listener: self,
+            registered: false,
+        }
+    }
+}
+
+impl Drop for AsyncUnixListener {
+    fn drop(&mut self) {
+        let _ = self.selector.unregister(self.listener.as_raw_fd());
+    }
+}
+
+// Unix ドメインソケットの非同期アクセプト用 Future
+// Accept と同じく、アクセプトすべきコネクションがない場合は IOSelector に登録して待機する
+struct UnixAccept<'a> {
+    listener: &'a AsyncUnixListener,
+    registered: bool, // IOSelector に登録して待機中か
+}
+
+impl<'a> Future for UnixAccept<'a> {
+    type Output = io::Result<(
+        AsyncReader<UnixStream>, // 非同期読み込みストリーム
+        AsyncWriter<UnixStream>, // 非同期書き込みストリーム
+        UnixSocketAddr,          // アドレス
+    )>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.registered = false;
         match self.listener.listener.accept() {
             Ok((stream, addr)) => {
-                // アクセプトした倍は
-                // 読み込みと書き込み用オブジェクト及びアドレスをリターン
-                let stream0 = stream.try_clone().unwrap();
-                Poll::Ready((
-                    AsyncReader::new(stream0, self.listener.selector.clone()),
-                    BufWriter::new(stream),
-                    addr,
-                ))
+                let selector = &self.listener.selector;
+                let accepted = stream.try_clone().and_then(|stream0| {
+                    let reader = AsyncReader::new(stream0, selector.clone())?;
+                    let writer = AsyncWriter::new(stream, selector.clone())?;
+                    Ok((reader, writer, addr))
+                });
+                Poll::Ready(accepted)
             }
-            Err(err) => {
-                // アクセプトすべきコネクションがない場合は epoll に登録
-                if err.kind() == std::io::ErrorKind::WouldBlock {
-                    self.listener.selector.register(
-                        EpollFlags::EPOLLIN,
-                        self.listener.listener.as_raw_fd(),
-                        cx.waker().clone(),
-                    );
-                    Poll::Pending
-                } else {
-                    panic!("accept: {}", err)
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                // アクセプトすべきコネクションがない場合は IOSelector に登録
+                let fd = self.listener.listener.as_raw_fd();
+                match self
+                    .listener
+                    .selector
+                    .register(Interest::READ, fd, cx.waker().clone())
+                {
+                    Ok(()) => {
+                        self.registered = true;
+                        Poll::Pending
+                    }
+                    Err(err) => Poll::Ready(Err(err)),
                 }
             }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl<'a> Drop for UnixAccept<'a> {
+    fn drop(&mut self) {
+        // 完了前に破棄された場合は、登録した Waker が残らないように取り消す
+        if self.registered {
+            let fd = self.listener.listener.as_raw_fd();
+            let _ = self.listener.selector.cancel(Interest::READ, fd);
         }
     }
 }
 
-struct AsyncReader {
+// 非同期読み込みストリーム
+// TcpStream に限らず、UnixStream やパイプなど読み込み可能な任意の fd を扱える
+struct AsyncReader<S = TcpStream> {
     fd: RawFd,
-    reader: BufReader<TcpStream>,
+    reader: BufReader<S>,
+    // 読み込み途中のデータ。完了するまで poll をまたいで保持する
+    // read_line と read_exact、read_to_end で共用する
+    line: Vec<u8>,
+    max_line_len: usize,                   // 1行の最大バイト数（改行を含む）
+    discarding: bool,                      // 長すぎる行の残りを、次の改行まで読み捨て中か
+    trigger: Interest,                     // Interest::EDGE を指定するとエッジトリガで監視
+    eof: bool,                             // 相手が書き込み側をクローズし、EOF まで読み込んだか
+    permit: Option<Arc<ConnectionPermit>>, // アクセプト時に確保した接続枠
     selector: Arc<IOSelector>,
 }
 
-impl AsyncReader {
-    fn new(stream: TcpStream, selector: Arc<IOSelector>) -> AsyncReader {
+// 1行が AsyncReader の max_line_len を超えた場合のエラー
+// io::ErrorKind::InvalidData の io::Error に格納してリターンする
+#[derive(Debug)]
+struct LineTooLong {
+    max_line_len: usize,
+}
+
+impl std::fmt::Display for LineTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line exceeds {} bytes", self.max_line_len)
+    }
+}
+
+impl std::error::Error for LineTooLong {}
+
+impl<S: AsRawFd + Read> AsyncReader<S> {
+    fn new(stream: S, selector: Arc<IOSelector>) -> io::Result<AsyncReader<S>> {
         // ノンブロッキングに設定
-        stream.set_nonblocking(true).unwrap();
-        AsyncReader {
-            fd: stream.as_raw_fd(),
+        // 標準入力など他と共有している fd の場合、共有先の読み込みもノンブロッキングとなる
+        let fd = stream.as_raw_fd();
+        set_nonblocking(fd)?;
+        Ok(AsyncReader {
+            fd,
             reader: BufReader::new(stream),
+            line: Vec::new(),
+            max_line_len: usize::MAX,
+            trigger: Interest::empty(),
+            discarding: false,
+            eof: false,
+            permit: None,
             selector,
-        }
+        })
+    }
+
+    // 1行の最大バイト数を設定
+    // 改行が届かないまま超えた場合、読み込み途中の行を破棄して LineTooLong エラーとする
+    // その行の残りは次の改行まで読み捨てるため、続く read_line は次の行から読み込む
+    #[allow(dead_code)] // main では使用しない
+    fn set_max_line_len(&mut self, max_line_len: usize) {
+        self.max_line_len = max_line_len;
+    }
+
+    // エッジトリガで監視するか設定
+    // 読み込みは常に WouldBlock となるまで行ってから IOSelector に登録するため、
+    // バックログの多いソケットでも1回の起床でまとめて読み込める
+    #[allow(dead_code)] // main では使用しない
+    fn set_edge_triggered(&mut self, edge: bool) {
+        self.trigger = if edge {
+            Interest::EDGE
+        } else {
+            Interest::empty()
+        };
+    }
+
+    // 相手がコネクションをクローズ、またはハーフクローズ（shutdown(SHUT_WR)）したか
+    // 一度 EOF を読み込むと、以降の read_line は読み込みを行わずに None をリターンする
+    // ハーフクローズの場合、AsyncWriter からは引き続き書き込める
+    #[allow(dead_code)] // main では使用しない
+    fn is_peer_closed(&self) -> bool {
+        self.eof
     }
 
     // 1行読み込みのための Future をリターン
-    fn read_line(&mut self) -> ReadLine {
-        ReadLine { reader: self }
+    #[allow(dead_code)] // main では使用しない
+    fn read_line(&mut self) -> ReadLine<'_, S> {
+        ReadLine {
+            reader: self,
+            registered: false,
+        }
+    }
+
+    // 1行読み込み、buf をクリアしてから格納するための Future をリターン
+    // 読み込んだバイト数をリターンし、コネクションクローズの場合は 0 となる
+    // 同じ buf を使い回すことで、行ごとにメモリを確保せずに読み込める
+    fn read_line_into<'b>(&'b mut self, buf: &'b mut String) -> ReadLineInto<'b, S> {
+        ReadLineInto {
+            reader: self,
+            buf,
+            registered: false,
+        }
+    }
+
+    // ちょうど n バイト読み込むための Future をリターン
+    // n バイト揃う前に EOF となった場合は UnexpectedEof エラーとなる
+    #[allow(dead_code)] // main では使用しない
+    fn read_exact(&mut self, n: usize) -> ReadExact<'_, S> {
+        ReadExact {
+            reader: self,
+            n,
+            registered: false,
+        }
+    }
+
+    // EOF まで読み込むための Future をリターン
+    #[allow(dead_code)] // main では使用しない
+    fn read_to_end(&mut self) -> ReadToEnd<'_, S> {
+        ReadToEnd {
+            reader: self,
+            registered: false,
+        }
+    }
+
+    // 1行ずつ読み込む Stream をリターン
+    // BufRead::lines と同様に、各行の末尾の改行は取り除かれる
+    #[allow(dead_code)] // main では使用しない
+    fn lines(self) -> Lines<S> {
+        Lines { reader: self }
+    }
+
+    // 改行まで self.line に読み込む
+    // BufRead::read_until と異なり、max_line_len を超えて読み込まない
+    fn read_until_newline(&mut self) -> io::Result<()> {
+        if self.eof {
+            return Ok(());
+        }
+        loop {
+            let available = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if available.is_empty() {
+                // コネクションクローズ、またはハーフクローズ
+                self.eof = true;
+                return Ok(());
+            }
+
+            let (found, used) = match available.iter().position(|b| *b == b'\n') {
+                Some(i) => (true, i + 1),
+                None => (false, available.len()),
+            };
+            // 前回 LineTooLong となった行の残りを読み捨てる
+            if self.discarding {
+                self.reader.consume(used);
+                self.discarding = !found;
+                continue;
+            }
+            if self.line.len() + used > self.max_line_len {
+                self.line.clear();
+                self.reader.consume(used);
+                // 行の残りがまだ届いていない場合は、次回の読み込みで改行まで読み捨てる
+                self.discarding = !found;
+                let err = LineTooLong {
+                    max_line_len: self.max_line_len,
+                };
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+            self.line.extend_from_slice(&available[..used]);
+            self.reader.consume(used);
+
+            if found {
+                return Ok(());
+            }
+        }
+    }
+
+    // 1行読み込みを試み、読み込めない場合は IOSelector に登録
+    fn poll_read_line(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<String>>> {
+        let mut buf = String::new();
+        self.poll_read_line_into(&mut buf, cx)
+            .map(|result| result.map(|n| (n > 0).then_some(buf)))
+    }
+
+    // 1行読み込みを試み、読み込めた場合は buf に格納
+    fn poll_read_line_into(
+        &mut self,
+        buf: &mut String,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<usize>> {
+        // 非同期読み込み
+        // WouldBlock の場合でも読み込めた分は self.line に追記されているため、
+        // 次回の poll ではその続きから読み込む
+        // 文字列への変換は行が揃ってから行う（マルチバイト文字が分割されて届く場合があるため）
+        match self.read_until_newline() {
+            Ok(_) => {
+                // 1行読み込み成功、または行が空ならコネクションクローズ
+                // 改行で終わらないのはコネクションがクローズされた場合のみ
+                // UTF-8 として不正な行はエラーとするが、行自体は読み捨てるので次の行は読み込める
+                // self.line はクリアのみ行い、確保済みの領域を次の行で再利用する
+                buf.clear();
+                let result = match std::str::from_utf8(&self.line) {
+                    Ok(line) => {
+                        buf.push_str(line);
+                        Ok(line.len())
+                    }
+                    Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+                };
+                self.line.clear();
+                Poll::Ready(result)
+            }
+            // 読み込みできない場合は IOSelector に登録
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.poll_register(cx),
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    // n バイト揃うまで self.line に読み込み、揃った場合はその n バイトをリターン
+    // WouldBlock の場合でも読み込めた分は self.line に残るため、Future を破棄してもデータは失われない
+    fn poll_read_exact(&mut self, n: usize, cx: &mut Context<'_>) -> Poll<io::Result<Vec<u8>>> {
+        while self.line.len() < n {
+            if self.eof {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            let available = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return self.poll_register(cx)
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+            if available.is_empty() {
+                self.eof = true;
+                continue;
+            }
+            let used = available.len().min(n - self.line.len());
+            self.line.extend_from_slice(&available[..used]);
+            self.reader.consume(used);
+        }
+
+        // 読み込み途中の行が n バイトより長い場合は、残りを次回の読み込みのために保持する
+        let rest = self.line.split_off(n);
+        Poll::Ready(Ok(std::mem::replace(&mut self.line, rest)))
+    }
+
+    // EOF まで self.line に読み込み、読み込んだデータをすべてリターン
+    fn poll_read_to_end(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Vec<u8>>> {
+        while !self.eof {
+            let available = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return self.poll_register(cx)
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+            if available.is_empty() {
+                self.eof = true;
+                break;
+            }
+            let used = available.len();
+            self.line.extend_from_slice(available);
+            self.reader.consume(used);
+        }
+        Poll::Ready(Ok(std::mem::take(&mut self.line)))
+    }
+
+    // 読み込み可能になるまで IOSelector に登録して Pending をリターン
+    fn poll_register<T>(&self, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+        let flags = Interest::READ | self.trigger;
+        match self.selector.register(flags, self.fd, cx.waker().clone()) {
+            Ok(()) => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
     }
 }
 
-impl Drop for AsyncReader {
+impl<S> Drop for AsyncReader<S> {
     fn drop(&mut self) {
-        self.selector.unregister(self.fd);
+        let _ = self.selector.unregister(self.fd);
     }
 }
 
-struct ReadLine<'a> {
-    reader: &'a mut AsyncReader,
+#[allow(dead_code)] // main では使用しない
+struct ReadLine<'a, S = TcpStream> {
+    reader: &'a mut AsyncReader<S>,
+    registered: bool, // IOSelector に登録して待機中か
 }
 
-impl<'a> Future for ReadLine<'a> {
-    type Output = Option<String>;
+impl<'a, S: AsRawFd + Read> Future for ReadLine<'a, S> {
+    type Output = io::Result<Option<String>>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut line = String::new();
+        // Pending となるのは IOSelector に登録した場合のみ
+        let result = self.reader.poll_read_line(cx);
+        self.registered = result.is_pending();
+        result
+    }
+}
 
-        // 非同期読み込み
-        match self.reader.reader.read_line(&mut line) {
-            Ok(0) => Poll::Ready(None),       // コネクションクローズ
-            Ok(_) => Poll::Ready(Some(line)), // 1行読み込み成功
-            Err(err) => {
-                // 読み込みできない場合は epoll に登録
-                if err.kind() == std::io::ErrorKind::WouldBlock {
-                    self.reader.selector.register(
-                        EpollFlags::EPOLLIN,
-                        self.reader.fd,
-                        cx.waker().clone(),
-                    );
-                    Poll::Pending
-                } else {
-                    Poll::Ready(None)
-                }
-            }
+impl<'a, S> Drop for ReadLine<'a, S> {
+    fn drop(&mut self) {
+        // 完了前に破棄された場合は、登録した Waker が残らないように取り消す
+        // 読み込み途中の行は AsyncReader に残るため、次の read_line で続きから読み込める
+        if self.registered {
+            let _ = self.reader.selector.cancel(Interest::READ, self.reader.fd);
         }
     }
 }
 
-struct Task {
-    // 実行するコルーチン
-    future: Mutex<BoxFuture<'static, ()>>,
-    // Executor へスケジューリングするためのチャネル
-    sender: SyncSender<Arc<Task>>,
+struct ReadLineInto<'a, S = TcpStream> {
+    reader: &'a mut AsyncReader<S>,
+    buf: &'a mut String, // 読み込んだ行の格納先
+    registered: bool,    // IOSelector に登録して待機中か
+}
+
+impl<'a, S: AsRawFd + Read> Future for ReadLineInto<'a, S> {
+    type Output = io::Result<usize>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let result = this.reader.poll_read_line_into(this.buf, cx);
+        this.registered = result.is_pending();
+        result
+    }
 }
 
-impl ArcWake for Task {
-    fn wake_by_ref(arc_self: &Arc<Self>) {
-        // 自身をスケジューリング
-        let self0 = arc_self.clone();
-        arc_self.sender.send(self0).unwrap();
+impl<'a, S> Drop for ReadLineInto<'a, S> {
+    fn drop(&mut self) {
+        // 読み込み途中の行は AsyncReader に残り、buf は変更しない
+        if self.registered {
+            let _ = self.reader.selector.cancel(Interest::READ, self.reader.fd);
+        }
     }
 }
 
-struct Executor {
-    // 実行キュー
-    sender: SyncSender<Arc<Task>>,
-    receiver: Receiver<Arc<Task>>,
+struct ReadExact<'a, S = TcpStream> {
+    reader: &'a mut AsyncReader<S>,
+    n: usize,         // 読み込むバイト数
+    registered: bool, // IOSelector に登録して待機中か
 }
 
-impl Executor {
-    fn new() -> Self {
-        // チャネルを生成
-        let (sender, receiver) = sync_channel(1024);
-        Executor {
-            sender: sender.clone(),
-            receiver,
-        }
+impl<'a, S: AsRawFd + Read> Future for ReadExact<'a, S> {
+    type Output = io::Result<Vec<u8>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let n = self.n;
+        let result = self.reader.poll_read_exact(n, cx);
+        self.registered = result.is_pending();
+        result
     }
+}
 
-    // 新たに Task を生成するための Spawner を作成
-    fn get_spawner(&self) -> Spawner {
-        Spawner {
-            sender: self.sender.clone(),
+impl<'a, S> Drop for ReadExact<'a, S> {
+    fn drop(&mut self) {
+        // 読み込み途中のデータは AsyncReader に残るため、次の read_exact で続きから読み込める
+        if self.registered {
+            let _ = self.reader.selector.cancel(Interest::READ, self.reader.fd);
         }
     }
+}
+
+struct ReadToEnd<'a, S = TcpStream> {
+    reader: &'a mut AsyncReader<S>,
+    registered: bool, // IOSelector に登録して待機中か
+}
+
+impl<'a, S: AsRawFd + Read> Future for ReadToEnd<'a, S> {
+    type Output = io::Result<Vec<u8>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = self.reader.poll_read_to_end(cx);
+        self.registered = result.is_pending();
+        result
+    }
+}
 
-    fn run(&self) {
-        // チャネルから Task を受信して順に実行
-        while let Ok(task) = self.receiver.recv() {
-            // コンテキストを生成
-            let mut future = task.future.lock().unwrap();
-            let waker = waker_ref(&task);
-            let mut ctx = Context::from_waker(&waker);
-            // poll 呼び出し実行
-            let _ = future.as_mut().poll(&mut ctx);
+impl<'a, S> Drop for ReadToEnd<'a, S> {
+    fn drop(&mut self) {
+        if self.registered {
+            let _ = self.reader.selector.cancel(Interest::READ, self.reader.fd);
         }
     }
 }
 
-struct Spawner {
-    sender: SyncSender<Arc<Task>>,
+// AsyncReader から1行ずつ読み込む Stream
+// コネクションがクローズされると終了する
+struct Lines<S = TcpStream> {
+    reader: AsyncReader<S>,
 }
 
-impl Spawner {
-    // 今回のコードは Output = Option<String> のやつもあったけどそれはここには関係ないのかな
-    fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) {
-        let future = future.boxed();
-        let task = Arc::new(Task {
-            future: Mutex::new(future),
-            sender: self.sender.clone(),
-        });
+impl<S: AsRawFd + Read + Unpin> Stream for Lines<S> {
+    type Item = io::Result<String>;
 
-        // 実行キューにえんきゅー
-        self.sender.send(task).unwrap();
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.reader.poll_read_line(cx).map(|line| match line {
+            Ok(Some(mut line)) => {
+                // 末尾の改行を取り除く
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                Some(Ok(line))
+            }
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        })
     }
 }
 
-fn main() {
-    let executor = Executor::new();
-    let selector = IOSelector::new();
-    let spawner = executor.get_spawner();
+// 非同期書き込みストリーム
+// AsyncReader と同じく、書き込み可能な任意の fd を扱える
+struct AsyncWriter<S = TcpStream> {
+    fd: RawFd,
+    stream: S,
+    permit: Option<Arc<ConnectionPermit>>, // アクセプト時に確保した接続枠
+    selector: Arc<IOSelector>,
+}
 
-    let server = async move {
-        let listener = AsyncListener::listen("127.0.0.1:10000", selector.clone());
+impl<S: AsRawFd + Write> AsyncWriter<S> {
+    fn new(stream: S, selector: Arc<IOSelector>) -> io::Result<AsyncWriter<S>> {
+        // ノンブロッキングに設定
+        let fd = stream.as_raw_fd();
+        set_nonblocking(fd)?;
+        Ok(AsyncWriter {
+            fd,
+            stream,
+            permit: None,
+            selector,
+        })
+    }
 
-        loop {
-            // 非同期コネクションアクセプト
-            let (mut reader, mut writer, addr) = listener.accept().await;
-            println!("accept: {}", addr);
-
-            // コネクションごとにタスクを作成
-            spawner.spawn(async move {
-                // 1行非同期読み込み
-                while let Some(buf) = reader.read_line().await {
-                    print!("read: {}, {}", addr, buf);
-                    writer.write_all(buf.as_bytes()).unwrap();
-                    writer.flush().unwrap();
-                }
-                println!("close: {}", addr);
-            });
+    // buf をすべて書き込むための Future をリターン
+    fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> WriteAll<'a, S> {
+        WriteAll {
+            writer: self,
+            buf,
+            pos: 0,
+            registered: false,
         }
-    };
+    }
+}
 
-    // タスクを生成して実行
-    executor.get_spawner().spawn(server);
-    executor.run();
+impl<S> Drop for AsyncWriter<S> {
+    fn drop(&mut self) {
+        let _ = self.selector.unregister(self.fd);
+    }
+}
+
+// 非同期書き込み用 Future
+// 一度に全部書き込めるとは限らないため、書き込み済みの位置を pos に保持して続きから書き込む
+struct WriteAll<'a, S = TcpStream> {
+    writer: &'a mut AsyncWriter<S>,
+    buf: &'a [u8],
+    pos: usize,       // 書き込み済みのバイト数
+    registered: bool, // IOSelector に登録して待機中か
+}
+
+impl<'a, S: AsRawFd + Write> Future for WriteAll<'a, S> {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.registered = false;
+        while this.pos < this.buf.len() {
+            // 非同期書き込み
+            match this.writer.stream.write(&this.buf[this.pos..]) {
+                Ok(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Ok(n) => this.pos += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    // 送信バッファが一杯の場合は書き込み可能になるまで IOSelector に登録
+                    return match this.writer.selector.register(
+                        Interest::WRITE,
+                        this.writer.fd,
+                        cx.waker().clone(),
+                    ) {
+                        Ok(()) => {
+                            this.registered = true;
+                            Poll::Pending
+                        }
+                        Err(err) => Poll::Ready(Err(err)),
+                    };
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a, S> Drop for WriteAll<'a, S> {
+    fn drop(&mut self) {
+        // 完了前に破棄された場合は、登録した Waker が残らないように取り消す
+        if self.registered {
+            let _ = self.writer.selector.cancel(Interest::WRITE, self.writer.fd);
+        }
+    }
+}
+
+// TCP のクライアント側の接続
+struct AsyncStream;
+
+impl AsyncStream {
+    // addr へ接続するための Future をリターン
+    // ノンブロッキングに connect を開始し、接続が完了すると読み込みと書き込み用のストリームをリターンする
+    #[allow(dead_code)] // main では使用しない
+    fn connect(addr: SocketAddr, selector: Arc<IOSelector>) -> Connect {
+        let family = match addr {
+            SocketAddr::V4(_) => AddressFamily::Inet,
+            SocketAddr::V6(_) => AddressFamily::Inet6,
+        };
+
+        let stream = nonblocking_socket(family, SockType::Stream).and_then(|fd| {
+            // 以降はエラー時も含めて TcpStream がクローズする
+            let stream = unsafe { TcpStream::from_raw_fd(fd) };
+            match connect(fd, &SockAddr::new_inet(InetAddr::from_std(&addr))) {
+                // ノンブロッキングの場合、接続が完了していなければ EINPROGRESS となる
+                Ok(()) | Err(nix::Error::Sys(Errno::EINPROGRESS)) => Ok(stream),
+                Err(err) => Err(err),
+            }
+        });
+
+        match stream {
+            Ok(stream) => Connect {
+                stream: Some(stream),
+                error: None,
+                selector,
+            },
+            Err(err) => Connect {
+                stream: None,
+                error: Some(nix_to_io(err)),
+                selector,
+            },
+        }
+    }
+}
+
+// 非同期接続用 Future
+// 接続が完了するとソケットが書き込み可能になるため、Interest::WRITE で登録して待機する
+struct Connect {
+    stream: Option<TcpStream>,
+    error: Option<io::Error>, // connect の開始に失敗した場合のエラー。最初の poll でリターン
+    selector: Arc<IOSelector>,
+}
+
+impl Future for Connect {
+    type Output = io::Result<(AsyncReader, AsyncWriter)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(err) = self.error.take() {
+            return Poll::Ready(Err(err));
+        }
+        let stream = self.stream.as_ref().expect("polled after completion");
+        let fd = stream.as_raw_fd();
+
+        // 接続に失敗した場合は SO_ERROR にエラーが設定される
+        match getsockopt(fd, sockopt::SocketError) {
+            Ok(0) => (),
+            Ok(errno) => {
+                self.stream = None;
+                let _ = self.selector.unregister(fd);
+                return Poll::Ready(Err(io::Error::from_raw_os_error(errno)));
+            }
+            Err(err) => return Poll::Ready(Err(nix_to_io(err))),
+        }
+
+        // 接続先アドレスが取得できなければ接続中
+        match stream.peer_addr() {
+            Ok(_) => {
+                let stream = self.stream.take().unwrap();
+                let selector = self.selector.clone();
+                let accepted = stream.try_clone().and_then(|stream0| {
+                    Ok((
+                        AsyncReader::new(stream0, selector.clone())?,
+                        AsyncWriter::new(stream, selector)?,
+                    ))
+                });
+                Poll::Ready(accepted)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotConnected => {
+                match self
+                    .selector
+                    .register(Interest::WRITE, fd, cx.waker().clone())
+                {
+                    Ok(()) => Poll::Pending,
+                    Err(err) => Poll::Ready(Err(err)),
+                }
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl Drop for Connect {
+    fn drop(&mut self) {
+        // 接続完了前に破棄された場合は IOSelector の監視から削除
+        if let Some(stream) = &self.stream {
+            let _ = self.selector.unregister(stream.as_raw_fd());
+        }
+    }
+}
+
+// 非同期 UDP ソケット
+// 読み込みと書き込みは別方向として登録されるため、recv_from と send_to を同時に待機できる
+#[allow(dead_code)] // main では使用しない
+struct AsyncUdpSocket {
+    socket: UdpSocket,
+    selector: Arc<IOSelector>,
+}
+
+#[allow(dead_code)] // main では使用しない
+impl AsyncUdpSocket {
+    fn bind(addr: impl ToSocketAddrs, selector: Arc<IOSelector>) -> io::Result<AsyncUdpSocket> {
+        let socket = UdpSocket::bind(addr)?;
+        // ノンブロッキングに設定
+        socket.set_nonblocking(true)?;
+        Ok(AsyncUdpSocket { socket, selector })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // データグラムを1つ受信するための Future をリターン
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFrom<'a> {
+        RecvFrom {
+            socket: self,
+            buf,
+            registered: false,
+        }
+    }
+
+    // データグラムを1つ送信するための Future をリターン
+    fn send_to<'a>(&'a self, buf: &'a [u8], addr: SocketAddr) -> SendTo<'a> {
+        SendTo {
+            socket: self,
+            buf,
+            addr,
+            registered: false,
+        }
+    }
+
+    // WouldBlock の場合は IOSelector に登録して Pending をリターン
+    fn poll_io<T>(
+        &self,
+        flags: Interest,
+        cx: &mut Context<'_>,
+        mut f: impl FnMut(&UdpSocket) -> io::Result<T>,
+    ) -> Poll<io::Result<T>> {
+        loop {
+            match f(&self.socket) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let fd = self.socket.as_raw_fd();
+                    return match self.selector.register(flags, fd, cx.waker().clone()) {
+                        Ok(()) => Poll::Pending,
+                        Err(err) => Poll::Ready(Err(err)),
+                    };
+                }
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl Drop for AsyncUdpSocket {
+    fn drop(&mut self) {
+        let _ = self.selector.unregister(self.socket.as_raw_fd());
+    }
+}
+
+struct RecvFrom<'a> {
+    socket: &'a AsyncUdpSocket,
+    buf: &'a mut [u8],
+    registered: bool, // IOSelector に登録して待機中か
+}
+
+impl<'a> Future for RecvFrom<'a> {
+    type Output = io::Result<(usize, SocketAddr)>; // 受信したバイト数と送信元アドレス
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let result = this
+            .socket
+            .poll_io(Interest::READ, cx, |socket| socket.recv_from(this.buf));
+        this.registered = result.is_pending();
+        result
+    }
+}
+
+impl<'a> Drop for RecvFrom<'a> {
+    fn drop(&mut self) {
+        // 完了前に破棄された場合は、登録した Waker が残らないように取り消す
+        // send_to で待機中の Waker は残す
+        if self.registered {
+            let fd = self.socket.socket.as_raw_fd();
+            let _ = self.socket.selector.cancel(Interest::READ, fd);
+        }
+    }
+}
+
+struct SendTo<'a> {
+    socket: &'a AsyncUdpSocket,
+    buf: &'a [u8],
+    addr: SocketAddr,
+    registered: bool, // IOSelector に登録して待機中か
+}
+
+impl<'a> Future for SendTo<'a> {
+    type Output = io::Result<usize>; // 送信したバイト数
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (buf, addr) = (self.buf, self.addr);
+        let result = self
+            .socket
+            .poll_io(Interest::WRITE, cx, |socket| socket.send_to(buf, addr));
+        self.registered = result.is_pending();
+        result
+    }
+}
+
+impl<'a> Drop for SendTo<'a> {
+    fn drop(&mut self) {
+        // 完了前に破棄された場合は、登録した Waker が残らないように取り消す
+        if self.registered {
+            let fd = self.socket.socket.as_raw_fd();
+            let _ = self.socket.selector.cancel(Interest::WRITE, fd);
+        }
+    }
+}
+
+// 1行ずつ読み込んでそのまま送り返すエコーサーバ
+// IOSelector のバックエンド（epoll、kqueue）には依存しない
+// token が cancel されるまでコネクションをアクセプトし、送信された行をそのまま返す
+// cancel 後は新たなコネクションをアクセプトせず、接続中のコネクションがクライアントから切断されてからリターン
+async fn echo_server(listener: AsyncListener, spawner: Spawner, token: CancellationToken) {
+    // 各コネクションのタスクが Sender を保持し、すべて破棄されると recv が完了する
+    let (conn_tx, conn_rx) = channel::channel::<()>(1);
+
+    loop {
+        // 非同期コネクションアクセプト
+        // アクセプトに失敗してもサーバは停止させない
+        let accepted = match select(listener.accept(), token.cancelled()).await {
+            Either::Left(accepted) => accepted,
+            Either::Right(()) => {
+                // 待ち受けを終了し、以降の接続を拒否する
+                drop(listener);
+                break;
+            }
+        };
+        let conn = match accepted {
+            Ok(conn) => conn,
+            Err(err) => {
+                eprintln!("accept: {}", err);
+                continue;
+            }
+        };
+        let addr = conn.peer_addr();
+        println!("accept: {}", addr);
+        let (mut reader, mut writer) = conn.split();
+
+        // コネクションごとにタスクを作成
+        // cancel されても接続中のコネクションは切断せず、クライアントが切断するまで処理を続ける
+        let conn_tx = conn_tx.clone();
+        spawner.spawn(async move {
+            let _conn_tx = conn_tx;
+            // 1行非同期読み込み
+            // 読み込み先のバッファはコネクション内で使い回す
+            let mut buf = String::new();
+            while let Ok(1..) = reader.read_line_into(&mut buf).await {
+                print!("read: {}, {}", addr, buf);
+                if writer.write_all(buf.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            println!("close: {}", addr);
+        });
+    }
+
+    // 接続中のコネクションがすべて終了するまで待機
+    drop(conn_tx);
+    let _ = conn_rx.recv().await;
+}
+
+// 同時に接続できるコネクション数
+const MAX_CONNECTIONS: usize = 1024;
+
+// echo サーバを実行し、SIGINT を受信すると接続中のコネクションが終了してからリターン
+fn run_echo_server(listener: AsyncListener, selector: Arc<IOSelector>) -> io::Result<()> {
+    let executor = Executor::new();
+    let token = CancellationToken::new();
+
+    // signalfd は Linux のみ使用できるため、他の OS では SIGINT で直ちに終了する
+    #[cfg(target_os = "linux")]
+    {
+        use futures::StreamExt;
+        use nix::sys::signal::Signal;
+
+        // ワーカースレッドの生成前に SIGINT をブロックし、すべてのスレッドで signalfd から受信させる
+        let mut sigint = signal::signals(selector.clone(), &[Signal::SIGINT])?;
+        let token = token.clone();
+        executor.get_spawner().spawn(async move {
+            if sigint.next().await.is_some() {
+                println!("shutdown");
+                token.cancel();
+            }
+        });
+    }
+
+    let server = executor.get_spawner().spawn_with_output(echo_server(
+        listener,
+        executor.get_spawner(),
+        token,
+    ));
+    std::thread::scope(|s| {
+        s.spawn(|| executor.run());
+        server.join();
+        // 待機中のタスクを起床させてから Executor を停止
+        selector.shutdown();
+        executor.shutdown();
+    });
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    // IOSelector の監視用スレッドに SIGINT が配送されないように、スレッドの生成前にブロックする
+    #[cfg(target_os = "linux")]
+    {
+        let mut mask = nix::sys::signal::SigSet::empty();
+        mask.add(nix::sys::signal::Signal::SIGINT);
+        mask.thread_block().map_err(nix_to_io)?;
+    }
+
+    let selector = IOSelector::new()?;
+    let listener = AsyncListener::listen("127.0.0.1:10000", selector.clone())?
+        .with_max_connections(MAX_CONNECTIONS);
+    run_echo_server(listener, selector)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Read};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    // Executor を別スレッドで動かし、future が完了するまで待機
+    fn run<F: Future<Output = ()> + Send + 'static>(future: F) {
+        let executor = Executor::new();
+        let (tx, rx) = channel();
+        executor.get_spawner().spawn(async move {
+            future.await;
+            tx.send(()).unwrap();
+        });
+        std::thread::spawn(move || executor.run());
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    // エフェメラルポートで待ち受ける AsyncListener とそのアドレスを作成
+    fn listen(selector: Arc<IOSelector>) -> (AsyncListener, SocketAddr) {
+        let listener = AsyncListener::listen("127.0.0.1:0", selector).unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[test]
+    fn test_echo_with_async_writer() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"hello\nworld\n").unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut lines = Vec::new();
+            for line in BufReader::new(stream).lines() {
+                lines.push(line.unwrap());
+            }
+            lines
+        });
+
+        run(async move {
+            let (mut reader, mut writer) = listener.accept().await.unwrap().split();
+            while let Some(line) = reader.read_line().await.unwrap() {
+                writer.write_all(line.as_bytes()).await.unwrap();
+            }
+        });
+
+        assert_eq!(client.join().unwrap(), vec!["hello", "world"]);
+    }
+
+    #[test]
+    fn test_connection() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let local = stream.local_addr().unwrap();
+            stream.write_all(b"ping\n").unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            (local, line)
+        });
+
+        let (tx, rx) = channel();
+        run(async move {
+            let mut conn = listener.accept().await.unwrap();
+            let line = conn.reader().read_line().await.unwrap().unwrap();
+            assert_eq!(line, "ping\n");
+            conn.writer().write_all(b"pong\n").await.unwrap();
+            tx.send(conn.peer_addr()).unwrap();
+        });
+
+        // 接続相手のアドレスはクライアント側のソケットのアドレス
+        let (local, line) = client.join().unwrap();
+        assert_eq!(line, "pong\n");
+        assert_eq!(rx.recv().unwrap(), local);
+    }
+
+    #[test]
+    fn test_listen_on_pool() {
+        const NUM_SHARDS: usize = 3;
+        const NUM_CLIENTS: usize = 8;
+        let pool = IOSelectorPool::new(NUM_SHARDS).unwrap();
+        let listener = AsyncListener::listen_on_pool("127.0.0.1:0", pool.clone()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(Arc::ptr_eq(
+            &listener.selector,
+            pool.selector(listener.listener.as_raw_fd())
+        ));
+
+        let clients: Vec<_> = (0..NUM_CLIENTS)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    writeln!(stream, "client {}", i).unwrap();
+                    let mut line = String::new();
+                    BufReader::new(stream).read_line(&mut line).unwrap();
+                    line
+                })
+            })
+            .collect();
+
+        // アクセプトしたストリームはそれぞれの fd を担当する IOSelector で監視される
+        let (tx, rx) = channel();
+        let pool0 = pool.clone();
+        run(async move {
+            for _ in 0..NUM_CLIENTS {
+                let (mut reader, mut writer) = listener.accept().await.unwrap().split();
+                assert!(Arc::ptr_eq(&reader.selector, pool0.selector(reader.fd)));
+                assert!(Arc::ptr_eq(&writer.selector, pool0.selector(writer.fd)));
+                tx.send(reader.fd as usize % NUM_SHARDS).unwrap();
+                tx.send(writer.fd as usize % NUM_SHARDS).unwrap();
+                let line = reader.read_line().await.unwrap().unwrap();
+                writer.write_all(line.as_bytes()).await.unwrap();
+            }
+        });
+
+        for (i, client) in clients.into_iter().enumerate() {
+            let line = client.join().unwrap();
+            assert!(line.starts_with("client "), "{}: {}", i, line);
+        }
+        // 読み込みと書き込みで 2 * NUM_CLIENTS 個の fd が、複数の IOSelector に分散する
+        let shards: std::collections::HashSet<_> = rx.try_iter().collect();
+        assert!(shards.len() > 1, "{:?}", shards);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_echo_server() {
+        const NUM_CLIENTS: usize = 8;
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let token = CancellationToken::new();
+        executor
+            .get_spawner()
+            .spawn(echo_server(listener, spawner, token));
+        std::thread::spawn(move || executor.run_workers(4));
+
+        // 複数のクライアントから同時に接続し、送信した行がそのまま返ることを確認
+        let clients: Vec<_> = (0..NUM_CLIENTS)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    stream
+                        .set_read_timeout(Some(Duration::from_secs(10)))
+                        .unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut lines = Vec::new();
+                    for j in 0..3 {
+                        let msg = format!("{}-{}\n", i, j);
+                        stream.write_all(msg.as_bytes()).unwrap();
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        lines.push(line);
+                    }
+                    lines
+                })
+            })
+            .collect();
+
+        for (i, client) in clients.into_iter().enumerate() {
+            let expected: Vec<_> = (0..3).map(|j| format!("{}-{}\n", i, j)).collect();
+            assert_eq!(client.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_half_close() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector.clone());
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (mut reader, mut writer) = executor::block_on(listener.accept()).unwrap().split();
+        let fd = reader.fd;
+
+        let (tx, rx) = channel();
+        let executor = Executor::new();
+        executor.get_spawner().spawn(async move {
+            let line = reader.read_line().await.unwrap();
+            // ハーフクローズ後も書き込める
+            writer.write_all(b"bye\n").await.unwrap();
+            tx.send((line, reader.is_peer_closed())).unwrap();
+        });
+        std::thread::spawn(move || executor.run());
+
+        // 読み込みで待機している状態でハーフクローズすると、すぐに EOF を受け取る
+        wait_until(|| selector.is_registered(fd));
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let (line, peer_closed) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(line, None);
+        assert!(peer_closed);
+
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "bye\n");
+    }
+
+    #[test]
+    fn test_unix_echo() {
+        let dir = std::env::temp_dir().join(format!("ch5_ioselect_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("echo.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let selector = IOSelector::new().unwrap();
+        let listener = AsyncUnixListener::bind(&path, selector).unwrap();
+
+        let path0 = path.clone();
+        let client = std::thread::spawn(move || {
+            let mut stream = UnixStream::connect(path0).unwrap();
+            stream.write_all(b"hello\nworld\n").unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        });
+
+        run(async move {
+            let (mut reader, mut writer, _) = listener.accept().await.unwrap();
+            while let Some(line) = reader.read_line().await.unwrap() {
+                writer.write_all(line.as_bytes()).await.unwrap();
+            }
+        });
+
+        assert_eq!(client.join().unwrap(), "hello\nworld\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_line_into_reuses_buffer() {
+        const NUM_LINES: usize = 1000;
+        const LINE: &str = "hello world\n";
+        let selector = IOSelector::new().unwrap();
+        let (rfd, wfd) = nix::unistd::pipe().unwrap();
+        let (rx, mut tx) = unsafe { (File::from_raw_fd(rfd), File::from_raw_fd(wfd)) };
+        let mut reader = AsyncReader::new(rx, selector).unwrap();
+
+        // パイプのバッファに収まる量を書き込んでおく
+        tx.write_all(LINE.repeat(NUM_LINES).as_bytes()).unwrap();
+        drop(tx);
+
+        executor::block_on(async move {
+            // 最初の行で確保した領域を以降の行で再利用し、再確保しない
+            let mut buf = String::new();
+            reader.read_line_into(&mut buf).await.unwrap();
+            let (ptr, cap) = (buf.as_ptr(), buf.capacity());
+            for _ in 1..NUM_LINES {
+                let n = reader.read_line_into(&mut buf).await.unwrap();
+                assert_eq!(n, LINE.len());
+                assert_eq!(buf, LINE);
+                assert_eq!((buf.as_ptr(), buf.capacity()), (ptr, cap));
+            }
+
+            // EOF では 0 をリターン
+            assert_eq!(reader.read_line_into(&mut buf).await.unwrap(), 0);
+            assert!(buf.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_pipe_read_line() {
+        let selector = IOSelector::new().unwrap();
+        let (rfd, wfd) = nix::unistd::pipe().unwrap();
+        let (rx, mut tx) = unsafe { (File::from_raw_fd(rfd), File::from_raw_fd(wfd)) };
+        let mut reader = AsyncReader::new(rx, selector.clone()).unwrap();
+        let fd = reader.fd;
+
+        let writer = std::thread::spawn(move || {
+            // 読み込み側が IOSelector に登録されてから、行を分割して書き込む
+            wait_until(|| selector.is_registered(fd));
+            tx.write_all(b"hello\nwor").unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            tx.write_all(b"ld\n").unwrap();
+            // 書き込み側をクローズすると、読み込み側は EOF を受け取る
+        });
+
+        let lines = executor::block_on(async move {
+            let mut lines = Vec::new();
+            while let Some(line) = reader.read_line().await.unwrap() {
+                lines.push(line);
+            }
+            lines
+        });
+        writer.join().unwrap();
+        assert_eq!(lines, vec!["hello\n", "world\n"]);
+    }
+
+    #[test]
+    fn test_read_exact_length_prefixed() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+
+        // 4 バイトのビッグエンディアンの長さに続けて本体を送信するメッセージ
+        // 長さと本体の境界をまたいで分割して送信する
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut msg = Vec::new();
+            for body in [&b"hello"[..], &b"async world"[..]] {
+                msg.extend_from_slice(&(body.len() as u32).to_be_bytes());
+                msg.extend_from_slice(body);
+            }
+            for chunk in msg.chunks(3) {
+                stream.write_all(chunk).unwrap();
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            stream.write_all(b"rest").unwrap();
+        });
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results0 = results.clone();
+        run(async move {
+            let (mut reader, _writer) = listener.accept().await.unwrap().split();
+            for _ in 0..2 {
+                let len = reader.read_exact(4).await.unwrap();
+                let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                let body = reader.read_exact(len).await.unwrap();
+                results0.lock().unwrap().push(body);
+            }
+            let rest = reader.read_to_end().await.unwrap();
+            results0.lock().unwrap().push(rest);
+
+            // EOF 後は UnexpectedEof となる
+            let err = reader.read_exact(1).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        });
+
+        client.join().unwrap();
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![b"hello".to_vec(), b"async world".to_vec(), b"rest".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_drop_pending_read_exact() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector.clone());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (mut reader, _writer) = executor::block_on(listener.accept()).unwrap().split();
+        let fd = reader.fd;
+
+        // 一部のみ届いた状態で破棄しても、読み込んだ分は失われない
+        client.write_all(b"0123").unwrap();
+        let waker = futures::task::noop_waker();
+        let mut ctx = Context::from_waker(&waker);
+        let mut read_exact = reader.read_exact(8);
+        for _ in 0..1000 {
+            assert!(Pin::new(&mut read_exact).poll(&mut ctx).is_pending());
+            if read_exact.reader.line.len() == 4 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(read_exact.reader.line.len(), 4);
+        wait_until(|| selector.is_registered(fd));
+        drop(read_exact);
+        wait_until(|| !selector.is_registered(fd));
+
+        client.write_all(b"4567").unwrap();
+        let buf = executor::block_on(reader.read_exact(8)).unwrap();
+        assert_eq!(buf, b"01234567");
+    }
+
+    #[test]
+    fn test_write_all_partial() {
+        // 送信バッファに収まらない大きさのデータを書き込む
+        const SIZE: usize = 16 * 1024 * 1024;
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // 読み込みを遅らせて、書き込み側で WouldBlock を発生させる
+            std::thread::sleep(Duration::from_millis(100));
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).unwrap();
+            buf
+        });
+
+        run(async move {
+            let (_reader, mut writer) = listener.accept().await.unwrap().split();
+            let data: Vec<u8> = (0..SIZE).map(|i| i as u8).collect();
+            writer.write_all(&data).await.unwrap();
+        });
+
+        let buf = client.join().unwrap();
+        assert_eq!(buf.len(), SIZE);
+        assert!(buf.iter().enumerate().all(|(i, b)| *b == i as u8));
+    }
+
+    #[test]
+    fn test_read_line_split_segments() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            // 1行を2つのセグメントに分けて送信する
+            // 「あ」の UTF-8 表現の途中で分割する
+            stream.write_all(b"hello, \xe3\x81").unwrap();
+            std::thread::sleep(Duration::from_millis(100));
+            stream.write_all(b"\x82\nworld").unwrap();
+        });
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let lines0 = lines.clone();
+        run(async move {
+            let (mut reader, _writer) = listener.accept().await.unwrap().split();
+            while let Some(line) = reader.read_line().await.unwrap() {
+                lines0.lock().unwrap().push(line);
+            }
+        });
+
+        client.join().unwrap();
+        assert_eq!(*lines.lock().unwrap(), vec!["hello, あ\n", "world"]);
+    }
+
+    #[test]
+    fn test_error_is_returned() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector.clone());
+
+        // 使用中のアドレスでの待ち受けはパニックせずにエラーとなる
+        let err = AsyncListener::listen(&addr.to_string(), selector)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"\xff\xfe\nok\n").unwrap();
+        });
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results0 = results.clone();
+        run(async move {
+            let (mut reader, _writer) = listener.accept().await.unwrap().split();
+            // 不正な UTF-8 の行はエラーとなるが、続く行は読み込める
+            loop {
+                match reader.read_line().await {
+                    Ok(None) => break,
+                    result => results0
+                        .lock()
+                        .unwrap()
+                        .push(result.map_err(|err| err.kind())),
+                }
+            }
+        });
+
+        client.join().unwrap();
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![
+                Err(io::ErrorKind::InvalidData),
+                Ok(Some("ok\n".to_string()))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shutdown_wakes_pending() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, _) = listen(selector.clone());
+
+        let (tx, rx) = channel();
+        let executor = Executor::new();
+        executor.get_spawner().spawn(async move {
+            let result = listener.accept().await;
+            tx.send(result.map(|_| ()).map_err(|err| err.kind()))
+                .unwrap();
+        });
+        std::thread::spawn(move || executor.run());
+
+        // accept が IOSelector に登録されるのを待ってから shutdown
+        std::thread::sleep(Duration::from_millis(100));
+        selector.shutdown();
+
+        let result = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(result, Err(io::ErrorKind::Other));
+        let waker = futures::task::noop_waker();
+        assert!(selector.register(Interest::READ, 0, waker).is_err());
+    }
+
+    // wake された回数を数える Waker
+    struct CountWaker(std::sync::atomic::AtomicUsize);
+
+    impl futures::task::ArcWake for CountWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn count_waker() -> (Arc<CountWaker>, Waker) {
+        let count = Arc::new(CountWaker(Default::default()));
+        let waker = futures::task::waker(count.clone());
+        (count, waker)
+    }
+
+    // 条件が満たされるまで待機
+    fn wait_until(f: impl Fn() -> bool) {
+        for _ in 0..1000 {
+            if f() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn test_read_and_write_interest() {
+        let selector = IOSelector::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let fd = server.as_raw_fd();
+
+        // 同じ fd に読み込みと書き込みの両方を登録
+        let (read, read_waker) = count_waker();
+        let (write, write_waker) = count_waker();
+        selector.register(Interest::READ, fd, read_waker).unwrap();
+        selector.register(Interest::WRITE, fd, write_waker).unwrap();
+
+        // 書き込みはすぐに可能になるが、読み込みはまだ起床しない
+        wait_until(|| write.0.load(Ordering::SeqCst) == 1);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(read.0.load(Ordering::SeqCst), 0);
+
+        // データが届くと読み込み側も起床する
+        client.write_all(b"hello\n").unwrap();
+        wait_until(|| read.0.load(Ordering::SeqCst) == 1);
+        assert_eq!(write.0.load(Ordering::SeqCst), 1);
+
+        selector.unregister(fd).unwrap();
+    }
+
+    // poll された回数を数える Future
+    struct CountPoll<F> {
+        future: F,
+        count: usize,
+    }
+
+    impl<F: Future + Unpin> Future for CountPoll<F> {
+        type Output = F::Output;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            self.count += 1;
+            Pin::new(&mut self.future).poll(cx)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sleep() {
+        let selector = IOSelector::new().unwrap();
+        let mut timer = CountPoll {
+            future: timer::sleep(selector, Duration::from_millis(50)),
+            count: 0,
+        };
+
+        let start = std::time::Instant::now();
+        executor::block_on(&mut timer).unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_millis(500));
+        // IOSelector で待機するため、busy wait せずに 2 回の poll で完了する
+        assert_eq!(timer.count, 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sleep_cancel() {
+        let selector = IOSelector::new().unwrap();
+        let (count, waker) = count_waker();
+        let mut ctx = Context::from_waker(&waker);
+
+        // 満了前に破棄されたタイマーは wake しない
+        let mut timer = timer::sleep(selector.clone(), Duration::from_millis(50));
+        assert!(Pin::new(&mut timer).poll(&mut ctx).is_pending());
+        drop(timer);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(count.0.load(Ordering::SeqCst), 0);
+        assert_eq!(selector.num_registered(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_interval() {
+        use futures::StreamExt;
+
+        const N: u32 = 5;
+        let period = Duration::from_millis(20);
+        let selector = IOSelector::new().unwrap();
+        let start = std::time::Instant::now();
+        let mut interval = timer::interval(selector.clone(), period).unwrap();
+
+        // 満了予定時刻は起床時刻ではなく前回の満了予定時刻から計算される
+        let mut targets = Vec::new();
+        for _ in 0..N {
+            targets.push(executor::block_on(interval.tick()).unwrap());
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= period * N);
+        assert!(elapsed < period * N + Duration::from_millis(200));
+        for w in targets.windows(2) {
+            assert_eq!(w[1] - w[0], period);
+        }
+
+        // Stream としても使用できる
+        let next: Vec<_> = executor::block_on(interval.by_ref().take(2).collect());
+        assert_eq!(next[0] - targets[N as usize - 1], period);
+        assert_eq!(next[1] - next[0], period);
+
+        drop(interval);
+        assert_eq!(selector.num_registered(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_with_timeout() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector.clone());
+
+        // 接続するが何も送信しないクライアント
+        let (tx, rx) = channel::<()>();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            rx.recv().unwrap();
+            stream.write_all(b"hello\n").unwrap();
+        });
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results0 = results.clone();
+        run(async move {
+            let (mut reader, _writer) = listener.accept().await.unwrap().split();
+            let dur = Duration::from_millis(100);
+            let result = timer::with_timeout(selector.clone(), reader.read_line(), dur).await;
+            results0.lock().unwrap().push(result.map(|r| r.unwrap()));
+
+            // タイムアウト後も同じ reader から読み込める
+            tx.send(()).unwrap();
+            let dur = Duration::from_secs(10);
+            let result = timer::with_timeout(selector, reader.read_line(), dur).await;
+            results0.lock().unwrap().push(result.map(|r| r.unwrap()));
+        });
+
+        client.join().unwrap();
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![Err(timer::Timeout), Ok(Some("hello\n".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_udp_echo() {
+        let selector = IOSelector::new().unwrap();
+        let server = AsyncUdpSocket::bind("127.0.0.1:0", selector).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            let mut replies = Vec::new();
+            for msg in [&b"hello"[..], b"world"] {
+                socket.send_to(msg, addr).unwrap();
+                let mut buf = [0; 64];
+                let (n, from) = socket.recv_from(&mut buf).unwrap();
+                assert_eq!(from, addr);
+                replies.push(buf[..n].to_vec());
+            }
+            replies
+        });
+
+        // 受信したデータグラムを送信元に送り返す
+        run(async move {
+            let mut buf = [0; 64];
+            for _ in 0..2 {
+                let (n, from) = server.recv_from(&mut buf).await.unwrap();
+                let sent = server.send_to(&buf[..n], from).await.unwrap();
+                assert_eq!(sent, n);
+            }
+        });
+
+        assert_eq!(
+            client.join().unwrap(),
+            vec![b"hello".to_vec(), b"world".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_connect() {
+        let selector = IOSelector::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // 1行受信して送り返すサーバ
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            (&stream).write_all(line.as_bytes()).unwrap();
+        });
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let lines0 = lines.clone();
+        run(async move {
+            let connect = AsyncStream::connect(addr, selector);
+            let (mut reader, mut writer) = connect.await.unwrap();
+            writer.write_all(b"hello\n").await.unwrap();
+            while let Some(line) = reader.read_line().await.unwrap() {
+                lines0.lock().unwrap().push(line);
+            }
+        });
+
+        server.join().unwrap();
+        assert_eq!(*lines.lock().unwrap(), vec!["hello\n"]);
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        let selector = IOSelector::new().unwrap();
+        // 待ち受けていないポートを取得
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let result = executor::block_on(AsyncStream::connect(addr, selector));
+        assert_eq!(
+            result.err().unwrap().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn test_lines() {
+        use futures::TryStreamExt;
+
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            stream.write_all(b"one\ntwo\r\nth").unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            stream.write_all(b"ree\nfour").unwrap();
+        });
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let lines0 = lines.clone();
+        run(async move {
+            let (reader, _writer) = listener.accept().await.unwrap().split();
+            let collected: Vec<String> = reader.lines().try_collect().await.unwrap();
+            *lines0.lock().unwrap() = collected;
+        });
+
+        client.join().unwrap();
+        assert_eq!(*lines.lock().unwrap(), vec!["one", "two", "three", "four"]);
+    }
+
+    #[test]
+    fn test_max_line_len() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+
+        // 改行を送らずに大量のデータを送り続けるクライアント
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let buf = [b'a'; 4096];
+            while stream.write_all(&buf).is_ok() {}
+        });
+
+        let result = Arc::new(Mutex::new(None));
+        let result0 = result.clone();
+        run(async move {
+            let (mut reader, _writer) = listener.accept().await.unwrap().split();
+            reader.set_max_line_len(1024);
+            let err = reader.read_line().await.unwrap_err();
+            let too_long = err
+                .get_ref()
+                .and_then(|err| err.downcast_ref::<LineTooLong>());
+            *result0.lock().unwrap() = Some((
+                err.kind(),
+                too_long.map(|err| err.max_line_len),
+                reader.line.capacity(),
+            ));
+        });
+
+        let (kind, max_line_len, capacity) = result.lock().unwrap().take().unwrap();
+        assert_eq!(kind, io::ErrorKind::InvalidData);
+        assert_eq!(max_line_len, Some(1024));
+        assert!(capacity <= 1024);
+        // サーバ側が切断するとクライアントの書き込みが失敗して終了する
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_max_line_len_discards_rest() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+
+        // 長すぎる行を、上限を超えた後に残りが届くよう2回に分けて送信
+        // 残りだけなら上限に収まるため、読み捨てないと1行として返る
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&[b'a'; 1500]).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            stream.write_all(&[b'b'; 500]).unwrap();
+            stream.write_all(b"\nhello\n").unwrap();
+            // 最後の行は上限を超えた部分に改行が含まれる
+            stream.write_all(&[b'c'; 1100]).unwrap();
+            stream.write_all(b"\nworld\n").unwrap();
+        });
+
+        let (tx, rx) = channel();
+        run(async move {
+            let (mut reader, _writer) = listener.accept().await.unwrap().split();
+            reader.set_max_line_len(1024);
+            let mut lines = Vec::new();
+            loop {
+                match reader.read_line().await {
+                    Ok(Some(line)) => lines.push(line),
+                    Ok(None) => break,
+                    Err(err) => {
+                        assert!(err.get_ref().is_some_and(|err| err.is::<LineTooLong>()));
+                        lines.push("too long".to_string());
+                    }
+                }
+            }
+            tx.send(lines).unwrap();
+        });
+
+        // 長すぎる行の残りは次の行として返らない
+        assert_eq!(
+            rx.recv().unwrap(),
+            vec!["too long", "hello\n", "too long", "world\n"]
+        );
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_listen_options() {
+        let selector = IOSelector::new().unwrap();
+        let options = || ListenOptions::new().reuse_port(true).nodelay(true);
+        let listener = options().listen("127.0.0.1:0", selector.clone()).unwrap();
+        let addr = listener.listener.local_addr().unwrap();
+
+        // SO_REUSEPORT により同じポートで待ち受けられる
+        let other = options().listen(&addr.to_string(), selector.clone());
+        assert!(other.is_ok());
+        drop(other);
+
+        // サーバ側から切断して TIME_WAIT のコネクションを残す
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).unwrap();
+        });
+
+        let nodelay = Arc::new(Mutex::new(None));
+        let nodelay0 = nodelay.clone();
+        run(async move {
+            let (_reader, writer) = listener.accept().await.unwrap().split();
+            *nodelay0.lock().unwrap() = Some(writer.stream.nodelay().unwrap());
+        });
+        client.join().unwrap();
+        assert_eq!(*nodelay.lock().unwrap(), Some(true));
+
+        // 破棄した直後に同じポートで待ち受けてもエラーとならない
+        let listener = options().listen(&addr.to_string(), selector).unwrap();
+        assert_eq!(listener.listener.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn test_local_addr_and_accepted_count() {
+        let selector = IOSelector::new().unwrap();
+        let listener = Arc::new(AsyncListener::listen("127.0.0.1:0", selector).unwrap());
+        // ポート 0 で待ち受けると、OS が割り当てたポートが得られる
+        let addr = listener.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert_eq!(listener.accepted_count(), 0);
+
+        let clients: Vec<_> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let listener0 = listener.clone();
+        run(async move {
+            for _ in 0..3 {
+                listener0.accept().await.unwrap();
+            }
+        });
+        // アクセプトしたストリームが破棄されてもカウントは減らない
+        assert_eq!(listener.accepted_count(), 3);
+        drop(clients);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_max_connections() {
+        const MAX: usize = 2;
+        const NUM_CLIENTS: usize = 8;
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+        let listener = Arc::new(listener.with_max_connections(MAX));
+
+        // 各クライアントは1行送信し、返信を受け取ってから切断する
+        let clients: Vec<_> = (0..NUM_CLIENTS)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    writeln!(stream, "{}", i).unwrap();
+                    let mut line = String::new();
+                    BufReader::new(stream).read_line(&mut line).unwrap();
+                    line
+                })
+            })
+            .collect();
+
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = channel();
+        let max_active0 = max_active.clone();
+        executor.get_spawner().spawn(async move {
+            for _ in 0..NUM_CLIENTS {
+                let (mut reader, mut writer) = listener.accept().await.unwrap().split();
+                max_active0.fetch_max(listener.active_connections(), Ordering::SeqCst);
+
+                let tx = tx.clone();
+                spawner.spawn(async move {
+                    let line = reader.read_line().await.unwrap().unwrap();
+                    // 接続を保持して他のコネクションを待たせる
+                    timer::sleep(reader.selector.clone(), Duration::from_millis(20))
+                        .await
+                        .unwrap();
+                    writer.write_all(line.as_bytes()).await.unwrap();
+                    tx.send(()).unwrap();
+                });
+            }
+        });
+        std::thread::spawn(move || executor.run_workers(4));
+
+        for _ in 0..NUM_CLIENTS {
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        let mut replies: Vec<_> = clients.into_iter().map(|c| c.join().unwrap()).collect();
+        replies.sort();
+        let expected: Vec<_> = (0..NUM_CLIENTS).map(|i| format!("{}\n", i)).collect();
+        assert_eq!(replies, expected);
+        assert_eq!(max_active.load(Ordering::SeqCst), MAX);
+    }
+
+    #[test]
+    fn test_connection_limit_waiters() {
+        let limit = Arc::new(ConnectionLimit {
+            max: 1,
+            active: AtomicUsize::new(0),
+            waiters: Mutex::new(Vec::new()),
+        });
+        let permit = limit.try_acquire().unwrap();
+
+        // 同じタスクが何度 poll しても Waker は1つだけ登録される
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..10 {
+            assert!(limit.poll_acquire(&mut cx).is_pending());
+            assert!(limit.poll_available(&mut cx).is_pending());
+        }
+        assert_eq!(limit.waiters.lock().unwrap().len(), 1);
+
+        drop(permit);
+        assert!(limit.waiters.lock().unwrap().is_empty());
+        assert!(limit.poll_acquire(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_pending_accept_not_counted() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector);
+        let listener = listener.with_max_connections(2);
+
+        // コネクションを待機中の Accept は接続枠を確保しない
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut accept = Box::pin(listener.accept());
+        assert!(accept.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(listener.active_connections(), 0);
+        drop(accept);
+
+        let _client = TcpStream::connect(addr).unwrap();
+        // 接続済みのため、最初の poll でアクセプトできる
+        let conn = match Box::pin(listener.accept()).as_mut().poll(&mut cx) {
+            Poll::Ready(conn) => conn.unwrap(),
+            Poll::Pending => panic!("accept is pending"),
+        };
+        assert_eq!(listener.active_connections(), 1);
+        drop(conn);
+        assert_eq!(listener.active_connections(), 0);
+    }
+
+    #[test]
+    fn test_edge_triggered() {
+        const NUM_LINES: usize = 100;
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector.clone());
+
+        let (tx, rx) = channel::<()>();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // サーバが IOSelector に登録するまで待ってから、全行をまとめて送信
+            rx.recv().unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            let burst: String = (0..NUM_LINES).map(|i| format!("{}\n", i)).collect();
+            stream.write_all(burst.as_bytes()).unwrap();
+        });
+
+        let executor = Executor::new();
+        let (done_tx, done_rx) = channel();
+        let (mut reader, _writer) = executor::block_on(listener.accept()).unwrap().split();
+        reader.set_edge_triggered(true);
+        let read_lines = async move {
+            let mut lines = Vec::new();
+            for _ in 0..NUM_LINES {
+                lines.push(reader.read_line().await.unwrap().unwrap());
+            }
+            lines
+        };
+        let mut task = CountPoll {
+            future: Box::pin(read_lines),
+            count: 0,
+        };
+        executor.get_spawner().spawn(async move {
+            let lines = (&mut task).await;
+            done_tx.send((lines, task.count)).unwrap();
+        });
+        std::thread::spawn(move || executor.run());
+        tx.send(()).unwrap();
+
+        let (lines, polls) = done_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        client.join().unwrap();
+        let expected: Vec<_> = (0..NUM_LINES).map(|i| format!("{}\n", i)).collect();
+        assert_eq!(lines, expected);
+        // 最初の poll で登録し、1回の起床ですべての行を読み込む
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn test_abort_read_line() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector.clone());
+
+        // 接続するが何も送信しないクライアント
+        let _client = TcpStream::connect(addr).unwrap();
+        let (mut reader, _writer) = executor::block_on(listener.accept()).unwrap().split();
+        let fd = reader.fd;
+        let registered = || selector.is_registered(fd);
+
+        let executor = Executor::new();
+        let handle = executor.get_spawner().spawn_cancelable(async move {
+            let _ = reader.read_line().await;
+        });
+        std::thread::spawn(move || executor.run());
+        wait_until(registered);
+
+        // 中断すると future と共に AsyncReader が破棄され、IOSelector の監視から削除される
+        handle.abort();
+        wait_until(|| !registered());
+    }
+
+    #[test]
+    fn test_drop_pending_read_line() {
+        let selector = IOSelector::new().unwrap();
+        let (listener, addr) = listen(selector.clone());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (mut reader, _writer) = executor::block_on(listener.accept()).unwrap().split();
+        let fd = reader.fd;
+        let registered = || selector.is_registered(fd);
+
+        // 1回 poll して IOSelector に登録された状態で破棄する
+        let waker = futures::task::noop_waker();
+        let mut ctx = Context::from_waker(&waker);
+        let mut read_line = reader.read_line();
+        assert!(Pin::new(&mut read_line).poll(&mut ctx).is_pending());
+        wait_until(registered);
+        drop(read_line);
+        wait_until(|| !registered());
+
+        // 破棄後も同じ reader から読み込める
+        client.write_all(b"hello\n").unwrap();
+        let line = executor::block_on(reader.read_line()).unwrap();
+        assert_eq!(line.as_deref(), Some("hello\n"));
+    }
 }
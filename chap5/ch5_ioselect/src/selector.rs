@@ -0,0 +1,629 @@
+// OS ごとの I/O 多重化のバックエンド
+// Linux では epoll、macOS や BSD では kqueue を用いる
+#[cfg(target_os = "linux")]
+mod epoll;
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly"
+))]
+mod kqueue;
+
+#[cfg(target_os = "linux")]
+use nix::sys::signal::SigSet;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    ops::{BitOr, BitOrAssign},
+    os::unix::io::RawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::Waker,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+#[cfg(target_os = "linux")]
+pub type IOSelector = Selector<epoll::Epoll>;
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly"
+))]
+pub type IOSelector = Selector<kqueue::Kqueue>;
+
+// 監視する方向
+// バックエンドに依存しないフラグで、Interest::READ | Interest::EDGE のように組み合わせる
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Interest(u8);
+
+impl Interest {
+    pub const READ: Interest = Interest(1); // 読み込み可能（EPOLLIN、EVFILT_READ）
+    pub const WRITE: Interest = Interest(1 << 1); // 書き込み可能（EPOLLOUT、EVFILT_WRITE）
+
+    // エッジトリガ（EPOLLET、EV_CLEAR）で監視
+    // その場合、呼び出し元は WouldBlock となるまで読み書きしてから登録する必要がある
+    pub const EDGE: Interest = Interest(1 << 2);
+
+    pub const fn empty() -> Interest {
+        Interest(0)
+    }
+
+    pub const fn contains(self, other: Interest) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, rhs: Interest) -> Interest {
+        Interest(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Interest {
+    fn bitor_assign(&mut self, rhs: Interest) {
+        self.0 |= rhs.0;
+    }
+}
+
+// バックエンドが通知するイベント
+pub enum Event {
+    Notify, // notify による通知。キューの要求を処理する
+    Ready {
+        fd: RawFd,
+        readable: bool,
+        writable: bool,
+        hangup: bool, // 相手が書き込み側をクローズ（ハーフクローズ）。読み込み側は EOF を受け取る
+        closed: bool, // エラーや切断。どちらの方向も起床させ、読み書きでエラーを受け取らせる
+    },
+}
+
+// I/O 多重化のバックエンド
+// IOSelector と監視用スレッドの両方から参照され、両方が破棄された時点で Drop によりクローズする
+pub trait Poller: Send + Sync + Sized + 'static {
+    fn new() -> io::Result<Self>;
+
+    // fd を監視対象に追加、または監視する方向を再設定
+    // 一度イベントが発生すると、その fd へのイベントは再設定するまで通知されない
+    fn arm(&self, fd: RawFd, interest: Interest) -> io::Result<()>;
+
+    // fd を監視対象から削除
+    // 既にクローズされている場合もあるため、エラーは無視する
+    fn disarm(&self, fd: RawFd);
+
+    // wait で待機中の監視用スレッドを起床させる
+    fn notify(&self) -> io::Result<()>;
+
+    // イベントが発生するか timeout が経過するまで待機し、発生したイベントを events に追加
+    // timeout が None の場合はイベントが発生するまで待機する
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+enum IOOps {
+    Add(Interest, RawFd, Waker), // 監視対象へ追加
+    Remove(RawFd),               // 監視対象から削除
+    Cancel(Interest, RawFd),     // 指定方向の待機のみ取り消し
+    #[cfg(target_os = "linux")]
+    BlockSignals(SigSet), // 監視用スレッドでシグナルをブロック
+    Shutdown,                    // 監視用スレッドを終了
+}
+
+// fd ごとの待機中のタスク
+// 読み込みと書き込みを別々のタスクが同じ fd で待機できるように、方向ごとに Waker を保持する
+#[derive(Default)]
+struct Waiters {
+    read: Option<Waker>,  // READ を待機中のタスク
+    write: Option<Waker>, // WRITE を待機中のタスク
+    edge: bool,           // エッジトリガで監視するか
+}
+
+impl Waiters {
+    // 待機中の方向を合わせたフラグ
+    fn interest(&self) -> Interest {
+        let mut interest = Interest::empty();
+        if self.read.is_some() {
+            interest |= Interest::READ;
+        }
+        if self.write.is_some() {
+            interest |= Interest::WRITE;
+        }
+        if self.edge {
+            interest |= Interest::EDGE;
+        }
+        interest
+    }
+
+    // 待機中のタスクをすべて起床
+    fn wake_all(self) {
+        self.read
+            .into_iter()
+            .chain(self.write)
+            .for_each(Waker::wake);
+    }
+}
+
+// with_tick で指定する、監視用スレッドで定期的に実行する処理
+type TickFn<P> = Box<dyn Fn(&Selector<P>) + Send>;
+
+pub struct Selector<P: Poller> {
+    wakers: Mutex<HashMap<RawFd, Waiters>>,
+    queue: Mutex<VecDeque<IOOps>>, // IO のキュー
+    // 監視用スレッドで発生したエラー。次回の register 時に呼び出し元へ返す
+    errors: Mutex<HashMap<RawFd, io::Error>>,
+    poller: Arc<P>,
+    closed: AtomicBool,                    // shutdown 済みか
+    thread: Mutex<Option<JoinHandle<()>>>, // 監視用スレッド
+}
+
+impl<P: Poller> Selector<P> {
+    pub fn new() -> io::Result<Arc<Self>> {
+        Self::start(None)
+    }
+
+    // 監視用スレッドで、I/O がない場合も interval ごとに f を実行する IOSelector を作成
+    // 待機中の登録の期限切れの処理や、メトリクスの出力などの定期的な保守に用いる
+    // f は監視用スレッドで実行されるため、実行中はイベントを処理しない
+    #[allow(dead_code)] // main では使用しない
+    pub fn with_tick(
+        interval: Duration,
+        f: impl Fn(&Selector<P>) + Send + 'static,
+    ) -> io::Result<Arc<Self>> {
+        assert!(!interval.is_zero(), "tick interval must be non-zero");
+        Self::start(Some((interval, Box::new(f))))
+    }
+
+    fn start(tick: Option<(Duration, TickFn<P>)>) -> io::Result<Arc<Self>> {
+        let poller = Arc::new(P::new()?);
+        let s = Selector {
+            wakers: Mutex::new(HashMap::new()),
+            queue: Mutex::new(VecDeque::new()),
+            errors: Mutex::new(HashMap::new()),
+            poller: poller.clone(),
+            closed: AtomicBool::new(false),
+            thread: Mutex::new(None),
+        };
+        let result = Arc::new(s);
+
+        // 監視用スレッド作成
+        // スレッドが IOSelector を保持し続けると Drop が呼ばれなくなるため、弱参照を渡す
+        let weak = Arc::downgrade(&result);
+        // シグナルマスクは生成したスレッドから引き継ぐ
+        // signalfd で受信するシグナルは block_signals で追加でブロックする
+        let handle = std::thread::spawn(move || Selector::select(poller, weak, tick));
+        *result.thread.lock().unwrap() = Some(handle);
+
+        Ok(result)
+    }
+
+    // 監視用スレッドを終了させ、終了するまで待機
+    // 待機中のタスクはすべて起床させる。以降の register はエラーとなる
+    pub fn shutdown(&self) {
+        {
+            let mut q = self.queue.lock().unwrap();
+            if !self.closed.swap(true, Ordering::SeqCst) {
+                q.push_back(IOOps::Shutdown);
+                self.poller.notify().ok();
+            }
+        }
+
+        let handle = self.thread.lock().unwrap().take();
+        if let Some(handle) = handle {
+            // 監視用スレッド自身から呼び出された場合は join しない
+            if handle.thread().id() != std::thread::current().id() {
+                handle.join().ok();
+            }
+        }
+    }
+
+    // fd を監視するための関数
+    // 既に反対方向で待機中のタスクがある場合は、両方向を合わせて監視する
+    fn add_event(
+        &self,
+        flag: Interest, // 監視する方向
+        fd: RawFd,      // 監視対象のファイルディスクリプタ
+        waker: Waker,
+        wakers: &mut HashMap<RawFd, Waiters>,
+    ) -> io::Result<()> {
+        // 同じ方向で既に待機中の場合は Waker を置き換える
+        // タイマーなど別の要因で起床したタスクが、同じ fd を再度登録する場合があるため
+        let mut waiters = wakers.remove(&fd).unwrap_or_default();
+        if flag.contains(Interest::READ) {
+            waiters.read = Some(waker.clone());
+        }
+        if flag.contains(Interest::WRITE) {
+            waiters.write = Some(waker);
+        }
+        waiters.edge = flag.contains(Interest::EDGE);
+
+        match self.poller.arm(fd, waiters.interest()) {
+            Ok(()) => {
+                wakers.insert(fd, waiters);
+                Ok(())
+            }
+            Err(err) => {
+                // 反対方向で待機中のタスクも起床させ、再度 register させる
+                waiters.wake_all();
+                Err(err)
+            }
+        }
+    }
+
+    // 監視対象から削除するための関数
+    fn rm_event(&self, fd: RawFd, wakers: &mut HashMap<RawFd, Waiters>) {
+        self.poller.disarm(fd);
+        wakers.remove(&fd);
+    }
+
+    // 指定方向で待機中の Waker を削除
+    // 反対方向で待機中のタスクが残っていれば再設定し、残っていなければ監視対象から削除する
+    fn cancel_event(&self, flag: Interest, fd: RawFd, wakers: &mut HashMap<RawFd, Waiters>) {
+        let Some(mut waiters) = wakers.remove(&fd) else {
+            return;
+        };
+        if flag.contains(Interest::READ) {
+            waiters.read = None;
+        }
+        if flag.contains(Interest::WRITE) {
+            waiters.write = None;
+        }
+
+        if waiters.read.is_none() && waiters.write.is_none() {
+            self.rm_event(fd, wakers);
+        } else if let Err(err) = self.poller.arm(fd, waiters.interest()) {
+            self.errors.lock().unwrap().insert(fd, err);
+            waiters.wake_all();
+        } else {
+            wakers.insert(fd, waiters);
+        }
+    }
+
+    // 専用のスレッドでファイルディスクリプタの監視を行うための関数
+    // tick を指定した場合は、次の実行時刻までをタイムアウトとして待機する
+    fn select(poller: Arc<P>, selector: Weak<Self>, tick: Option<(Duration, TickFn<P>)>) {
+        let mut events = Vec::new();
+        let mut next_tick = tick
+            .as_ref()
+            .map(|(interval, _)| Instant::now() + *interval);
+        // event 発生を監視
+        loop {
+            events.clear();
+            // イベントが続けて発生しても tick が遅れないよう、毎回残り時間を計算する
+            let timeout = next_tick.map(|next| next.saturating_duration_since(Instant::now()));
+            match poller.wait(&mut events, timeout) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue, // シグナルによる中断は再試行
+                Err(err) => {
+                    eprintln!("wait: {}", err);
+                    break;
+                }
+            }
+
+            // IOSelector が既に破棄されていれば終了
+            let Some(selector) = selector.upgrade() else {
+                break;
+            };
+            if !selector.dispatch(&events) {
+                break;
+            }
+
+            if let (Some((interval, f)), Some(next)) = (&tick, next_tick.as_mut()) {
+                let now = Instant::now();
+                if now >= *next {
+                    f(&selector);
+                    // 処理が interval より長くかかった場合は、遅れた分をまとめて実行せずに次の周期から再開
+                    *next += *interval;
+                    if *next <= now {
+                        *next = now + *interval;
+                    }
+                }
+            }
+        }
+    }
+
+    // 発生したイベントを処理
+    // Shutdown 要求を受け取った場合は false をリターン
+    fn dispatch(&self, events: &[Event]) -> bool {
+        let mut running = true;
+        let mut t = self.wakers.lock().unwrap();
+        for event in events {
+            match *event {
+                // notify の場合、追加、削除要求を処理
+                Event::Notify => {
+                    let mut q = self.queue.lock().unwrap();
+                    while let Some(op) = q.pop_front() {
+                        match op {
+                            // 追加
+                            IOOps::Add(flag, fd, waker) => {
+                                // 後続の要求で削除される fd は登録しない
+                                // 既にクローズされている可能性があり、登録に失敗して不要な wake が発生するため
+                                let removed = q
+                                    .iter()
+                                    .any(|op| matches!(op, IOOps::Remove(rm) if *rm == fd));
+                                if removed {
+                                    continue;
+                                }
+
+                                // 登録に失敗した場合はエラーを保存する
+                                // add_event が起床させたタスクは再度 register を呼び出し、そこでエラーを受け取る
+                                if let Err(err) = self.add_event(flag, fd, waker, &mut t) {
+                                    self.errors.lock().unwrap().insert(fd, err);
+                                }
+                            }
+                            IOOps::Remove(fd) => self.rm_event(fd, &mut t),
+                            IOOps::Cancel(flag, fd) => self.cancel_event(flag, fd, &mut t),
+                            #[cfg(target_os = "linux")]
+                            IOOps::BlockSignals(mask) => {
+                                let _ = mask.thread_block();
+                            }
+                            IOOps::Shutdown => {
+                                // 待機中のタスクをすべて起床させて終了
+                                // 起床したタスクは register でエラーを受け取る
+                                for (_, waiters) in t.drain() {
+                                    waiters.wake_all();
+                                }
+                                running = false;
+                            }
+                        }
+                    }
+                }
+                // ファイルディスクリプタの場合の処理
+                // 発生した方向で待機中のタスクのみ起床させる
+                Event::Ready {
+                    fd,
+                    readable,
+                    writable,
+                    hangup,
+                    closed,
+                } => {
+                    let Some(mut waiters) = t.remove(&fd) else {
+                        continue;
+                    };
+                    // ハーフクローズの場合は読み込み側のみ起床させる
+                    // 書き込み側は相手が読み込みをやめるまで引き続き書き込める
+                    if closed || hangup || readable {
+                        if let Some(waker) = waiters.read.take() {
+                            waker.wake();
+                        }
+                    }
+                    if closed || writable {
+                        if let Some(waker) = waiters.write.take() {
+                            waker.wake();
+                        }
+                    }
+
+                    // 一度通知すると fd の監視は無効化されているため、
+                    // 反対方向で待機中のタスクが残っていれば再設定
+                    if waiters.read.is_some() || waiters.write.is_some() {
+                        match self.poller.arm(fd, waiters.interest()) {
+                            Ok(()) => {
+                                t.insert(fd, waiters);
+                            }
+                            Err(err) => {
+                                self.errors.lock().unwrap().insert(fd, err);
+                                waiters.wake_all();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        running
+    }
+
+    // ファイルディスクリプタ登録用関数
+    // flags に Interest::EDGE を含めるとエッジトリガで監視する
+    // 前回の登録で監視用スレッドがエラーとなっていた場合はそのエラーをリターン
+    pub fn register(&self, flags: Interest, fd: RawFd, waker: Waker) -> io::Result<()> {
+        if let Some(err) = self.errors.lock().unwrap().remove(&fd) {
+            return Err(err);
+        }
+        let mut q = self.queue.lock().unwrap();
+        // shutdown 後は監視用スレッドが存在しないため登録できない
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::Error::other("IOSelector is shut down"));
+        }
+        q.push_back(IOOps::Add(flags, fd, waker));
+        self.poller.notify()
+    }
+
+    // ファイルディスクリプタ削除用関数
+    pub fn unregister(&self, fd: RawFd) -> io::Result<()> {
+        self.errors.lock().unwrap().remove(&fd);
+        let mut q = self.queue.lock().unwrap();
+        q.push_back(IOOps::Remove(fd));
+        self.poller.notify()
+    }
+
+    // register した待機を完了前に取り消すための関数
+    // fd 自体は監視対象のまま、flags の方向の Waker のみ削除する
+    pub fn cancel(&self, flags: Interest, fd: RawFd) -> io::Result<()> {
+        let mut q = self.queue.lock().unwrap();
+        q.push_back(IOOps::Cancel(flags, fd));
+        self.poller.notify()
+    }
+
+    // 監視用スレッドで mask のシグナルをブロックする
+    // IOSelector の生成後に signalfd で受信するシグナルが、監視用スレッドに配送されて失われないようにする
+    // SIGSEGV などの同期的なシグナルまでブロックしないように、受信するシグナルのみを指定する
+    #[cfg(target_os = "linux")]
+    pub fn block_signals(&self, mask: SigSet) -> io::Result<()> {
+        let mut q = self.queue.lock().unwrap();
+        q.push_back(IOOps::BlockSignals(mask));
+        self.poller.notify()
+    }
+
+    // fd で待機中のタスクがあるか
+    #[allow(dead_code)] // main では使用しない
+    pub fn is_registered(&self, fd: RawFd) -> bool {
+        self.wakers.lock().unwrap().contains_key(&fd)
+    }
+
+    // 待機中のタスクがある fd の数
+    #[allow(dead_code)] // main では使用しない
+    pub fn num_registered(&self) -> usize {
+        self.wakers.lock().unwrap().len()
+    }
+}
+
+impl<P: Poller> Drop for Selector<P> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// 複数の IOSelector に fd を振り分けるプール
+// 監視用スレッドが1つだと fd が多い場合にボトルネックとなるため、fd % n で選んだ IOSelector に登録し、
+// 各 IOSelector のバックエンドと監視用スレッドに負荷を分散する
+// 同じ fd は常に同じ IOSelector に登録されるため、register、unregister、cancel の対応は崩れない
+pub struct IOSelectorPool {
+    shards: Vec<Arc<IOSelector>>,
+}
+
+#[allow(dead_code)] // main では使用しない
+impl IOSelectorPool {
+    // n 個の IOSelector を作成
+    pub fn new(n: usize) -> io::Result<Arc<Self>> {
+        assert!(n > 0, "the number of selectors must be non-zero");
+        let shards = (0..n)
+            .map(|_| IOSelector::new())
+            .collect::<io::Result<_>>()?;
+        Ok(Arc::new(IOSelectorPool { shards }))
+    }
+
+    // IOSelector の数
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    // fd を担当する IOSelector
+    // 非同期 I/O の型は、fd ごとにここで得た IOSelector を保持して登録に用いる
+    pub fn selector(&self, fd: RawFd) -> &Arc<IOSelector> {
+        &self.shards[fd as usize % self.shards.len()]
+    }
+
+    pub fn register(&self, flags: Interest, fd: RawFd, waker: Waker) -> io::Result<()> {
+        self.selector(fd).register(flags, fd, waker)
+    }
+
+    pub fn unregister(&self, fd: RawFd) -> io::Result<()> {
+        self.selector(fd).unregister(fd)
+    }
+
+    pub fn cancel(&self, flags: Interest, fd: RawFd) -> io::Result<()> {
+        self.selector(fd).cancel(flags, fd)
+    }
+
+    // すべての IOSelector を shutdown
+    pub fn shutdown(&self) {
+        for shard in self.shards.iter() {
+            shard.shutdown();
+        }
+    }
+
+    // IOSelector ごとの待機中のタスクがある fd の数
+    pub fn num_registered(&self) -> Vec<usize> {
+        self.shards.iter().map(|s| s.num_registered()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_selector_drop() {
+        let selector = IOSelector::new().unwrap();
+        let poller = selector.poller.clone();
+        assert_eq!(Arc::strong_count(&poller), 3);
+
+        // drop は監視用スレッドの終了を待つため、
+        // drop 後に残っているバックエンドの参照はこのテストのもののみ
+        drop(selector);
+        assert_eq!(Arc::strong_count(&poller), 1);
+    }
+
+    #[test]
+    fn test_with_tick() {
+        const INTERVAL: Duration = Duration::from_millis(20);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let selector = IOSelector::with_tick(INTERVAL, move |s| {
+            let _ = tx.send((Instant::now(), s.num_registered()));
+        })
+        .unwrap();
+
+        // I/O がなくても interval ごとに実行される
+        let mut ticks = Vec::new();
+        for _ in 0..5 {
+            let (t, n) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!(n, 0);
+            ticks.push(t);
+        }
+        // 最初の実行は作成から interval 後で、以降もおおよそ interval の間隔で実行される
+        assert!(ticks[0] - start >= INTERVAL);
+        for w in ticks.windows(2) {
+            assert!(w[1] - w[0] >= INTERVAL / 2, "{:?}", w[1] - w[0]);
+        }
+        // 5 回の実行が 5 周期分の時間から大きく遅れない
+        let elapsed = ticks[4] - start;
+        assert!(elapsed >= INTERVAL * 5);
+        assert!(
+            elapsed < INTERVAL * 5 + Duration::from_secs(1),
+            "{:?}",
+            elapsed
+        );
+
+        // shutdown 後は実行されない
+        selector.shutdown();
+        while rx.try_recv().is_ok() {}
+        std::thread::sleep(INTERVAL * 3);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_pool_distributes_fds() {
+        const NUM_SHARDS: usize = 4;
+        const NUM_SOCKETS: usize = 32;
+        let pool = IOSelectorPool::new(NUM_SHARDS).unwrap();
+
+        // 受信するデータがないため、登録したままとなる
+        let sockets: Vec<_> = (0..NUM_SOCKETS)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        for socket in sockets.iter() {
+            let waker = futures::task::noop_waker();
+            pool.register(Interest::READ, socket.as_raw_fd(), waker)
+                .unwrap();
+        }
+
+        // 監視用スレッドが登録を処理するまで待機
+        let start = std::time::Instant::now();
+        while pool.num_registered().iter().sum::<usize>() < NUM_SOCKETS {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // 各 fd は fd % n の IOSelector に登録され、すべての IOSelector に分散している
+        for socket in sockets.iter() {
+            let fd = socket.as_raw_fd();
+            assert!(pool.shards[fd as usize % NUM_SHARDS].is_registered(fd));
+        }
+        let counts = pool.num_registered();
+        // 他のテストが並行して fd を作成するため、fd が連番になるとは限らない
+        assert!(counts.iter().all(|&n| n > 0), "{:?}", counts);
+
+        for socket in sockets.iter() {
+            pool.unregister(socket.as_raw_fd()).unwrap();
+        }
+        pool.shutdown();
+    }
+}
@@ -0,0 +1,195 @@
+use super::{Event, Interest, Poller};
+use crate::nix_to_io;
+
+use nix::{
+    errno::Errno,
+    sys::{
+        epoll::{
+            epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+        },
+        eventfd::{eventfd, EfdFlags},
+    },
+    unistd::{close, read, write},
+};
+
+use std::{io, os::unix::io::RawFd, time::Duration};
+
+fn write_eventfd(fd: RawFd, n: usize) -> io::Result<()> {
+    let ptr = &n as *const usize as *const u8;
+    // n をメモリ上の生バイト列としてスライス形式で取得する
+    let val = unsafe { std::slice::from_raw_parts(ptr, std::mem::size_of_val(&n)) };
+    // fd の直観はチャネル。ここに val を流し込むイメージ
+    // 「流し込む」が意味するとこをは、fd が指す具体的なリソースに依存する
+    // たとえば file なら末尾に書き込みだったり、eventfd ならカウント値に追加されるとか？
+    write(fd, val).map_err(nix_to_io)?;
+    Ok(())
+}
+
+// epfd と event はどちらも RawFd だけど全然違うものらしい
+
+// 1. epfd（epoll のファイルディスクリプタ）
+// 生成元: epoll_create1 システムコール。
+// 役割:
+//  epfd は epoll インスタンスを表すファイルディスクリプタ です。
+//  複数のファイルディスクリプタ（ソケット、パイプ、eventfd など）をまとめて監視するために使用されます。
+// 主な使い方:
+//  - イベントの登録: 監視対象のファイルディスクリプタを epoll_ctl を使って epfd に登録します。
+//  - イベントの待機: epoll_wait を使って、登録したファイルディスクリプタに発生したイベントを待機します。
+// 特徴:
+//  - 複数のファイルディスクリプタを効率的に監視:
+//      - ネットワーク接続やファイルI/Oなど、多数のイベントを扱うプログラムで重要。
+//  - 状態管理はカーネルが担当:
+//      - 登録された監視対象の状態を、ユーザー空間が個別に管理する必要がない。
+
+// 2. event（eventfd のファイルディスクリプタ）
+// 生成元: eventfd システムコール。
+// 役割:
+//  event は プロセス間通信（IPC）やスレッド間通信のためのファイルディスクリプタ です。
+//  特定のイベント（通知やシグナル）を発火する目的で使用されます。
+// 主な使い方:
+//  - 通知の送信:
+//      - eventfd_write を使って特定の値（通知）を送信します。
+//  - 通知の受信:
+//      - eventfd_read を使って通知を受信し、その後に必要な処理を行います。
+//  - epoll と組み合わせる:
+//      - eventfd を epoll に登録し、タスクやスレッド間の通知を効率よく処理します。
+// 特徴:
+//  - 通知専用:
+//      - eventfd は簡易的な通知の送信・受信専用。
+//  - スレッド間やプロセス間での利用:
+//      - スレッドセーフなイベント通知を実現。
+//  - 軽量でシンプル:
+//      - 通知専用の仕組みなので、特定の用途に対して非常に効率的。
+
+// | 特徴               | epfd（epoll fd）                     | event（eventfd）                 |
+// |--------------------|-------------------------------------|----------------------------------|
+// | **生成システムコール** | `epoll_create1`                   | `eventfd`                       |
+// | **目的**            | 複数のファイルディスクリプタを効率的に監視 | 通知（イベント発火）の送受信       |
+// | **監視対象**        | ソケット、ファイル、パイプ、`eventfd` など | なし（自身がイベントの発火元）     |
+// | **主な操作**        | `epoll_ctl` で対象を登録・管理       | `eventfd_write` / `eventfd_read` |
+// | **使い方の規模**    | 大規模な非同期I/Oや多重化             | 単純な通知やシグナル              |
+// | **`epoll` との組み合わせ** | `epfd` 自体が `epoll` のインスタンス  | `event` を `epoll` に登録可能     |
+
+// また、この eventfd はほかの全く関係ないプロセスの eventfd とバッティングすることはないらしい
+// なぜなら、Linux の eventfd はプロセスやスレッドごとに独立したカーネルリソースとして扱われるから
+// 別の観点だが、このプログラム自体は、1つの eventfd を使って処理を実現するように作っていそう
+
+// epoll と eventfd を用いたバックエンド（Linux）
+// IOSelector と epoll 用スレッドの両方から参照され、両方が破棄された時点でクローズする
+pub struct Epoll {
+    epfd: RawFd,  // epoll の fd
+    event: RawFd, // eventfd の fd
+}
+
+impl Poller for Epoll {
+    fn new() -> io::Result<Self> {
+        let epfd = epoll_create1(EpollCreateFlags::empty()).map_err(nix_to_io)?;
+        let event = match eventfd(0, EfdFlags::empty()) {
+            Ok(event) => event,
+            Err(err) => {
+                close(epfd).ok();
+                return Err(nix_to_io(err));
+            }
+        };
+        // 以降はエラー時も含めて Drop でクローズする
+        let epoll = Epoll { epfd, event };
+
+        // eventfd を epoll の監視対象に追加
+        let mut ev = EpollEvent::new(EpollFlags::EPOLLIN, event as u64);
+        epoll_ctl(epfd, EpollOp::EpollCtlAdd, event, &mut ev).map_err(nix_to_io)?;
+        Ok(epoll)
+    }
+
+    fn arm(&self, fd: RawFd, interest: Interest) -> io::Result<()> {
+        // 各定義のショートカット
+        let epoll_add = EpollOp::EpollCtlAdd;
+        let epoll_mod = EpollOp::EpollCtlMod;
+        let epoll_one = EpollFlags::EPOLLONESHOT;
+
+        let mut flag = EpollFlags::empty();
+        if interest.contains(Interest::READ) {
+            // EPOLLRDHUP を指定して、相手のハーフクローズも検知する
+            flag |= EpollFlags::EPOLLIN | EpollFlags::EPOLLRDHUP;
+        }
+        if interest.contains(Interest::WRITE) {
+            flag |= EpollFlags::EPOLLOUT;
+        }
+        if interest.contains(Interest::EDGE) {
+            flag |= EpollFlags::EPOLLET;
+        }
+
+        // EPOLLONESHOT を指定して、一度イベントが発生すると
+        // その fd へのイベントは再設定するまで通知されないようにする
+        // ONSHOT にすることでマルチスレッド環境で同じ fd を複数回処理する問題を防げる
+        let mut ev = EpollEvent::new(flag | epoll_one, fd as u64);
+
+        // 監視対象に追加
+        if let Err(err) = epoll_ctl(self.epfd, epoll_add, fd, &mut ev) {
+            match err {
+                nix::Error::Sys(Errno::EEXIST) => {
+                    // 既に追加されていた場合は再設定
+                    // epoll_add じゃなくて epoll_mod にしてる
+                    epoll_ctl(self.epfd, epoll_mod, fd, &mut ev).map_err(nix_to_io)?;
+                }
+                _ => return Err(nix_to_io(err)),
+            }
+        }
+        Ok(())
+    }
+
+    fn disarm(&self, fd: RawFd) {
+        let epoll_del = EpollOp::EpollCtlDel;
+        let mut ev = EpollEvent::new(EpollFlags::empty(), fd as u64);
+        epoll_ctl(self.epfd, epoll_del, fd, &mut ev).ok();
+    }
+
+    fn notify(&self) -> io::Result<()> {
+        // eventfd は内部的に 64 ビットの整数カウンタを持っているので 1 を使うことが多い
+        // 多分決まりはない？
+        // write でここに指定した値が加算される
+        // read の時に 0 にリセットされる
+        write_eventfd(self.event, 1)
+    }
+
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        // タイムアウトはミリ秒単位のため切り上げる
+        // 切り捨てると 1 ミリ秒未満の残り時間が 0 となり、期限まで epoll_wait が即座に戻り続ける
+        // -1 は無期限に待機
+        let timeout_ms = match timeout {
+            Some(timeout) => {
+                let ms = timeout.as_nanos().div_ceil(1_000_000);
+                ms.min(i32::MAX as u128) as isize
+            }
+            None => -1,
+        };
+        let mut buf = [EpollEvent::empty(); 1024];
+        let nfds = epoll_wait(self.epfd, &mut buf, timeout_ms).map_err(nix_to_io)?;
+
+        for event in &buf[..nfds] {
+            if event.data() == self.event as u64 {
+                // eventfd の通知解除
+                let mut buf: [u8; 8] = [0; 8];
+                read(self.event, &mut buf).ok();
+                events.push(Event::Notify);
+            } else {
+                // エラーや切断の場合はどちらの方向も起床させ、読み書きでエラーを受け取らせる
+                let flags = event.events();
+                events.push(Event::Ready {
+                    fd: event.data() as RawFd,
+                    readable: flags.contains(EpollFlags::EPOLLIN),
+                    writable: flags.contains(EpollFlags::EPOLLOUT),
+                    hangup: flags.contains(EpollFlags::EPOLLRDHUP),
+                    closed: flags.intersects(EpollFlags::EPOLLERR | EpollFlags::EPOLLHUP),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        close(self.epfd).ok();
+        close(self.event).ok();
+    }
+}
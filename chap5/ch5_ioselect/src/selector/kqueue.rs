@@ -0,0 +1,152 @@
+use super::{Event, Interest, Poller};
+use crate::nix_to_io;
+
+use nix::{
+    errno::Errno,
+    sys::event::{kevent_ts, kqueue, EventFilter, EventFlag, FilterFlag, KEvent},
+    unistd::close,
+};
+
+use std::{io, os::unix::io::RawFd, time::Duration};
+
+// EVFILT_USER で通知に用いる識別子
+// fd とは名前空間が異なるため、任意の値でよい
+const NOTIFY_IDENT: usize = 0;
+
+fn kevent(ident: usize, filter: EventFilter, flags: EventFlag, fflags: FilterFlag) -> KEvent {
+    KEvent::new(ident, filter, flags, fflags, 0, 0)
+}
+
+// kqueue を用いたバックエンド（macOS、BSD）
+// epoll と異なり、読み込み（EVFILT_READ）と書き込み（EVFILT_WRITE）は別々のフィルタとして登録する
+// また、eventfd の代わりに EVFILT_USER を用いて kqueue 用スレッドへ通知する
+pub struct Kqueue {
+    kq: RawFd, // kqueue の fd
+}
+
+impl Poller for Kqueue {
+    fn new() -> io::Result<Self> {
+        let kq = kqueue().map_err(nix_to_io)?;
+        // 以降はエラー時も含めて Drop でクローズする
+        let kqueue = Kqueue { kq };
+
+        // 通知用のユーザーイベントを追加
+        // EV_CLEAR を指定して、通知を受け取ると自動的に解除されるようにする
+        let ev = kevent(
+            NOTIFY_IDENT,
+            EventFilter::EVFILT_USER,
+            EventFlag::EV_ADD | EventFlag::EV_CLEAR,
+            FilterFlag::empty(),
+        );
+        kevent_ts(kq, &[ev], &mut [], None).map_err(nix_to_io)?;
+        Ok(kqueue)
+    }
+
+    fn arm(&self, fd: RawFd, interest: Interest) -> io::Result<()> {
+        // EV_ONESHOT を指定して、epoll の EPOLLONESHOT と同じく一度通知すると無効化されるようにする
+        // エッジトリガの場合は EV_CLEAR を指定する
+        let mut add = EventFlag::EV_ADD | EventFlag::EV_ONESHOT;
+        if interest.contains(Interest::EDGE) {
+            add |= EventFlag::EV_CLEAR;
+        }
+
+        // 待機しない方向のフィルタは削除し、以前の登録が残らないようにする
+        // EV_RECEIPT を指定すると、変更ごとの結果が EV_ERROR 付きのイベントとして返る
+        let filters = [
+            (EventFilter::EVFILT_READ, interest.contains(Interest::READ)),
+            (
+                EventFilter::EVFILT_WRITE,
+                interest.contains(Interest::WRITE),
+            ),
+        ];
+        let changes: Vec<_> = filters
+            .iter()
+            .map(|&(filter, wanted)| {
+                let flags = if wanted { add } else { EventFlag::EV_DELETE };
+                kevent(
+                    fd as usize,
+                    filter,
+                    flags | EventFlag::EV_RECEIPT,
+                    FilterFlag::empty(),
+                )
+            })
+            .collect();
+        let mut results = changes.clone();
+        let n = kevent_ts(self.kq, &changes, &mut results, None).map_err(nix_to_io)?;
+
+        for (change, result) in changes.iter().zip(&results[..n]) {
+            let errno = result.data() as i32;
+            if errno == 0 {
+                continue;
+            }
+            // 登録されていないフィルタの削除は無視
+            if change.flags().contains(EventFlag::EV_DELETE) && errno == Errno::ENOENT as i32 {
+                continue;
+            }
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        Ok(())
+    }
+
+    fn disarm(&self, fd: RawFd) {
+        // 登録されていないフィルタの削除はエラーとなるため、フィルタごとに削除する
+        for filter in [EventFilter::EVFILT_READ, EventFilter::EVFILT_WRITE] {
+            let ev = kevent(
+                fd as usize,
+                filter,
+                EventFlag::EV_DELETE,
+                FilterFlag::empty(),
+            );
+            kevent_ts(self.kq, &[ev], &mut [], None).ok();
+        }
+    }
+
+    fn notify(&self) -> io::Result<()> {
+        let ev = kevent(
+            NOTIFY_IDENT,
+            EventFilter::EVFILT_USER,
+            EventFlag::empty(),
+            FilterFlag::NOTE_TRIGGER,
+        );
+        kevent_ts(self.kq, &[ev], &mut [], None).map_err(nix_to_io)?;
+        Ok(())
+    }
+
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        let empty = kevent(
+            0,
+            EventFilter::EVFILT_READ,
+            EventFlag::empty(),
+            FilterFlag::empty(),
+        );
+        let mut buf = vec![empty; 1024];
+        // タイムアウトに None を指定すると、イベントが発生するまで待機する
+        let timeout = timeout.map(|timeout| nix::libc::timespec {
+            tv_sec: timeout.as_secs().min(nix::libc::time_t::MAX as u64) as nix::libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as nix::libc::c_long,
+        });
+        let n = kevent_ts(self.kq, &[], &mut buf, timeout).map_err(nix_to_io)?;
+
+        for ev in &buf[..n] {
+            match ev.filter() {
+                EventFilter::EVFILT_USER => events.push(Event::Notify),
+                filter => events.push(Event::Ready {
+                    fd: ev.ident() as RawFd,
+                    readable: filter == EventFilter::EVFILT_READ,
+                    writable: filter == EventFilter::EVFILT_WRITE,
+                    // EVFILT_READ の EV_EOF は相手のハーフクローズを表す
+                    hangup: filter == EventFilter::EVFILT_READ
+                        && ev.flags().contains(EventFlag::EV_EOF),
+                    closed: ev.flags().contains(EventFlag::EV_ERROR),
+                }),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Kqueue {
+    fn drop(&mut self) {
+        close(self.kq).ok();
+    }
+}
@@ -0,0 +1,134 @@
+use crate::nix_to_io;
+use crate::selector::{IOSelector, Interest};
+
+use futures::Stream;
+
+use nix::sys::{
+    signal::{SigSet, Signal},
+    signalfd::{SfdFlags, SignalFd},
+};
+
+use std::{
+    convert::TryFrom,
+    io,
+    os::unix::io::AsRawFd,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+// signalfd を用いて受信したシグナルを順にリターンする Stream
+// シグナルを受信すると signalfd が読み込み可能になるため、IOSelector に Interest::READ で登録して待機する
+// IOSelector への登録や読み込みに失敗した場合は終了する
+pub struct SignalStream {
+    fd: SignalFd,
+    selector: Arc<IOSelector>,
+}
+
+// signals で指定したシグナルを受信する SignalStream をリターン
+// シグナルハンドラではなく signalfd で受信するため、呼び出したスレッドと selector の監視用スレッドでシグナルをブロックする
+// ブロックしていないスレッドがあるとそのスレッドに配送されるため、他のスレッドを生成する前に呼び出す必要がある
+// 以降に生成したスレッドはシグナルマスクを引き継ぐ
+pub fn signals(selector: Arc<IOSelector>, signals: &[Signal]) -> io::Result<SignalStream> {
+    let mut mask = SigSet::empty();
+    for &sig in signals {
+        mask.add(sig);
+    }
+    mask.thread_block().map_err(nix_to_io)?;
+    selector.block_signals(mask)?;
+    let fd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
+        .map_err(nix_to_io)?;
+    Ok(SignalStream { fd, selector })
+}
+
+impl Stream for SignalStream {
+    type Item = Signal;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.fd.read_signal() {
+            Ok(Some(info)) => {
+                // マスクに追加したシグナルのみ受信するため、変換に失敗することはない
+                let sig = Signal::try_from(info.ssi_signo as i32).ok();
+                Poll::Ready(sig)
+            }
+            Ok(None) => {
+                // 受信していない場合は IOSelector に登録
+                match this.selector.register(
+                    Interest::READ,
+                    this.fd.as_raw_fd(),
+                    cx.waker().clone(),
+                ) {
+                    Ok(()) => Poll::Pending,
+                    Err(_) => Poll::Ready(None),
+                }
+            }
+            Err(_) => Poll::Ready(None),
+        }
+    }
+}
+
+impl Drop for SignalStream {
+    fn drop(&mut self) {
+        // signalfd は unregister の後にクローズされる
+        let _ = self.selector.unregister(self.fd.as_raw_fd());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor;
+    use futures::{FutureExt, StreamExt};
+    use nix::sys::signal::raise;
+    use std::time::Duration;
+
+    #[test]
+    fn test_signals() {
+        // テストを実行する他のスレッドはシグナルをブロックしていないため、プロセス宛てには送信できない
+        // シグナルをブロックしたスレッド自身に raise で送信し、そのスレッドの signalfd で受信する
+        std::thread::spawn(|| {
+            let selector = IOSelector::new().unwrap();
+            let mut stream = signals(selector, &[Signal::SIGUSR1]).unwrap();
+
+            // 受信していない場合は待機する
+            assert_eq!(stream.next().now_or_never(), None);
+
+            // ブロックしているため、シグナルは配送されずに保留される
+            raise(Signal::SIGUSR1).unwrap();
+            let sig = executor::block_on(stream.next());
+            assert_eq!(sig, Some(Signal::SIGUSR1));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_selector_signal_mask() {
+        std::thread::spawn(|| {
+            // 監視用スレッドで tick を実行し、そのスレッドのシグナルマスクを受け取る
+            let (tx, rx) = std::sync::mpsc::channel();
+            let selector = IOSelector::with_tick(Duration::from_millis(1), move |_| {
+                let _ = tx.send(SigSet::thread_get_mask().unwrap());
+            })
+            .unwrap();
+            let mask = rx.recv().unwrap();
+            assert!(!mask.contains(Signal::SIGUSR2));
+
+            // 受信するシグナルのみブロックし、SIGSEGV などはブロックしない
+            let _stream = signals(selector.clone(), &[Signal::SIGUSR2]).unwrap();
+            let mask = loop {
+                let mask = rx.recv().unwrap();
+                if mask.contains(Signal::SIGUSR2) {
+                    break mask;
+                }
+            };
+            assert!(!mask.contains(Signal::SIGSEGV));
+            assert!(!mask.contains(Signal::SIGBUS));
+            assert!(!mask.contains(Signal::SIGFPE));
+            selector.shutdown();
+        })
+        .join()
+        .unwrap();
+    }
+}
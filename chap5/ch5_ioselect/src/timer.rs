@@ -0,0 +1,249 @@
+use crate::nix_to_io;
+use crate::selector::{IOSelector, Interest};
+
+use nix::{
+    errno::Errno,
+    sys::{
+        time::TimeSpec,
+        timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags},
+    },
+    unistd::read,
+};
+
+use futures::Stream;
+
+use std::{
+    future::Future,
+    io,
+    os::unix::io::AsRawFd,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+// timerfd を用いた非同期タイマー
+// 指定時間経過すると timerfd が読み込み可能になるため、IOSelector に Interest::READ で登録して待機する
+pub struct Timer {
+    timer: Option<TimerFd>,
+    error: Option<io::Error>, // timerfd の作成に失敗した場合のエラー。最初の poll でリターン
+    selector: Arc<IOSelector>,
+}
+
+impl Timer {
+    pub fn new(selector: Arc<IOSelector>, dur: Duration) -> io::Result<Timer> {
+        let timer =
+            TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::TFD_NONBLOCK).map_err(nix_to_io)?;
+        // 0 を設定するとタイマーが停止してしまうため、最小でも 1ns とする
+        let dur = dur.max(Duration::from_nanos(1));
+        timer
+            .set(
+                Expiration::OneShot(TimeSpec::from(dur)),
+                TimerSetTimeFlags::empty(),
+            )
+            .map_err(nix_to_io)?;
+        Ok(Timer {
+            timer: Some(timer),
+            error: None,
+            selector,
+        })
+    }
+}
+
+// dur 経過後に完了する Future をリターン
+pub fn sleep(selector: Arc<IOSelector>, dur: Duration) -> Timer {
+    Timer::new(selector.clone(), dur).unwrap_or_else(|err| Timer {
+        timer: None,
+        error: Some(err),
+        selector,
+    })
+}
+
+impl Future for Timer {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(err) = self.error.take() {
+            return Poll::Ready(Err(err));
+        }
+        let Some(fd) = self.timer.as_ref().map(|timer| timer.as_raw_fd()) else {
+            // 完了済み
+            return Poll::Ready(Ok(()));
+        };
+
+        // 満了していれば満了回数を読み込める
+        let mut buf = [0; 8];
+        match read(fd, &mut buf) {
+            Ok(_) => Poll::Ready(Ok(())),
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                // 満了していない場合は IOSelector に登録
+                match self
+                    .selector
+                    .register(Interest::READ, fd, cx.waker().clone())
+                {
+                    Ok(()) => Poll::Pending,
+                    Err(err) => Poll::Ready(Err(err)),
+                }
+            }
+            Err(err) => Poll::Ready(Err(nix_to_io(err))),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        // 満了前に破棄された場合でも IOSelector の監視から削除する
+        // timerfd は unregister の後にクローズされる
+        if let Some(timer) = &self.timer {
+            let _ = self.selector.unregister(timer.as_raw_fd());
+        }
+    }
+}
+
+// period ごとに満了する周期タイマー
+// tick().await か Stream として使用し、満了予定時刻をリターンする
+// 周期は timerfd 自体に設定するため、待機の開始や起床が遅れても次の満了予定時刻はずれない
+pub struct Interval {
+    timer: TimerFd,
+    next: Instant, // 次の満了予定時刻
+    period: Duration,
+    selector: Arc<IOSelector>,
+}
+
+// 現在時刻から period ごとに満了する Interval をリターン
+// 最初の満了は period 経過後。period が 0 の場合はパニック
+pub fn interval(selector: Arc<IOSelector>, period: Duration) -> io::Result<Interval> {
+    assert!(!period.is_zero(), "period must be non-zero");
+    let timer =
+        TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::TFD_NONBLOCK).map_err(nix_to_io)?;
+    let next = Instant::now() + period;
+    timer
+        .set(
+            Expiration::Interval(TimeSpec::from(period)),
+            TimerSetTimeFlags::empty(),
+        )
+        .map_err(nix_to_io)?;
+    Ok(Interval {
+        timer,
+        next,
+        period,
+        selector,
+    })
+}
+
+impl Interval {
+    // 次に満了するまで待機し、その満了予定時刻をリターン
+    pub fn tick(&mut self) -> Tick<'_> {
+        Tick { interval: self }
+    }
+
+    fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Instant>> {
+        let fd = self.timer.as_raw_fd();
+        // 前回の読み込み以降の満了回数を読み込める
+        let mut buf = [0; 8];
+        match read(fd, &mut buf) {
+            Ok(_) => {
+                // 待機が遅れて複数回満了していた場合は、まとめて1回とし最後の満了予定時刻をリターン
+                let n = u64::from_ne_bytes(buf).max(1) as u32;
+                let target = self.next + self.period * (n - 1);
+                self.next = target + self.period;
+                Poll::Ready(Ok(target))
+            }
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                match self
+                    .selector
+                    .register(Interest::READ, fd, cx.waker().clone())
+                {
+                    Ok(()) => Poll::Pending,
+                    Err(err) => Poll::Ready(Err(err)),
+                }
+            }
+            Err(err) => Poll::Ready(Err(nix_to_io(err))),
+        }
+    }
+}
+
+// Interval::tick の Future
+pub struct Tick<'a> {
+    interval: &'a mut Interval,
+}
+
+impl Future for Tick<'_> {
+    type Output = io::Result<Instant>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.interval.poll_tick(cx)
+    }
+}
+
+// IOSelector への登録に失敗した場合は終了する
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_tick(cx).map(Result::ok)
+    }
+}
+
+impl Drop for Interval {
+    fn drop(&mut self) {
+        // timerfd は unregister の後にクローズされる
+        let _ = self.selector.unregister(self.timer.as_raw_fd());
+    }
+}
+
+// with_timeout でタイムアウトした場合のエラー
+#[derive(Debug, PartialEq, Eq)]
+pub struct Timeout;
+
+impl std::fmt::Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "operation timed out")
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+// future とタイマーを同時に poll し、先に完了した方の結果をリターンする Future
+// 完了しなかった方は破棄する
+pub struct WithTimeout<F> {
+    future: Option<Pin<Box<F>>>,
+    timer: Option<Timer>,
+}
+
+// future が dur 以内に完了しなければ Err(Timeout) をリターン
+pub fn with_timeout<F: Future>(selector: Arc<IOSelector>, f: F, dur: Duration) -> WithTimeout<F> {
+    WithTimeout {
+        future: Some(Box::pin(f)),
+        timer: Some(sleep(selector, dur)),
+    }
+}
+
+impl<F: Future> Future for WithTimeout<F> {
+    type Output = Result<F::Output, Timeout>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.future.as_mut().expect("polled after completion");
+        if let Poll::Ready(val) = future.as_mut().poll(cx) {
+            // タイマーを破棄して IOSelector の監視から削除
+            self.future = None;
+            self.timer = None;
+            return Poll::Ready(Ok(val));
+        }
+
+        if let Some(timer) = self.timer.as_mut() {
+            match Pin::new(timer).poll(cx) {
+                Poll::Ready(Ok(())) => {
+                    // タイムアウトした future は破棄
+                    self.future = None;
+                    self.timer = None;
+                    return Poll::Ready(Err(Timeout));
+                }
+                // タイマーが使用できない場合はタイムアウトせずに future の完了を待つ
+                Poll::Ready(Err(_)) => self.timer = None,
+                Poll::Pending => (),
+            }
+        }
+        Poll::Pending
+    }
+}
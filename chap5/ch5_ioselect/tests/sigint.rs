@@ -0,0 +1,95 @@
+// ビルドした echo サーバを子プロセスとして起動し、SIGINT で終了させる
+// テストを実行する他のスレッドは SIGINT をブロックしていないため、テストのプロセス内では送信できない
+#![cfg(target_os = "linux")]
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+// main が待ち受けるアドレス
+const ADDR: &str = "127.0.0.1:10000";
+
+// サーバが待ち受けを開始するまで接続を繰り返す
+fn connect(server: &mut Child) -> TcpStream {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match TcpStream::connect(ADDR) {
+            Ok(stream) => return stream,
+            Err(_) if Instant::now() < deadline => {
+                assert!(server.try_wait().unwrap().is_none(), "server exited");
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(err) => {
+                let _ = server.kill();
+                panic!("failed to connect: {}", err);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sigint_shutdown() {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_ch5_ioselect"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut out = BufReader::new(server.stdout.take().unwrap());
+
+    let mut stream = connect(&mut server);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    stream.write_all(b"hello\n").unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "hello\n");
+
+    // SIGINT を受信するまで待機
+    kill(Pid::from_raw(server.id() as i32), Signal::SIGINT).unwrap();
+    let mut log = String::new();
+    while !log.ends_with("shutdown\n") {
+        assert_ne!(out.read_line(&mut log).unwrap(), 0, "server exited");
+    }
+
+    // 新たな接続は拒否される
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(ADDR).is_ok() {
+        assert!(Instant::now() < deadline, "server still accepting");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // 接続中のコネクションは切断されず、送信した行が返る
+    stream.write_all(b"world\n").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "world\n");
+    assert!(server.try_wait().unwrap().is_none());
+
+    // クライアントが切断すると、シグナルで強制終了せずに main からリターンして終了する
+    drop(reader);
+    drop(stream);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = server.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = server.kill();
+            panic!("server did not exit");
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(status.success());
+
+    out.read_to_string(&mut log).unwrap();
+    assert!(log.contains("close:"));
+}
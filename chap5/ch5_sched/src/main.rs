@@ -1,14 +1,18 @@
 use std::{
+    any::Any,
     future::Future,
+    panic::{self, AssertUnwindSafe},
     pin::Pin,
     sync::{
-        mpsc::{sync_channel, Receiver, SyncSender},
+        atomic::{AtomicBool, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
         Arc, Mutex,
     },
     task::{Context, Poll},
 };
 
 use futures::{
+    channel::oneshot,
     future::{BoxFuture, FutureExt},
     task::{waker_ref, ArcWake},
 };
@@ -55,29 +59,55 @@ impl Future for Hello {
 
 struct Task {
     // 実行するコルーチン
-    future: Mutex<BoxFuture<'static, ()>>,
+    // 完了または中断した場合は None
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    // AbortHandle::abort で中断されたか
+    aborted: AtomicBool,
+    // 実行キューに入っているか
+    // 既に入っている場合は wake されても再度キューに入れず、無駄な poll を防ぐ
+    scheduled: AtomicBool,
     // Executor へスケジューリングするためのチャネル
-    sender: SyncSender<Arc<Task>>,
+    sender: SyncSender<Option<Arc<Task>>>,
 }
 
 impl ArcWake for Task {
     fn wake_by_ref(arc_self: &Arc<Self>) {
+        // 実行キューに入っていない場合のみスケジューリング
+        // poll 中に自身を wake した場合もキューの末尾に入るため、他の Task より先に再度 poll されることはない
+        if arc_self.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
         let self0 = arc_self.clone();
-        arc_self.sender.send(self0).unwrap();
+        // Executor が既に破棄されている場合は実行できないため、Task を破棄する
+        let _ = arc_self.sender.send(Some(self0));
     }
 }
 
 struct Executor {
-    sender: SyncSender<Arc<Task>>,
-    receiver: Receiver<Arc<Task>>,
+    // None は shutdown の通知
+    sender: SyncSender<Option<Arc<Task>>>,
+    // 複数のワーカースレッドから受信できるように Mutex で保護する
+    receiver: Arc<Mutex<Receiver<Option<Arc<Task>>>>>,
+    closed: Arc<AtomicBool>,  // shutdown され、新たな Task を生成できないか
+    aborted: Arc<AtomicBool>, // shutdown_now され、実行キューの Task を実行せずに終了するべきか
 }
 
 impl Executor {
     fn new() -> Self {
-        let (sender, receiver) = sync_channel(1024);
+        Self::with_capacity(1024)
+    }
+
+    // 実行キューに cap 個まで Task を格納できる Executor を作成
+    // キューが一杯の場合、spawn や Task の wake は空きができるまでブロックし、try_spawn は Full をリターンする
+    // ワーカースレッド上で wake した場合もブロックするため、ワーカーが1つで cap が小さいとデッドロックする可能性がある
+    fn with_capacity(cap: usize) -> Self {
+        assert!(cap > 0, "capacity must be non-zero");
+        let (sender, receiver) = sync_channel(cap);
         Executor {
             sender: sender.clone(),
-            receiver,
+            receiver: Arc::new(Mutex::new(receiver)),
+            closed: Arc::new(AtomicBool::new(false)),
+            aborted: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -85,36 +115,273 @@ impl Executor {
     fn get_spawner(&self) -> Spawner {
         Spawner {
             sender: self.sender.clone(),
+            closed: self.closed.clone(),
         }
     }
 
+    // shutdown されるまでリターンしない
     fn run(&self) {
-        // チャネルから Task を受信して順に実行
-        while let Ok(task) = self.receiver.recv() {
-            // コンテキストを生成
-            let mut future = task.future.lock().unwrap();
-            let waker = waker_ref(&task);
-            let mut ctx = Context::from_waker(&waker);
-            // poll を呼び出し実行
-            let _ = future.as_mut().poll(&mut ctx);
+        worker(&self.receiver, &self.sender, &self.aborted);
+    }
+
+    // n 個のワーカースレッドで Task を並行に実行
+    // すべてのワーカーが終了するまでリターンしない
+    #[allow(dead_code)] // main では使用しない
+    fn run_workers(&self, n: usize) {
+        let workers: Vec<_> = (0..n)
+            .map(|_| {
+                let receiver = self.receiver.clone();
+                let sender = self.sender.clone();
+                let aborted = self.aborted.clone();
+                std::thread::spawn(move || worker(&receiver, &sender, &aborted))
+            })
+            .collect();
+
+        for w in workers {
+            w.join().unwrap();
+        }
+    }
+
+    // 新たな Task の生成を停止し、その時点で実行キューに入っている Task を実行してから run を終了させる
+    // 以降にキューに入った Task は実行しない
+    // ただし run_workers では、終了の通知を受け取るまでに他のワーカーが実行する場合がある
+    #[allow(dead_code)] // main では使用しない
+    fn shutdown(&self) {
+        self.closed.store(true, Ordering::Release);
+        // 実行キューの末尾に終了の通知を入れる
+        let _ = self.sender.send(None);
+    }
+
+    // 新たな Task の生成を停止し、実行キューに入っている Task も実行せずに run を終了させる
+    // poll 中の Task は poll が完了してから終了する
+    #[allow(dead_code)] // main では使用しない
+    fn shutdown_now(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.aborted.store(true, Ordering::Release);
+        // 受信待ちのワーカーを起床させる
+        // キューが一杯の場合は受信待ちのワーカーはいないため、通知しなくてよい
+        let _ = self.sender.try_send(None);
+    }
+}
+
+// チャネルから Task を受信して順に実行
+// shutdown の通知を受け取ると終了する
+fn worker(
+    receiver: &Mutex<Receiver<Option<Arc<Task>>>>,
+    sender: &SyncSender<Option<Arc<Task>>>,
+    aborted: &AtomicBool,
+) {
+    loop {
+        // ロックは受信中のみ保持し、poll 中は他のワーカーが受信できるようにする
+        let task = receiver.lock().unwrap().recv();
+        let Ok(task) = task else {
+            break;
+        };
+
+        match task {
+            // shutdown の通知より前に入った Task は、他のワーカーが通知を受け取った後でも実行する
+            Some(task) if !aborted.load(Ordering::Acquire) => run_task(&task),
+            // shutdown の通知、または shutdown_now 後
+            // 他のワーカーや、次に run を呼び出した場合も終了するように通知を戻す
+            _ => {
+                let _ = sender.try_send(None);
+                break;
+            }
         }
     }
 }
 
+// Task を1回 poll する
+fn run_task(task: &Arc<Task>) {
+    // poll 中に wake された場合は再度キューに入れる必要があるため、poll の前にフラグを下ろす
+    task.scheduled.store(false, Ordering::Release);
+
+    // コンテキストを生成
+    let mut future = task.future.lock().unwrap();
+    // 中断された Task は poll せずに future を破棄する
+    // future が保持していた IOSelector への登録などは、各 Drop で解除される
+    if task.aborted.load(Ordering::Acquire) {
+        *future = None;
+        return;
+    }
+    let Some(fut) = future.as_mut() else {
+        // 完了済みの Task が再度 wake された
+        return;
+    };
+    let waker = waker_ref(task);
+    let mut ctx = Context::from_waker(&waker);
+    // poll を呼び出し実行
+    // Task 内でパニックが発生しても、その Task のみ破棄して他の Task の実行を続ける
+    match panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(&mut ctx))) {
+        Ok(Poll::Ready(())) => *future = None,
+        Ok(Poll::Pending) => (),
+        Err(err) => {
+            eprintln!("task panicked: {}", panic_message(&*err));
+            *future = None;
+        }
+    }
+}
+
+// パニック時に渡された値からメッセージを取り出す
+// panic! に文字列以外を渡した場合は取り出せない
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+// try_spawn のエラー
+#[derive(Debug, PartialEq, Eq)]
+#[allow(dead_code)] // main では使用しない
+enum SpawnError {
+    Full,     // 実行キューが一杯
+    Shutdown, // Executor が shutdown または破棄されている
+}
+
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpawnError::Full => write!(f, "run queue is full"),
+            SpawnError::Shutdown => write!(f, "executor has shut down"),
+        }
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
 struct Spawner {
-    sender: SyncSender<Arc<Task>>,
+    sender: SyncSender<Option<Arc<Task>>>,
+    closed: Arc<AtomicBool>, // Executor が shutdown されたか
 }
 
 impl Spawner {
+    // Executor が shutdown されている場合はパニック
     fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) {
+        assert!(!self.is_closed(), "executor has shut down");
         let future = future.boxed();
         let task = Arc::new(Task {
-            future: Mutex::new(future),
+            future: Mutex::new(Some(future)),
+            aborted: AtomicBool::new(false),
+            scheduled: AtomicBool::new(true), // 生成時に実行キューに入れる
             sender: self.sender.clone(),
         });
 
         // 実行 queue に enqueue
-        self.sender.send(task).unwrap();
+        self.sender.send(Some(task)).unwrap();
+    }
+
+    // Executor が shutdown されたか
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    // 実行キューが一杯の場合や Executor が破棄されている場合に、ブロックやパニックせずにエラーをリターン
+    #[allow(dead_code)] // main では使用しない
+    fn try_spawn(
+        &self,
+        future: impl Future<Output = ()> + 'static + Send,
+    ) -> Result<(), SpawnError> {
+        if self.is_closed() {
+            return Err(SpawnError::Shutdown);
+        }
+        let future = future.boxed();
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future)),
+            aborted: AtomicBool::new(false),
+            scheduled: AtomicBool::new(true), // 生成時に実行キューに入れる
+            sender: self.sender.clone(),
+        });
+
+        self.sender.try_send(Some(task)).map_err(|err| match err {
+            TrySendError::Full(_) => SpawnError::Full,
+            TrySendError::Disconnected(_) => SpawnError::Shutdown,
+        })
+    }
+
+    // 中断可能な Task を生成し、中断するための AbortHandle をリターン
+    #[allow(dead_code)] // main では使用しない
+    fn spawn_cancelable(&self, future: impl Future<Output = ()> + 'static + Send) -> AbortHandle {
+        assert!(!self.is_closed(), "executor has shut down");
+        let future = future.boxed();
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future)),
+            aborted: AtomicBool::new(false),
+            scheduled: AtomicBool::new(true), // 生成時に実行キューに入れる
+            sender: self.sender.clone(),
+        });
+
+        self.sender.send(Some(task.clone())).unwrap();
+        AbortHandle { task }
+    }
+
+    // future を実行する Task を生成し、その結果を受け取るための JoinHandle をリターン
+    #[allow(dead_code)] // main では使用しない
+    fn spawn_with_output<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> {
+        // 結果は oneshot チャネル経由で JoinHandle に渡す
+        let (tx, rx) = oneshot::channel();
+        self.spawn(async move {
+            // JoinHandle が既に破棄されている場合は結果を捨てる
+            let _ = tx.send(future.await);
+        });
+        JoinHandle { receiver: rx }
+    }
+}
+
+// spawn_cancelable で生成した Task を中断するためのハンドル
+#[allow(dead_code)] // main では使用しない
+struct AbortHandle {
+    task: Arc<Task>,
+}
+
+#[allow(dead_code)] // main では使用しない
+impl AbortHandle {
+    // Task を中断する
+    // 次に Executor が Task を取り出した際に、poll せずに future を破棄する
+    fn abort(&self) {
+        self.task.aborted.store(true, Ordering::Release);
+        // 待機中の Task でも破棄されるように、実行キューに入れる
+        ArcWake::wake_by_ref(&self.task);
+    }
+
+    // 中断済みか
+    fn is_aborted(&self) -> bool {
+        self.task.aborted.load(Ordering::Acquire)
+    }
+}
+
+// spawn_with_output で生成した Task の結果を受け取るためのハンドル
+// await するか join でブロックして待機する
+#[allow(dead_code)] // main では使用しない
+struct JoinHandle<T> {
+    receiver: oneshot::Receiver<T>,
+}
+
+#[allow(dead_code)] // main では使用しない
+impl<T> JoinHandle<T> {
+    // Task が完了するまで呼び出したスレッドをブロックして結果をリターン
+    // Task を実行する Executor とは別のスレッドから呼び出す必要がある
+    fn join(self) -> T {
+        futures::executor::block_on(self)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        match self.receiver.poll_unpin(cx) {
+            Poll::Ready(Ok(val)) => Poll::Ready(val),
+            // 結果を送信せずに Task が破棄された
+            Poll::Ready(Err(oneshot::Canceled)) => panic!("task was dropped before completion"),
+            Poll::Pending => Poll::Pending,
+        }
     }
 }
 
@@ -123,3 +390,317 @@ fn main() {
     executor.get_spawner().spawn(Hello::new());
     executor.run();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_run_workers() {
+        const NUM_TASKS: usize = 500;
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+
+        for _ in 0..NUM_TASKS {
+            let count = count.clone();
+            let threads = threads.clone();
+            spawner.spawn(async move {
+                // Hello は Pending を返して再スケジューリングされる
+                Hello::new().await;
+                // CPU を使う処理の代わり
+                std::thread::sleep(Duration::from_millis(1));
+                threads.lock().unwrap().insert(std::thread::current().id());
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        std::thread::spawn(move || executor.run_workers(4));
+
+        let start = Instant::now();
+        while count.load(Ordering::SeqCst) < NUM_TASKS {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), NUM_TASKS);
+        assert!(threads.lock().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn test_spawn_with_output() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+
+        let handle = spawner.spawn_with_output(async { 40 + 2 });
+        // 別の Task の中で await して結果を受け取る
+        let inner = spawner.spawn_with_output(async { String::from("hello") });
+        let outer = spawner.spawn_with_output(async move { inner.await.len() });
+
+        std::thread::spawn(move || executor.run());
+        assert_eq!(handle.join(), 42);
+        assert_eq!(outer.join(), 5);
+    }
+
+    #[test]
+    fn test_try_spawn() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+
+        // 実行キューを一杯にする
+        for _ in 0..1024 {
+            spawner.try_spawn(async {}).unwrap();
+        }
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Full));
+
+        // Executor 破棄後の spawn や wake はパニックしない
+        let task = executor.receiver.lock().unwrap().recv().unwrap().unwrap();
+        drop(executor);
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Shutdown));
+        futures::task::waker(task).wake();
+    }
+
+    // 破棄されるとフラグを立てる
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_spawn_cancelable() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let polled = Arc::new(AtomicBool::new(false));
+
+        // wake されないため永遠に完了しない Task
+        let flag = DropFlag(dropped.clone());
+        let polled0 = polled.clone();
+        let handle = spawner.spawn_cancelable(async move {
+            let _flag = flag;
+            polled0.store(true, Ordering::SeqCst);
+            futures::future::pending::<()>().await;
+        });
+        std::thread::spawn(move || executor.run());
+
+        let start = Instant::now();
+        while !polled.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        handle.abort();
+        assert!(handle.is_aborted());
+        while !dropped.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_panic_in_task() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        // パニックする Task の後に通常の Task を実行
+        spawner.spawn(async {
+            Hello::new().await;
+            panic!("task panicked");
+        });
+        spawner.spawn(async move {
+            Hello::new().await;
+            tx.send(()).unwrap();
+        });
+        std::thread::spawn(move || executor.run());
+
+        // パニックしても Executor は停止せず、他の Task は完了する
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    // 1回目の poll で自身を2回 wake して Pending を返し、2回目の poll で完了する Future
+    struct WakeTwice(bool);
+
+    impl Future for WakeTwice {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn test_wake_dedup() {
+        let executor = Executor::new();
+        executor.get_spawner().spawn(WakeTwice(false));
+        let receiver = executor.receiver.lock().unwrap();
+
+        // poll される前に wake されても、再度キューには入らない
+        let task = receiver.try_recv().unwrap().unwrap();
+        ArcWake::wake_by_ref(&task);
+        ArcWake::wake_by_ref(&task);
+        assert!(receiver.try_recv().is_err());
+
+        // poll 中に2回 wake されても、キューに入るのは1回のみ
+        run_task(&task);
+        let task = receiver.try_recv().unwrap().unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        run_task(&task);
+        assert!(task.future.lock().unwrap().is_none());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let count = count.clone();
+            spawner.spawn(async move {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // shutdown 前に実行キューに入っていた Task は実行してから run が終了する
+        executor.shutdown();
+        executor.run();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert!(spawner.is_closed());
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Shutdown));
+
+        // 再度 run を呼び出してもすぐに終了する
+        executor.run();
+    }
+
+    #[test]
+    fn test_shutdown_workers_drain() {
+        const NUM_TASKS: usize = 1000;
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..NUM_TASKS {
+            let count = count.clone();
+            spawner.spawn(async move {
+                std::thread::yield_now();
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // 他のワーカーが終了の通知を受け取っても、それより前に取り出した Task は実行する
+        executor.shutdown();
+        executor.run_workers(4);
+        assert_eq!(count.load(Ordering::SeqCst), NUM_TASKS);
+    }
+
+    #[test]
+    fn test_shutdown_now() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+        let count0 = count.clone();
+        spawner.spawn(async move {
+            count0.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // 実行キューに入っている Task も実行せずに終了する
+        executor.shutdown_now();
+        executor.run();
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Shutdown));
+    }
+
+    #[test]
+    fn test_shutdown_running_workers() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        std::thread::scope(|s| {
+            let workers = s.spawn(|| executor.run_workers(4));
+            spawner.spawn(async move {
+                tx.send(()).unwrap();
+            });
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+            // 受信待ちのワーカーもすべて終了する
+            executor.shutdown();
+            workers.join().unwrap();
+        });
+    }
+
+    // 自身を wake し続け、完了しない Future
+    struct Greedy(Arc<AtomicUsize>);
+
+    impl Future for Greedy {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_greedy_task() {
+        let executor = Executor::new();
+        let spawner = executor.get_spawner();
+        let count = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        spawner.spawn(Greedy(count.clone()));
+        spawner.spawn(async move {
+            Hello::new().await;
+            tx.send(()).unwrap();
+        });
+
+        std::thread::scope(|s| {
+            let worker = s.spawn(|| executor.run());
+
+            // 1つのワーカーでも、自身を wake し続ける Task と並行して他の Task が完了する
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(count.load(Ordering::SeqCst) > 0);
+
+            executor.shutdown_now();
+            worker.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let executor = Executor::with_capacity(1);
+        let spawner = executor.get_spawner();
+        spawner.try_spawn(async {}).unwrap();
+        assert_eq!(spawner.try_spawn(async {}), Err(SpawnError::Full));
+
+        // キューが一杯の場合、spawn は Task が取り出されるまでブロックする
+        let (tx, rx) = std::sync::mpsc::channel();
+        let t = std::thread::spawn(move || {
+            spawner.spawn(async {});
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        let receiver = executor.receiver.lock().unwrap();
+        receiver.recv().unwrap().unwrap();
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        t.join().unwrap();
+        receiver.recv().unwrap().unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+}
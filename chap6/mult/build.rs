@@ -1,25 +1,43 @@
+use std::env;
+use std::path::Path;
 use std::process::Command;
 
-const ASM_FILE: &str = "asm/context.S";
-const O_FILE: &str = "asm/context.o";
-const LIB_FILE: &str = "asm/libcontext.a";
-
 // build.rs という名前のファイルには特別な意味がある
 // プロジェクトルートに置いておくと、ビルド前に実行される
 // cargo: で始まる命令は Cargo が特別な意味を持って解釈するらしい
 
 fn main() {
-    Command::new("cc")
-        .args([ASM_FILE, "-c", "-fPIC", "-ggdb", "-o"])
-        .arg(O_FILE)
+    // ターゲットのアーキテクチャに応じてコンテキストスイッチのアセンブリを選択
+    let asm_file = match env::var("CARGO_CFG_TARGET_ARCH").unwrap().as_str() {
+        "x86_64" => "asm/context.S",
+        "aarch64" => "asm/context_aarch64.S",
+        arch => panic!("unsupported target architecture: {}", arch),
+    };
+
+    // 生成物はソースツリーではなく OUT_DIR に置く
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let o_file = Path::new(&out_dir).join("context.o");
+    let lib_file = Path::new(&out_dir).join("libcontext.a");
+
+    // クロスコンパイル時は CC でコンパイラを指定できる
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+
+    let status = Command::new(cc)
+        .args([asm_file, "-c", "-fPIC", "-ggdb", "-o"])
+        .arg(&o_file)
         .status()
         .unwrap();
-    Command::new("ar")
-        .args(["crus", LIB_FILE, O_FILE])
+    assert!(status.success(), "failed to assemble {}", asm_file);
+    let status = Command::new("ar")
+        .arg("crs")
+        .arg(&lib_file)
+        .arg(&o_file)
         .status()
         .unwrap();
+    assert!(status.success(), "failed to archive {}", o_file.display());
 
-    println!("cargo:rustc-link-search=native=asm"); // asm をライブラリ検索パスに追加
-    println!("cargo:rustc-link-lib=static=context"); // libcontext.o という静的ライブラリをリンク,  prefix の lib と .o という拡張子を除いて指定
-    println!("cargo:return-if-changed=asm/context.S"); // asm/context.S というファイルに保存
+    println!("cargo:rustc-link-search=native={}", out_dir); // OUT_DIR をライブラリ検索パスに追加
+    println!("cargo:rustc-link-lib=static=context"); // libcontext.a という静的ライブラリをリンク,  prefix の lib と .a という拡張子を除いて指定
+    println!("cargo:rerun-if-changed={}", asm_file); // アセンブリが変更された場合のみ再実行
+    println!("cargo:rerun-if-changed=build.rs");
 }
@@ -1,26 +1,18 @@
+use nix::libc;
 use nix::sys::mman::{mprotect, ProtFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use std::alloc::{alloc, dealloc, Layout};
-use std::collections::{HashMap, HashSet, LinkedList};
+use std::any::Any;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, LinkedList, VecDeque};
 use std::ffi::c_void;
-use std::ptr;
-
-// すべてのスレッド終了時に戻ってくる先 <1>
-static mut CTX_MAIN: Option<Box<Registers>> = None;
-
-// 不要なスタック領域 <2>
-static mut UNUSED_STACK: (*mut u8, Layout) = (ptr::null_mut(), Layout::new::<u8>());
-
-// スレッドの実行キュー <3>
-static mut CONTEXTS: LinkedList<Box<Context>> = LinkedList::new();
-
-// スレッドIDの集合 <4>
-static mut ID: *mut HashSet<u64> = ptr::null_mut();
-
-// メッセージキュー <1>
-static mut MESSAGES: *mut MappedList<u64> = ptr::null_mut();
-
-// 待機スレッド集合 <2>
-static mut WAITING: *mut HashMap<u64, Box<Context>> = ptr::null_mut();
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 // # Callee-saved vs Caller-saved
 // x86_64 arch には 16 個の汎用レジスタがあり、そのうち、次の6つは Callee-saved (呼び出された側が保存する)
@@ -38,6 +30,7 @@ static mut WAITING: *mut HashMap<u64, Box<Context>> = ptr::null_mut();
 // 他の省かれたレジスタは、関数呼び出し時に破壊される可能性があるため、スレッドの切り替え時には特に保存する必要がない
 
 // 物理的なメモリの順番がフィールド定義の順番と同じになるように repr(C) がいる
+#[cfg(target_arch = "x86_64")]
 #[repr(C)] // <1>
 struct Registers {
     rbx: u64, // 引数として渡した場合、(%rdi)
@@ -50,6 +43,7 @@ struct Registers {
     rdx: u64,
 }
 
+#[cfg(target_arch = "x86_64")]
 impl Registers {
     // <3>
     fn new(rsp: u64) -> Self {
@@ -61,11 +55,65 @@ impl Registers {
             r14: 0,
             r15: 0,
             rsp,
-            rdx: entry_point as u64, // <4>
+            rdx: entry_point as *const () as u64, // <4>
+        }
+    }
+}
+
+// AArch64 の Callee-saved レジスタは x19 から x28、フレームポインタ x29 (fp)、
+// および浮動小数点レジスタ d8 から d15 (v8 から v15 の下位 64 ビット)
+// 戻りアドレスはスタックではなくリンクレジスタ x30 (lr) に置かれるため、
+// lr に再開アドレス（新規スレッドの場合は entry_point）を保存しておき、そこへ分岐する
+// フィールドのオフセットは asm/context_aarch64.S と一致している必要がある
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+struct Registers {
+    x19: u64, // [x0, #0]
+    x20: u64,
+    x21: u64,
+    x22: u64,
+    x23: u64,
+    x24: u64,
+    x25: u64,
+    x26: u64,
+    x27: u64,
+    x28: u64,
+    fp: u64,     // [x0, #80]
+    lr: u64,     // [x0, #88]
+    sp: u64,     // [x0, #96]
+    d: [u64; 8], // [x0, #104] から d8..d15
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Registers {
+    fn new(sp: u64) -> Self {
+        Registers {
+            x19: 0,
+            x20: 0,
+            x21: 0,
+            x22: 0,
+            x23: 0,
+            x24: 0,
+            x25: 0,
+            x26: 0,
+            x27: 0,
+            x28: 0,
+            fp: 0,
+            lr: entry_point as *const () as u64,
+            sp,
+            d: [0; 8],
         }
     }
 }
 
+// 新規スレッドの開始時にスタックの末尾から空けておくバイト数
+// x86_64 では関数の入口で rsp + 8 が 16 バイト境界になっている必要があるため、戻りアドレス分の 8 バイトを空ける
+// AArch64 では sp は常に 16 バイト境界である必要があり、戻りアドレスも lr に置かれるため空けない
+#[cfg(target_arch = "x86_64")]
+const STACK_TOP_OFFSET: u64 = 8;
+#[cfg(target_arch = "aarch64")]
+const STACK_TOP_OFFSET: u64 = 0;
+
 // ctx というポインタを引数にしてる
 // これは、アセンブリ内で %rdi レジスタを通じてアクセスできる
 // switch_context の返り値の型が never 型になっているのは、アセンブリの定義を見ると話kる
@@ -78,8 +126,24 @@ extern "C" {
 // スレッド開始時に実行する関数の型
 type Entry = fn(); // <1>
 
+// コンテキストが保持するエントリ関数。クロージャも実行できるように Box 化する
+type BoxedEntry = Box<dyn FnOnce()>;
+
 // ページサイズ。Linuxだと4KiB
+// Apple Silicon の macOS は 16KiB
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
 const PAGE_SIZE: usize = 4 * 1024; // 4KiB <2>
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+const PAGE_SIZE: usize = 16 * 1024; // 16KiB
+
+// サイズごとに保持しておく再利用可能なスタック領域の最大数
+const MAX_POOLED_STACKS: usize = 64;
+
+// spawn などで生成したスレッドの優先度
+pub const DEFAULT_PRIORITY: u32 = 0;
+
+// スタック使用量の計測時に、スタック領域をあらかじめ埋めておく値
+const STACK_SENTINEL: u8 = 0xcd;
 
 struct MappedList<T> {
     // <1>
@@ -110,7 +174,7 @@ impl<T> MappedList<T> {
     fn pop_front(&mut self, key: u64) -> Option<T> {
         if let Some(list) = self.map.get_mut(&key) {
             let val = list.pop_front();
-            if list.len() == 0 {
+            if list.is_empty() {
                 self.map.remove(&key);
             }
             val
@@ -118,19 +182,82 @@ impl<T> MappedList<T> {
             None
         }
     }
+}
+
+// スタック領域
+// 先頭の1ページはガードページとしてアクセスを禁止している
+struct Stack {
+    ptr: *mut u8,   // スタック
+    layout: Layout, // スタックレイアウト
+}
+
+impl Stack {
+    fn new(stack_size: usize) -> Self {
+        // スタック領域の確保 <5>
+        // アライメントは、メモリの先頭が特定の倍数であることを保証するためのもの
+        let layout = Layout::from_size_align(stack_size, PAGE_SIZE).unwrap();
+        let ptr = unsafe { alloc(layout) };
+
+        // ガードページの設定 <6>
+        // 今回は PROT_NONE なのｄ
+        // stack から始まる PAGE_SIZE 分の領域のメモリに対して、すべてのアクセス（読み書き実行）を禁止している
+        unsafe { mprotect(ptr as *mut c_void, PAGE_SIZE, ProtFlags::PROT_NONE).unwrap() };
+
+        Stack { ptr, layout }
+    }
+
+    fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    // ガードページを除くスタック領域を番兵で埋める
+    // 末尾の 16 バイトは新規スレッドの戻りアドレスの位置にあたり、
+    // panic 時のバックトレースの取得などでフレームを辿る際の終端となるよう 0 のままにする
+    fn fill_sentinel(&mut self) {
+        let len = self.size() - PAGE_SIZE - 16;
+        unsafe {
+            let bottom = self.ptr.add(PAGE_SIZE);
+            bottom.write_bytes(STACK_SENTINEL, len);
+            bottom.add(len).write_bytes(0, 16);
+        };
+    }
+
+    // 番兵が書き換えられた最も深い位置から、スタックの末尾までのバイト数
+    // スタックは末尾から先頭に向かって伸びるため、ガードページの直後から番兵でない最初のバイトを探す
+    fn high_water(&self) -> usize {
+        let usable =
+            unsafe { std::slice::from_raw_parts(self.ptr.add(PAGE_SIZE), self.size() - PAGE_SIZE) };
+        match usable.iter().position(|b| *b != STACK_SENTINEL) {
+            Some(i) => usable.len() - i,
+            None => 0,
+        }
+    }
+}
 
-    fn clear(&mut self) {
-        self.map.clear();
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe {
+            // スタック領域の保護を解除 <1>
+            mprotect(
+                self.ptr as *mut c_void,
+                PAGE_SIZE,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            )
+            .unwrap();
+            // スタック領域解放 <2>
+            dealloc(self.ptr, self.layout);
+        }
     }
 }
 
 // コンテキスト <3>
 struct Context {
-    regs: Registers,      // レジスタ
-    stack: *mut u8,       // スタック
-    stack_layout: Layout, // スタックレイアウト
-    entry: Entry,         // エントリポイント
-    id: u64,              // スレッドID
+    regs: Registers,           // レジスタ
+    stack: Stack,              // スタック
+    entry: Option<BoxedEntry>, // エントリポイント。実行時に取り出す
+    id: u64,                   // スレッドID
+    priority: u32,             // 優先度。大きいほど優先して実行される
+    age: u64,                  // 実行可能な状態で、前回実行されてから選ばれなかった回数
 }
 
 impl Context {
@@ -143,222 +270,2462 @@ impl Context {
         &self.regs as *const Registers
     }
 
+    // fill が true の場合、スタック使用量の計測のためにスタック領域を番兵で埋める
     #[inline(never)]
-    fn new(func: Entry, stack_size: usize, id: u64) -> Self {
+    fn new(func: BoxedEntry, mut stack: Stack, id: u64, fill: bool) -> Self {
         // <4>
-        // スタック領域の確保 <5>
-        // アライメントは、メモリの先頭が特定の倍数であることを保証するためのもの
-        let layout = Layout::from_size_align(stack_size, PAGE_SIZE).unwrap();
-        let stack = unsafe { alloc(layout) };
-
-        // ガードページの設定 <6>
-        // 今回は PROT_NONE なのｄ
-        // stack から始まる PAGE_SIZE 分の領域のメモリに対して、すべてのアクセス（読み書き実行）を禁止している
-        unsafe { mprotect(stack as *mut c_void, PAGE_SIZE, ProtFlags::PROT_NONE).unwrap() };
+        if fill {
+            stack.fill_sentinel();
+        }
 
         // レジスタの初期化 <7>
-        let regs = Registers::new(stack as u64 + stack_size as u64);
+        let regs = Registers::new(stack.ptr as u64 + stack.size() as u64 - STACK_TOP_OFFSET);
 
         // コンテキストの初期化
         Context {
-            regs: regs,
-            stack: stack,
-            stack_layout: layout,
-            entry: func,
-            id: id,
+            regs,
+            stack,
+            entry: Some(func),
+            id,
+            priority: DEFAULT_PRIORITY,
+            age: 0,
         }
     }
 }
 
-fn get_id() -> u64 {
-    loop {
-        let rnd = rand::random::<u64>(); // <1>
+// グリーンスレッドのランタイム
+// 実行キューやメッセージキューなど、ランタイムの状態をすべて保持する
+// run の実行中はスレッドローカルな RUNTIME に格納され、各スレッドからはそこを経由して操作される
+pub struct GreenRuntime {
+    // すべてのスレッド終了時に戻ってくる先 <1>
+    ctx_main: Option<Box<Registers>>,
+
+    // 終了したスレッドのコンテキスト
+    // 終了処理中はそのスタックを使っているため、コンテキストスイッチ後に解放する <2>
+    unused: Option<Box<Context>>,
+
+    // スレッドの実行キュー <3>
+    contexts: LinkedList<Box<Context>>,
+
+    // スレッドIDの集合 <4>
+    ids: HashSet<u64>,
+
+    // メッセージキュー
+    // 任意の型のメッセージを送受信できるように Box<dyn Any> で保持する
+    messages: MappedList<Box<dyn Any>>,
+
+    // 待機スレッド集合
+    waiting: HashMap<u64, Box<Context>>,
+
+    // join 待ちスレッドの集合。join 対象のスレッドIDをキーに、待機しているスレッドIDを保持
+    joiners: MappedList<u64>,
+
+    // spawn_with で生成したスレッドの返り値。スレッドIDをキーに、終了時に書き込まれる
+    results: HashMap<u64, Box<dyn Any>>,
+
+    // panic したスレッドの panic 内容。スレッドIDをキーに、終了時に書き込まれる
+    // detached なスレッドの panic 内容は保持しない
+    panics: HashMap<u64, Box<dyn Any + Send>>,
+
+    // recv_timeout や sleep で待機中のスレッドの起床期限。スレッドIDをキーに保持
+    deadlines: HashMap<u64, Instant>,
+
+    // 終了したスレッドから回収した再利用可能なスタック領域。サイズごとに保持
+    stack_pool: HashMap<usize, Vec<Stack>>,
+
+    // spawn_detached で生成したスレッドのID。終了時に取り除く
+    detached: HashSet<u64>,
+
+    // GreenLocal の値。スレッドIDをキーに、GreenLocal ごとの値を保持し、スレッド終了時に破棄する
+    locals: HashMap<u64, HashMap<usize, Box<dyn Any>>>,
+
+    // true の場合、スレッドのスタック使用量の最大値を計測する
+    measure_stack: bool,
+
+    // 終了したスレッドのスタック使用量の最大値。measure_stack が true の場合のみ、スレッドIDをキーに記録
+    high_water: HashMap<u64, usize>,
+
+    // BridgeReceiver::recv で待機中のスレッドのID
+    // これらのスレッドは他の OS スレッドから起こされるため、実行可能なスレッドがなくてもデッドロックとしない
+    bridge_waiters: HashSet<u64>,
+
+    // 他の OS スレッドから起こされたスレッドのID。schedule などの際に実行キューに移動する
+    inbox: Arc<Inbox>,
+
+    // プリエンプションのタイマーの間隔。None ならプリエンプションしない
+    preempt_interval: Option<Duration>,
+
+    // すべてのスレッドが待機状態となり、main関数のスレッドに戻った場合に true
+    deadlocked: bool,
+
+    // 実際にスタック領域を確保した回数
+    stack_allocs: usize,
+
+    // スケジューリングの統計情報
+    stats: Stats,
+}
+
+// スケジューリングの統計情報
+// ランタイムはシングルスレッドで動作するため、単なるカウンタで管理する
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub context_switches: u64,  // コンテキストスイッチの回数
+    pub spawns: u64,            // 生成したスレッドの数
+    pub messages_sent: u64,     // 送信したメッセージの数
+    pub messages_received: u64, // 受信したメッセージの数
+    pub runnable: usize,        // 実行可能なスレッドの数
+    pub waiting: usize,         // 待機中のスレッドの数
+}
+
+// GreenRuntime::try_run のエラー
+// 実行可能なスレッドがなくなり、待機中のスレッドを起こすことができなくなった
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deadlock {
+    pub waiting: Vec<u64>, // 待機したまま破棄されたスレッドのID。昇順
+}
+
+impl std::fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadlock: {} threads are waiting", self.waiting.len())
+    }
+}
+
+impl std::error::Error for Deadlock {}
+
+thread_local! {
+    // 実行中のランタイム。GreenRuntime::run の間だけ設定される
+    static RUNTIME: RefCell<Option<GreenRuntime>> = const { RefCell::new(None) };
+}
+
+// 実行中のランタイムに対して f を適用
+// コンテキストスイッチを跨いで借用しないよう、ランタイムへの参照はこのクロージャ内に閉じ込める
+fn with_runtime<R>(f: impl FnOnce(&mut GreenRuntime) -> R) -> R {
+    RUNTIME.with(|rt| {
+        let mut rt = rt.borrow_mut();
+        f(rt.as_mut().expect("green runtime is not running"))
+    })
+}
+
+impl Default for GreenRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GreenRuntime {
+    pub fn new() -> Self {
+        GreenRuntime {
+            ctx_main: None,
+            unused: None,
+            contexts: LinkedList::new(),
+            ids: HashSet::new(),
+            messages: MappedList::new(),
+            waiting: HashMap::new(),
+            joiners: MappedList::new(),
+            results: HashMap::new(),
+            panics: HashMap::new(),
+            deadlines: HashMap::new(),
+            stack_pool: HashMap::new(),
+            detached: HashSet::new(),
+            locals: HashMap::new(),
+            measure_stack: false,
+            high_water: HashMap::new(),
+            bridge_waiters: HashSet::new(),
+            inbox: Arc::new(Inbox::default()),
+            preempt_interval: None,
+            deadlocked: false,
+            stack_allocs: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    // func を最初のスレッドとして実行し、すべてのスレッドが終了するまで待機
+    // 実行中は同じ OS スレッド上で別のランタイムを実行することはできない
+    // デッドロックを検出した場合は panic
+    pub fn run(&mut self, func: Entry, stack_size: usize) {
+        if let Err(e) = self.try_run(func, stack_size) {
+            panic!("{}", e);
+        }
+    }
+
+    // run と同様に実行し、デッドロックを検出した場合はエラーをリターン
+    // 実行可能なスレッドがなくなった時点で待機中のスレッドが残っており、
+    // タイムアウトで起きるスレッドもいない場合をデッドロックとする
+    // 待機中のスレッドはスタック上の値を破棄せずにスタック領域ごと解放する
+    pub fn try_run(&mut self, func: Entry, stack_size: usize) -> Result<(), Deadlock> {
+        // 実行中のみプリエンプション用のタイマーを動作させる
+        let _timer = self.preempt_interval.map(PreemptTimer::start);
+
+        RUNTIME.with(|rt| {
+            let mut rt = rt.borrow_mut();
+            // すでに実行中ならエラーとする
+            if rt.is_some() {
+                panic!("green runtime is already running");
+            }
+
+            // main関数用のコンテキストを生成
+            let mut this = std::mem::take(self);
+            this.ctx_main = Some(Box::new(Registers::new(0)));
+            *rt = Some(this);
+        });
+
+        // 最初に起動するスレッドのコンテキストを生成 <3>
+        let (main, first) = with_runtime(|rt| {
+            rt.spawn(Box::new(func), stack_size, DEFAULT_PRIORITY);
+            let main = &mut **rt.ctx_main.as_mut().unwrap() as *mut Registers;
+            (main, rt.contexts.front().unwrap().get_regs())
+        });
+
         unsafe {
-            if !(*ID).contains(&rnd) {
+            // すべてのスレッド終了時の戻り先を保存 <2>
+            if set_context(main) == 0 {
+                switch_context(first);
+            }
+        }
+
+        // ランタイムを取り出して、不要なスタックを解放 <4>
+        let mut this = RUNTIME.with(|rt| rt.borrow_mut().take().unwrap());
+        this.rm_unused_stack();
+        this.ctx_main = None;
+        let result = if this.deadlocked {
+            Err(this.clear_waiting())
+        } else {
+            Ok(())
+        };
+        *self = this;
+        result
+    }
+
+    // デッドロックした待機中のスレッドを破棄し、そのスレッドIDをリターン
+    // 再度 run できるように、待機中のスレッドに関する状態もすべて削除する
+    fn clear_waiting(&mut self) -> Deadlock {
+        self.deadlocked = false;
+        let mut waiting: Vec<u64> = self.waiting.keys().copied().collect();
+        waiting.sort();
+        for id in waiting.iter() {
+            self.ids.remove(id);
+            self.deadlines.remove(id);
+            self.bridge_waiters.remove(id);
+            self.detached.remove(id);
+            self.locals.remove(id);
+            self.messages.map.remove(id);
+        }
+        // join 待ちのスレッドはすべて待機中のスレッド
+        self.joiners.map.clear();
+        self.waiting.clear();
+        Deadlock { waiting }
+    }
+
+    // 実行可能なスレッドがいない場合、待機中のスレッドがいればデッドロックとして記録
+    // 戻り先となる main関数のスレッドのレジスタを返す
+    fn return_to_main(&mut self) -> *const Registers {
+        if !self.waiting.is_empty() {
+            self.deadlocked = true;
+        }
+        &**self.ctx_main.as_ref().unwrap() as *const Registers
+    }
+
+    // interval ごとにプリエンプションを要求するタイマーを設定
+    // 要求されたスレッドは、次にランタイムの関数を呼び出した時点で他のスレッドに実行を譲る
+    // 実行を譲る関数 (spawn、send、recv など) に加えて、try_recv、GreenMutex の解放、
+    // GreenSemaphore::release と preempt_check が安全な切り替え箇所となる
+    // シグナルハンドラ内ではコンテキストスイッチしないため、これらを一切呼び出さないループはプリエンプションされない
+    // タイマーは run の間のみ動作し、プリエンプションはランタイムを実行する OS スレッドにのみ要求される
+    // SIGALRM のハンドラはプロセス全体で共有されるため、プロセス内で同時に使用できるのは1つのランタイムのみ
+    pub fn set_preemption(&mut self, interval: Option<Duration>) {
+        if let Some(interval) = interval {
+            assert!(interval >= Duration::from_micros(1));
+        }
+        self.preempt_interval = interval;
+    }
+
+    // スレッドのスタック使用量の最大値を計測するかを設定
+    // 計測する場合は、スレッドの生成ごとにスタック領域全体を番兵で埋めるため、生成のコストが増える
+    pub fn set_measure_stack(&mut self, enable: bool) {
+        self.measure_stack = enable;
+    }
+
+    // スケジューリングの統計情報を取得
+    pub fn stats(&self) -> Stats {
+        Stats {
+            runnable: self.contexts.len(),
+            waiting: self.waiting.len(),
+            ..self.stats
+        }
+    }
+
+    // 実行中のスレッドのID
+    fn current(&self) -> u64 {
+        self.contexts.front().unwrap().id
+    }
+
+    fn get_id(&mut self) -> u64 {
+        loop {
+            let rnd = rand::random::<u64>(); // <1>
+            if !self.ids.contains(&rnd) {
                 // <2>
-                (*ID).insert(rnd); // <3>
+                self.ids.insert(rnd); // <3>
                 return rnd;
             };
         }
     }
-}
 
-pub fn spawn(func: Entry, stack_size: usize) -> u64 {
-    // <1>
-    unsafe {
-        let id = get_id(); // <2>
-        CONTEXTS.push_back(Box::new(Context::new(func, stack_size, id))); // <3>
-        schedule(); // <4>
-        id // <5>
+    // スレッドを生成して実行キューの最後尾に追加
+    fn spawn(&mut self, func: BoxedEntry, stack_size: usize, priority: u32) -> u64 {
+        let id = self.get_id();
+        let stack = self.alloc_stack(stack_size);
+        let mut ctx = Box::new(Context::new(func, stack, id, self.measure_stack));
+        ctx.priority = priority;
+        self.contexts.push_back(ctx);
+        self.stats.spawns += 1;
+        id
     }
-}
 
-pub fn schedule() {
-    unsafe {
-        // 実行可能なプロセスが自身のみであるため即座にリターン <1>
-        if CONTEXTS.len() == 1 {
+    // スタック領域を取得
+    // 同じサイズの再利用可能なスタック領域があればそれを使い、なければ新たに確保する
+    fn alloc_stack(&mut self, stack_size: usize) -> Stack {
+        if let Some(stack) = self.stack_pool.get_mut(&stack_size).and_then(Vec::pop) {
+            return stack;
+        }
+        self.stack_allocs += 1;
+        Stack::new(stack_size)
+    }
+
+    // 自身のコンテキストを実行キューの最後に移動し、
+    // レジスタの保存先と次に実行するスレッドのレジスタを返す
+    // 実行可能なスレッドが自身のみの場合は None
+    fn schedule(&mut self) -> Option<(*mut Registers, *const Registers)> {
+        // タイムアウト期限を過ぎたスレッドや、他の OS スレッドから起こされたスレッドを実行キューに戻す
+        self.wake_expired();
+        self.drain_inbox();
+
+        if self.contexts.len() == 1 {
+            return None;
+        }
+
+        let mut ctx = self.contexts.pop_front().unwrap();
+        let id = ctx.id;
+        let regs = ctx.get_regs_mut();
+        self.contexts.push_back(ctx);
+        self.pick_next();
+
+        // 自身が最も優先度が高い場合はそのまま実行を続ける
+        if self.current() == id {
+            return None;
+        }
+        self.stats.context_switches += 1;
+        Some((regs, self.contexts.front().unwrap().get_regs()))
+    }
+
+    // 実効優先度 (優先度 + 選ばれなかった回数) が最も高いスレッドを実行キューの先頭に移動
+    // 選ばれなかったスレッドは実効優先度が上がっていくため、優先度の低いスレッドも飢餓状態にならない
+    // 実効優先度が同じ場合は実行キューの前にあるスレッドを選ぶため、優先度がすべて同じならラウンドロビンとなる
+    fn pick_next(&mut self) {
+        let Some(i) = self
+            .contexts
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, ctx)| (ctx.priority as u64 + ctx.age, Reverse(*i)))
+            .map(|(i, _)| i)
+        else {
             return;
+        };
+
+        let mut rest = self.contexts.split_off(i);
+        let mut next = rest.pop_front().unwrap();
+        self.contexts.append(&mut rest);
+        for ctx in self.contexts.iter_mut() {
+            ctx.age += 1;
         }
+        next.age = 0;
+        self.contexts.push_front(next);
+    }
 
-        // 自身のコンテキストを実行キューの最後に移動
-        let mut ctx = CONTEXTS.pop_front().unwrap(); // <2>
-                                                     // レジスタ保存領域へのポインタを取得 <3>
+    // 実行中のスレッドを待機状態に移行し、
+    // レジスタの保存先と次に実行するスレッドのレジスタを返す
+    fn park(&mut self) -> (*mut Registers, *const Registers) {
+        let id = self.current();
+        let mut ctx = self.contexts.pop_front().unwrap();
         let regs = ctx.get_regs_mut();
-        CONTEXTS.push_back(ctx);
+        self.waiting.insert(id, ctx);
 
-        // レジスタを保存 <4>
-        if set_context(regs) == 0 {
-            // 次のスレッドにコンテキストスイッチ
-            let next = CONTEXTS.front().unwrap();
-            switch_context((**next).get_regs());
+        // 待機状態に移行する前に他の OS スレッドから起こされていれば、ここで実行キューに戻す
+        self.drain_inbox();
+
+        // 実行可能なスレッドがいなければ、タイムアウト期限か他の OS スレッドから起こされるまで待機
+        // 自身が起こされた場合は自身へのコンテキストスイッチとなる
+        if self.contexts.is_empty() {
+            self.sleep_until_woken();
         }
 
-        // 不要なスタック領域を削除
-        rm_unused_stack(); // <5>
+        // 実行可能なスレッドが他におらず、タイムアウトで起きるスレッドもいない場合はデッドロック
+        // 待機中のスレッドが起こされることはないため、main関数のスレッドに戻る
+        if self.contexts.is_empty() {
+            return (regs, self.return_to_main());
+        }
+        self.pick_next();
+        self.stats.context_switches += 1;
+        (regs, self.contexts.front().unwrap().get_regs())
     }
-}
 
-#[no_mangle]
-pub extern "C" fn entry_point() {
-    unsafe {
-        // 指定されたエントリ関数を実行 <1>
-        let ctx = CONTEXTS.front().unwrap();
-        (ctx.entry)();
+    // タイムアウト期限を過ぎた待機中のスレッドを、期限の早い順に実行キューに移動
+    fn wake_expired(&mut self) {
+        let now = Instant::now();
+        let mut expired: Vec<(Instant, u64)> = self
+            .deadlines
+            .iter()
+            .filter(|(id, deadline)| **deadline <= now && self.waiting.contains_key(id))
+            .map(|(id, deadline)| (*deadline, *id))
+            .collect();
+        expired.sort();
+
+        for (_, id) in expired {
+            let ctx = self.waiting.remove(&id).unwrap();
+            self.contexts.push_back(ctx);
+        }
+    }
+
+    // 他の OS スレッドから起こされたスレッドを実行キューに移動
+    fn drain_inbox(&mut self) {
+        let ids = std::mem::take(&mut *self.inbox.ready.lock().unwrap());
+        for id in ids {
+            self.wake(id);
+        }
+    }
 
-        // 以降がスレッド終了時の後処理
+    // 最も早いタイムアウト期限まで OS スレッドをスリープさせ、期限を過ぎたスレッドを起こす
+    // BridgeReceiver::recv で待機中のスレッドがいる場合は、他の OS スレッドから起こされた時点でも戻る
+    // 実行可能なスレッドがいない場合にのみ呼び出す
+    fn sleep_until_woken(&mut self) {
+        let earliest = self
+            .deadlines
+            .iter()
+            .filter(|(id, _)| self.waiting.contains_key(id))
+            .map(|(_, deadline)| *deadline)
+            .min();
+
+        if !self.bridge_waiters.is_empty() {
+            let inbox = self.inbox.clone();
+            let mut ready = inbox.ready.lock().unwrap();
+            while ready.is_empty() {
+                match earliest {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if deadline <= now {
+                            break;
+                        }
+                        ready = inbox.cond.wait_timeout(ready, deadline - now).unwrap().0;
+                    }
+                    None => ready = inbox.cond.wait(ready).unwrap(),
+                }
+            }
+            drop(ready);
+            self.drain_inbox();
+        } else if let Some(deadline) = earliest {
+            let now = Instant::now();
+            if deadline > now {
+                std::thread::sleep(deadline - now);
+            }
+        }
+        self.wake_expired();
+    }
 
+    // 実行中のスレッドの終了処理を行い、次に実行するスレッドのレジスタを返す
+    fn exit(&mut self) -> *const Registers {
         // 自身のコンテキストを取り除く
-        let ctx = CONTEXTS.pop_front().unwrap();
+        let ctx = self.contexts.pop_front().unwrap();
 
         // スレッドIDを削除
-        (*ID).remove(&ctx.id);
+        self.ids.remove(&ctx.id);
+
+        // スタック使用量の最大値を記録
+        // detached なスレッドのIDは呼び出し側に返していないため、記録しない
+        let detached = self.detached.remove(&ctx.id);
+        if self.measure_stack && !detached {
+            self.high_water.insert(ctx.id, ctx.stack.high_water());
+        }
+
+        // join 待ちのスレッドに終了を通知し、実行キューに移動
+        while let Some(joiner) = self.joiners.pop_front(ctx.id) {
+            self.wake(joiner);
+        }
 
         // 不要なスタック領域として保存
         // この段階で解放すると、以降のコードでスタックが使えなくなる
-        // ので、context_switch 後に呼び出す
-        UNUSED_STACK = ((*ctx).stack, (*ctx).stack_layout); // <2>
-
-        match CONTEXTS.front() {
-            // <3>
-            Some(c) => {
-                // 次のスレッドにコンテキストスイッチ
-                switch_context((**c).get_regs());
-            }
-            None => {
-                // すべてのスレッドが終了した場合、main関数のスレッドに戻る
-                if let Some(c) = &CTX_MAIN {
-                    switch_context(&**c as *const Registers);
-                }
-            }
-        };
-    }
-    panic!("entry_point"); // <4>
-}
+        // ので、context_switch 後に解放する
+        // 以前に終了したスレッドのスタックが残っていれば、ここで回収する
+        self.rm_unused_stack();
+        self.unused = Some(ctx);
 
-pub fn spawn_from_main(func: Entry, stack_size: usize) {
-    unsafe {
-        // すでに初期化済みならエラーとする
-        if let Some(_) = &CTX_MAIN {
-            panic!("spawn_from_main is called twice");
+        // 実行可能なスレッドがいなくても、タイムアウトや他の OS スレッドから起きるスレッドがいればそれを待つ
+        self.drain_inbox();
+        if self.contexts.is_empty() {
+            self.sleep_until_woken();
+        }
+
+        self.pick_next();
+        self.stats.context_switches += 1;
+        match self.contexts.front() {
+            // 次のスレッドにコンテキストスイッチ
+            Some(c) => c.get_regs(),
+            // すべてのスレッドが終了した場合、main関数のスレッドに戻る
+            // 待機中のスレッドが残っている場合はデッドロック
+            None => self.return_to_main(),
         }
+    }
 
-        // main関数用のコンテキストを生成
-        CTX_MAIN = Some(Box::new(Registers::new(0)));
-        if let Some(ctx) = &mut CTX_MAIN {
-            // グローバル変数を初期化 <1>
-            let mut msgs = MappedList::new();
-            MESSAGES = &mut msgs as *mut MappedList<u64>;
+    // key のスレッドにメッセージを送信
+    fn send(&mut self, key: u64, msg: Box<dyn Any>) {
+        // メッセージキューの最後尾に追加
+        self.messages.push_back(key, msg);
+        self.stats.messages_sent += 1;
 
-            let mut waiting = HashMap::new();
-            WAITING = &mut waiting as *mut HashMap<u64, Box<Context>>;
+        // スレッドが受信待ちの場合に実行キューに移動
+        self.wake(key);
+    }
 
-            let mut ids = HashSet::new();
-            ID = &mut ids as *mut HashSet<u64>;
+    // 待機中の id のスレッドを実行キューに移動
+    // 待機中でなかった場合は false をリターン
+    fn wake(&mut self, id: u64) -> bool {
+        if let Some(ctx) = self.waiting.remove(&id) {
+            self.contexts.push_back(ctx);
+            true
+        } else {
+            false
+        }
+    }
 
-            // すべてのスレッド終了時の戻り先を保存 <2>
-            if set_context(&mut **ctx as *mut Registers) == 0 {
-                // 最初に起動するスレッドのコンテキストを生成して実行 <3>
-                CONTEXTS.push_back(Box::new(Context::new(func, stack_size, get_id())));
-                let first = CONTEXTS.front().unwrap();
-                switch_context(first.get_regs());
+    // 実行中のスレッド宛のメッセージを取り出す
+    fn recv(&mut self) -> Option<Box<dyn Any>> {
+        let key = self.current();
+        let msg = self.messages.pop_front(key);
+        if msg.is_some() {
+            self.stats.messages_received += 1;
+        }
+        msg
+    }
+
+    // 終了したスレッドのスタック領域を回収し、再利用のためにプールに戻す
+    fn rm_unused_stack(&mut self) {
+        if let Some(ctx) = self.unused.take() {
+            let stack = ctx.stack;
+            let pool = self.stack_pool.entry(stack.size()).or_default();
+            if pool.len() < MAX_POOLED_STACKS {
+                pool.push(stack);
             }
+        }
+    }
+}
+
+pub fn spawn(func: Entry, stack_size: usize) -> u64 {
+    // <1>
+    spawn_boxed(Box::new(func), stack_size, DEFAULT_PRIORITY)
+}
+
+// 返り値を持つ関数・クロージャを実行するスレッドを生成
+// 返り値は join_value で取得する
+pub fn spawn_with<T, F>(func: F, stack_size: usize) -> u64
+where
+    T: 'static,
+    F: FnOnce() -> T + 'static,
+{
+    let entry = move || {
+        let val = func();
+        // 終了時に自身のスレッドIDをキーとして返り値を保存
+        with_runtime(|rt| {
+            let id = rt.current();
+            rt.results.insert(id, Box::new(val));
+        });
+    };
+    spawn_boxed(Box::new(entry), stack_size, DEFAULT_PRIORITY)
+}
 
-            // 不要なスタックを解放 <4>
-            rm_unused_stack();
+// 優先度を指定してスレッドを生成
+// schedule では実行可能なスレッドのうち優先度の高いものから実行される
+// 待機が長いスレッドほど優先されるようになるため、優先度の低いスレッドも一定回数ごとに実行される
+pub fn spawn_with_priority(func: Entry, stack_size: usize, priority: u32) -> u64 {
+    spawn_boxed(Box::new(func), stack_size, priority)
+}
 
-            // グローバル変数をクリア
-            CTX_MAIN = None;
-            CONTEXTS.clear();
-            MESSAGES = ptr::null_mut();
-            WAITING = ptr::null_mut();
-            ID = ptr::null_mut();
+// join しないスレッドを生成
+// 終了時に panic 内容などのスレッドごとの状態を保持せず、即座に破棄する
+pub fn spawn_detached(func: Entry, stack_size: usize) {
+    // 生成直後に実行されて終了する場合もあるため、schedule の前に登録する
+    with_runtime(|rt| {
+        let id = rt.spawn(Box::new(func), stack_size, DEFAULT_PRIORITY);
+        rt.detached.insert(id);
+    });
+    schedule();
+}
 
-            msgs.clear(); // <5>
-            waiting.clear();
-            ids.clear();
-        }
+fn spawn_boxed(func: BoxedEntry, stack_size: usize, priority: u32) -> u64 {
+    let id = with_runtime(|rt| rt.spawn(func, stack_size, priority)); // <2>
+    schedule(); // <4>
+    id // <5>
+}
+
+pub fn schedule() {
+    // 実行を譲るため、それまでのプリエンプションの要求は不要となる
+    PREEMPT_REQUESTED.with(|f| f.store(false, Ordering::Relaxed));
+
+    // 実行可能なプロセスが自身のみであるため即座にリターン <1>
+    if let Some((regs, next)) = with_runtime(|rt| rt.schedule()) {
+        unsafe { switch_to(regs, next) };
     }
 }
 
-unsafe fn rm_unused_stack() {
-    if UNUSED_STACK.0 != ptr::null_mut() {
-        // スタック領域の保護を解除 <1>
-        mprotect(
-            UNUSED_STACK.0 as *mut c_void,
-            PAGE_SIZE,
-            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-        )
-        .unwrap();
-        // スタック領域解放 <2>
-        dealloc(UNUSED_STACK.0, UNUSED_STACK.1);
-        UNUSED_STACK = (ptr::null_mut(), Layout::new::<u8>());
+// プリエンプションが要求されていれば、他のスレッドに実行を譲る
+// シグナルハンドラ内ではコンテキストスイッチせず、フラグを立てるのみのため、
+// ランタイムの関数を呼び出さない計算中心のループでは、安全な箇所でこの関数を呼び出す
+pub fn preempt_check() {
+    if PREEMPT_REQUESTED.with(|f| f.load(Ordering::Relaxed)) {
+        schedule();
     }
 }
 
-pub fn send(key: u64, msg: u64) {
-    // <1>
-    unsafe {
-        // メッセージキューの最後尾に追加
-        (*MESSAGES).push_back(key, msg);
+thread_local! {
+    // タイマーによってプリエンプションが要求されると true
+    // 同じプロセス内の他の OS スレッドで動作するランタイムには影響しないよう、OS スレッドごとに持つ
+    static PREEMPT_REQUESTED: AtomicBool = const { AtomicBool::new(false) };
+}
 
-        // スレッドが受信待ちの場合に実行キューに移動
-        if let Some(ctx) = (*WAITING).remove(&key) {
-            CONTEXTS.push_back(ctx);
+// SIGALRM のハンドラ
+// シグナルハンドラ内で安全に行えるのはアトミック変数の操作程度のため、フラグを立てるのみとする
+// タイマーはランタイムを実行する OS スレッドを宛先とするため、そのスレッドのフラグが立つ
+extern "C" fn on_alarm(_: libc::c_int) {
+    // setitimer のシグナルはプロセス内の任意のスレッドに届くため、ランタイムのスレッドに転送する
+    #[cfg(not(target_os = "linux"))]
+    {
+        let target = PREEMPT_TARGET.load(Ordering::Relaxed);
+        if target != 0 && unsafe { libc::pthread_self() } as usize != target {
+            unsafe { libc::pthread_kill(target as libc::pthread_t, libc::SIGALRM) };
+            return;
         }
     }
-    schedule(); // <2>
+    let _ = PREEMPT_REQUESTED.try_with(|f| f.store(true, Ordering::Relaxed));
+}
+
+// プリエンプション用のタイマーを動作させている OS スレッド。0 なら動作していない
+#[cfg(not(target_os = "linux"))]
+static PREEMPT_TARGET: AtomicUsize = AtomicUsize::new(0);
+
+// プリエンプション用のタイマー
+// 生成時に SIGALRM のハンドラを設定してタイマーを開始し、破棄時に停止してハンドラを元に戻す
+// 生成した OS スレッドのみがプリエンプションを要求されるため、テストなどで並行して動作する他のスレッドには影響しない
+struct PreemptTimer {
+    #[cfg(target_os = "linux")]
+    timer: libc::timer_t,
+    old_action: SigAction,
 }
 
-pub fn recv() -> Option<u64> {
-    unsafe {
-        // スレッドIDを取得
-        let key = CONTEXTS.front().unwrap().id;
+impl PreemptTimer {
+    fn start(interval: Duration) -> Self {
+        // SA_RESTART により、シグナルで中断されたシステムコールは再開される
+        let action = SigAction::new(
+            SigHandler::Handler(on_alarm),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        let old_action = unsafe { sigaction(Signal::SIGALRM, &action).unwrap() };
 
-        // メッセージがすでにキューにある場合即座にリターン
-        if let Some(msg) = (*MESSAGES).pop_front(key) {
-            return Some(msg);
+        #[cfg(target_os = "linux")]
+        let timer = start_thread_timer(interval);
+        #[cfg(not(target_os = "linux"))]
+        {
+            PREEMPT_TARGET.store(unsafe { libc::pthread_self() } as usize, Ordering::Relaxed);
+            set_timer(interval);
         }
 
-        // 実行可能なスレッドが他にいない場合はデッドロック
-        if CONTEXTS.len() == 1 {
-            panic!("deadlock");
+        PreemptTimer {
+            #[cfg(target_os = "linux")]
+            timer,
+            old_action,
         }
+    }
+}
 
-        // 実行中のスレッドを受信待ち状態に移行
-        let mut ctx = CONTEXTS.pop_front().unwrap();
-        let regs = ctx.get_regs_mut();
-        (*WAITING).insert(key, ctx);
+impl Drop for PreemptTimer {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::timer_delete(self.timer);
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            set_timer(Duration::ZERO);
+            PREEMPT_TARGET.store(0, Ordering::Relaxed);
+        }
+        unsafe { sigaction(Signal::SIGALRM, &self.old_action).unwrap() };
+        PREEMPT_REQUESTED.with(|f| f.store(false, Ordering::Relaxed));
+    }
+}
+
+// interval ごとに、呼び出したスレッドに SIGALRM を送信するタイマーを生成
+// setitimer のタイマーはプロセス全体に送信されるため、Linux では timer_create でスレッドを宛先とする
+#[cfg(target_os = "linux")]
+fn start_thread_timer(interval: Duration) -> libc::timer_t {
+    let mut sev: libc::sigevent = unsafe { std::mem::zeroed() };
+    sev.sigev_notify = libc::SIGEV_THREAD_ID;
+    sev.sigev_signo = libc::SIGALRM;
+    sev.sigev_notify_thread_id = unsafe { libc::gettid() };
+    let mut timer: libc::timer_t = std::ptr::null_mut();
+    let ret = unsafe { libc::timer_create(libc::CLOCK_MONOTONIC, &mut sev, &mut timer) };
+    assert_eq!(ret, 0, "timer_create failed");
+
+    let ts = libc::timespec {
+        tv_sec: interval.as_secs() as libc::time_t,
+        tv_nsec: interval.subsec_nanos() as libc::c_long,
+    };
+    let spec = libc::itimerspec {
+        it_interval: ts,
+        it_value: ts,
+    };
+    let ret = unsafe { libc::timer_settime(timer, 0, &spec, std::ptr::null_mut()) };
+    assert_eq!(ret, 0, "timer_settime failed");
+    timer
+}
+
+// libc クレートでは定義されていない環境があるため宣言する
+#[cfg(not(target_os = "linux"))]
+extern "C" {
+    fn setitimer(
+        which: libc::c_int,
+        new_value: *const libc::itimerval,
+        old_value: *mut libc::itimerval,
+    ) -> libc::c_int;
+}
+
+// interval ごとに SIGALRM を送信するタイマーを設定。0 の場合は停止
+#[cfg(not(target_os = "linux"))]
+fn set_timer(interval: Duration) {
+    let tv = libc::timeval {
+        tv_sec: interval.as_secs() as libc::time_t,
+        tv_usec: interval.subsec_micros() as libc::suseconds_t,
+    };
+    let timer = libc::itimerval {
+        it_interval: tv,
+        it_value: tv,
+    };
+    let ret = unsafe { setitimer(libc::ITIMER_REAL, &timer, std::ptr::null_mut()) };
+    assert_eq!(ret, 0, "setitimer failed");
+}
+
+// 実行中のスレッドのIDを取得
+// 実行中のグリーンスレッドの中からのみ呼び出せる。ランタイムの外から呼び出した場合は panic
+pub fn current_id() -> u64 {
+    with_runtime(|rt| rt.current())
+}
+
+// 終了した id のスレッドのスタック使用量の最大値 (バイト数) を取得
+// set_measure_stack で計測を有効にしていない場合や、スレッドが終了していない場合は None
+// ガードページに達する前に、スタックサイズが十分かを確認するために使用する
+pub fn stack_high_water(id: u64) -> Option<usize> {
+    with_runtime(|rt| rt.high_water.get(&id).copied())
+}
+
+// 実行中のランタイムのスケジューリングの統計情報を取得
+pub fn stats() -> Stats {
+    with_runtime(|rt| rt.stats())
+}
+
+// 実行中のスレッドを中断し、他の実行可能なスレッドに実行を譲る
+// 実行可能なスレッドが自身のみの場合は何もせずにリターンする
+pub fn yield_now() {
+    schedule();
+}
+
+// レジスタを regs に保存し、next のスレッドにコンテキストスイッチ
+// 再びこのスレッドにスイッチされてくるとリターンする
+unsafe fn switch_to(regs: *mut Registers, next: *const Registers) {
+    // レジスタを保存 <4>
+    if set_context(regs) == 0 {
+        // 次のスレッドにコンテキストスイッチ
+        switch_context(next);
+    }
+
+    // 不要なスタック領域を削除 <5>
+    with_runtime(|rt| rt.rm_unused_stack());
+}
+
+#[no_mangle]
+pub extern "C" fn entry_point() {
+    // 指定されたエントリ関数を実行 <1>
+    let entry = with_runtime(|rt| rt.contexts.front_mut().unwrap().entry.take().unwrap());
+
+    // panic がスレッドの外に伝搬するとランタイムが壊れるため、ここで捕捉して通常の終了と同様に扱う
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(entry)) {
+        // detached なスレッドは join されないため、panic 内容はランタイムの借用外で破棄する
+        let payload = with_runtime(|rt| {
+            let id = rt.current();
+            if rt.detached.contains(&id) {
+                Some(payload)
+            } else {
+                rt.panics.insert(id, payload);
+                None
+            }
+        });
+        drop(payload);
+    }
+
+    // 以降がスレッド終了時の後処理
+    // GreenLocal の値の Drop がランタイムを操作する場合もあるため、ランタイムの借用外で破棄する
+    let locals = with_runtime(|rt| {
+        let id = rt.current();
+        rt.locals.remove(&id)
+    });
+    drop(locals);
+
+    let next = with_runtime(|rt| rt.exit());
+    unsafe { switch_context(next) }; // <3>
+}
+
+pub fn spawn_from_main(func: Entry, stack_size: usize) {
+    GreenRuntime::new().run(func, stack_size);
+}
+
+pub fn send<T: 'static>(key: u64, msg: T) {
+    // <1>
+    with_runtime(|rt| rt.send(key, Box::new(msg)));
+    schedule(); // <2>
+}
 
-        // 次の実行可能なスレッドにコンテキストスイッチ
-        if set_context(regs) == 0 {
-            let next = CONTEXTS.front().unwrap();
-            switch_context((**next).get_regs());
+// 実行中のスレッド以外の、現在存在するすべてのスレッドに msg を送信
+// 各スレッドのメッセージキューの最後尾に追加されるため、それ以前に送信されたメッセージの後に受信される
+// 受信待ちのスレッドはスレッドIDの昇順に実行キューに移動する
+// この後に生成されたスレッドには届かない
+pub fn broadcast<T: Clone + 'static>(msg: T) {
+    with_runtime(|rt| {
+        let me = rt.current();
+        let mut ids: Vec<u64> = rt.ids.iter().copied().filter(|id| *id != me).collect();
+        ids.sort();
+        for id in ids {
+            rt.send(id, Box::new(msg.clone()));
         }
+    });
+    schedule();
+}
+
+// メッセージを受信
+// 受信したメッセージの型が T でない場合は panic
+pub fn recv<T: 'static>() -> Option<T> {
+    // メッセージがすでにキューにある場合即座にリターン
+    if let Some(msg) = with_runtime(|rt| rt.recv()) {
+        return Some(downcast_msg(msg));
+    }
+
+    // 実行中のスレッドを受信待ち状態に移行
+    park();
+
+    // 受信したメッセージを取得
+    with_runtime(|rt| rt.recv()).map(downcast_msg)
+}
+
+// メッセージを受信
+// dur が経過してもメッセージが届かない場合は None をリターン
+// 他に実行可能なスレッドがない間は OS スレッドごとスリープして期限を待つ
+pub fn recv_timeout<T: 'static>(dur: Duration) -> Option<T> {
+    // メッセージがすでにキューにある場合即座にリターン
+    if let Some(msg) = with_runtime(|rt| rt.recv()) {
+        return Some(downcast_msg(msg));
+    }
+
+    // タイムアウト期限を記録して受信待ち状態に移行
+    let deadline = Instant::now() + dur;
+    with_runtime(|rt| {
+        let id = rt.current();
+        rt.deadlines.insert(id, deadline);
+    });
+    park();
+
+    // 期限を削除し、受信したメッセージを取得。タイムアウトした場合は None
+    with_runtime(|rt| {
+        let id = rt.current();
+        rt.deadlines.remove(&id);
+        rt.recv()
+    })
+    .map(downcast_msg)
+}
+
+// 実行中のスレッドを dur の間待機させる
+// 待機中は他のスレッドが実行され、期限を過ぎると schedule などの際に実行キューに戻される
+// 他に実行可能なスレッドがない間は OS スレッドごとスリープして期限を待つ
+pub fn sleep(dur: Duration) {
+    let deadline = Instant::now() + dur;
+
+    // send などで起こされる場合もあるため、期限を過ぎるまで待機を繰り返す
+    while Instant::now() < deadline {
+        with_runtime(|rt| {
+            let id = rt.current();
+            rt.deadlines.insert(id, deadline);
+        });
+        park();
+    }
+
+    with_runtime(|rt| {
+        let id = rt.current();
+        rt.deadlines.remove(&id);
+    });
+}
+
+// メッセージをノンブロッキングに受信
+// キューが空の場合は待機せずに即座に None をリターン
+pub fn try_recv<T: 'static>() -> Option<T> {
+    let msg = with_runtime(|rt| rt.recv()).map(downcast_msg);
+    // 受信を繰り返して待つループがプリエンプションされるよう、安全な切り替え箇所とする
+    preempt_check();
+    msg
+}
+
+fn downcast_msg<T: 'static>(msg: Box<dyn Any>) -> T {
+    *msg.downcast::<T>()
+        .unwrap_or_else(|_| panic!("recv: unexpected message type"))
+}
+
+// 実行中のスレッドを待機状態に移行し、次の実行可能なスレッドにコンテキストスイッチ
+// send や join 対象スレッドの終了により実行キューに戻されるとリターンする
+fn park() {
+    let (regs, next) = with_runtime(|rt| rt.park());
+    unsafe { switch_to(regs, next) };
+}
+
+// id のスレッドが終了するまで待機
+// 待機中は他のスレッドが実行される
+pub fn join(id: u64) {
+    let key = current_id();
+    assert_ne!(key, id, "join: cannot join the current thread");
+
+    // send によって起こされる場合もあるため、終了するまで待機を繰り返す
+    while with_runtime(|rt| {
+        let running = rt.ids.contains(&id);
+        if running {
+            rt.joiners.push_back(id, key);
+        }
+        running
+    }) {
+        park();
+    }
+}
+
+// join した id のスレッドが panic していた場合、その panic 内容を取り出す
+pub fn take_panic(id: u64) -> Option<Box<dyn Any + Send>> {
+    with_runtime(|rt| rt.panics.remove(&id))
+}
+
+// spawn_with で生成した id のスレッドの終了を待機し、その返り値を取得
+// 返り値の型が T でない場合や、既に取得済みの場合は panic
+// 対象のスレッドが panic していた場合は、その panic を呼び出し元のスレッドで再開する
+pub fn join_value<T: 'static>(id: u64) -> T {
+    join(id);
+    if let Some(payload) = take_panic(id) {
+        panic::resume_unwind(payload);
+    }
+    let val =
+        with_runtime(|rt| rt.results.remove(&id)).expect("join_value: no result for the thread");
+    *val.downcast::<T>()
+        .unwrap_or_else(|_| panic!("join_value: type mismatch"))
+}
+
+// グリーンスレッド用のミューテックス
+// ロックが獲得されている間は、OS のロックやスピンではなくスレッドを待機状態にして他のスレッドに実行を譲る
+// 同一ランタイム上のグリーンスレッド間でのみ共有できる
+pub struct GreenMutex<T> {
+    locked: Cell<bool>,              // ロック獲得中なら true
+    waiters: RefCell<VecDeque<u64>>, // ロック待ちのスレッドID
+    data: UnsafeCell<T>,             // 保護対象データ
+}
+
+pub struct GreenMutexGuard<'a, T> {
+    mutex: &'a GreenMutex<T>,
+}
+
+impl<T> GreenMutex<T> {
+    pub fn new(v: T) -> Self {
+        GreenMutex {
+            locked: Cell::new(false),
+            waiters: RefCell::new(VecDeque::new()),
+            data: UnsafeCell::new(v),
+        }
+    }
+
+    pub fn lock(&self) -> GreenMutexGuard<'_, T> {
+        // ロックが解放されるまで待機
+        // 起こされた後に他のスレッドが先にロックを獲得している場合もあるため、繰り返し確認する
+        while self.locked.get() {
+            let id = current_id();
+            self.waiters.borrow_mut().push_back(id);
+            park();
+            // unlock 以外で起こされた場合は待機スレッドから取り除く
+            // 残したまま再度待機すると、古い ID が別の待機中のスレッドを起こしてしまう
+            self.waiters.borrow_mut().retain(|w| *w != id);
+        }
+
+        self.locked.set(true);
+        GreenMutexGuard { mutex: self }
+    }
+}
+
+impl<'a, T> Drop for GreenMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.set(false);
+
+        // 待機中のスレッドを1つ起こす
+        // send などで既に起こされているスレッドは飛ばす
+        let mut waiters = self.mutex.waiters.borrow_mut();
+        while let Some(id) = waiters.pop_front() {
+            if with_runtime(|rt| rt.wake(id)) {
+                break;
+            }
+        }
+        drop(waiters);
+
+        // ロックを解放した後は、プリエンプションの安全な切り替え箇所となる
+        preempt_check();
+    }
+}
+
+impl<'a, T> Deref for GreenMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for GreenMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+// グリーンスレッド用の条件変数
+// GreenMutex と組み合わせて使用し、待機中は OS スレッドをブロックせずにスレッドを待機状態にして他のスレッドに実行を譲る
+pub struct GreenCondvar {
+    waiters: RefCell<VecDeque<u64>>, // 待機中のスレッドID
+}
+
+impl Default for GreenCondvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GreenCondvar {
+    pub fn new() -> Self {
+        GreenCondvar {
+            waiters: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    // ロックを解放して待機し、起こされるとロックを再度獲得してリターン
+    // send などで notify 以外から起こされる場合もあるため、呼び出し元で条件を再確認する
+    pub fn wait<'a, T>(&self, guard: GreenMutexGuard<'a, T>) -> GreenMutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let id = current_id();
+        self.waiters.borrow_mut().push_back(id);
+
+        // ロックを解放してから待機
+        // ロック待ちのスレッドは実行キューに移動するだけなので、ここではコンテキストスイッチしない
+        drop(guard);
+        park();
+
+        // notify 以外で起こされた場合は待機スレッドから取り除く
+        self.waiters.borrow_mut().retain(|w| *w != id);
+        mutex.lock()
+    }
+
+    // 待機中のスレッドを1つ実行キューに移動
+    pub fn notify_one(&self) {
+        let mut waiters = self.waiters.borrow_mut();
+        while let Some(id) = waiters.pop_front() {
+            if with_runtime(|rt| rt.wake(id)) {
+                break;
+            }
+        }
+    }
+
+    // 待機中のスレッドをすべて実行キューに移動
+    pub fn notify_all(&self) {
+        let mut waiters = self.waiters.borrow_mut();
+        for id in waiters.drain(..) {
+            with_runtime(|rt| rt.wake(id));
+        }
+    }
+}
+
+// グリーンスレッド用のカウンティングセマフォ
+// 許可がない間は OS スレッドをブロックせずにスレッドを待機状態にして他のスレッドに実行を譲る
+pub struct GreenSemaphore {
+    permits: Cell<usize>,            // 残りの許可数
+    waiters: RefCell<VecDeque<u64>>, // 許可待ちのスレッドID
+}
+
+impl GreenSemaphore {
+    pub fn new(permits: usize) -> Self {
+        GreenSemaphore {
+            permits: Cell::new(permits),
+            waiters: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    // 許可を1つ獲得
+    // 許可がなければ release されるまで待機する
+    pub fn acquire(&self) {
+        // 起こされた後に他のスレッドが先に獲得している場合もあるため、繰り返し確認する
+        while self.permits.get() == 0 {
+            let id = current_id();
+            self.waiters.borrow_mut().push_back(id);
+            park();
+            // release 以外で起こされた場合は待機スレッドから取り除く
+            self.waiters.borrow_mut().retain(|w| *w != id);
+        }
+        self.permits.set(self.permits.get() - 1);
+    }
+
+    // 許可を1つ返却し、待機中のスレッドを1つ実行キューに移動
+    pub fn release(&self) {
+        self.permits.set(self.permits.get() + 1);
+        let mut waiters = self.waiters.borrow_mut();
+        while let Some(id) = waiters.pop_front() {
+            if with_runtime(|rt| rt.wake(id)) {
+                break;
+            }
+        }
+        drop(waiters);
+        preempt_check();
+    }
+
+    // 残りの許可数
+    pub fn available_permits(&self) -> usize {
+        self.permits.get()
+    }
+}
+
+// グリーンスレッドごとに独立した値を保持する変数
+// OS スレッドのスレッドローカル変数は同じ OS スレッド上のグリーンスレッド間で共有されてしまうため、
+// ランタイム内にスレッドIDごとの値を保持する。値はスレッドの終了時に破棄される
+pub struct GreenLocal<T> {
+    key: usize, // ランタイム内で値を識別するキー
+    _marker: PhantomData<T>,
+}
+
+// GreenLocal のキーの発行に使用
+static NEXT_LOCAL_KEY: AtomicUsize = AtomicUsize::new(0);
+
+impl<T: 'static> Default for GreenLocal<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static> GreenLocal<T> {
+    pub fn new() -> Self {
+        GreenLocal {
+            key: NEXT_LOCAL_KEY.fetch_add(1, Ordering::Relaxed),
+            _marker: PhantomData,
+        }
+    }
+
+    // 実行中のスレッドの値を設定し、以前の値をリターン
+    pub fn set(&self, v: T) -> Option<T> {
+        with_runtime(|rt| {
+            let id = rt.current();
+            rt.locals
+                .entry(id)
+                .or_default()
+                .insert(self.key, Box::new(v))
+        })
+        .map(downcast_local)
+    }
+
+    // 実行中のスレッドの値の複製を取得。未設定の場合は None
+    pub fn get(&self) -> Option<T>
+    where
+        T: Clone,
+    {
+        with_runtime(|rt| {
+            let id = rt.current();
+            rt.locals
+                .get(&id)
+                .and_then(|m| m.get(&self.key))
+                .map(|v| v.downcast_ref::<T>().unwrap().clone())
+        })
+    }
+
+    // 実行中のスレッドの値を取り出す
+    pub fn take(&self) -> Option<T> {
+        with_runtime(|rt| {
+            let id = rt.current();
+            rt.locals.get_mut(&id).and_then(|m| m.remove(&self.key))
+        })
+        .map(downcast_local)
+    }
+}
+
+fn downcast_local<T: 'static>(v: Box<dyn Any>) -> T {
+    *v.downcast::<T>().unwrap()
+}
+
+// 他の OS スレッドから、ランタイム内の待機中のスレッドを起こすための受け口
+// ランタイムは実行中の OS スレッドからしか操作できないため、起こすスレッドのIDをここに追加し、
+// ランタイム側が schedule などの際に取り出して実行キューに移動する
+#[derive(Default)]
+struct Inbox {
+    ready: Mutex<Vec<u64>>, // 起こされたスレッドのID
+    cond: Condvar,          // 実行可能なスレッドがいない間、ランタイムはここで待機する
+}
+
+struct BridgeState<T> {
+    buf: VecDeque<T>,
+    senders: usize,                    // 生存している BridgeSender の数
+    waiter: Option<(u64, Arc<Inbox>)>, // recv で待機中のグリーンスレッドと、そのランタイムの Inbox
+}
+
+struct BridgeShared<T> {
+    state: Mutex<BridgeState<T>>,
+    cond: Condvar, // recv で待機中の OS スレッド用
+}
+
+impl<T> BridgeState<T> {
+    // recv で待機中のスレッドを起こす
+    fn notify(&mut self, cond: &Condvar) {
+        cond.notify_one();
+        if let Some((id, inbox)) = self.waiter.take() {
+            inbox.ready.lock().unwrap().push(id);
+            inbox.cond.notify_one();
+        }
+    }
+}
+
+// グリーンスレッドと OS スレッドの間で使用するチャネル
+// send と recv は、グリーンスレッドと OS スレッドのどちらからでも呼び出せる
+// グリーンスレッドの recv は OS スレッドをブロックせずにスレッドを待機状態にして他のスレッドに実行を譲る
+pub struct BridgeSender<T> {
+    shared: Arc<BridgeShared<T>>,
+}
+
+pub struct BridgeReceiver<T> {
+    shared: Arc<BridgeShared<T>>,
+}
+
+// グリーンスレッドと OS スレッドの間で使用する、上限のないチャネルを作成
+pub fn bridge_channel<T: Send>() -> (BridgeSender<T>, BridgeReceiver<T>) {
+    let shared = Arc::new(BridgeShared {
+        state: Mutex::new(BridgeState {
+            buf: VecDeque::new(),
+            senders: 1,
+            waiter: None,
+        }),
+        cond: Condvar::new(),
+    });
+    let tx = BridgeSender {
+        shared: shared.clone(),
+    };
+    (tx, BridgeReceiver { shared })
+}
+
+impl<T: Send> BridgeSender<T> {
+    // 送信はブロックしない
+    pub fn send(&self, data: T) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.buf.push_back(data);
+        state.notify(&self.shared.cond);
+    }
+}
+
+impl<T> Clone for BridgeSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        BridgeSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for BridgeSender<T> {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.shared.state.lock() else {
+            return;
+        };
+        state.senders -= 1;
+        if state.senders == 0 {
+            // 待機中の recv を起こし、None をリターンさせる
+            state.notify(&self.shared.cond);
+        }
+    }
+}
+
+impl<T: Send> BridgeReceiver<T> {
+    // データを受信
+    // データがなく、BridgeSender がすべて破棄されている場合は None をリターン
+    // グリーンスレッドから呼び出した場合はスレッドを待機状態にし、OS スレッドから呼び出した場合はブロックする
+    pub fn recv(&self) -> Option<T> {
+        if RUNTIME.with(|rt| rt.borrow().is_some()) {
+            self.recv_green()
+        } else {
+            self.recv_blocking()
+        }
+    }
+
+    fn recv_blocking(&self) -> Option<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(data) = state.buf.pop_front() {
+                return Some(data);
+            }
+            if state.senders == 0 {
+                return None;
+            }
+            state = self.shared.cond.wait(state).unwrap();
+        }
+    }
+
+    fn recv_green(&self) -> Option<T> {
+        loop {
+            let id = {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(data) = state.buf.pop_front() {
+                    return Some(data);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+
+                // send 時に起こされるように登録してから待機
+                // park までの間に send された場合も、park 時に Inbox から取り出されて実行キューに戻る
+                let (id, inbox) = with_runtime(|rt| {
+                    let id = rt.current();
+                    rt.bridge_waiters.insert(id);
+                    (id, rt.inbox.clone())
+                });
+                state.waiter = Some((id, inbox));
+                id
+            };
+            park();
+
+            // send 以外で起こされた場合は登録を取り消す
+            // 取り消す前に send されていた場合は、後で別の理由で待機した際に起こされないよう Inbox からも取り除く
+            self.shared.state.lock().unwrap().waiter = None;
+            with_runtime(|rt| {
+                rt.bridge_waiters.remove(&id);
+                rt.inbox.ready.lock().unwrap().retain(|w| *w != id);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+
+    const STACK_SIZE: usize = 2 * 1024 * 1024;
+
+    static JOIN_VALUE: AtomicU64 = AtomicU64::new(0);
+    static JOIN_OBSERVED: AtomicU64 = AtomicU64::new(0);
+
+    fn join_worker() {
+        // join する側に一度実行を譲ってから値を書き込む
+        schedule();
+        JOIN_VALUE.store(42, Ordering::SeqCst);
+    }
+
+    fn join_main() {
+        let id = spawn(join_worker, STACK_SIZE);
+        join(id);
+        JOIN_OBSERVED.store(JOIN_VALUE.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_join() {
+        spawn_from_main(join_main, STACK_SIZE);
+        assert_eq!(JOIN_OBSERVED.load(Ordering::SeqCst), 42);
+    }
+
+    static SUM: AtomicU64 = AtomicU64::new(0);
+
+    fn join_value_main() {
+        let n: u64 = 100;
+        let id = spawn_with(
+            move || {
+                let mut sum = 0;
+                for i in 1..=n {
+                    sum += i;
+                    schedule();
+                }
+                sum
+            },
+            STACK_SIZE,
+        );
+        SUM.store(join_value::<u64>(id), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_join_value() {
+        spawn_from_main(join_value_main, STACK_SIZE);
+        assert_eq!(SUM.load(Ordering::SeqCst), 5050);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: i32,
+        y: i32,
+        label: String,
+    }
+
+    static RECEIVED: Mutex<Vec<Point>> = Mutex::new(Vec::new());
+
+    fn point_consumer() {
+        for _ in 0..2 {
+            let p = recv::<Point>().unwrap();
+            RECEIVED.lock().unwrap().push(p);
+        }
+    }
+
+    fn point_producer() {
+        let id = spawn(point_consumer, STACK_SIZE);
+        for i in 0..2 {
+            let label = format!("p{}", i);
+            send(id, Point { x: i, y: -i, label });
+        }
+        join(id);
+    }
+
+    #[test]
+    fn test_typed_message() {
+        spawn_from_main(point_producer, STACK_SIZE);
+        let received = RECEIVED.lock().unwrap();
+        assert_eq!(
+            *received,
+            vec![
+                Point {
+                    x: 0,
+                    y: 0,
+                    label: "p0".to_string()
+                },
+                Point {
+                    x: 1,
+                    y: -1,
+                    label: "p1".to_string()
+                },
+            ]
+        );
+    }
+
+    static TRY_RECV_BEFORE: Mutex<Option<Option<u64>>> = Mutex::new(None);
+    static TRY_RECV_AFTER: Mutex<Option<Option<u64>>> = Mutex::new(None);
+
+    fn try_recv_worker() {
+        // まだ何も送信されていない
+        *TRY_RECV_BEFORE.lock().unwrap() = Some(try_recv::<u64>());
+
+        // 受信できるまでポーリングしながら他のスレッドに実行を譲る
+        loop {
+            if let Some(msg) = try_recv::<u64>() {
+                *TRY_RECV_AFTER.lock().unwrap() = Some(Some(msg));
+                break;
+            }
+            schedule();
+        }
+    }
+
+    fn try_recv_main() {
+        let id = spawn(try_recv_worker, STACK_SIZE);
+        send(id, 7u64);
+        join(id);
+    }
+
+    #[test]
+    fn test_try_recv() {
+        spawn_from_main(try_recv_main, STACK_SIZE);
+        assert_eq!(*TRY_RECV_BEFORE.lock().unwrap(), Some(None));
+        assert_eq!(*TRY_RECV_AFTER.lock().unwrap(), Some(Some(7)));
+    }
+
+    static TRACE: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    fn yield_worker() {
+        for _ in 0..3 {
+            TRACE.lock().unwrap().push("worker");
+            yield_now();
+        }
+    }
+
+    fn yield_main() {
+        let id = spawn(yield_worker, STACK_SIZE);
+        for _ in 0..3 {
+            TRACE.lock().unwrap().push("main");
+            yield_now();
+        }
+        join(id);
+
+        // 実行可能なスレッドが自身のみの場合は即座にリターン
+        yield_now();
+        TRACE.lock().unwrap().push("alone");
+    }
+
+    #[test]
+    fn test_yield_now() {
+        spawn_from_main(yield_main, STACK_SIZE);
+        assert_eq!(
+            *TRACE.lock().unwrap(),
+            vec!["worker", "main", "worker", "main", "worker", "main", "alone"]
+        );
+    }
+
+    static TEARDOWN_COUNT: AtomicU64 = AtomicU64::new(0);
+
+    fn teardown_worker() {
+        for _ in 0..3 {
+            TEARDOWN_COUNT.fetch_add(1, Ordering::SeqCst);
+            yield_now();
+        }
+    }
+
+    fn teardown_main() {
+        let ids: Vec<u64> = (0..3).map(|_| spawn(teardown_worker, STACK_SIZE)).collect();
+        for id in ids {
+            join(id);
+        }
+    }
+
+    fn never_received() {
+        recv::<u64>();
+    }
+
+    fn leave_waiting_main() {
+        spawn(never_received, STACK_SIZE);
+    }
+
+    #[test]
+    fn test_runtime() {
+        let mut rt = GreenRuntime::new();
+        rt.run(teardown_main, STACK_SIZE);
+        assert_eq!(TEARDOWN_COUNT.load(Ordering::SeqCst), 9);
+
+        // すべてのスレッドが終了し、ランタイムが空になっている
+        assert!(rt.contexts.is_empty());
+        assert!(rt.waiting.is_empty());
+        assert!(rt.ids.is_empty());
+        assert!(rt.unused.is_none());
+        assert!(rt.ctx_main.is_none());
+
+        // 同じランタイムで再度実行可能
+        rt.run(teardown_main, STACK_SIZE);
+        assert_eq!(TEARDOWN_COUNT.load(Ordering::SeqCst), 18);
+
+        // 受信待ちのまま残ったスレッドはデッドロックとして解放される
+        let err = rt.try_run(leave_waiting_main, STACK_SIZE).unwrap_err();
+        assert_eq!(err.waiting.len(), 1);
+        assert!(rt.waiting.is_empty());
+        drop(rt);
+
+        // 実行中でなければスレッドローカルにランタイムは残っていない
+        assert!(RUNTIME.with(|rt| rt.borrow().is_none()));
+    }
+
+    static AFTER_PANIC: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    fn panicking_worker() {
+        yield_now();
+        panic!("boom");
+    }
+
+    fn panic_main() {
+        let id = spawn(panicking_worker, STACK_SIZE);
+        join(id);
+
+        let payload = take_panic(id).unwrap();
+        let msg = *payload.downcast::<&str>().unwrap();
+        AFTER_PANIC.lock().unwrap().push(msg.to_string());
+
+        // panic したスレッドの後も他のスレッドは問題なく動作する
+        let id = spawn_with(|| "still running".to_string(), STACK_SIZE);
+        let msg = join_value::<String>(id);
+        AFTER_PANIC.lock().unwrap().push(msg);
+
+        // join_value は panic を呼び出し元に伝搬する
+        let id = spawn_with(|| -> u64 { panic!("inner") }, STACK_SIZE);
+        let id2 = spawn_with(move || join_value::<u64>(id), STACK_SIZE);
+        join(id2);
+        let msg = *take_panic(id2).unwrap().downcast::<&str>().unwrap();
+        AFTER_PANIC.lock().unwrap().push(msg.to_string());
+    }
+
+    #[test]
+    fn test_panic_in_thread() {
+        let mut rt = GreenRuntime::new();
+        rt.run(panic_main, STACK_SIZE);
+        assert_eq!(
+            *AFTER_PANIC.lock().unwrap(),
+            vec!["boom", "still running", "inner"]
+        );
+        assert!(rt.contexts.is_empty());
+        assert!(rt.ids.is_empty());
+        assert!(rt.panics.is_empty());
+    }
+
+    static TIMEOUT_RESULTS: Mutex<Vec<(Option<u64>, Duration)>> = Mutex::new(Vec::new());
+
+    fn timeout_worker() {
+        let start = Instant::now();
+        let msg = recv_timeout::<u64>(Duration::from_millis(50));
+        TIMEOUT_RESULTS.lock().unwrap().push((msg, start.elapsed()));
+    }
+
+    fn timeout_main() {
+        // メッセージが届かないので None
+        let id = spawn(timeout_worker, STACK_SIZE);
+        join(id);
+
+        // 期限内にメッセージが届けば Some
+        let id = spawn(timeout_worker, STACK_SIZE);
+        send(id, 3u64);
+        join(id);
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let mut rt = GreenRuntime::new();
+        rt.run(timeout_main, STACK_SIZE);
+
+        let results = TIMEOUT_RESULTS.lock().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, None);
+        assert!(results[0].1 >= Duration::from_millis(50));
+        assert_eq!(results[1].0, Some(3));
+        assert!(results[1].1 < Duration::from_millis(50));
+        assert!(rt.deadlines.is_empty());
+    }
+
+    fn short_lived() {}
+
+    fn pool_main() {
+        for _ in 0..100 {
+            let id = spawn(short_lived, STACK_SIZE);
+            join(id);
+        }
+    }
+
+    #[test]
+    fn test_stack_pool() {
+        let mut rt = GreenRuntime::new();
+        rt.run(pool_main, STACK_SIZE);
+
+        // 終了したスレッドのスタックが再利用されるため、確保はごく少数で済む
+        let allocs = rt.stack_allocs;
+        assert!(allocs <= 3, "allocs = {}", allocs);
+        assert_eq!(rt.stack_pool[&STACK_SIZE].len(), allocs);
+
+        // 同じランタイムで再度実行してもプールから再利用される
+        rt.run(pool_main, STACK_SIZE);
+        assert_eq!(rt.stack_allocs, allocs);
+    }
+
+    static MUTEX_COUNT: AtomicU64 = AtomicU64::new(0);
+
+    fn mutex_main() {
+        let mutex = Rc::new(GreenMutex::new(0u64));
+        let ids: Vec<u64> = (0..2)
+            .map(|_| {
+                let mutex = mutex.clone();
+                spawn_with(
+                    move || {
+                        for _ in 0..100 {
+                            let mut n = mutex.lock();
+                            let v = *n;
+                            // ロックを保持したまま実行を譲っても更新は失われない
+                            yield_now();
+                            *n = v + 1;
+                        }
+                    },
+                    STACK_SIZE,
+                )
+            })
+            .collect();
+
+        for id in ids {
+            join(id);
+        }
+        MUTEX_COUNT.store(*mutex.lock(), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_green_mutex() {
+        let mut rt = GreenRuntime::new();
+        rt.run(mutex_main, STACK_SIZE);
+        assert_eq!(MUTEX_COUNT.load(Ordering::SeqCst), 200);
+        assert!(rt.waiting.is_empty());
+    }
+
+    static MUTEX_WOKEN_ORDER: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+    // ロック待ちの b をメッセージで起こし、ロックが保持されたままの状態で再度待機させる
+    // 待機スレッドに b の古い ID が残っていると、後で b がメッセージ待ちの間にその ID で b が起こされ、
+    // 後ろに並んでいる d が起こされなくなる
+    fn mutex_rewait_main() {
+        let mutex = Rc::new(GreenMutex::new(()));
+        let locker = |n: u32, mutex: &Rc<GreenMutex<()>>| {
+            let mutex = mutex.clone();
+            spawn_with(
+                move || {
+                    drop(mutex.lock());
+                    MUTEX_WOKEN_ORDER.lock().unwrap().push(n);
+                },
+                STACK_SIZE,
+            )
+        };
+
+        let m = mutex.clone();
+        let a = spawn_with(
+            move || {
+                let _guard = m.lock();
+                for _ in 0..10 {
+                    yield_now();
+                }
+            },
+            STACK_SIZE,
+        );
+        let m = mutex.clone();
+        let b = spawn_with(
+            move || {
+                drop(m.lock());
+                MUTEX_WOKEN_ORDER.lock().unwrap().push(2);
+                // ロック待ちの間に届いたメッセージを受信してから、次のメッセージを待つ
+                assert_eq!(recv::<u32>(), Some(1));
+                assert_eq!(recv::<u32>(), Some(2));
+            },
+            STACK_SIZE,
+        );
+        let c = locker(3, &mutex);
+        // a がロックを保持している間に b を起こす
+        send(b, 1u32);
+        let d = locker(4, &mutex);
+
+        for id in [a, c, d] {
+            join(id);
+        }
+        send(b, 2u32);
+        join(b);
+    }
+
+    #[test]
+    fn test_green_mutex_rewait() {
+        let mut rt = GreenRuntime::new();
+        assert!(rt.try_run(mutex_rewait_main, STACK_SIZE).is_ok());
+        // 再度待機した b は c の後ろに並ぶ
+        assert_eq!(*MUTEX_WOKEN_ORDER.lock().unwrap(), vec![3, 2, 4]);
+        assert!(rt.waiting.is_empty());
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    static AARCH64_TRACE: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+    #[cfg(target_arch = "aarch64")]
+    fn aarch64_worker() {
+        for i in 0..3 {
+            AARCH64_TRACE.lock().unwrap().push(i * 2 + 1);
+            yield_now();
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn aarch64_main() {
+        let id = spawn(aarch64_worker, STACK_SIZE);
+        for i in 0..3 {
+            AARCH64_TRACE.lock().unwrap().push(i * 2 + 2);
+            yield_now();
+        }
+        join(id);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    extern "C" fn yield_now_c() {
+        yield_now();
+    }
+
+    // d8 から d15 に vals を設定して実行を譲り、再開後の d8 から d15 の値をリターン
+    // コンパイラが値をスタックに退避しないよう、設定から読み出しまでを1つのインラインアセンブリで行う
+    #[cfg(target_arch = "aarch64")]
+    fn yield_with_d8_d15(vals: [f64; 8]) -> [f64; 8] {
+        let mut out = [0f64; 8];
+        unsafe {
+            std::arch::asm!(
+                "ldp d8, d9, [x20]",
+                "ldp d10, d11, [x20, #16]",
+                "ldp d12, d13, [x20, #32]",
+                "ldp d14, d15, [x20, #48]",
+                "bl {yield_now}",
+                "stp d8, d9, [x21]",
+                "stp d10, d11, [x21, #16]",
+                "stp d12, d13, [x21, #32]",
+                "stp d14, d15, [x21, #48]",
+                yield_now = sym yield_now_c,
+                // x20 と x21 は Callee-saved のため、bl をまたいで保持される
+                in("x20") vals.as_ptr(),
+                in("x21") out.as_mut_ptr(),
+                out("v8") _, out("v9") _, out("v10") _, out("v11") _,
+                out("v12") _, out("v13") _, out("v14") _, out("v15") _,
+                clobber_abi("C"),
+            );
+        }
+        out
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    static AARCH64_FLOATS: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+    // 2つのスレッドがそれぞれ異なる値を d8 から d15 に設定して交互に実行を譲る
+    // 相手のスレッドの値が残っていれば、コンテキストスイッチで復元されていない
+    #[cfg(target_arch = "aarch64")]
+    fn aarch64_float_main() {
+        let ids: Vec<u64> = (0..2)
+            .map(|n| {
+                spawn_with(
+                    move || {
+                        for round in 0..3 {
+                            let vals: [f64; 8] =
+                                std::array::from_fn(|i| (n * 100 + round * 10 + i) as f64 + 0.5);
+                            let ok = yield_with_d8_d15(vals) == vals;
+                            AARCH64_FLOATS.lock().unwrap().push(ok);
+                        }
+                    },
+                    STACK_SIZE,
+                )
+            })
+            .collect();
+        for id in ids {
+            join(id);
+        }
+    }
+
+    // AArch64 でのコンテキストスイッチ
+    // 浮動小数点レジスタを含めた Callee-saved レジスタが、スレッドごとに保存、復元されていることも確認する
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_aarch64_context_switch() {
+        let mut rt = GreenRuntime::new();
+        rt.run(aarch64_main, STACK_SIZE);
+        assert_eq!(*AARCH64_TRACE.lock().unwrap(), vec![1, 2, 3, 4, 5, 6]);
+
+        let mut rt = GreenRuntime::new();
+        rt.run(aarch64_float_main, STACK_SIZE);
+        assert_eq!(*AARCH64_FLOATS.lock().unwrap(), vec![true; 6]);
+    }
+
+    static SPAWNED_ID: AtomicU64 = AtomicU64::new(0);
+    static OBSERVED_ID: AtomicU64 = AtomicU64::new(0);
+
+    fn record_id() {
+        OBSERVED_ID.store(current_id(), Ordering::SeqCst);
+    }
+
+    fn current_id_main() {
+        let id = spawn(record_id, STACK_SIZE);
+        SPAWNED_ID.store(id, Ordering::SeqCst);
+        join(id);
+    }
+
+    #[test]
+    fn test_current_id() {
+        let mut rt = GreenRuntime::new();
+        rt.run(current_id_main, STACK_SIZE);
+        assert_eq!(
+            OBSERVED_ID.load(Ordering::SeqCst),
+            SPAWNED_ID.load(Ordering::SeqCst)
+        );
+    }
+
+    static BROADCAST_RECEIVED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+    fn broadcast_main() {
+        let ids: Vec<u64> = (0..3)
+            .map(|_| spawn_with(|| recv::<u64>().unwrap(), STACK_SIZE))
+            .collect();
+        broadcast(99u64);
+
+        // broadcast 後に生成したスレッドには届かない
+        let late = spawn_with(try_recv::<u64>, STACK_SIZE);
+
+        let mut received: Vec<u64> = ids.into_iter().map(join_value::<u64>).collect();
+        received.extend(join_value::<Option<u64>>(late));
+
+        // 送信元自身には届かない
+        received.extend(try_recv::<u64>());
+        *BROADCAST_RECEIVED.lock().unwrap() = received;
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let mut rt = GreenRuntime::new();
+        rt.run(broadcast_main, STACK_SIZE);
+        assert_eq!(*BROADCAST_RECEIVED.lock().unwrap(), vec![99, 99, 99]);
+    }
+
+    static STATS_INSIDE: Mutex<Option<Stats>> = Mutex::new(None);
+
+    fn stats_worker() {
+        let n = recv::<u64>().unwrap();
+        for _ in 0..n {
+            yield_now();
+        }
+    }
+
+    fn stats_main() {
+        // 生成直後に worker に切り替わり、recv で待機して戻ってくる (2回)
+        let id = spawn(stats_worker, STACK_SIZE);
+        *STATS_INSIDE.lock().unwrap() = Some(stats());
+
+        // send で worker を起こして切り替え、worker の yield_now で戻る (2回)
+        send(id, 3u64);
+        // 3回ずつ交互に yield_now し、最後は worker の終了で戻る (6回)
+        for _ in 0..3 {
+            yield_now();
+        }
+        join(id);
+        // main のスレッドの終了で run に戻る (1回)
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut rt = GreenRuntime::new();
+        rt.run(stats_main, STACK_SIZE);
+
+        let inside = STATS_INSIDE.lock().unwrap().unwrap();
+        assert_eq!(inside.context_switches, 2);
+        assert_eq!(inside.spawns, 2);
+        assert_eq!(inside.runnable, 1);
+        assert_eq!(inside.waiting, 1);
+
+        assert_eq!(
+            rt.stats(),
+            Stats {
+                context_switches: 11,
+                spawns: 2,
+                messages_sent: 1,
+                messages_received: 1,
+                runnable: 0,
+                waiting: 0,
+            }
+        );
+    }
+
+    const BUFFER_CAP: usize = 2;
+    static CONDVAR_RECEIVED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+    static CONDVAR_MAX_LEN: AtomicU64 = AtomicU64::new(0);
+
+    // GreenMutex と GreenCondvar による有限バッファ
+    struct BoundedBuffer {
+        buf: GreenMutex<VecDeque<u64>>,
+        not_empty: GreenCondvar,
+        not_full: GreenCondvar,
+    }
+
+    fn condvar_main() {
+        let shared = Rc::new(BoundedBuffer {
+            buf: GreenMutex::new(VecDeque::new()),
+            not_empty: GreenCondvar::new(),
+            not_full: GreenCondvar::new(),
+        });
+
+        let shared0 = shared.clone();
+        let producer = spawn_with(
+            move || {
+                for i in 0..20 {
+                    let mut buf = shared0.buf.lock();
+                    while buf.len() == BUFFER_CAP {
+                        buf = shared0.not_full.wait(buf);
+                    }
+                    buf.push_back(i);
+                    CONDVAR_MAX_LEN.fetch_max(buf.len() as u64, Ordering::SeqCst);
+                    shared0.not_empty.notify_one();
+                }
+            },
+            STACK_SIZE,
+        );
+
+        let consumer = spawn_with(
+            move || {
+                for _ in 0..20 {
+                    let mut buf = shared.buf.lock();
+                    while buf.is_empty() {
+                        buf = shared.not_empty.wait(buf);
+                    }
+                    let n = buf.pop_front().unwrap();
+                    CONDVAR_RECEIVED.lock().unwrap().push(n);
+                    shared.not_full.notify_one();
+                    drop(buf);
+                    yield_now();
+                }
+            },
+            STACK_SIZE,
+        );
+
+        join(producer);
+        join(consumer);
+    }
+
+    #[test]
+    fn test_green_condvar() {
+        let mut rt = GreenRuntime::new();
+        rt.run(condvar_main, STACK_SIZE);
+        assert_eq!(
+            *CONDVAR_RECEIVED.lock().unwrap(),
+            (0..20).collect::<Vec<_>>()
+        );
+        // バッファが一杯の間、producer は待機していた
+        assert_eq!(CONDVAR_MAX_LEN.load(Ordering::SeqCst), BUFFER_CAP as u64);
+        assert!(rt.waiting.is_empty());
+    }
+
+    static SEM_IN_CS: AtomicU64 = AtomicU64::new(0);
+    static SEM_MAX_IN_CS: AtomicU64 = AtomicU64::new(0);
+    static SEM_ENTERED: AtomicU64 = AtomicU64::new(0);
+    static SEM_PERMITS_AFTER: AtomicU64 = AtomicU64::new(0);
+
+    fn semaphore_main() {
+        let sem = Rc::new(GreenSemaphore::new(2));
+        let ids: Vec<u64> = (0..3)
+            .map(|_| {
+                let sem = sem.clone();
+                spawn_with(
+                    move || {
+                        for _ in 0..10 {
+                            sem.acquire();
+                            let n = SEM_IN_CS.fetch_add(1, Ordering::SeqCst) + 1;
+                            SEM_MAX_IN_CS.fetch_max(n, Ordering::SeqCst);
+                            SEM_ENTERED.fetch_add(1, Ordering::SeqCst);
+                            // 許可を保持したまま実行を譲り、他のスレッドと競合させる
+                            yield_now();
+                            yield_now();
+                            SEM_IN_CS.fetch_sub(1, Ordering::SeqCst);
+                            sem.release();
+                        }
+                    },
+                    STACK_SIZE,
+                )
+            })
+            .collect();
+
+        for id in ids {
+            join(id);
+        }
+        SEM_PERMITS_AFTER.store(sem.available_permits() as u64, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_green_semaphore() {
+        let mut rt = GreenRuntime::new();
+        rt.run(semaphore_main, STACK_SIZE);
+        // クリティカルセクションには同時に 2 つまでしか入れない
+        assert_eq!(SEM_MAX_IN_CS.load(Ordering::SeqCst), 2);
+        assert_eq!(SEM_ENTERED.load(Ordering::SeqCst), 30);
+        assert_eq!(SEM_PERMITS_AFTER.load(Ordering::SeqCst), 2);
+        assert!(rt.waiting.is_empty());
+    }
+
+    static SLEEP_COUNTER: AtomicU64 = AtomicU64::new(0);
+    static SLEEP_COUNT_AT_WAKE: AtomicU64 = AtomicU64::new(0);
+    static SLEEP_ELAPSED: Mutex<Option<Duration>> = Mutex::new(None);
+
+    fn sleeper() {
+        let start = Instant::now();
+        sleep(Duration::from_millis(50));
+        *SLEEP_ELAPSED.lock().unwrap() = Some(start.elapsed());
+        SLEEP_COUNT_AT_WAKE.store(SLEEP_COUNTER.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    fn sleep_main() {
+        let id = spawn(sleeper, STACK_SIZE);
+        // sleeper の待機中もこのスレッドは実行され続ける
+        for _ in 0..100 {
+            SLEEP_COUNTER.fetch_add(1, Ordering::SeqCst);
+            yield_now();
+        }
+        join(id);
+    }
+
+    #[test]
+    fn test_sleep() {
+        let mut rt = GreenRuntime::new();
+        rt.run(sleep_main, STACK_SIZE);
+
+        // sleeper の待機中に、もう一方のスレッドがすべてのインクリメントを終えていた
+        assert_eq!(SLEEP_COUNTER.load(Ordering::SeqCst), 100);
+        assert_eq!(SLEEP_COUNT_AT_WAKE.load(Ordering::SeqCst), 100);
+        let elapsed = SLEEP_ELAPSED.lock().unwrap().unwrap();
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(rt.deadlines.is_empty());
+        assert!(rt.waiting.is_empty());
+    }
+
+    static LOCAL_RESULTS: Mutex<Vec<(u32, Option<u32>)>> = Mutex::new(Vec::new());
+    static LOCAL_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+    // 破棄された回数を数える
+    struct CountDrop;
+
+    impl Drop for CountDrop {
+        fn drop(&mut self) {
+            LOCAL_DROPPED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn local_main() {
+        let local = Rc::new(GreenLocal::<u32>::new());
+        let dropped = Rc::new(GreenLocal::<CountDrop>::new());
+        let ids: Vec<u64> = (0..2)
+            .map(|i| {
+                let local = local.clone();
+                let dropped = dropped.clone();
+                spawn_with(
+                    move || {
+                        assert_eq!(local.get(), None);
+                        local.set(i);
+                        dropped.set(CountDrop);
+                        // 他方のスレッドが値を設定するまで実行を譲る
+                        yield_now();
+                        yield_now();
+                        let v = local.get().unwrap();
+                        assert_eq!(local.set(v + 10), Some(i));
+                        LOCAL_RESULTS.lock().unwrap().push((i, local.get()));
+                    },
+                    STACK_SIZE,
+                )
+            })
+            .collect();
+        for id in ids {
+            join(id);
+        }
+        // 親スレッドの値は子スレッドとは独立している
+        assert_eq!(local.get(), None);
+    }
+
+    #[test]
+    fn test_green_local() {
+        let mut rt = GreenRuntime::new();
+        rt.run(local_main, STACK_SIZE);
+
+        let mut results = LOCAL_RESULTS.lock().unwrap().clone();
+        results.sort();
+        assert_eq!(results, vec![(0, Some(10)), (1, Some(11))]);
+        // スレッドの終了時に値が破棄される
+        assert_eq!(LOCAL_DROPPED.load(Ordering::SeqCst), 2);
+        assert!(rt.locals.is_empty());
+    }
+
+    static HIGH_WATER: Mutex<Vec<Option<usize>>> = Mutex::new(Vec::new());
+
+    // 再帰呼び出しごとに 1KiB のスタックを使用する
+    fn recurse(n: u64) -> u64 {
+        let buf = std::hint::black_box([n as u8; 1024]);
+        if n == 0 {
+            0
+        } else {
+            recurse(n - 1) + buf[0] as u64
+        }
+    }
+
+    fn high_water_main() {
+        let deep = spawn(
+            || {
+                std::hint::black_box(recurse(100));
+            },
+            STACK_SIZE,
+        );
+        let shallow = spawn(short_lived, STACK_SIZE);
+        join(deep);
+        join(shallow);
+        let mut v = HIGH_WATER.lock().unwrap();
+        v.push(stack_high_water(deep));
+        v.push(stack_high_water(shallow));
+    }
+
+    #[test]
+    fn test_stack_high_water() {
+        let mut rt = GreenRuntime::new();
+        rt.set_measure_stack(true);
+        rt.run(high_water_main, STACK_SIZE);
+
+        let v = HIGH_WATER.lock().unwrap();
+        let deep = v[0].unwrap();
+        let shallow = v[1].unwrap();
+        // 再帰の深さ分 (100KiB 以上) のスタックを使用している
+        assert!(deep >= 100 * 1024, "deep = {}", deep);
+        assert!(deep < STACK_SIZE - PAGE_SIZE);
+        assert!(shallow < deep);
+        assert_eq!(rt.high_water.len(), 3);
+    }
+
+    static PRIORITY_LOG: Mutex<Vec<char>> = Mutex::new(Vec::new());
+
+    fn low_priority() {
+        for _ in 0..5 {
+            PRIORITY_LOG.lock().unwrap().push('L');
+            yield_now();
+        }
+    }
+
+    fn high_priority() {
+        for _ in 0..5 {
+            PRIORITY_LOG.lock().unwrap().push('H');
+            yield_now();
+        }
+    }
+
+    fn priority_main() {
+        let low = spawn_with_priority(low_priority, STACK_SIZE, DEFAULT_PRIORITY);
+        let high = spawn_with_priority(high_priority, STACK_SIZE, 10);
+        join(low);
+        join(high);
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut rt = GreenRuntime::new();
+        rt.run(priority_main, STACK_SIZE);
+
+        // 生成された後、high は終了するまで low より先に実行され続ける
+        let log: String = PRIORITY_LOG.lock().unwrap().iter().collect();
+        let first = log.find('H').unwrap();
+        assert_eq!(&log[first..first + 5], "HHHHH", "log = {}", log);
+        assert_eq!(log.matches('L').count(), 5);
+    }
+
+    static AGING_LOW_DONE: AtomicU64 = AtomicU64::new(0);
+    static AGING_HIGH_AT_LOW_DONE: AtomicU64 = AtomicU64::new(0);
+    static AGING_HIGH_COUNT: AtomicU64 = AtomicU64::new(0);
+
+    fn aging_low() {
+        yield_now();
+        AGING_HIGH_AT_LOW_DONE.store(AGING_HIGH_COUNT.load(Ordering::SeqCst), Ordering::SeqCst);
+        AGING_LOW_DONE.store(1, Ordering::SeqCst);
+    }
+
+    fn aging_high() {
+        for _ in 0..1000 {
+            AGING_HIGH_COUNT.fetch_add(1, Ordering::SeqCst);
+            yield_now();
+        }
+    }
+
+    fn aging_main() {
+        let high = spawn_with_priority(aging_high, STACK_SIZE, 5);
+        let low = spawn(aging_low, STACK_SIZE);
+        join(high);
+        join(low);
+    }
+
+    #[test]
+    fn test_priority_aging() {
+        let mut rt = GreenRuntime::new();
+        rt.run(aging_main, STACK_SIZE);
+
+        // 優先度の高いスレッドが実行可能であり続けても、低いスレッドは待機が長くなると実行される
+        assert_eq!(AGING_LOW_DONE.load(Ordering::SeqCst), 1);
+        assert!(AGING_HIGH_AT_LOW_DONE.load(Ordering::SeqCst) < 100);
+    }
+
+    static CYCLE_IDS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+    fn cycle_worker() {
+        // cycle_main からのメッセージを待つが、cycle_main も自身からのメッセージを待っている
+        let peer = recv::<u64>().unwrap();
+        send(peer, 0u64);
+    }
+
+    fn cycle_main() {
+        let id = spawn(cycle_worker, STACK_SIZE);
+        CYCLE_IDS.lock().unwrap().extend([current_id(), id]);
+        recv::<u64>().unwrap();
+        send(id, current_id());
+    }
+
+    fn orphan_main() {
+        // 送信せずに終了するスレッドからのメッセージを待つ
+        spawn(short_lived, STACK_SIZE);
+        recv::<u64>();
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let mut rt = GreenRuntime::new();
+
+        // 互いのメッセージを待つ 2 つのスレッド
+        let err = rt.try_run(cycle_main, STACK_SIZE).unwrap_err();
+        let mut ids = CYCLE_IDS.lock().unwrap().clone();
+        ids.sort();
+        assert_eq!(err.waiting, ids);
+        assert!(rt.waiting.is_empty());
+        assert!(rt.ids.is_empty());
+
+        // 最後の実行可能なスレッドが終了した時点で待機中のスレッドが残っている
+        let err = rt.try_run(orphan_main, STACK_SIZE).unwrap_err();
+        assert_eq!(err.waiting.len(), 1);
+
+        // デッドロックした後も再度実行できる
+        rt.try_run(short_lived, STACK_SIZE).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "deadlock")]
+    fn test_deadlock_panics_in_run() {
+        spawn_from_main(orphan_main, STACK_SIZE);
+    }
+
+    static BRIDGE_RX: Mutex<Option<BridgeReceiver<u64>>> = Mutex::new(None);
+    static BRIDGE_TX: Mutex<Option<BridgeSender<u64>>> = Mutex::new(None);
+    static BRIDGE_OTHER: AtomicU64 = AtomicU64::new(0);
+
+    fn bridge_main() {
+        let rx = BRIDGE_RX.lock().unwrap().take().unwrap();
+        let tx = BRIDGE_TX.lock().unwrap().take().unwrap();
+
+        // 受信待ちの間も他のグリーンスレッドは実行される
+        let other = spawn_with(
+            || {
+                for _ in 0..10 {
+                    BRIDGE_OTHER.fetch_add(1, Ordering::SeqCst);
+                    yield_now();
+                }
+            },
+            STACK_SIZE,
+        );
+
+        // OS スレッドから受信した値を、2 倍して OS スレッドに送り返す
+        while let Some(n) = rx.recv() {
+            tx.send(n * 2);
+        }
+        join(other);
+    }
+
+    #[test]
+    fn test_bridge_channel() {
+        const NUM_ITEMS: u64 = 100;
+        let (tx, rx) = bridge_channel();
+        let (tx0, rx0) = bridge_channel();
+        *BRIDGE_RX.lock().unwrap() = Some(rx);
+        *BRIDGE_TX.lock().unwrap() = Some(tx0);
+
+        let producer = std::thread::spawn(move || {
+            for i in 0..NUM_ITEMS {
+                tx.send(i);
+                if i % 10 == 0 {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+        let consumer = std::thread::spawn(move || {
+            let mut v = Vec::new();
+            while let Some(n) = rx0.recv() {
+                v.push(n);
+            }
+            v
+        });
+
+        let mut rt = GreenRuntime::new();
+        rt.try_run(bridge_main, STACK_SIZE).unwrap();
+        producer.join().unwrap();
+
+        let v = consumer.join().unwrap();
+        assert_eq!(v, (0..NUM_ITEMS).map(|n| n * 2).collect::<Vec<_>>());
+        assert_eq!(BRIDGE_OTHER.load(Ordering::SeqCst), 10);
+        assert!(rt.bridge_waiters.is_empty());
+    }
+
+    static DETACHED_RAN: AtomicU64 = AtomicU64::new(0);
+
+    fn detached_worker() {
+        DETACHED_RAN.fetch_add(1, Ordering::SeqCst);
+        yield_now();
+        DETACHED_RAN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn detached_panic() {
+        DETACHED_RAN.fetch_add(1, Ordering::SeqCst);
+        panic!("detached");
+    }
+
+    fn detached_main() {
+        spawn_detached(detached_worker, STACK_SIZE);
+        spawn_detached(detached_panic, STACK_SIZE);
+        // join できるスレッドの状態は従来どおり保持される
+        let id = spawn(detached_panic, STACK_SIZE);
+        join(id);
+        assert!(take_panic(id).is_some());
+    }
+
+    #[test]
+    fn test_spawn_detached() {
+        let mut rt = GreenRuntime::new();
+        rt.set_measure_stack(true);
+        rt.run(detached_main, STACK_SIZE);
+
+        assert_eq!(DETACHED_RAN.load(Ordering::SeqCst), 4);
+        // 終了した detached なスレッドの状態は残っていない
+        assert!(rt.detached.is_empty());
+        assert!(rt.panics.is_empty());
+        assert!(rt.ids.is_empty());
+        // detached_main と join したスレッドの分のみ記録される
+        assert_eq!(rt.high_water.len(), 2);
+    }
+
+    static PREEMPT_STOP: AtomicBool = AtomicBool::new(false);
+    static PREEMPT_SPINS: AtomicU64 = AtomicU64::new(0);
+    static PREEMPT_STEPS: AtomicU64 = AtomicU64::new(0);
+    static PREEMPT_SWITCHED: AtomicU64 = AtomicU64::new(0);
+
+    // ランタイムの関数を一切呼び出さずに、タイマーの間隔より長く計算することを繰り返す
+    fn compute_bound() {
+        for _ in 0..5 {
+            let steps = PREEMPT_STEPS.load(Ordering::Relaxed);
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(5) {
+                PREEMPT_SPINS.fetch_add(1, Ordering::Relaxed);
+            }
+            // シグナルハンドラ内では切り替えないため、計算中に stepper は実行されない
+            if PREEMPT_STEPS.load(Ordering::Relaxed) != steps {
+                break;
+            }
+
+            // 計算中に要求されたプリエンプションにより、次の安全な切り替え箇所で stepper に実行が移る
+            // プリエンプションが要求されていなければ、try_recv は実行を譲らない
+            try_recv::<()>();
+            if PREEMPT_STEPS.load(Ordering::Relaxed) > steps {
+                PREEMPT_SWITCHED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        PREEMPT_STOP.store(true, Ordering::Relaxed);
+    }
+
+    fn stepper() {
+        while !PREEMPT_STOP.load(Ordering::Relaxed) {
+            PREEMPT_STEPS.fetch_add(1, Ordering::Relaxed);
+            yield_now();
+        }
+    }
+
+    fn preempt_main() {
+        let a = spawn(stepper, STACK_SIZE);
+        let b = spawn(compute_bound, STACK_SIZE);
+        join(b);
+        join(a);
+    }
+
+    #[test]
+    fn test_preemption() {
+        let mut rt = GreenRuntime::new();
+        rt.set_preemption(Some(Duration::from_millis(1)));
+        rt.run(preempt_main, STACK_SIZE);
 
-        // 不要なスタックを削除
-        rm_unused_stack();
+        // compute_bound が実行を譲らなくても、プリエンプションにより stepper が実行される
+        assert!(PREEMPT_SPINS.load(Ordering::Relaxed) > 0);
+        assert_eq!(PREEMPT_SWITCHED.load(Ordering::Relaxed), 5);
+        assert!(rt.stats().context_switches > 10);
 
-        // 受信したメッセージを取得
-        (*MESSAGES).pop_front(key)
+        // run からリターンするとタイマーは停止している
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(!PREEMPT_REQUESTED.with(|f| f.load(Ordering::Relaxed)));
     }
 }
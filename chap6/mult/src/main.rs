@@ -1,3 +1,5 @@
+// green はライブラリとして API を公開しており、main ではその一部のみを使用する
+#[allow(dead_code)]
 mod green;
 
 use std::io::Write;
@@ -31,15 +33,18 @@ fn gaia() {
 fn producer() {
     // <1>
     let id = green::spawn(consumer, 2 * 1024 * 1024);
-    for i in 0..10 {
+    for i in 0..10u64 {
         green::send(id, i);
     }
+    // consumer の終了を待機
+    green::join(id);
+    println!("consumer finished");
 }
 
 fn consumer() {
     // <2>
     for _ in 0..10 {
-        let msg = green::recv().unwrap();
+        let msg = green::recv::<u64>().unwrap();
         println!("received: count = {}", msg);
     }
 }
@@ -1,7 +1,21 @@
-use std::cell::UnsafeCell;
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
 use std::ptr::null_mut;
-use std::sync::atomic::{fence, AtomicBool, AtomicPtr, Ordering};
+
+// loom でモデル検査する場合は、アトミック変数とスピンのヒントを loom のものに差し替える
+// RUSTFLAGS="--cfg loom" cargo test --release loom で実行する
+#[cfg(loom)]
+use loom::{
+    hint,
+    sync::atomic::{fence, AtomicBool, AtomicPtr, Ordering},
+};
+#[cfg(not(loom))]
+use std::{
+    hint,
+    sync::atomic::{fence, AtomicBool, AtomicPtr, Ordering},
+};
 
 // メモリオーダー
 // Relaxed: 制約なし
@@ -18,6 +32,8 @@ pub struct MCSLock<T> {
 
 // リンクリスト用のノード型
 // ロックを獲得する際は locked を true にし、他のスレッドによって false に設定されるまでスピンする
+// lock_tls で T によらず同じノードを使用するため、レイアウトを固定する
+#[repr(C)]
 pub struct MCSNode<T> {
     next: AtomicPtr<MCSNode<T>>, // 次のノード
     locked: AtomicBool,          // true ならロック獲得(試行?)中
@@ -28,6 +44,30 @@ pub struct MCSLockGuard<'a, T> {
     mcs_lock: &'a MCSLock<T>, // キューの最後尾と保護対象データへの参照
 }
 
+// MCSLockGuard::map で保護対象データの一部に射影したロックガード
+// 元のガードを保持してロックとノードを維持し、破棄時に元のガードの Drop で解放する
+// 元のガードには外部からアクセスできないため、射影した部分以外のデータは参照できない
+pub struct MappedMCSLockGuard<'a, T, U> {
+    _guard: MCSLockGuard<'a, T>,
+    data: *mut U, // 保護対象データ中の射影先
+    _marker: PhantomData<&'a mut U>,
+}
+
+// lock_tls が返すロックガード
+// スレッドローカルなノードを使用するため、他のスレッドに送信できないよう !Send とする
+pub struct MCSTlsGuard<'a, T> {
+    guard: ManuallyDrop<MCSLockGuard<'a, T>>,
+    _marker: PhantomData<*const ()>,
+}
+
+thread_local! {
+    // lock_tls で使用するノード
+    // ノードのレイアウトは T によらないため、MCSNode<()> を各 T のノードとして使用する
+    static TLS_NODE: UnsafeCell<MCSNode<()>> = UnsafeCell::new(MCSNode::new());
+    // TLS_NODE がロックの獲得に使用中なら true
+    static TLS_NODE_IN_USE: Cell<bool> = const { Cell::new(false) };
+}
+
 // スレッド間のデータ共有と、チャネルを使っ送受信が可能と設定
 unsafe impl<T> Sync for MCSLock<T> {}
 unsafe impl<T> Send for MCSLock<T> {}
@@ -57,6 +97,38 @@ impl<'a, T> DerefMut for MCSLockGuard<'a, T> {
     }
 }
 
+impl<'a, T> MCSLockGuard<'a, T> {
+    // ロックを保持したまま、保護対象データの一部への参照のみを持つガードに変換
+    // Deref と名前が衝突しないよう、MCSLockGuard::map(guard, f) の形で呼び出す
+    #[allow(dead_code)] // main では使用しない
+    pub fn map<U>(
+        mut this: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedMCSLockGuard<'a, T, U> {
+        // f はデータ自身の一部か 'static な値しか返せないため、ロックの保持中は有効
+        let data = f(&mut this) as *mut U;
+        MappedMCSLockGuard {
+            _guard: this,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, U> Deref for MappedMCSLockGuard<'_, T, U> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.data }
+    }
+}
+
+impl<T, U> DerefMut for MappedMCSLockGuard<'_, T, U> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.data }
+    }
+}
+
 impl<T> MCSLock<T> {
     pub fn new(v: T) -> Self {
         MCSLock {
@@ -67,7 +139,7 @@ impl<T> MCSLock<T> {
 
     // lock を獲得する側で MCSNode::new() で作ったものを渡す想定?
     // じゃあこっちで吸収できないのか？みたいな疑問が当然沸き...
-    pub fn lock<'a>(&'a self, node: &'a mut MCSNode<T>) -> MCSLockGuard<T> {
+    pub fn lock<'a>(&'a self, node: &'a mut MCSNode<T>) -> MCSLockGuard<'a, T> {
         // 自スレッド用のノードを初期化
         // MCSNode::new() で作ったものが渡されている場合は既にされてる
         node.next = AtomicPtr::new(null_mut());
@@ -81,7 +153,8 @@ impl<T> MCSLock<T> {
         // 自身をキューの最後尾とする
         let ptr = guard.node as *mut MCSNode<T>;
         // 既存の最後尾を prev とする
-        let prev = self.last.swap(ptr, Ordering::Relaxed);
+        // Release でノードの初期化を、Acquire で前の保持者の保護対象データへの書き込みを同期する
+        let prev = self.last.swap(ptr, Ordering::AcqRel);
 
         // 最後尾が null の場合は誰もロックを獲得しようとしていないためロック獲得
         // null 以外の場合は、自身をキューの最後尾に追加
@@ -91,16 +164,48 @@ impl<T> MCSLock<T> {
 
             // 自身をキューの最後尾に追加
             let prev = unsafe { &*prev };
-            prev.next.store(ptr, Ordering::Relaxed);
+            // Release により、解放する側からは locked = true の書き込み後に見える
+            prev.next.store(ptr, Ordering::Release);
 
             // 他のスレッドから false に設定されるまでスピン
-            while guard.node.locked.load(Ordering::Relaxed) {}
+            while guard.node.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
         }
 
         fence(Ordering::Acquire);
         // guard が返れば、deref で普通に値がとれる
         guard
     }
+
+    // ノードを作成してロックを獲得し、f を実行してから解放する
+    // ノードは呼び出しごとにスタック上に作成するため、呼び出し側で用意する必要がない
+    #[allow(dead_code)] // main では使用しない
+    pub fn lock_with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut node = MCSNode::new();
+        let mut guard = self.lock(&mut node);
+        f(&mut guard)
+    }
+
+    // スレッドローカルなノードを使用してロックを獲得
+    // ノードを用意する必要がなく、ロックのたびにノードを確保する必要もない
+    // ノードはスレッドで1つのみのため、ガードの保持中に同じスレッドで再度 lock_tls を呼び出すと panic する
+    // 対象が別の MCSLock であっても同様
+    #[allow(dead_code)] // main では使用しない
+    pub fn lock_tls(&self) -> MCSTlsGuard<'_, T> {
+        assert!(
+            !TLS_NODE_IN_USE.replace(true),
+            "lock_tls: the thread-local node is already in use"
+        );
+
+        // ノードはスレッドの終了まで存在し、ガードは他のスレッドに送信できないため、ガードより長く生存する
+        // TLS_NODE_IN_USE により、ノードへの可変参照は同時に1つのみ
+        let node = TLS_NODE.with(|node| node.get() as *mut MCSNode<T>);
+        MCSTlsGuard {
+            guard: ManuallyDrop::new(self.lock(unsafe { &mut *node })),
+            _marker: PhantomData,
+        }
+    }
 }
 
 // ロックの解除とはすなわち
@@ -108,13 +213,14 @@ impl<T> MCSLock<T> {
 impl<'a, T> Drop for MCSLockGuard<'a, T> {
     fn drop(&mut self) {
         // 自身の次のノードが null かつ自身が最後尾のノードなら、最後尾を null に設定
-        if self.node.next.load(Ordering::Relaxed).is_null() {
+        if self.node.next.load(Ordering::Acquire).is_null() {
             let ptr = self.node as *mut MCSNode<T>;
             // ↓で Err になるときは、↑の if 文評価後から↓の if 文評価の間に他のスレッドによって last が追加された場合
+            // 成功した場合は Release により、次にロックを獲得するスレッドへ保護対象データへの書き込みを同期する
             if self
                 .mcs_lock
                 .last
-                .compare_exchange(ptr, null_mut(), Ordering::Relaxed, Ordering::Relaxed)
+                .compare_exchange(ptr, null_mut(), Ordering::Release, Ordering::Relaxed)
                 .is_ok()
             {
                 return;
@@ -123,8 +229,175 @@ impl<'a, T> Drop for MCSLockGuard<'a, T> {
 
         // 自身の次のスレッドが Lock 関数実行中なので、その終了を待機
         // ロック獲得待機中のスレッドが必ずいるので、この while loop は必ず終わるはず
-        while self.node.next.load(Ordering::Relaxed).is_null() {}
-        let next = unsafe { &mut *self.node.next.load(Ordering::Relaxed) };
+        while self.node.next.load(Ordering::Acquire).is_null() {
+            hint::spin_loop();
+        }
+        let next = unsafe { &mut *self.node.next.load(Ordering::Acquire) };
         next.locked.store(false, Ordering::Release);
     }
 }
+
+impl<T> Deref for MCSTlsGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for MCSTlsGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for MCSTlsGuard<'_, T> {
+    fn drop(&mut self) {
+        // ロックを解放してから、ノードを再度使用可能にする
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        TLS_NODE_IN_USE.set(false);
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_lock_tls() {
+        const NUM_THREADS: usize = 4;
+        const NUM_LOOP: usize = 1000;
+        let lock = Arc::new(MCSLock::new(0));
+        let v: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let lock = lock.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..NUM_LOOP {
+                        let mut data = lock.lock_tls();
+                        *data += 1;
+                    }
+                })
+            })
+            .collect();
+        for t in v {
+            t.join().unwrap();
+        }
+        assert_eq!(*lock.lock_tls(), NUM_THREADS * NUM_LOOP);
+
+        // 型の異なるロックでも同じノードを順に使用できる
+        let other = MCSLock::new(String::new());
+        other.lock_tls().push('a');
+        assert_eq!(*other.lock_tls(), "a");
+    }
+
+    #[test]
+    fn test_lock_with() {
+        const NUM_THREADS: usize = 4;
+        const NUM_LOOP: usize = 1000;
+        let lock = Arc::new(MCSLock::new(0));
+        let v: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let lock = lock.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..NUM_LOOP {
+                        lock.lock_with(|n| *n += 1);
+                    }
+                })
+            })
+            .collect();
+        for t in v {
+            t.join().unwrap();
+        }
+        assert_eq!(lock.lock_with(|n| *n), NUM_THREADS * NUM_LOOP);
+        // 解放後はキューが空になっている
+        assert!(lock.last.load(Ordering::Relaxed).is_null());
+    }
+
+    #[test]
+    fn test_map() {
+        const NUM_THREADS: usize = 4;
+        const NUM_LOOP: usize = 1000;
+        struct Pair {
+            count: usize,
+            name: String,
+        }
+        let lock = Arc::new(MCSLock::new(Pair {
+            count: 0,
+            name: "pair".to_string(),
+        }));
+
+        // count のみを射影したガードで更新し、破棄時に次のスレッドへロックが渡る
+        let v: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let lock = lock.clone();
+                std::thread::spawn(move || {
+                    let mut node = MCSNode::new();
+                    for _ in 0..NUM_LOOP {
+                        let guard = lock.lock(&mut node);
+                        let mut count = MCSLockGuard::map(guard, |p| &mut p.count);
+                        *count += 1;
+                    }
+                })
+            })
+            .collect();
+        for t in v {
+            t.join().unwrap();
+        }
+
+        let mut node = MCSNode::new();
+        let pair = lock.lock(&mut node);
+        assert_eq!(pair.count, NUM_THREADS * NUM_LOOP);
+        // 射影していないフィールドは変更されない
+        assert_eq!(pair.name, "pair");
+        drop(pair);
+        assert!(lock.last.load(Ordering::Relaxed).is_null());
+    }
+
+    #[test]
+    #[should_panic(expected = "lock_tls: the thread-local node is already in use")]
+    fn test_lock_tls_reentrant() {
+        let a = MCSLock::new(0);
+        let b = MCSLock::new(0);
+        let _guard = a.lock_tls();
+        let _nested = b.lock_tls();
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_test {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    // 2つのスレッドから同時にインクリメントしても、更新が失われない
+    // 同じノードを再利用して2回ロックし、ノードの再初期化も検査する
+    // スピンを含むため、プリエンプションの回数を制限して探索空間を抑える
+    #[test]
+    fn loom_mcslock() {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(2);
+        builder.check(|| {
+            let lock = Arc::new(MCSLock::new(0));
+            let v: Vec<_> = (0..2)
+                .map(|_| {
+                    let lock = lock.clone();
+                    thread::spawn(move || {
+                        let mut node = MCSNode::new();
+                        for _ in 0..2 {
+                            let mut data = lock.lock(&mut node);
+                            let n = *data;
+                            *data = n + 1;
+                        }
+                    })
+                })
+                .collect();
+            for t in v {
+                t.join().unwrap();
+            }
+
+            let mut node = MCSNode::new();
+            assert_eq!(*lock.lock(&mut node), 4);
+        });
+    }
+}
@@ -73,8 +73,9 @@ fn philosopher(stm: Arc<tl2::STM>, n: usize) {
 // 哲学者を観測する観測者のコード
 fn observer(stm: Arc<tl2::STM>) {
     for _ in 0..10000 {
+        // すべての箸が同じ時点の状態となるよう、スナップショットとして読み込む
         let chopsticks = stm
-            .read_transaction(|tr| {
+            .read_snapshot(|tr| {
                 let mut v = [0; NUM_PHILOSOPHERS];
                 for i in 0..NUM_PHILOSOPHERS {
                     v[i] = load!(tr, 8 * i)[0];
@@ -105,5 +106,22 @@ fn observer(stm: Arc<tl2::STM>) {
 }
 
 fn main() {
-    println!("Hello, world!");
+    let stm = Arc::new(tl2::STM::new());
+    let mut v = Vec::new();
+
+    // 哲学者のスレッド生成
+    for i in 0..NUM_PHILOSOPHERS {
+        let s = stm.clone();
+        let th = std::thread::spawn(move || philosopher(s, i));
+        v.push(th);
+    }
+
+    // 観測者のスレッド生成
+    let obs = std::thread::spawn(move || observer(stm));
+
+    for th in v {
+        th.join().unwrap();
+    }
+
+    obs.join().unwrap();
 }
@@ -1,7 +1,9 @@
 use std::cell::UnsafeCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::marker::PhantomData;
 use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 // ストライプのサイズ
 const STRIPE_SIZE: usize = 8; // u64, 8 バイト
@@ -10,10 +12,28 @@ const STRIPE_SIZE: usize = 8; // u64, 8 バイト
 // このため 512 / 8 = 64 個のストライプを使用可能
 const MEM_SIZE: usize = 512; // 512 バイト
 
+// コミット時のロック競合の扱い
+// デフォルトは Retry で、OlderWaits は STM::with_policy で指定した場合のみ使用する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentionPolicy {
+    // 競合したら即座にアボートしてリトライ
+    Retry,
+    // 開始タイムスタンプの古いトランザクションを優先する
+    // 若いトランザクションは即座にアボートし、古いトランザクションは limit の間だけロックの解放を待つ
+    #[allow(dead_code)] // main では使用しない
+    OlderWaits {
+        limit: Duration,
+    },
+}
+
 pub struct Memory {
-    mem: Vec<u8>,             // メモリ
-    lock_ver: Vec<AtomicU64>, // ストライプに対する lock & verson
-    global_clock: AtomicU64,  // global version-clock
+    mem: Vec<u8>,               // メモリ
+    lock_ver: Vec<AtomicU64>,   // ストライプに対する lock & verson
+    lock_ts: Vec<AtomicU64>,    // ストライプをロックしているトランザクションの開始タイムスタンプ
+    contention: Vec<AtomicU64>, // ストライプごとの競合回数
+    global_clock: AtomicU64,    // global version-clock
+    next_ts: AtomicU64,         // 書き込みトランザクションの開始タイムスタンプ
+    policy: ContentionPolicy,   // ロック競合時の方針
 
     // アドレスからストライプ番号に変換するシフト量
     // ストライプサイズが1バイトならメモリとストライプは1対1なのでシフト量0
@@ -38,21 +58,36 @@ impl Memory {
 
         // lock&version を初期化
         let mut lock_ver = Vec::new();
+        let mut lock_ts = Vec::new();
+        let mut contention = Vec::new();
 
         // MEM_SIZE >> shift
         // メモリサイズをストライプサイズで割ってることになる(ストライプが2冪の場合)
         for _ in 0..MEM_SIZE >> shift {
             lock_ver.push(AtomicU64::new(0));
+            lock_ts.push(AtomicU64::new(0));
+            contention.push(AtomicU64::new(0));
         }
 
         Memory {
             mem,
             lock_ver,
+            lock_ts,
+            contention,
             global_clock: AtomicU64::new(0),
+            next_ts: AtomicU64::new(1),
+            policy: ContentionPolicy::Retry,
             shift_size: shift,
         }
     }
 
+    // 書き込みトランザクションの開始タイムスタンプを発行
+    // global version-clock はコミットしか進めず、同時に開始したトランザクションで値が重なるため、
+    // 優先度の比較には一意な値を別に発行する
+    fn new_ts(&self) -> u64 {
+        self.next_ts.fetch_add(1, Ordering::Relaxed)
+    }
+
     // global version-clock をインクリメント
     fn inc_global_clock(&mut self) -> u64 {
         self.global_clock.fetch_add(1, Ordering::AcqRel)
@@ -95,16 +130,92 @@ impl Memory {
             .is_ok()
     }
 
+    // 対象アドレスのロックを獲得し、ロックしたトランザクションの開始タイムスタンプを記録
+    // 失敗した場合は、ロックしているトランザクションの開始タイムスタンプをリターン
+    // 解放と獲得の間に読むと古い値となることがあるが、優先度の判定に用いるだけなので問題ない
+    fn lock_addr_ts(&mut self, addr: usize, ts: u64) -> Result<(), u64> {
+        let idx = addr >> self.shift_size;
+        if self.lock_addr(addr) {
+            self.lock_ts[idx].store(ts, Ordering::Relaxed);
+            Ok(())
+        } else {
+            Err(self.lock_ts[idx].load(Ordering::Relaxed))
+        }
+    }
+
+    // 対象アドレスのストライプの競合回数をインクリメント
+    // 統計のみに用いるため Relaxed で十分
+    fn record_contention(&self, addr: usize) {
+        let idx = addr >> self.shift_size;
+        self.contention[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // 競合が発生したストライプの番号と競合回数を、競合回数の多い順にリターン
+    // ロックの獲得失敗と read-set の検証失敗を競合として数える
+    #[allow(dead_code)] // main では使用しない
+    pub fn hot_stripes(&self) -> Vec<(usize, u64)> {
+        let mut v: Vec<_> = self
+            .contention
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .enumerate()
+            .filter(|(_, n)| *n > 0)
+            .collect();
+        // 回数が同じ場合はストライプ番号順
+        v.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        v
+    }
+
     // 対象アドレスのロックを解放
     fn unlock_addr(&mut self, addr: usize) {
         let idx = addr >> self.shift_size;
-        self.lock_ver[idx].fetch_add(!(1 << 63), Ordering::Relaxed);
+        self.lock_ver[idx].fetch_and(!(1 << 63), Ordering::Relaxed);
+    }
+
+    // メモリの内容と各ストライプのバージョン、global version-clock をバイト列にコピー
+    // レイアウトは mem (MEM_SIZE バイト)、各ストライプのバージョン、global version-clock の順で、
+    // バージョンと global version-clock はそれぞれ 8 バイトのリトルエンディアン
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::snapshot_len());
+        buf.extend_from_slice(&self.mem);
+        for lv in self.lock_ver.iter() {
+            let n = lv.load(Ordering::Relaxed);
+            // トランザクションの実行中に呼び出すとロック中のストライプが残る
+            assert_eq!(n & (1 << 63), 0, "snapshot: a stripe is locked");
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.global_clock.load(Ordering::Acquire).to_le_bytes());
+        buf
+    }
+
+    // snapshot で作成したバイト列から、メモリの内容とバージョンを復元
+    // 実行中のトランザクションがない状態で呼び出す必要がある
+    pub fn restore(&mut self, buf: &[u8]) {
+        assert_eq!(buf.len(), Self::snapshot_len(), "restore: invalid length");
+        let (mem, rest) = buf.split_at(MEM_SIZE);
+        let (vers, clock) = rest.split_at(self.lock_ver.len() * 8);
+        let clock = u64::from_le_bytes(clock.try_into().unwrap());
+
+        for (lv, ver) in self.lock_ver.iter_mut().zip(vers.chunks_exact(8)) {
+            let ver = u64::from_le_bytes(ver.try_into().unwrap());
+            // ロック中や global version-clock より新しいバージョンは不正
+            assert!(ver <= clock, "restore: invalid version");
+            *lv.get_mut() = ver;
+        }
+        self.mem.copy_from_slice(mem);
+        *self.global_clock.get_mut() = clock;
+    }
+
+    // snapshot のバイト数
+    fn snapshot_len() -> usize {
+        MEM_SIZE + (MEM_SIZE / STRIPE_SIZE) * 8 + 8
     }
 }
 
 pub struct ReadTrans<'a> {
-    read_ver: u64,  // read-version
-    is_abort: bool, // 競合を検知した場合に true
+    read_ver: u64,                // read-version
+    is_abort: bool,               // 競合を検知した場合に true
+    read_set: Option<Vec<usize>>, // read_snapshot の場合のみ、読み込んだアドレスを記録
     mem: &'a Memory,
 }
 
@@ -112,6 +223,7 @@ impl<'a> ReadTrans<'a> {
     fn new(mem: &'a Memory) -> Self {
         ReadTrans {
             is_abort: false,
+            read_set: None,
             // global version-clock 読み込み
             read_ver: mem.global_clock.load(Ordering::Acquire),
 
@@ -155,12 +267,27 @@ impl<'a> ReadTrans<'a> {
             return None;
         }
 
+        if let Some(read_set) = self.read_set.as_mut() {
+            read_set.push(addr);
+        }
         Some(mem)
     }
+
+    // 読み込んだすべてのアドレスが、ロックされておらず read-version 以下のままか検証
+    fn validate_read_set(&self) -> bool {
+        self.read_set.iter().flatten().all(|addr| {
+            let ok = self.mem.test_not_modify(*addr, self.read_ver);
+            if !ok {
+                self.mem.record_contention(*addr);
+            }
+            ok
+        })
+    }
 }
 
 pub struct WriteTrans<'a> {
     read_ver: u64,                                // read-version
+    ts: u64,                                      // 開始タイムスタンプ。小さいほど古い
     read_set: HashSet<usize>,                     // read-set
     write_set: HashMap<usize, [u8; STRIPE_SIZE]>, // write-set
     locked: Vec<usize>,                           // ロック済みアドレス
@@ -178,8 +305,9 @@ impl<'a> Drop for WriteTrans<'a> {
 }
 
 impl<'a> WriteTrans<'a> {
-    fn new(mem: &'a mut Memory) -> Self {
+    fn new(mem: &'a mut Memory, ts: u64) -> Self {
         WriteTrans {
+            ts,
             read_set: HashSet::new(),
             write_set: HashMap::new(),
             locked: Vec::new(),
@@ -200,6 +328,20 @@ impl<'a> WriteTrans<'a> {
         self.write_set.insert(addr, val);
     }
 
+    // addr から data を書き込む。複数のストライプにまたがってもよい
+    // data の長さがストライプサイズの倍数でない場合、最後のストライプの残りのバイトは 0 とする
+    #[allow(dead_code)] // main では使用しない
+    pub fn store_bytes(&mut self, addr: usize, data: &[u8]) {
+        // アドレスがストライプのアラインメントに沿っており、メモリに収まるかチェック
+        assert_eq!(addr & (STRIPE_SIZE - 1), 0);
+        assert!(addr + data.len() <= MEM_SIZE);
+        for (i, chunk) in data.chunks(STRIPE_SIZE).enumerate() {
+            let mut val = [0; STRIPE_SIZE];
+            val[..chunk.len()].copy_from_slice(chunk);
+            self.store(addr + i * STRIPE_SIZE, val);
+        }
+    }
+
     // メモリ読み込み関数
     pub fn load(&mut self, addr: usize) -> Option<[u8; STRIPE_SIZE]> {
         // 競合を検知したら終了
@@ -249,10 +391,11 @@ impl<'a> WriteTrans<'a> {
     // write-set 中のアドレスをロック
     // すべてのアドレスをロックで獲得できた場合は真をリターンする
     fn lock_write_set(&mut self) -> bool {
-        for (addr, _) in self.write_set.iter() {
-            if self.mem.lock_addr(*addr) {
+        let addrs: Vec<usize> = self.write_set.keys().copied().collect();
+        for addr in addrs {
+            if self.lock_contended(addr) {
                 // ロックが獲得できた場合は、locked に追加
-                self.locked.push(*addr);
+                self.locked.push(addr);
             } else {
                 // できなかった場合は false を返す
                 return false;
@@ -261,6 +404,34 @@ impl<'a> WriteTrans<'a> {
         true
     }
 
+    // addr のロックを獲得
+    // 競合した場合、ロックしているトランザクションより自身が古ければ、ポリシーに従ってしばらく待つ
+    // 若ければ即座に諦める
+    fn lock_contended(&mut self, addr: usize) -> bool {
+        let owner = match self.mem.lock_addr_ts(addr, self.ts) {
+            Ok(()) => return true,
+            Err(owner) => owner,
+        };
+        // 待って獲得できた場合も競合として数える
+        self.mem.record_contention(addr);
+
+        let ContentionPolicy::OlderWaits { limit } = self.mem.policy else {
+            return false;
+        };
+        if self.ts > owner {
+            return false;
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < limit {
+            std::thread::yield_now();
+            if self.mem.lock_addr_ts(addr, self.ts).is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+
     // read-set の検証
     fn validate_read_set(&self) -> bool {
         for addr in self.read_set.iter() {
@@ -270,11 +441,13 @@ impl<'a> WriteTrans<'a> {
                 // バージョンのみ検査
                 let ver = self.mem.get_addr_ver(*addr);
                 if ver > self.read_ver {
+                    self.mem.record_contention(*addr);
                     return false;
                 }
             } else {
                 // 他スレッドがロックしてないかバージョンチェック
                 if !self.mem.test_not_modify(*addr, self.read_ver) {
+                    self.mem.record_contention(*addr);
                     return false;
                 }
             }
@@ -305,12 +478,84 @@ impl<'a> WriteTrans<'a> {
     }
 }
 
+// ストライプの読み込み
+// ReadTrans と WriteTrans のどちらからでも TArray を読み込めるようにする
+pub trait Load {
+    fn load(&mut self, addr: usize) -> Option<[u8; STRIPE_SIZE]>;
+}
+
+impl Load for ReadTrans<'_> {
+    fn load(&mut self, addr: usize) -> Option<[u8; STRIPE_SIZE]> {
+        ReadTrans::load(self, addr)
+    }
+}
+
+impl Load for WriteTrans<'_> {
+    fn load(&mut self, addr: usize) -> Option<[u8; STRIPE_SIZE]> {
+        WriteTrans::load(self, addr)
+    }
+}
+
+// 連続する N 個のストライプに、T 型の値を1つずつ格納する配列
+// 1要素が1ストライプに対応するため、T のサイズはストライプサイズ以下である必要がある
+#[allow(dead_code)] // main では使用しない
+pub struct TArray<T: Copy, const N: usize> {
+    base: usize, // 先頭のストライプのアドレス
+    _marker: PhantomData<T>,
+}
+
+#[allow(dead_code)] // main では使用しない
+impl<T: Copy, const N: usize> TArray<T, N> {
+    // base から N 個のストライプを使用する
+    // 安全性: T はパディングを含まず、各ストライプの先頭には set で書き込んだ値か、
+    // T として有効なビット列 (初期状態の 0 など) が入っていること
+    pub unsafe fn new(base: usize) -> Self {
+        assert!(std::mem::size_of::<T>() <= STRIPE_SIZE);
+        // アドレスがストライプのアラインメントに沿っており、メモリに収まるかチェック
+        assert_eq!(base & (STRIPE_SIZE - 1), 0);
+        assert!(base + N * STRIPE_SIZE <= MEM_SIZE);
+        TArray {
+            base,
+            _marker: PhantomData,
+        }
+    }
+
+    // i 番目の要素のストライプのアドレス
+    fn addr(&self, i: usize) -> usize {
+        assert!(
+            i < N,
+            "index out of bounds: the len is {} but the index is {}",
+            N,
+            i
+        );
+        self.base + i * STRIPE_SIZE
+    }
+
+    // i 番目の要素を読み込む
+    // 競合を検知した場合は None
+    pub fn get<L: Load>(&self, tr: &mut L, i: usize) -> Option<T> {
+        let val = tr.load(self.addr(i))?;
+        // new の条件により、ストライプの先頭には T の値が入っている
+        Some(unsafe { std::ptr::read_unaligned(val.as_ptr() as *const T) })
+    }
+
+    // i 番目の要素に書き込む
+    // ストライプの残りのバイトは 0 とする
+    pub fn set(&self, tr: &mut WriteTrans, i: usize, v: T) {
+        let mut val = [0; STRIPE_SIZE];
+        unsafe { std::ptr::write_unaligned(val.as_mut_ptr() as *mut T, v) };
+        tr.store(self.addr(i), val);
+    }
+}
+
 pub enum STMResult<T> {
     Ok(T),
     Retry, // トランザクションをリトライ
+    #[allow(dead_code)] // main では使用しない
     Abort, // トランザクションを中止
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct STM {
     mem: UnsafeCell<Memory>, // 実際のメモリ
 }
@@ -326,7 +571,18 @@ impl STM {
         }
     }
 
+    // ロック競合時の方針を指定して生成
+    #[allow(dead_code)] // main では使用しない
+    pub fn with_policy(policy: ContentionPolicy) -> Self {
+        let mut mem = Memory::new();
+        mem.policy = policy;
+        STM {
+            mem: UnsafeCell::new(mem),
+        }
+    }
+
     // 読み込みトランザクション
+    #[allow(dead_code)] // main では使用しない
     pub fn read_transaction<F, R>(&self, f: F) -> Option<R>
     where
         F: Fn(&mut ReadTrans) -> STMResult<R>,
@@ -355,18 +611,74 @@ impl STM {
         }
     }
 
+    // 読み込みトランザクションと同様に実行し、終了時に読み込んだすべてのストライプを read-version で再度検証する
+    // 各読み込み時の検証に加えて、全体が read-version 時点の一貫したスナップショットであることを保証する
+    pub fn read_snapshot<F, R>(&self, f: F) -> Option<R>
+    where
+        F: Fn(&mut ReadTrans) -> STMResult<R>,
+    {
+        loop {
+            // 1. global version-clock 読み込み
+            let mut tr = ReadTrans::new(unsafe { &*self.mem.get() });
+            tr.read_set = Some(Vec::new());
+
+            // 2. 投機的実行
+            match f(&mut tr) {
+                STMResult::Abort => return None,
+                STMResult::Retry => {
+                    if tr.is_abort {
+                        continue;
+                    }
+                    return None;
+                }
+                STMResult::Ok(val) => {
+                    // 3. read-set の検証
+                    if tr.is_abort || !tr.validate_read_set() {
+                        continue;
+                    }
+                    return Some(val);
+                }
+            }
+        }
+    }
+
+    // 競合の多いストライプを、ストライプ番号と競合回数の組で競合回数の多い順にリターン
+    #[allow(dead_code)] // main では使用しない
+    pub fn hot_stripes(&self) -> Vec<(usize, u64)> {
+        unsafe { &*self.mem.get() }.hot_stripes()
+    }
+
+    // global version-clock の現在の値
+    // 書き込みトランザクションがコミットするたびに増加する
+    #[allow(dead_code)] // main では使用しない
+    pub fn global_version(&self) -> u64 {
+        unsafe { &*self.mem.get() }
+            .global_clock
+            .load(Ordering::Acquire)
+    }
+
     // 書き込みトランザクション
     pub fn write_transaction<F, R>(&self, f: F) -> Option<R>
     where
         F: Fn(&mut WriteTrans) -> STMResult<R>,
     {
+        self.write_transaction_versioned(f).map(|(val, _)| val)
+    }
+
+    // 書き込みトランザクションを実行し、結果とコミット時のバージョンをリターン
+    // バージョンはコミットしたトランザクションの順序を表すため、外部への副作用の順序付けに用いることができる
+    pub fn write_transaction_versioned<F, R>(&self, f: F) -> Option<(R, u64)>
+    where
+        F: Fn(&mut WriteTrans) -> STMResult<R>,
+    {
+        // リトライしても同じ開始タイムスタンプを用いるため、アボートを繰り返すほど相対的に古くなり優先される
+        let ts = unsafe { &*self.mem.get() }.new_ts();
         loop {
             // 1. global version-clock 読み込み
-            let mut tr = WriteTrans::new(unsafe { &mut *self.mem.get() });
+            let mut tr = WriteTrans::new(unsafe { &mut *self.mem.get() }, ts);
 
             // 2. 投機的実行
-            let result;
-            match f(&mut tr) {
+            let result = match f(&mut tr) {
                 STMResult::Abort => return None,
                 STMResult::Retry => {
                     if tr.is_abort {
@@ -378,10 +690,409 @@ impl STM {
                     if tr.is_abort {
                         continue;
                     }
-                    result = val;
+                    val
                 }
+            };
+
+            // 3. write-set のロック
+            // 競合時は開始タイムスタンプの古いトランザクションを優先する
+            if !tr.lock_write_set() {
+                continue;
+            }
+
+            // 4. global version-clock のインクリメント
+            let ver = 1 + tr.mem.inc_global_clock();
+
+            // 5. read-set の検証
+            // read-version の直後のバージョンであれば、他のトランザクションはコミットしていないため検証不要
+            if tr.read_ver + 1 != ver && !tr.validate_read_set() {
+                continue;
+            }
+
+            // 6. コミットとリリース
+            tr.commit(ver);
+
+            return Some((result, ver));
+        }
+    }
+
+    // メモリの内容のスナップショットを取得
+    // 他のスレッドと共有している間は &mut を得られないため、トランザクションの実行中には呼び出せない
+    #[allow(dead_code)] // main では使用しない
+    pub fn snapshot(&mut self) -> Vec<u8> {
+        self.mem.get_mut().snapshot()
+    }
+
+    // snapshot で取得した状態にメモリを復元
+    #[allow(dead_code)] // main では使用しない
+    pub fn restore(&mut self, buf: &[u8]) {
+        self.mem.get_mut().restore(buf);
+    }
+
+    // addr のストライプを読み込み、f を適用した値を書き込む
+    // 競合した場合はリトライするため、f は複数回呼び出される可能性がある
+    // 書き込んだ値をリターン
+    #[allow(dead_code)] // main では使用しない
+    pub fn update<F>(&self, addr: usize, f: F) -> [u8; STRIPE_SIZE]
+    where
+        F: Fn([u8; STRIPE_SIZE]) -> [u8; STRIPE_SIZE],
+    {
+        self.write_transaction(|tr| {
+            let Some(val) = tr.load(addr) else {
+                // 競合を検知した場合はリトライ
+                return STMResult::Retry;
+            };
+            let val = f(val);
+            tr.store(addr, val);
+            STMResult::Ok(val)
+        })
+        .unwrap() // Abort しないため必ず成功する
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_update() {
+        const NUM_THREADS: usize = 8;
+        const NUM_LOOP: u64 = 10000;
+        let stm = Arc::new(STM::new());
+
+        // 複数のスレッドから同じストライプのカウンタをインクリメント
+        let v: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let stm = stm.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..NUM_LOOP {
+                        stm.update(8, |val| (u64::from_le_bytes(val) + 1).to_le_bytes());
+                    }
+                })
+            })
+            .collect();
+        for t in v {
+            t.join().unwrap();
+        }
+
+        let n = stm.update(8, |val| val);
+        assert_eq!(u64::from_le_bytes(n), NUM_THREADS as u64 * NUM_LOOP);
+        // 隣のストライプは変更されない
+        assert_eq!(stm.update(0, |val| val), [0; STRIPE_SIZE]);
+    }
+
+    #[test]
+    fn test_tarray() {
+        let stm = STM::new();
+        let arr: TArray<u32, 4> = unsafe { TArray::new(16) };
+
+        // 1つのトランザクションで複数の要素を書き換える
+        stm.write_transaction(|tr| {
+            for i in 0..4 {
+                let Some(v) = arr.get(tr, i) else {
+                    return STMResult::Retry;
+                };
+                arr.set(tr, i, v + i as u32 * 10);
             }
-            todo!()
+            STMResult::Ok(())
+        });
+        stm.write_transaction(|tr| {
+            arr.set(tr, 0, u32::MAX);
+            arr.set(tr, 3, 7);
+            STMResult::Ok(())
+        });
+
+        let v = stm
+            .read_transaction(|tr| {
+                let mut v = [0; 4];
+                for (i, x) in v.iter_mut().enumerate() {
+                    let Some(y) = arr.get(tr, i) else {
+                        return STMResult::Retry;
+                    };
+                    *x = y;
+                }
+                STMResult::Ok(v)
+            })
+            .unwrap();
+        assert_eq!(v, [u32::MAX, 10, 20, 7]);
+
+        // 各要素は別のストライプに配置され、前後のストライプは変更されない
+        assert_eq!(stm.update(16 + 8, |val| val), [10, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(stm.update(8, |val| val), [0; STRIPE_SIZE]);
+        assert_eq!(stm.update(48, |val| val), [0; STRIPE_SIZE]);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn test_tarray_out_of_bounds() {
+        let stm = STM::new();
+        let arr: TArray<u8, 2> = unsafe { TArray::new(0) };
+        stm.read_transaction(|tr| STMResult::Ok(arr.get(tr, 2)));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut stm = STM::new();
+        for addr in [0, 8, 64] {
+            stm.update(addr, |_| (addr as u64 + 1).to_le_bytes());
         }
+        let snapshot = stm.snapshot();
+
+        // スナップショットの取得後に変更
+        stm.update(8, |_| [0xff; STRIPE_SIZE]);
+        stm.update(16, |_| [1; STRIPE_SIZE]);
+
+        stm.restore(&snapshot);
+        for addr in [0, 8, 64] {
+            let val = stm.update(addr, |val| val);
+            assert_eq!(u64::from_le_bytes(val), addr as u64 + 1);
+        }
+        assert_eq!(stm.update(16, |val| val), [0; STRIPE_SIZE]);
+
+        // 復元後もトランザクションは実行できる
+        let n = stm.update(8, |val| (u64::from_le_bytes(val) + 1).to_le_bytes());
+        assert_eq!(u64::from_le_bytes(n), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "restore: invalid length")]
+    fn test_restore_invalid_length() {
+        let mut stm = STM::new();
+        stm.restore(&[0; 8]);
+    }
+
+    #[test]
+    fn test_store_bytes() {
+        let stm = STM::new();
+        let data: Vec<u8> = (1..=24).collect();
+        stm.write_transaction(|tr| {
+            tr.store_bytes(8, &data);
+            // 端数は 0 で埋められる
+            tr.store_bytes(40, &[0xff; 3]);
+            STMResult::Ok(())
+        });
+
+        // 3 つのストライプにまたがって書き込まれている
+        let v = stm
+            .read_transaction(|tr| {
+                let mut v = Vec::new();
+                for addr in (8..32).step_by(STRIPE_SIZE) {
+                    let Some(val) = tr.load(addr) else {
+                        return STMResult::Retry;
+                    };
+                    v.extend_from_slice(&val);
+                }
+                STMResult::Ok(v)
+            })
+            .unwrap();
+        assert_eq!(v, data);
+        assert_eq!(stm.update(40, |val| val), [0xff, 0xff, 0xff, 0, 0, 0, 0, 0]);
+        assert_eq!(stm.update(0, |val| val), [0; STRIPE_SIZE]);
+        assert_eq!(stm.update(32, |val| val), [0; STRIPE_SIZE]);
+    }
+
+    #[test]
+    fn test_global_version() {
+        let stm = STM::new();
+        assert_eq!(stm.global_version(), 0);
+        stm.update(0, |val| val);
+        stm.update(8, |val| val);
+        assert_eq!(stm.global_version(), 2);
+        // 読み込みトランザクションでは変化しない
+        stm.read_snapshot(|tr| STMResult::Ok(tr.load(0)));
+        assert_eq!(stm.global_version(), 2);
+    }
+
+    #[test]
+    fn test_read_snapshot() {
+        const NUM_PHILOSOPHERS: usize = 4;
+        const NUM_LOOP: usize = 2000;
+        let stm = Arc::new(STM::new());
+
+        // 隣り合う 2 本の箸を同時に取り上げ、同時に置く
+        let v: Vec<_> = (0..NUM_PHILOSOPHERS)
+            .map(|n| {
+                let stm = stm.clone();
+                std::thread::spawn(move || {
+                    let left = 8 * n;
+                    let right = 8 * ((n + 1) % NUM_PHILOSOPHERS);
+                    for i in 0..NUM_LOOP {
+                        let up = (i % 2 == 0) as u8;
+                        stm.write_transaction(|tr| {
+                            let (Some(mut f1), Some(mut f2)) = (tr.load(left), tr.load(right))
+                            else {
+                                return STMResult::Retry;
+                            };
+                            if up == 1 && (f1[0] == 1 || f2[0] == 1) {
+                                return STMResult::Ok(());
+                            }
+                            if up == 0 && (f1[0] == 0 || f2[0] == 0) {
+                                return STMResult::Ok(());
+                            }
+                            f1[0] = up;
+                            f2[0] = up;
+                            tr.store(left, f1);
+                            tr.store(right, f2);
+                            STMResult::Ok(())
+                        });
+                    }
+                })
+            })
+            .collect();
+
+        // 取り上げられている箸は常に偶数本に見える
+        for _ in 0..NUM_LOOP {
+            let n = stm
+                .read_snapshot(|tr| {
+                    let mut n = 0;
+                    for i in 0..NUM_PHILOSOPHERS {
+                        let Some(val) = tr.load(8 * i) else {
+                            return STMResult::Retry;
+                        };
+                        n += val[0] as usize;
+                    }
+                    STMResult::Ok(n)
+                })
+                .unwrap();
+            assert_eq!(n % 2, 0);
+        }
+
+        for t in v {
+            t.join().unwrap();
+        }
+    }
+
+    // 開始タイムスタンプ holder のトランザクションがストライプ 0 をロックしている間に、
+    // 別スレッドの書き込みトランザクションが同じストライプに書き込み、アボートした回数をリターン
+    // holder は 10 ミリ秒後にロックを解放する
+    fn count_aborts(policy: ContentionPolicy, holder: u64) -> u64 {
+        let stm = STM::with_policy(policy);
+        // 他のスレッドが Memory への参照を持つ前にロックし、ロック用の変数への参照のみを残す
+        let lock_ver = {
+            let mem = unsafe { &mut *stm.mem.get() };
+            assert!(mem.lock_addr_ts(0, holder).is_ok());
+            &mem.lock_ver[0]
+        };
+        let calls = AtomicU64::new(0);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                stm.write_transaction(|tr| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    tr.store(0, [1; STRIPE_SIZE]);
+                    STMResult::Ok(())
+                })
+                .unwrap();
+            });
+            std::thread::sleep(Duration::from_millis(10));
+            // unlock_addr と同様に解放
+            lock_ver.fetch_and(!(1 << 63), Ordering::Relaxed);
+        });
+
+        // コミットした1回以外はアボート
+        calls.load(Ordering::Relaxed) - 1
+    }
+
+    #[test]
+    fn test_contention_aborts() {
+        let wait = ContentionPolicy::OlderWaits {
+            limit: Duration::from_secs(10),
+        };
+        // write_transaction の開始タイムスタンプは 1 から順に発行されるため、
+        // holder が 0 なら書き込み側が若く、u64::MAX なら書き込み側が古い
+        let (older, younger) = (0, u64::MAX);
+
+        // Retry では、解放されるまでアボートを繰り返す
+        assert!(count_aborts(ContentionPolicy::Retry, older) > 0);
+        assert!(count_aborts(ContentionPolicy::Retry, younger) > 0);
+        // OlderWaits では、古いトランザクションはアボートせずに解放を待つ
+        assert!(count_aborts(wait, older) > 0);
+        assert_eq!(count_aborts(wait, younger), 0);
+    }
+
+    #[test]
+    fn test_update_older_waits() {
+        const NUM_THREADS: usize = 4;
+        const NUM_LOOP: u64 = 1000;
+        let stm = Arc::new(STM::with_policy(ContentionPolicy::OlderWaits {
+            limit: Duration::from_millis(1),
+        }));
+
+        let v: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let stm = stm.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..NUM_LOOP {
+                        stm.update(0, |val| (u64::from_le_bytes(val) + 1).to_le_bytes());
+                    }
+                })
+            })
+            .collect();
+        for t in v {
+            t.join().unwrap();
+        }
+
+        let n = stm.update(0, |val| val);
+        assert_eq!(u64::from_le_bytes(n), NUM_THREADS as u64 * NUM_LOOP);
+    }
+
+    #[test]
+    fn test_hot_stripes() {
+        let stm = STM::new();
+        assert!(stm.hot_stripes().is_empty());
+
+        // 他のトランザクションがロックしている間に、同じストライプのロックを試みる
+        // Memory への &mut が同時に2つ存在しないよう、ロックしているトランザクションはロックのみで表す
+        let lock_and_try = |addr: usize, n: usize| {
+            for _ in 0..n {
+                let mem = unsafe { &mut *stm.mem.get() };
+                assert!(mem.lock_addr_ts(addr, 1).is_ok());
+                let mut c = WriteTrans::new(mem, 2);
+                c.store(addr, [2; STRIPE_SIZE]);
+                assert!(!c.lock_write_set());
+                c.mem.unlock_addr(addr);
+            }
+        };
+        lock_and_try(8, 5);
+        lock_and_try(64, 2);
+
+        // 読み込んだストライプが他のトランザクションに更新され、read-set の検証に失敗
+        {
+            let mut tr = WriteTrans::new(unsafe { &mut *stm.mem.get() }, 3);
+            tr.load(64).unwrap();
+            tr.store(128, [1; STRIPE_SIZE]);
+            // 他のトランザクションが 64 のストライプにコミットしたものとして、バージョンを進める
+            let ver = 1 + tr.mem.inc_global_clock();
+            tr.mem.lock_ver[64 >> tr.mem.shift_size].store(ver, Ordering::Relaxed);
+            assert!(tr.lock_write_set());
+            assert!(!tr.validate_read_set());
+        }
+
+        assert_eq!(stm.hot_stripes(), vec![(1, 5), (8, 3)]);
+    }
+
+    #[test]
+    fn test_write_transaction_versioned() {
+        let stm = STM::new();
+        let write = |v: u8| {
+            stm.write_transaction_versioned(|tr| {
+                tr.store(0, [v; STRIPE_SIZE]);
+                STMResult::Ok(v)
+            })
+            .unwrap()
+        };
+
+        let (r1, v1) = write(1);
+        let (r2, v2) = write(2);
+        assert_eq!((r1, r2), (1, 2));
+        assert!(v2 > v1);
+        // コミット時のバージョンは global version-clock の値となる
+        assert_eq!(v2, stm.global_version());
+
+        // 中止したトランザクションはバージョンを持たない
+        assert!(stm
+            .write_transaction_versioned(|_| STMResult::<()>::Abort)
+            .is_none());
     }
 }
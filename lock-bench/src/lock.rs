@@ -0,0 +1,128 @@
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+
+use crate::bakery::{BakeryLock, BakeryLockGuard};
+use crate::mcs::{MCSLock, MCSLockGuard, MCSNode};
+use crate::spinlock::{SpinLock, SpinLockGuard};
+
+// 各章のロックを同じように扱うためのトレイト
+// lock でロックを獲得し、ガードが破棄されると解放する
+// ジェネリックなコードやベンチマークで、ロックの実装を差し替えられる
+pub trait Locking<T> {
+    type Guard<'a>: DerefMut<Target = T>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> Self::Guard<'_>;
+}
+
+impl<T> Locking<T> for SpinLock<T> {
+    type Guard<'a>
+        = SpinLockGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> Self::Guard<'_> {
+        SpinLock::lock(self)
+    }
+}
+
+// スレッド番号は、呼び出したスレッドごとに空いているスロットを割り当てる
+impl<T, const N: usize> Locking<T> for BakeryLock<T, N> {
+    type Guard<'a>
+        = BakeryLockGuard<'a, T, N>
+    where
+        T: 'a;
+
+    fn lock(&self) -> Self::Guard<'_> {
+        self.lock_current()
+    }
+}
+
+// MCSLock のノードをヒープに確保し、ガードと一緒に保持する
+// ノードはガードが参照しているため、ガードを先に破棄してから解放する
+pub struct MCSGuard<'a, T> {
+    guard: ManuallyDrop<MCSLockGuard<'a, T>>,
+    node: *mut MCSNode<T>,
+}
+
+impl<T> Locking<T> for MCSLock<T> {
+    type Guard<'a>
+        = MCSGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> Self::Guard<'_> {
+        let node = Box::into_raw(Box::new(MCSNode::new()));
+        // node は MCSGuard の破棄時まで解放しない
+        let guard = MCSLock::lock(self, unsafe { &mut *node });
+        MCSGuard {
+            guard: ManuallyDrop::new(guard),
+            node,
+        }
+    }
+}
+
+impl<'a, T> Drop for MCSGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            drop(Box::from_raw(self.node));
+        }
+    }
+}
+
+impl<'a, T> Deref for MCSGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for MCSGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    // 読み込みと書き込みの間に実行を譲り、排他されていなければ更新が失われるようにする
+    fn test_lock<L: Locking<u64> + Sync>(lock: &L) {
+        const NUM_THREADS: usize = 3;
+        const NUM_LOOP: usize = 100;
+        thread::scope(|s| {
+            for _ in 0..NUM_THREADS {
+                s.spawn(|| {
+                    for _ in 0..NUM_LOOP {
+                        let mut data = lock.lock();
+                        let v = *data;
+                        thread::yield_now();
+                        *data = v + 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), (NUM_THREADS * NUM_LOOP) as u64);
+    }
+
+    #[test]
+    fn test_spinlock() {
+        test_lock(&SpinLock::new(0));
+    }
+
+    #[test]
+    fn test_mcslock() {
+        test_lock(&MCSLock::new(0));
+    }
+
+    #[test]
+    fn test_bakery_lock() {
+        // ワーカーとメインスレッドの分のスロット
+        test_lock(&BakeryLock::<_, 4>::new(0));
+    }
+}
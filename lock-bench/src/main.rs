@@ -0,0 +1,79 @@
+// 各章のロックを同じワークロードで比較するベンチマーク
+// cargo run --release -- [NUM_LOOP] で実行する
+// 各ロックの実装は、それぞれの章のソースファイルをそのまま取り込む
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bakery::BakeryLock;
+use lock::Locking;
+use mcs::MCSLock;
+use spinlock::SpinLock;
+
+// 取り込んだロックの API のうち、ベンチマークでは一部のみを使用する
+#[allow(dead_code)]
+#[path = "../../chap3/ch3_bakery/src/bakery.rs"]
+mod bakery;
+mod lock;
+#[allow(dead_code)]
+#[path = "../../chap7/mcslock/src/mcs.rs"]
+mod mcs;
+#[allow(dead_code)]
+#[path = "../../chap4/ch4_barrier/src/spinlock.rs"]
+mod spinlock;
+
+const NUM_THREADS: usize = 4;
+const NUM_LOOP: usize = 100000;
+
+// NUM_THREADS 個のスレッドから、それぞれ num_loop 回カウンタをインクリメントし、経過時間をリターン
+// すべての更新が反映されていない場合は panic
+fn bench<L: Locking<u64> + Sync>(lock: L, num_loop: usize) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..NUM_THREADS {
+            s.spawn(|| {
+                for _ in 0..num_loop {
+                    let mut data = lock.lock();
+                    *data += 1;
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+
+    assert_eq!(*lock.lock(), (NUM_THREADS * num_loop) as u64);
+    elapsed
+}
+
+fn report(name: &str, elapsed: Duration, num_loop: usize) {
+    let ops = (NUM_THREADS * num_loop) as f64 / elapsed.as_secs_f64();
+    println!("{:<12} {:>12.3?} {:>14.0} ops/s", name, elapsed, ops);
+}
+
+fn main() {
+    let num_loop = std::env::args()
+        .nth(1)
+        .map(|s| s.parse().expect("NUM_LOOP must be a number"))
+        .unwrap_or(NUM_LOOP);
+    println!("threads = {}, loops = {}", NUM_THREADS, num_loop);
+
+    report("SpinLock", bench(SpinLock::new(0), num_loop), num_loop);
+    report("MCSLock", bench(MCSLock::new(0), num_loop), num_loop);
+    // ワーカーに加えて、最後に値を確認するメインスレッドの分のスロットが必要
+    report(
+        "BakeryLock",
+        bench(BakeryLock::<_, { NUM_THREADS + 1 }>::new(0), num_loop),
+        num_loop,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bench() {
+        bench(SpinLock::new(0), 100);
+        bench(MCSLock::new(0), 100);
+        bench(BakeryLock::<_, { NUM_THREADS + 1 }>::new(0), 100);
+    }
+}
@@ -0,0 +1,9 @@
+#!/bin/sh
+# loom によるモデル検査を実行する
+# アトミック変数を loom のものに差し替えてビルドするため、通常のビルドとは別のディレクトリに出力する
+set -eu
+
+cd "$(dirname "$0")"
+for dir in chap4/ch4_barrier chap7/mcslock; do
+    (cd "$dir" && RUSTFLAGS="--cfg loom" CARGO_TARGET_DIR=target/loom cargo test --release loom)
+done
@@ -0,0 +1,240 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+};
+
+// BroadcastSender::send のエラー。送信できなかったデータを返す
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T); // BroadcastReceiver がすべて破棄された
+
+impl<T> std::fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sending on a channel with no receivers")
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for SendError<T> {}
+
+// BroadcastReceiver::recv のエラー
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    Lagged(u64),  // 受信が遅れ、古いデータが上書きされた。値は受信できなかった数
+    Disconnected, // 未受信のデータがなく、BroadcastSender がすべて破棄された
+}
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecvError::Lagged(n) => write!(f, "receiver lagged by {} messages", n),
+            RecvError::Disconnected => write!(f, "receiving on a disconnected channel"),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+struct State<T> {
+    buf: VecDeque<T>, // 直近 cap 個のデータ
+    head: u64,        // buf の先頭のデータの通し番号
+    senders: usize,   // 生存している BroadcastSender の数
+    receivers: usize, // 生存している BroadcastReceiver の数
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    cond: Condvar,
+    cap: usize,
+}
+
+// すべての BroadcastReceiver へデータを送信する
+// 送信はブロックせず、バッファが一杯の場合は最も古いデータを上書きする
+pub struct BroadcastSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> BroadcastSender<T> {
+    // 送信時点で生存している BroadcastReceiver の数をリターン
+    pub fn send(&self, data: T) -> Result<usize, SendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(data));
+        }
+        state.buf.push_back(data);
+        if state.buf.len() > self.shared.cap {
+            // 受信していない BroadcastReceiver は recv で Lagged を受け取る
+            state.buf.pop_front();
+            state.head += 1;
+        }
+        let n = state.receivers;
+        self.shared.cond.notify_all();
+        Ok(n)
+    }
+
+    // 以降に送信されたデータを受信する BroadcastReceiver を作成
+    pub fn subscribe(&self) -> BroadcastReceiver<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receivers += 1;
+        BroadcastReceiver {
+            shared: self.shared.clone(),
+            next: state.head + state.buf.len() as u64,
+        }
+    }
+}
+
+impl<T> Clone for BroadcastSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        BroadcastSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for BroadcastSender<T> {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.shared.state.lock() else {
+            return;
+        };
+        state.senders -= 1;
+        if state.senders == 0 {
+            // 待機中の BroadcastReceiver をすべて起床させ、Disconnected をリターンさせる
+            self.shared.cond.notify_all();
+        }
+    }
+}
+
+// BroadcastSender が送信したデータをすべて受信する
+// 各 BroadcastReceiver は次に受信するデータの位置を個別に持ち、同じバッファを共有する
+pub struct BroadcastReceiver<T> {
+    shared: Arc<Shared<T>>,
+    next: u64, // 次に受信するデータの通し番号
+}
+
+impl<T: Clone> BroadcastReceiver<T> {
+    // 未受信のデータがない場合は、送信されるまで待機
+    // 受信が遅れてデータが上書きされた場合は Lagged をリターンし、次回は残っている最も古いデータから受信する
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if self.next < state.head {
+                let lagged = state.head - self.next;
+                self.next = state.head;
+                return Err(RecvError::Lagged(lagged));
+            }
+            let i = (self.next - state.head) as usize;
+            if let Some(data) = state.buf.get(i) {
+                self.next += 1;
+                return Ok(data.clone());
+            }
+            if state.senders == 0 {
+                return Err(RecvError::Disconnected);
+            }
+            state = self.shared.cond.wait(state).unwrap();
+        }
+    }
+}
+
+// 複製元と同じ位置から受信する BroadcastReceiver を作成
+impl<T> Clone for BroadcastReceiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().receivers += 1;
+        BroadcastReceiver {
+            shared: self.shared.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for BroadcastReceiver<T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.receivers -= 1;
+        }
+    }
+}
+
+// 各 BroadcastReceiver が直近 cap 個までのデータを保持できる broadcast チャネルを作成
+pub fn broadcast_channel<T: Clone>(cap: usize) -> (BroadcastSender<T>, BroadcastReceiver<T>) {
+    assert!(cap > 0);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            buf: VecDeque::with_capacity(cap),
+            head: 0,
+            senders: 1,
+            receivers: 1,
+        }),
+        cond: Condvar::new(),
+        cap,
+    });
+    let tx = BroadcastSender {
+        shared: shared.clone(),
+    };
+    let rx = BroadcastReceiver { shared, next: 0 };
+    (tx, rx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_broadcast() {
+        const NUM_ITEMS: usize = 1000;
+        let (tx, rx) = broadcast_channel(NUM_ITEMS);
+        let rx0 = tx.subscribe();
+
+        let v: Vec<_> = [rx, rx0]
+            .into_iter()
+            .map(|mut rx| {
+                std::thread::spawn(move || {
+                    let mut v = Vec::new();
+                    while let Ok(n) = rx.recv() {
+                        v.push(n);
+                    }
+                    v
+                })
+            })
+            .collect();
+
+        for i in 0..NUM_ITEMS {
+            assert_eq!(tx.send(i), Ok(2));
+        }
+        drop(tx);
+
+        // すべての BroadcastReceiver が、すべてのデータを送信順に受信する
+        for t in v {
+            assert_eq!(t.join().unwrap(), (0..NUM_ITEMS).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_broadcast_lagged() {
+        let (tx, mut rx) = broadcast_channel(2);
+        for i in 0..5 {
+            tx.send(i).unwrap();
+        }
+
+        // 上書きされた 3 個は受信できず、残っているデータから受信を再開する
+        assert_eq!(rx.recv(), Err(RecvError::Lagged(3)));
+        assert_eq!(rx.recv(), Ok(3));
+
+        // 複製した BroadcastReceiver は同じ位置から受信する
+        let mut rx0 = rx.clone();
+        assert_eq!(rx.recv(), Ok(4));
+        assert_eq!(rx0.recv(), Ok(4));
+
+        // subscribe 以前のデータは受信しない
+        let mut rx1 = tx.subscribe();
+        tx.send(5).unwrap();
+        assert_eq!(rx1.recv(), Ok(5));
+
+        drop(tx);
+        assert_eq!(rx.recv(), Ok(5));
+        assert_eq!(rx.recv(), Err(RecvError::Disconnected));
+
+        // BroadcastReceiver がすべて破棄されると送信できない
+        let (tx, rx) = broadcast_channel(2);
+        drop(rx);
+        assert_eq!(tx.send(1), Err(SendError(1)));
+    }
+}
@@ -1,23 +1,203 @@
 use std::{
+    cell::Cell,
     collections::LinkedList,
-    sync::{Arc, Condvar, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, Thread},
 };
 
 use crate::semaphore::Semaphore;
 
-#[derive(Clone)]
+// send のエラー。送信できなかったデータを返す
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError<T> {
+    Disconnected(T), // Receiver がすべて破棄された
+    Poisoned(T),     // バッファのロックを保持したスレッドがパニックした
+}
+
+impl<T> std::fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Disconnected(_) => write!(f, "sending on a disconnected channel"),
+            SendError::Poisoned(_) => write!(f, "channel is poisoned"),
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for SendError<T> {}
+
+// recv のエラー
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    Disconnected, // バッファが空で、Sender がすべて破棄された
+    Poisoned,     // バッファのロックを保持したスレッドがパニックした
+}
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecvError::Disconnected => write!(f, "receiving on a disconnected channel"),
+            RecvError::Poisoned => write!(f, "channel is poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+// try_send のエラー。送信できなかったデータを返す
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),         // バッファが一杯
+    Disconnected(T), // Receiver がすべて破棄された
+    Poisoned(T),     // バッファのロックを保持したスレッドがパニックした
+}
+
+impl<T> std::fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel is full"),
+            TrySendError::Disconnected(_) => write!(f, "sending on a disconnected channel"),
+            TrySendError::Poisoned(_) => write!(f, "channel is poisoned"),
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for TrySendError<T> {}
+
+impl<T> From<SendError<T>> for TrySendError<T> {
+    fn from(err: SendError<T>) -> Self {
+        match err {
+            SendError::Disconnected(data) => TrySendError::Disconnected(data),
+            SendError::Poisoned(data) => TrySendError::Poisoned(data),
+        }
+    }
+}
+
+// try_recv のエラー
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,        // バッファが空
+    Disconnected, // バッファが空で、Sender がすべて破棄された
+    Poisoned,     // バッファのロックを保持したスレッドがパニックした
+}
+
+impl std::fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel is empty"),
+            TryRecvError::Disconnected => write!(f, "receiving on a disconnected channel"),
+            TryRecvError::Poisoned => write!(f, "channel is poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for TryRecvError {}
+
 pub struct Sender<T> {
     semaphore: Arc<Semaphore>,      // 有限性を実現するセマフォ
     buf: Arc<Mutex<LinkedList<T>>>, // queue
     cond: Arc<Condvar>,
+    senders: Arc<AtomicUsize>,          // 生存している Sender の数
+    receivers: Arc<AtomicUsize>,        // 生存している Receiver の数
+    max: usize,                         // バッファの上限
+    selectors: Arc<Mutex<Vec<Thread>>>, // select2 で待機中のスレッド
 }
 
 impl<T: Send> Sender<T> {
-    pub fn send(&self, data: T) {
+    pub fn send(&self, data: T) -> Result<(), SendError<T>> {
         self.semaphore.wait();
-        let mut buf = self.buf.lock().unwrap();
+        self.push(data)
+    }
+
+    // バッファが一杯の場合は待機せずに data を返す
+    #[allow(dead_code)] // main では使用しない
+    pub fn try_send(&self, data: T) -> Result<(), TrySendError<T>> {
+        if !self.semaphore.try_wait() {
+            return Err(TrySendError::Full(data));
+        }
+        self.push(data).map_err(TrySendError::from)
+    }
+}
+
+impl<T> Sender<T> {
+    // バッファ中のデータ数
+    #[allow(dead_code)] // main では使用しない
+    pub fn len(&self) -> usize {
+        self.buf.lock().unwrap().len()
+    }
+
+    #[allow(dead_code)] // main では使用しない
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // channel に指定したバッファの上限
+    #[allow(dead_code)] // main では使用しない
+    pub fn capacity(&self) -> usize {
+        self.max
+    }
+
+    // セマフォを獲得した後にバッファへ追加
+    // 追加できない場合は、獲得したセマフォを解放して data を返す
+    fn push(&self, data: T) -> Result<(), SendError<T>> {
+        let Ok(mut buf) = self.buf.lock() else {
+            self.semaphore.post();
+            return Err(SendError::Poisoned(data));
+        };
+        if self.receivers.load(Ordering::Acquire) == 0 {
+            drop(buf);
+            // 待機中の他の Sender も起床させ、同様にエラーとさせる
+            self.semaphore.post();
+            return Err(SendError::Disconnected(data));
+        }
         buf.push_back(data);
         self.cond.notify_one();
+        drop(buf);
+        self.wake_selectors();
+        Ok(())
+    }
+
+    // select2 で待機中のスレッドをすべて起床させる
+    // 起床したスレッドは自身で受信を試み、受信できなければ再度待機する
+    fn wake_selectors(&self) {
+        for t in self.selectors.lock().unwrap().iter() {
+            t.unpark();
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.senders.fetch_add(1, Ordering::AcqRel);
+        Sender {
+            semaphore: self.semaphore.clone(),
+            buf: self.buf.clone(),
+            cond: self.cond.clone(),
+            senders: self.senders.clone(),
+            receivers: self.receivers.clone(),
+            max: self.max,
+            selectors: self.selectors.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // 最後の Sender が破棄されたため、待機中の Receiver をすべて起床させる
+            // recv はロック中に Sender の数を確認するため、ロックを獲得してから通知し起床漏れを防ぐ
+            let buf = self.buf.lock();
+            self.cond.notify_all();
+            drop(buf);
+            // select2 で待機中のスレッドも起床させる
+            if let Ok(selectors) = self.selectors.lock() {
+                for t in selectors.iter() {
+                    t.unpark();
+                }
+            }
+        }
     }
 }
 
@@ -25,35 +205,518 @@ pub struct Receiver<T> {
     semaphore: Arc<Semaphore>,
     buf: Arc<Mutex<LinkedList<T>>>,
     cond: Arc<Condvar>,
+    senders: Arc<AtomicUsize>,
+    receivers: Arc<AtomicUsize>,
+    max: usize,
+    selectors: Arc<Mutex<Vec<Thread>>>,
 }
 
 impl<T> Receiver<T> {
-    pub fn recv(&self) -> T {
-        let mut buf = self.buf.lock().unwrap();
+    // バッファが空で、Sender がすべて破棄されている場合は Disconnected をリターン
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut buf = self.buf.lock().map_err(|_| RecvError::Poisoned)?;
         loop {
             if let Some(data) = buf.pop_front() {
                 self.semaphore.post();
-                return data;
+                return Ok(data);
+            }
+            if self.senders.load(Ordering::Acquire) == 0 {
+                return Err(RecvError::Disconnected);
+            }
+            buf = self.cond.wait(buf).map_err(|_| RecvError::Poisoned)?;
+        }
+    }
+
+    // 少なくとも1つのデータを受信するまで待機し、その時点でバッファにあるデータを最大 max 個まとめて受信
+    // 1回のロックで受信するため、1つずつ recv するよりもロックの競合を減らせる
+    #[allow(dead_code)] // main では使用しない
+    pub fn recv_many(&self, max: usize) -> Result<Vec<T>, RecvError> {
+        assert!(max > 0);
+        let mut buf = self.buf.lock().map_err(|_| RecvError::Poisoned)?;
+        while buf.is_empty() {
+            if self.senders.load(Ordering::Acquire) == 0 {
+                return Err(RecvError::Disconnected);
+            }
+            buf = self.cond.wait(buf).map_err(|_| RecvError::Poisoned)?;
+        }
+
+        let n = max.min(buf.len());
+        let mut rest = buf.split_off(n);
+        std::mem::swap(&mut *buf, &mut rest);
+        drop(buf);
+        for _ in 0..n {
+            self.semaphore.post();
+        }
+        Ok(rest.into_iter().collect())
+    }
+
+    // バッファが空の場合は待機せずに Empty をリターン
+    #[allow(dead_code)] // main では使用しない
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut buf = self.buf.lock().map_err(|_| TryRecvError::Poisoned)?;
+        if let Some(data) = buf.pop_front() {
+            self.semaphore.post();
+            return Ok(data);
+        }
+        if self.senders.load(Ordering::Acquire) == 0 {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    // バッファ中のデータ数
+    #[allow(dead_code)] // main では使用しない
+    pub fn len(&self) -> usize {
+        self.buf.lock().unwrap().len()
+    }
+
+    #[allow(dead_code)] // main では使用しない
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // channel に指定したバッファの上限
+    #[allow(dead_code)] // main では使用しない
+    pub fn capacity(&self) -> usize {
+        self.max
+    }
+}
+
+// recv を繰り返し呼び出すイテレータ
+// 切断またはエラーの場合は None をリターン
+pub struct Iter<'a, T> {
+    rx: &'a Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+// Receiver を消費して recv を繰り返し呼び出すイテレータ
+pub struct IntoIter<T> {
+    rx: Receiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<T> Receiver<T> {
+    // Sender がすべて破棄されるまで受信し続けるイテレータをリターン
+    #[allow(dead_code)] // main では使用しない
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { rx: self }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { rx: self }
+    }
+}
+
+// 複数の Receiver で同じバッファから受信する
+// 各データはいずれか1つの Receiver のみが受信する
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.receivers.fetch_add(1, Ordering::AcqRel);
+        Receiver {
+            semaphore: self.semaphore.clone(),
+            buf: self.buf.clone(),
+            cond: self.cond.clone(),
+            senders: self.senders.clone(),
+            receivers: self.receivers.clone(),
+            max: self.max,
+            selectors: self.selectors.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.receivers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // 最後の Receiver が破棄されたため、受信されないデータを破棄してセマフォを解放する
+            // 解放により send で待機中の Sender が起床し、Disconnected をリターンする
+            let Ok(mut buf) = self.buf.lock() else {
+                return;
+            };
+            let n = buf.len();
+            buf.clear();
+            drop(buf);
+            for _ in 0..n {
+                self.semaphore.post();
             }
-            buf = self.cond.wait(buf).unwrap();
         }
     }
 }
 
+// select2 の結果。どちらの Receiver から受信したか
+#[derive(Debug, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<T> Receiver<T> {
+    // 現在のスレッドを、データが送信された際に起床させるスレッドとして登録
+    fn register_selector(&self) {
+        self.selectors.lock().unwrap().push(thread::current());
+    }
+
+    fn unregister_selector(&self) {
+        let id = thread::current().id();
+        let mut selectors = self.selectors.lock().unwrap();
+        if let Some(i) = selectors.iter().position(|t| t.id() == id) {
+            selectors.swap_remove(i);
+        }
+    }
+}
+
+thread_local! {
+    // select2 で先に受信を試みるのが rx_b か
+    static PREFER_RIGHT: Cell<bool> = const { Cell::new(false) };
+}
+
+// rx_a と rx_b のいずれかにデータが届くまで待機し、先に受信できた方をリターン
+// 両方にデータがある場合に一方ばかりが受信されないよう、呼び出すごとに先に試みる方を交互に入れ替える
+// 両方とも切断された場合は Disconnected をリターン
+#[allow(dead_code)] // main では使用しない
+pub fn select2<A, B>(rx_a: &Receiver<A>, rx_b: &Receiver<B>) -> Result<Either<A, B>, RecvError> {
+    let prefer_right = PREFER_RIGHT.with(|p| p.replace(!p.get()));
+
+    // 受信を試みる前に登録し、試みた後に送信されたデータの通知を取りこぼさないようにする
+    // unpark は park の前に呼ばれても有効なため、登録後の送信で必ず起床する
+    rx_a.register_selector();
+    rx_b.register_selector();
+    let try_a = || rx_a.try_recv().map(Either::Left);
+    let try_b = || rx_b.try_recv().map(Either::Right);
+    let result = loop {
+        let first = if prefer_right { try_b() } else { try_a() };
+        let (first, second) = match first {
+            Ok(data) => break Ok(data),
+            Err(err) => (err, if prefer_right { try_a() } else { try_b() }),
+        };
+        match (first, second) {
+            (_, Ok(data)) => break Ok(data),
+            (TryRecvError::Poisoned, _) | (_, Err(TryRecvError::Poisoned)) => {
+                break Err(RecvError::Poisoned)
+            }
+            (TryRecvError::Disconnected, Err(TryRecvError::Disconnected)) => {
+                break Err(RecvError::Disconnected)
+            }
+            _ => thread::park(),
+        }
+    };
+    rx_a.unregister_selector();
+    rx_b.unregister_selector();
+    result
+}
+
 pub fn channel<T>(max: isize) -> (Sender<T>, Receiver<T>) {
     assert!(max > 0);
-    let semaphore = Arc::new(Semaphore::new(max));
+    // 誤って余分に post されても、バッファの上限が増えないようにする
+    let semaphore = Arc::new(Semaphore::with_max(max, max));
     let buf = Arc::new(Mutex::new(LinkedList::new()));
     let cond = Arc::new(Condvar::new());
+    let senders = Arc::new(AtomicUsize::new(1));
+    let receivers = Arc::new(AtomicUsize::new(1));
+    let selectors = Arc::new(Mutex::new(Vec::new()));
     let tx = Sender {
         semaphore: semaphore.clone(),
         buf: buf.clone(),
         cond: cond.clone(),
+        senders: senders.clone(),
+        receivers: receivers.clone(),
+        max: max as usize,
+        selectors: selectors.clone(),
     };
     let rx = Receiver {
         semaphore,
         buf,
         cond,
+        senders,
+        receivers,
+        max: max as usize,
+        selectors,
     };
     (tx, rx)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_try_send_full() {
+        let (tx, rx) = channel(2);
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(tx.try_send(2), Ok(()));
+        // バッファが一杯の場合はデータが返る
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+
+        // 受信すると再び送信できる
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(tx.try_send(3), Ok(()));
+        assert_eq!(tx.try_send(4), Err(TrySendError::Full(4)));
+        assert_eq!(rx.recv(), Ok(2));
+        assert_eq!(rx.recv(), Ok(3));
+    }
+
+    #[test]
+    fn test_try_recv_empty() {
+        let (tx, rx) = channel(2);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        tx.send(1).unwrap();
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        // try_recv でもセマフォが解放され、バッファの上限まで送信できる
+        assert_eq!(tx.try_send(2), Ok(()));
+        assert_eq!(tx.try_send(3), Ok(()));
+        assert_eq!(tx.try_send(4), Err(TrySendError::Full(4)));
+    }
+
+    #[test]
+    fn test_sender_dropped() {
+        let (tx, rx) = channel(2);
+        let tx0 = tx.clone();
+        tx.send(1).unwrap();
+        drop(tx);
+
+        // Sender が残っている間は切断されない
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        // バッファが空になってから切断される
+        tx0.send(2).unwrap();
+        drop(tx0);
+        assert_eq!(rx.recv(), Ok(2));
+        assert_eq!(rx.recv(), Err(RecvError::Disconnected));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+
+        // 受信待ちの Receiver も起床して Disconnected をリターン
+        let (tx, rx) = channel::<i32>(2);
+        let t = std::thread::spawn(move || rx.recv());
+        std::thread::sleep(Duration::from_millis(50));
+        drop(tx);
+        assert_eq!(t.join().unwrap(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_receiver_dropped() {
+        let (tx, rx) = channel(1);
+        tx.send(1).unwrap();
+
+        // バッファが一杯で待機中の Sender も起床して Disconnected をリターン
+        let tx0 = tx.clone();
+        let t = std::thread::spawn(move || tx0.send(2));
+        std::thread::sleep(Duration::from_millis(50));
+        drop(rx);
+        assert_eq!(t.join().unwrap(), Err(SendError::Disconnected(2)));
+        assert_eq!(tx.send(3), Err(SendError::Disconnected(3)));
+        assert_eq!(tx.try_send(4), Err(TrySendError::Disconnected(4)));
+    }
+
+    #[test]
+    fn test_len() {
+        let (tx, rx) = channel(4);
+        assert_eq!(tx.capacity(), 4);
+        assert_eq!(rx.capacity(), 4);
+        assert!(rx.is_empty());
+
+        for i in 0..3 {
+            tx.send(i).unwrap();
+            assert_eq!(tx.len(), i + 1);
+        }
+        assert_eq!(rx.len(), 3);
+        assert!(!tx.is_empty());
+
+        rx.recv().unwrap();
+        assert_eq!(rx.len(), 2);
+        rx.try_recv().unwrap();
+        rx.recv().unwrap();
+        assert!(tx.is_empty());
+        assert_eq!(tx.capacity(), 4);
+    }
+
+    #[test]
+    fn test_multiple_receivers() {
+        const NUM_ITEMS: usize = 10000;
+        let (tx, rx) = channel(4);
+
+        let receivers: Vec<_> = (0..2)
+            .map(|_| {
+                let rx = rx.clone();
+                std::thread::spawn(move || {
+                    let mut v = Vec::new();
+                    while let Ok(n) = rx.recv() {
+                        v.push(n);
+                    }
+                    v
+                })
+            })
+            .collect();
+        drop(rx);
+
+        for i in 0..NUM_ITEMS {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+
+        // 重複や欠落なく、2つの Receiver に分配される
+        let mut all = Vec::new();
+        for t in receivers {
+            let v = t.join().unwrap();
+            // 各 Receiver 内では送信順に受信する
+            assert!(v.windows(2).all(|w| w[0] < w[1]));
+            all.extend(v);
+        }
+        all.sort();
+        assert_eq!(all, (0..NUM_ITEMS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_select2() {
+        let (tx_a, rx_a) = channel::<i32>(4);
+        let (tx_b, rx_b) = channel(4);
+
+        // データがある方から受信する
+        tx_b.send("b").unwrap();
+        assert_eq!(select2(&rx_a, &rx_b), Ok(Either::Right("b")));
+
+        // どちらにもデータがない場合は、送信されるまで待機する
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            tx_b.send("b").unwrap();
+            tx_b
+        });
+        assert_eq!(select2(&rx_a, &rx_b), Ok(Either::Right("b")));
+        let tx_b = t.join().unwrap();
+
+        // 両方にデータがある場合は交互に受信し、一方ばかりが受信されない
+        for i in 0..2 {
+            tx_a.send(i).unwrap();
+            tx_b.send("b").unwrap();
+        }
+        let mut left = 0;
+        for _ in 0..2 {
+            if let Ok(Either::Left(_)) = select2(&rx_a, &rx_b) {
+                left += 1;
+            }
+        }
+        assert_eq!(left, 1);
+
+        // 一方が切断されても、もう一方から受信できる
+        while rx_a.try_recv().is_ok() {}
+        while rx_b.try_recv().is_ok() {}
+        drop(tx_a);
+        tx_b.send("b").unwrap();
+        assert_eq!(select2(&rx_a, &rx_b), Ok(Either::Right("b")));
+
+        // 両方が切断されると Disconnected をリターン
+        drop(tx_b);
+        assert_eq!(select2(&rx_a, &rx_b), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_recv_many() {
+        let (tx, rx) = channel(100);
+        for i in 0..100 {
+            tx.send(i).unwrap();
+        }
+
+        // 最大 50 個まとめて、送信順に受信する
+        assert_eq!(rx.recv_many(50), Ok((0..50).collect()));
+        assert_eq!(rx.len(), 50);
+
+        // 受信した分だけ送信できる
+        for i in 100..150 {
+            tx.try_send(i).unwrap();
+        }
+        assert_eq!(tx.try_send(150), Err(TrySendError::Full(150)));
+
+        let v = rx.recv_many(1000).unwrap();
+        assert_eq!(v, (50..150).collect::<Vec<_>>());
+
+        // データがない場合は送信されるまで待機する
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            tx.send(150).unwrap();
+        });
+        assert_eq!(rx.recv_many(50), Ok(vec![150]));
+        t.join().unwrap();
+        assert_eq!(rx.recv_many(50), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_poisoned() {
+        let (tx, rx) = channel(2);
+        tx.send(1).unwrap();
+
+        // バッファのロックを保持したままパニックさせる
+        let buf = rx.buf.clone();
+        std::thread::spawn(move || {
+            let _buf = buf.lock().unwrap();
+            panic!("poison");
+        })
+        .join()
+        .unwrap_err();
+
+        // パニックせずにエラーをリターンし、送信できなかったデータを返す
+        assert_eq!(tx.send(2), Err(SendError::Poisoned(2)));
+        assert_eq!(tx.try_send(3), Err(TrySendError::Poisoned(3)));
+        assert_eq!(rx.recv(), Err(RecvError::Poisoned));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Poisoned));
+
+        // 送信に失敗した分のセマフォは解放されている
+        assert_eq!(tx.try_send(4), Err(TrySendError::Poisoned(4)));
+    }
+
+    #[test]
+    fn test_iter() {
+        let (tx, rx) = channel(4);
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+        // 切断されるとイテレータが終了する
+        assert_eq!(rx.into_iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let (tx, rx) = channel(4);
+        let t = std::thread::spawn(move || {
+            for i in 0..3 {
+                tx.send(i).unwrap();
+            }
+        });
+        let mut v = Vec::new();
+        for n in &rx {
+            v.push(n);
+        }
+        t.join().unwrap();
+        assert_eq!(v, vec![0, 1, 2]);
+        assert_eq!(rx.iter().next(), None);
+    }
+}
@@ -1,12 +1,8 @@
+#[allow(dead_code)] // main では使用しない
+mod broadcast;
 mod channel;
 mod semaphore;
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
-};
-
 use channel::channel;
-use semaphore::Semaphore;
 
 const NUM_LOOP: usize = 100000;
 const NUM_THREADS: usize = 8;
@@ -18,7 +14,7 @@ fn main() {
     let t = std::thread::spawn(move || {
         let mut cnt = 0;
         while cnt < NUM_THREADS * NUM_LOOP {
-            let n = rx.recv();
+            let n = rx.recv().unwrap();
             println!("recv: n = {:?}", n);
             cnt += 1;
         }
@@ -30,7 +26,7 @@ fn main() {
         let tx0 = tx.clone();
         let t = std::thread::spawn(move || {
             for j in 0..NUM_LOOP {
-                tx0.send((i, j))
+                tx0.send((i, j)).unwrap();
             }
         });
         v.push(t);
@@ -1,34 +1,234 @@
-use std::sync::{Condvar, Mutex};
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+struct State {
+    cnt: isize,           // 獲得されている数
+    queue: VecDeque<u64>, // 公平モードで待機中のチケット。到着順
+    next_ticket: u64,     // 次に発行するチケット
+}
 
 pub struct Semaphore {
-    mutex: Mutex<isize>,
+    mutex: Mutex<State>,
     cond: Condvar,
     max: isize,
+    saturate: bool, // post でカウントが 0 未満にならないようにするか
+    fair: bool,     // 到着順に獲得させるか
 }
 
 impl Semaphore {
+    #[allow(dead_code)] // main では使用しない
     pub fn new(max: isize) -> Self {
+        Semaphore::build(0, max, false, false)
+    }
+
+    // 初期状態で initial 個獲得でき、post しても max 個より多くは獲得できないセマフォ
+    // wait より多く post されても、同時に獲得できる数が max を超えない
+    pub fn with_max(initial: isize, max: isize) -> Self {
+        assert!(0 <= initial && initial <= max);
+        Semaphore::build(max - initial, max, true, false)
+    }
+
+    // wait を呼び出した順に獲得させる公平なセマフォ
+    // Condvar の notify_one はどのスレッドを起床させるか保証しないため、チケットの待ち行列で順番を管理する
+    #[allow(dead_code)] // main では使用しない
+    pub fn new_fair(max: isize) -> Self {
+        Semaphore::build(0, max, false, true)
+    }
+
+    fn build(cnt: isize, max: isize, saturate: bool, fair: bool) -> Self {
         Semaphore {
-            mutex: Mutex::new(0),
+            mutex: Mutex::new(State {
+                cnt,
+                queue: VecDeque::new(),
+                next_ticket: 0,
+            }),
             cond: Condvar::new(),
             max,
+            saturate,
+            fair,
+        }
+    }
+
+    // 公平モードの場合は待ち行列に並び、チケットをリターン
+    fn enqueue(&self, state: &mut State) -> Option<u64> {
+        if !self.fair {
+            return None;
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.push_back(ticket);
+        Some(ticket)
+    }
+
+    // カウントが最大値未満で、公平モードの場合は ticket の順番が来ているか
+    fn ready(&self, state: &State, ticket: Option<u64>) -> bool {
+        state.cnt < self.max && (ticket.is_none() || state.queue.front() == ticket.as_ref())
+    }
+
+    // 獲得し、公平モードの場合は待ち行列から抜ける
+    fn acquire(&self, state: &mut State, ticket: Option<u64>) {
+        state.cnt += 1;
+        if ticket.is_some() {
+            state.queue.pop_front();
+            // 次のチケットのスレッドも獲得できる可能性があるため起床させる
+            self.cond.notify_all();
         }
     }
 
     pub fn wait(&self) {
         // カウントが最大値以上なら待機
-        let mut cnt = self.mutex.lock().unwrap();
-        while *cnt >= self.max {
-            cnt = self.cond.wait(cnt).unwrap();
+        let mut state = self.mutex.lock().unwrap();
+        let ticket = self.enqueue(&mut state);
+        while !self.ready(&state, ticket) {
+            state = self.cond.wait(state).unwrap();
         }
-        *cnt += 1;
+        self.acquire(&mut state, ticket);
+    }
+
+    // カウントが最大値未満の場合のみ獲得し、待機せずにリターン
+    // 公平モードでは、待機中のスレッドがいる場合も獲得しない
+    pub fn try_wait(&self) -> bool {
+        let mut state = self.mutex.lock().unwrap();
+        if state.cnt >= self.max || !state.queue.is_empty() {
+            return false;
+        }
+        state.cnt += 1;
+        true
+    }
+
+    // dur 経過しても獲得できない場合は false をリターン
+    #[allow(dead_code)] // main では使用しない
+    pub fn wait_timeout(&self, dur: Duration) -> bool {
+        let mut state = self.mutex.lock().unwrap();
+        let ticket = self.enqueue(&mut state);
+        let (mut state, _) = self
+            .cond
+            .wait_timeout_while(state, dur, |state| !self.ready(state, ticket))
+            .unwrap();
+        // タイムアウトと同時に解放された場合も獲得できる
+        if self.ready(&state, ticket) {
+            self.acquire(&mut state, ticket);
+            return true;
+        }
+
+        // 待ち行列から抜け、後ろのスレッドが先頭になった場合に獲得できるようにする
+        if let Some(ticket) = ticket {
+            state.queue.retain(|&t| t != ticket);
+            self.cond.notify_all();
+        }
+        false
     }
 
     pub fn post(&self) {
-        let mut cnt = self.mutex.lock().unwrap();
-        *cnt -= 1;
-        if *cnt <= self.max {
-            self.cond.notify_one();
+        let mut state = self.mutex.lock().unwrap();
+        if self.saturate && state.cnt <= 0 {
+            // 獲得されていない場合は何もしない
+            return;
+        }
+        state.cnt -= 1;
+        if state.cnt <= self.max {
+            if self.fair {
+                // 待ち行列の先頭のスレッドを確実に起床させる
+                self.cond.notify_all();
+            } else {
+                self.cond.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{sync::Arc, time::Instant};
+
+    #[test]
+    fn test_try_wait() {
+        let sem = Semaphore::new(2);
+        assert!(sem.try_wait());
+        assert!(sem.try_wait());
+        // 上限に達している場合は獲得できない
+        assert!(!sem.try_wait());
+
+        sem.post();
+        assert!(sem.try_wait());
+        assert!(!sem.try_wait());
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let sem = Arc::new(Semaphore::new(1));
+        sem.wait();
+
+        // 解放されない場合はタイムアウトする
+        let start = Instant::now();
+        assert!(!sem.wait_timeout(Duration::from_millis(50)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // タイムアウト前に解放されると獲得できる
+        let sem0 = sem.clone();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            sem0.post();
+        });
+        assert!(sem.wait_timeout(Duration::from_secs(10)));
+        t.join().unwrap();
+
+        // 獲得数は wait と同様に数えられる
+        assert!(!sem.try_wait());
+        sem.post();
+        assert!(sem.wait_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_with_max() {
+        let sem = Semaphore::with_max(1, 2);
+        assert!(sem.try_wait());
+        assert!(!sem.try_wait());
+
+        // max を超えて post しても、獲得できるのは max 個まで
+        for _ in 0..5 {
+            sem.post();
+        }
+        assert_eq!(sem.mutex.lock().unwrap().cnt, 0);
+        assert!(sem.try_wait());
+        assert!(sem.try_wait());
+        assert!(!sem.try_wait());
+    }
+
+    #[test]
+    fn test_fair() {
+        const NUM_THREADS: usize = 8;
+        let sem = Arc::new(Semaphore::new_fair(1));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        sem.wait();
+
+        // 1つずつ待ち行列に並ばせる
+        let mut v = Vec::new();
+        for i in 0..NUM_THREADS {
+            let sem0 = sem.clone();
+            let order0 = order.clone();
+            v.push(std::thread::spawn(move || {
+                sem0.wait();
+                order0.lock().unwrap().push(i);
+                sem0.post();
+            }));
+            while sem.mutex.lock().unwrap().queue.len() <= i {
+                std::thread::yield_now();
+            }
+        }
+        // タイムアウトしたスレッドは待ち行列から抜ける
+        assert!(!sem.wait_timeout(Duration::from_millis(10)));
+        assert_eq!(sem.mutex.lock().unwrap().queue.len(), NUM_THREADS);
+        sem.post();
+
+        for t in v {
+            t.join().unwrap();
         }
+        // 到着順に獲得する
+        assert_eq!(*order.lock().unwrap(), (0..NUM_THREADS).collect::<Vec<_>>());
     }
 }